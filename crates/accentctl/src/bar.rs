@@ -0,0 +1,134 @@
+use clap::ValueEnum;
+
+/// Output format for `accentctl watch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BarFormat {
+    /// "it enabled", one line per change
+    Plain,
+    /// Waybar custom module JSON (`return-type = "json"`)
+    Waybar,
+    /// i3blocks persistent block with pango markup (`interval=persist`, `markup=pango`)
+    I3blocks,
+    /// Polybar `custom/script` with `tail = true`, using polybar format tags
+    Polybar,
+}
+
+/// Dimmed color for the indicator when accents are off or suspended.
+const INACTIVE_COLOR: &str = "#888888";
+
+/// What the indicator shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indicator<'a> {
+    Enabled(&'a str),
+    Suspended(&'a str),
+    Disabled(&'a str),
+    /// Daemon not reachable.
+    Offline,
+}
+
+impl<'a> Indicator<'a> {
+    pub fn new(enabled: bool, suspended: bool, locale: &'a str) -> Self {
+        match (enabled, suspended) {
+            (false, _) => Indicator::Disabled(locale),
+            (true, true) => Indicator::Suspended(locale),
+            (true, false) => Indicator::Enabled(locale),
+        }
+    }
+
+    fn state(&self) -> &'static str {
+        match self {
+            Indicator::Enabled(_) => "enabled",
+            Indicator::Suspended(_) => "suspended",
+            Indicator::Disabled(_) => "disabled",
+            Indicator::Offline => "offline",
+        }
+    }
+
+    /// Short label, e.g. "IT".
+    fn label(&self) -> String {
+        match self {
+            Indicator::Enabled(l) | Indicator::Suspended(l) | Indicator::Disabled(l) => l.to_uppercase(),
+            Indicator::Offline => "--".into(),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        matches!(self, Indicator::Enabled(_))
+    }
+}
+
+/// Render one status line in the given format (without trailing newline).
+pub fn render(format: BarFormat, indicator: Indicator) -> String {
+    let label = indicator.label();
+    let state = indicator.state();
+    match format {
+        BarFormat::Plain => match indicator {
+            Indicator::Offline => "offline".into(),
+            _ => format!("{} {}", label.to_lowercase(), state),
+        },
+        BarFormat::Waybar => serde_json::json!({
+            "text": label,
+            "alt": state,
+            "class": state,
+            "tooltip": format!("accentd: {}", state),
+        })
+        .to_string(),
+        BarFormat::I3blocks if indicator.is_active() => label,
+        BarFormat::I3blocks => {
+            format!("<span foreground=\"{}\">{}</span>", INACTIVE_COLOR, escape_pango(&label))
+        }
+        BarFormat::Polybar if indicator.is_active() => label,
+        BarFormat::Polybar => format!("%{{F{}}}{}%{{F-}}", INACTIVE_COLOR, label),
+    }
+}
+
+fn escape_pango(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // --- spec: status bar output ---
+
+    #[test]
+    fn plain_shows_locale_and_state() {
+        assert_eq!(render(BarFormat::Plain, Indicator::new(true, false, "it")), "it enabled");
+        assert_eq!(render(BarFormat::Plain, Indicator::new(false, false, "fr")), "fr disabled");
+        assert_eq!(render(BarFormat::Plain, Indicator::Offline), "offline");
+    }
+
+    #[test]
+    fn waybar_emits_json_with_class() {
+        let line = render(BarFormat::Waybar, Indicator::new(true, true, "de"));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["text"], "DE");
+        assert_eq!(value["class"], "suspended");
+    }
+
+    #[test]
+    fn i3blocks_dims_inactive_with_pango() {
+        assert_eq!(render(BarFormat::I3blocks, Indicator::new(true, false, "it")), "IT");
+        assert_eq!(
+            render(BarFormat::I3blocks, Indicator::new(false, false, "it")),
+            "<span foreground=\"#888888\">IT</span>"
+        );
+    }
+
+    #[test]
+    fn polybar_dims_inactive_with_format_tags() {
+        assert_eq!(render(BarFormat::Polybar, Indicator::new(true, false, "es")), "ES");
+        assert_eq!(render(BarFormat::Polybar, Indicator::Offline), "%{F#888888}--%{F-}");
+    }
+
+    #[test]
+    fn disabled_wins_over_suspended() {
+        assert_eq!(Indicator::new(false, true, "it"), Indicator::Disabled("it"));
+    }
+
+    #[test]
+    fn pango_markup_is_escaped() {
+        assert_eq!(escape_pango("a<b>&"), "a&lt;b&gt;&amp;");
+    }
+}
@@ -1,9 +1,16 @@
-use accentd_core::config;
-use accentd_core::ipc::{self, ClientMsg, DaemonMsg};
+mod bar;
+
+use accentd_client::{Client, Event, Feed, Subscription};
+use accentd_core::charmap::LocaleMap;
+use accentd_core::config::Config;
+use accentd_core::ipc::{self, ClientMsg, DaemonMsg, DeviceStatus, LocaleInfo, LocaleSource, SuspendReason};
+use accentd_core::locale::{LocaleFile, LocaleMeta, Severity};
+use accentd_core::usage::UsageStats;
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
-use std::io::{BufRead, BufReader, Write};
-use std::os::unix::net::UnixStream;
+use bar::{BarFormat, Indicator};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 #[command(name = "accentctl", about = "Control the accentd daemon")]
@@ -24,20 +31,111 @@ enum Command {
     Toggle,
     /// Set the active locale
     SetLocale {
-        /// Locale name (e.g., it, es, fr, de, pt)
+        /// Locale name (e.g., it, es, fr, de, pt), or several to merge (it,de)
         locale: String,
     },
+    /// Print the status on every change, for status bars
+    Watch {
+        /// Output format
+        #[arg(long, value_enum, default_value = "plain")]
+        format: BarFormat,
+        /// Also print a line whenever the popup opens or closes, with why
+        #[arg(long)]
+        popups: bool,
+    },
+    /// Check a locale file and report problems by line and column
+    ValidateLocale {
+        file: PathBuf,
+    },
+    /// Make locale files from other systems' accent definitions
+    Locale {
+        #[command(subcommand)]
+        action: LocaleAction,
+    },
+    /// Back up or restore the learned candidate usage
+    Stats {
+        #[command(subcommand)]
+        action: StatsAction,
+    },
+    /// Measure how long you hold keys while typing and suggest a threshold
+    Calibrate {
+        /// How long to record normal typing
+        #[arg(long, default_value_t = 60)]
+        secs: u64,
+        /// Use the suggested threshold right away (until the daemon restarts)
+        #[arg(long)]
+        apply: bool,
+    },
+    /// Print every key event the daemon sees and what it decided (needs
+    /// allow_monitor = true in the daemon's config)
+    Monitor,
+    /// Show every key of the active locale in the popup, until Esc
+    CheatSheet,
+    /// Grab keyboards plugged in since the daemon started and let go of
+    /// unplugged ones
+    Rescan,
+    /// Print the JSON Schemas of the IPC protocol (this version)
+    Schema {
+        /// Only one direction
+        #[arg(value_enum)]
+        direction: Option<Direction>,
+    },
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+#[derive(Subcommand)]
+enum StatsAction {
+    /// Write the usage stats as JSON
+    Export {
+        /// Output file (default: stdout)
+        file: Option<PathBuf>,
+    },
+    /// Load usage stats from a JSON file written by `export`
+    Import {
+        file: PathBuf,
+        /// Add to the current stats instead of replacing them
+        #[arg(long)]
+        merge: bool,
+    },
+}
 
-    let socket_path = config::socket_path();
-    let stream = UnixStream::connect(&socket_path)
-        .with_context(|| format!("connecting to accentd at {}\nIs the daemon running?", socket_path.display()))?;
+#[derive(Subcommand)]
+enum LocaleAction {
+    /// List the locales set-locale accepts and where each comes from
+    List,
+    /// Turn the dead-key sequences of an XCompose file into a locale
+    ImportXcompose {
+        /// Compose file (default: ~/.XCompose)
+        file: Option<PathBuf>,
+        /// Save as ~/.config/accentd/locales/NAME.toml instead of printing
+        #[arg(long)]
+        name: Option<String>,
+        /// BCP 47 language tag for the locale's metadata
+        #[arg(long, default_value = "und")]
+        language: String,
+    },
+    /// Turn a macOS press-and-hold plist (Keyboard-*.plist) into a locale
+    ImportPressAndHold {
+        file: PathBuf,
+        /// Save as ~/.config/accentd/locales/NAME.toml instead of printing
+        #[arg(long)]
+        name: Option<String>,
+        /// BCP 47 language tag for the locale's metadata
+        #[arg(long, default_value = "und")]
+        language: String,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Direction {
+    /// Messages clients send (ClientMsg)
+    Client,
+    /// Messages the daemon sends (DaemonMsg)
+    Daemon,
+}
 
-    let mut writer = stream.try_clone().context("cloning stream")?;
-    let reader = BufReader::new(stream);
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
 
     let msg: ClientMsg = match cli.command {
         Command::Status => ClientMsg::GetStatus,
@@ -45,40 +143,420 @@ fn main() -> Result<()> {
         Command::Disable => ClientMsg::Disable,
         Command::Toggle => ClientMsg::Toggle,
         Command::SetLocale { locale } => ClientMsg::SetLocale { locale },
+        Command::CheatSheet => ClientMsg::ShowCheatSheet,
+        Command::Rescan => ClientMsg::Rescan,
+        Command::Calibrate { secs, apply } => {
+            eprintln!("Type normally for {}s; accentd is measuring how long you hold keys...", secs);
+            ClientMsg::Calibrate { secs, apply }
+        }
+        Command::Watch { format, popups } => return watch(format, popups).await,
+        Command::Schema { direction } => return schema(direction),
+        Command::Monitor => return monitor().await,
+        Command::ValidateLocale { file } => return validate_locale(file),
+        Command::Locale { action: LocaleAction::List } => ClientMsg::ListLocales,
+        Command::Locale { action: LocaleAction::ImportXcompose { file, name, language } } => {
+            return import_xcompose(file, name, language)
+        }
+        Command::Locale { action: LocaleAction::ImportPressAndHold { file, name, language } } => {
+            return import_press_and_hold(file, name, language)
+        }
+        Command::Stats { action: StatsAction::Export { file } } => return export_stats(file).await,
+        Command::Stats { action: StatsAction::Import { file, merge } } => {
+            let json = std::fs::read_to_string(&file)
+                .with_context(|| format!("reading {}", file.display()))?;
+            let stats = UsageStats::from_json(&json)
+                .with_context(|| format!("parsing {}", file.display()))?;
+            ClientMsg::ImportStats { stats, merge }
+        }
     };
 
-    let line = ipc::encode(&msg);
-    writer
-        .write_all(line.as_bytes())
-        .context("sending command")?;
-
-    // Read response
-    for line in reader.lines() {
-        let line = line.context("reading response")?;
-        if let Some(resp) = ipc::decode_daemon(&line) {
-            match resp {
-                DaemonMsg::Status {
-                    enabled,
-                    locale,
-                    version,
-                } => {
-                    println!("accentd v{}", version);
-                    println!("  enabled: {}", enabled);
-                    println!("  locale:  {}", locale);
-                }
-                DaemonMsg::Ack { ok, message } => {
-                    if ok {
-                        println!("{}", message);
-                    } else {
-                        eprintln!("error: {}", message);
-                        std::process::exit(1);
-                    }
-                }
-                _ => {}
+    let mut client = Client::connect().await.context("Is the daemon running?")?;
+    match client.request(&msg).await? {
+        DaemonMsg::Status {
+            enabled,
+            locale,
+            version,
+            suspended,
+            suspended_by,
+            uptime_secs,
+            backend,
+            devices,
+            popup_clients,
+        } => {
+            println!("accentd v{}", version);
+            println!("  enabled: {}", enabled);
+            println!("  locale:  {}", locale);
+            if suspended {
+                println!("  {}", describe_suspension(&suspended_by));
+            }
+            println!("  uptime:  {}", format_uptime(uptime_secs));
+            println!("  backend: {}", backend);
+            println!("  popups:  {} connected", popup_clients);
+            println!("  devices:");
+            for device in devices {
+                println!("    {}", describe_device(&device));
             }
-            break;
         }
+        DaemonMsg::Locales { locales } => {
+            let width = locales.iter().map(|l| l.name.len()).max().unwrap_or(0);
+            for locale in locales {
+                println!("{}", describe_locale(&locale, width));
+            }
+        }
+        DaemonMsg::Calibration { samples, p99_ms, suggested_ms, current_ms, applied } => {
+            println!("{}", describe_calibration(samples, p99_ms, suggested_ms, current_ms, applied));
+        }
+        DaemonMsg::Ack { ok, message } => {
+            if ok {
+                println!("{}", message);
+            } else {
+                eprintln!("error: {}", message);
+                std::process::exit(1);
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Subscribe to status changes and print one line per change. Keeps running
+/// across daemon restarts, showing an offline indicator in between.
+async fn watch(format: BarFormat, popups: bool) -> Result<()> {
+    let mut stdout = std::io::stdout();
+    let mut last = None;
+    let mut sub = Subscription::new(Feed::Status);
+
+    loop {
+        let out = match sub.next().await {
+            Event::Message(DaemonMsg::Status { enabled, locale, suspended, .. }) => {
+                bar::render(format, Indicator::new(enabled, suspended, &locale))
+            }
+            Event::Disconnected => bar::render(format, Indicator::Offline),
+            Event::Message(msg) if popups => match describe_popup(&msg) {
+                Some(line) => {
+                    writeln!(stdout, "{}", line).context("writing status")?;
+                    stdout.flush().context("writing status")?;
+                    continue;
+                }
+                None => continue,
+            },
+            _ => continue,
+        };
+        print_changed(&mut stdout, &mut last, out)?;
+    }
+}
+
+async fn export_stats(file: Option<PathBuf>) -> Result<()> {
+    let mut client = Client::connect().await.context("Is the daemon running?")?;
+    let DaemonMsg::Stats { stats } = client.request(&ClientMsg::ExportStats).await? else {
+        anyhow::bail!("unexpected reply from accentd");
+    };
+    let json = serde_json::to_string_pretty(&stats).context("encoding stats")?;
+    match file {
+        Some(path) => std::fs::write(&path, json + "\n")
+            .with_context(|| format!("writing {}", path.display()))?,
+        None => println!("{}", json),
     }
+    Ok(())
+}
+
+async fn monitor() -> Result<()> {
+    let mut client = Client::connect().await.context("Is the daemon running?")?;
+    match client.request(&ClientMsg::Monitor).await? {
+        DaemonMsg::Ack { ok: true, .. } => {}
+        DaemonMsg::Ack { message, .. } => anyhow::bail!(message),
+        _ => anyhow::bail!("unexpected reply from accentd"),
+    }
+    while let Some(msg) = client.recv().await? {
+        if let Some(line) = describe_monitor(&msg) {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
 
+fn validate_locale(file: PathBuf) -> Result<()> {
+    let text = std::fs::read_to_string(&file).with_context(|| format!("reading {}", file.display()))?;
+    let problems = accentd_core::locale::lint(&text);
+    for problem in &problems {
+        println!("{}:{}", file.display(), problem);
+    }
+    let errors = problems.iter().filter(|p| p.severity == Severity::Error).count();
+    anyhow::ensure!(errors == 0, "{} error(s) in {}", errors, file.display());
+    if problems.is_empty() {
+        println!("{}: ok", file.display());
+    }
     Ok(())
 }
+
+fn import_xcompose(file: Option<PathBuf>, name: Option<String>, language: String) -> Result<()> {
+    let file = match file {
+        Some(file) => file,
+        None => PathBuf::from(std::env::var("HOME").context("HOME is not set")?).join(".XCompose"),
+    };
+    let keys = accentd_core::import::load_xcompose(&file)?;
+    anyhow::ensure!(!keys.is_empty(), "no dead-key sequences for accentd's letters in {}", file.display());
+    save_locale(keys, &file, name, language)
+}
+
+fn import_press_and_hold(file: PathBuf, name: Option<String>, language: String) -> Result<()> {
+    let bytes = std::fs::read(&file).with_context(|| format!("reading {}", file.display()))?;
+    let keys = accentd_core::import::press_and_hold(&bytes).with_context(|| format!("parsing {}", file.display()))?;
+    anyhow::ensure!(!keys.is_empty(), "no Roman-Accent entries for accentd's letters in {}", file.display());
+    save_locale(keys, &file, name, language)
+}
+
+/// Print the locale imported from `file`, or with a `name` write it where
+/// `active = "NAME"` finds it.
+fn save_locale(keys: LocaleMap, file: &Path, name: Option<String>, language: String) -> Result<()> {
+    let meta = LocaleMeta {
+        name: name.clone().unwrap_or_else(|| "imported".into()),
+        language,
+        description: Some(format!("Imported from {}", file.display())),
+        version: Some("1.0".into()),
+        ..Default::default()
+    };
+    let locale = LocaleFile { meta: Some(meta), keys, ..Default::default() };
+    let toml = locale.to_toml()?;
+    let Some(name) = name else {
+        print!("{}", toml);
+        return Ok(());
+    };
+    let dir = Config::config_dir().join("locales");
+    std::fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+    let path = dir.join(format!("{}.toml", name));
+    std::fs::write(&path, toml).with_context(|| format!("writing {}", path.display()))?;
+    println!("Wrote {} ({} keys); use it with `accentctl set-locale {}`", path.display(), locale.keys.len(), name);
+    Ok(())
+}
+
+fn schema(direction: Option<Direction>) -> Result<()> {
+    let mut schemas = ipc::schemas();
+    let out = match direction {
+        None => schemas,
+        Some(Direction::Client) => schemas["ClientMsg"].take(),
+        Some(Direction::Daemon) => schemas["DaemonMsg"].take(),
+    };
+    println!("{}", serde_json::to_string_pretty(&out).context("encoding schema")?);
+    Ok(())
+}
+
+/// "3d 4h", "2h 13m", "5m 2s" or "40s".
+fn format_uptime(secs: u64) -> String {
+    let (d, h, m, s) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    match (d, h, m) {
+        (0, 0, 0) => format!("{}s", s),
+        (0, 0, _) => format!("{}m {}s", m, s),
+        (0, _, _) => format!("{}h {}m", h, m),
+        _ => format!("{}d {}h", d, h),
+    }
+}
+
+/// e.g. "AT Translated Set 2 keyboard (/dev/input/event3): enabled, 1234 events"
+fn describe_device(device: &DeviceStatus) -> String {
+    let state = match (device.grabbed, device.enabled, &device.error) {
+        (false, _, Some(error)) => format!("not grabbed ({})", error),
+        (false, _, None) => "not grabbed".into(),
+        (true, true, _) => "enabled".into(),
+        (true, false, _) => "disabled".into(),
+    };
+    let name = if device.name.is_empty() { "?" } else { &device.name };
+    format!("{} ({}): {}, {} events", name, device.path, state, device.events)
+}
+
+/// `suspended (locked, idle)`; just `suspended` from daemons that don't
+/// say why.
+fn describe_suspension(reasons: &[SuspendReason]) -> String {
+    if reasons.is_empty() {
+        return "suspended".into();
+    }
+    let reasons: Vec<&str> = reasons
+        .iter()
+        .map(|reason| match reason {
+            SuspendReason::Fullscreen => "fullscreen window",
+            SuspendReason::Locked => "locked",
+            SuspendReason::Idle => "idle",
+        })
+        .collect();
+    format!("suspended ({})", reasons.join(", "))
+}
+
+/// The calibration result and what to do with it.
+/// `* fr       builtin`, or a file's path and description; `*` marks the
+/// active ones.
+fn describe_locale(locale: &LocaleInfo, width: usize) -> String {
+    let mark = if locale.active { '*' } else { ' ' };
+    let source = match (locale.source, &locale.path) {
+        (LocaleSource::File, Some(path)) => path.as_str(),
+        (LocaleSource::Config, _) => "config.toml",
+        _ => "builtin",
+    };
+    let mut line = format!("{} {:width$}  {}", mark, locale.name, source, width = width);
+    if let Some(description) = &locale.description {
+        line = format!("{}  ({})", line, description);
+    }
+    line
+}
+
+fn describe_calibration(
+    samples: usize,
+    p99_ms: Option<u64>,
+    suggested_ms: Option<u64>,
+    current_ms: u64,
+    applied: bool,
+) -> String {
+    let Some(suggested) = suggested_ms else {
+        return format!(
+            "only {} key holds measured, not enough for a suggestion; type more or use a longer --secs",
+            samples
+        );
+    };
+    let mut out = format!(
+        "{} key holds measured, 99% under {} ms\nsuggested threshold_ms: {} (current {})",
+        samples,
+        p99_ms.unwrap_or(0),
+        suggested,
+        current_ms
+    );
+    if applied {
+        out.push_str("\napplied until accentd restarts");
+    }
+    out.push_str(&format!("\nto keep it, set threshold_ms = {} under [general] in config.toml", suggested));
+    out
+}
+
+/// One line for a popup message, e.g. "popup e: è é" or "popup closed (timeout)".
+fn describe_popup(msg: &DaemonMsg) -> Option<String> {
+    match msg {
+        DaemonMsg::ShowPopup { base, accents, .. } => Some(format!("popup {}: {}", base, accents.join(" "))),
+        DaemonMsg::HidePopup { reason } => {
+            let reason = serde_json::to_value(reason).ok()?;
+            Some(format!("popup closed ({})", reason.as_str()?))
+        }
+        _ => None,
+    }
+}
+
+/// One line per monitored event, e.g.
+/// "AT keyboard: key 18 press (e) -> relay [holding]".
+fn describe_monitor(msg: &DaemonMsg) -> Option<String> {
+    let DaemonMsg::Monitor { device, code, value, base, actions, state } = msg else {
+        return None;
+    };
+    let event = match code {
+        Some(code) => {
+            let what = match value {
+                0 => "release",
+                1 => "press",
+                _ => "repeat",
+            };
+            match base {
+                Some(base) => format!("key {} {} ({})", code, what, base),
+                None => format!("key {} {}", code, what),
+            }
+        }
+        None => "timer".to_string(),
+    };
+    Some(format!("{}: {} -> {} [{}]", device, event, actions, state))
+}
+
+/// Bars redraw on every line, so only print when the output changes.
+fn print_changed(stdout: &mut std::io::Stdout, last: &mut Option<String>, out: String) -> Result<()> {
+    if last.as_ref() != Some(&out) {
+        writeln!(stdout, "{}", out).context("writing status")?;
+        stdout.flush().context("writing status")?;
+        *last = Some(out);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // --- spec: status output ---
+
+    #[test]
+    fn uptime_shows_two_largest_units() {
+        assert_eq!(format_uptime(40), "40s");
+        assert_eq!(format_uptime(302), "5m 2s");
+        assert_eq!(format_uptime(2 * 3600 + 13 * 60 + 5), "2h 13m");
+        assert_eq!(format_uptime(3 * 86400 + 4 * 3600), "3d 4h");
+    }
+
+    #[test]
+    fn suspension_names_its_reasons() {
+        assert_eq!(describe_suspension(&[SuspendReason::Fullscreen]), "suspended (fullscreen window)");
+        assert_eq!(describe_suspension(&[SuspendReason::Locked, SuspendReason::Idle]), "suspended (locked, idle)");
+        assert_eq!(describe_suspension(&[]), "suspended");
+    }
+
+    #[test]
+    fn calibration_suggests_config_line() {
+        let out = describe_calibration(312, Some(158), Some(200), 300, true);
+        assert!(out.starts_with("312 key holds measured, 99% under 158 ms"));
+        assert!(out.contains("applied until accentd restarts"));
+        assert!(out.ends_with("threshold_ms = 200 under [general] in config.toml"));
+        assert!(describe_calibration(12, Some(90), None, 300, false).contains("not enough"));
+    }
+
+    #[test]
+    fn monitor_line_shows_event_decision_and_state() {
+        let msg = DaemonMsg::Monitor {
+            device: "AT keyboard".into(),
+            code: Some(18),
+            value: 1,
+            base: Some("e".into()),
+            actions: "relay".into(),
+            state: "holding".into(),
+        };
+        assert_eq!(describe_monitor(&msg).unwrap(), "AT keyboard: key 18 press (e) -> relay [holding]");
+        let timer = DaemonMsg::Monitor {
+            device: "AT keyboard".into(),
+            code: None,
+            value: 0,
+            base: None,
+            actions: "show popup e: è é".into(),
+            state: "popup".into(),
+        };
+        assert_eq!(describe_monitor(&timer).unwrap(), "AT keyboard: timer -> show popup e: è é [popup]");
+    }
+
+    #[test]
+    fn device_line_shows_state_and_events() {
+        let mut device = DeviceStatus {
+            name: "AT Translated Set 2 keyboard".into(),
+            path: "/dev/input/event3".into(),
+            grabbed: true,
+            enabled: true,
+            events: 1234,
+            error: None,
+        };
+        assert_eq!(
+            describe_device(&device),
+            "AT Translated Set 2 keyboard (/dev/input/event3): enabled, 1234 events"
+        );
+        device.grabbed = false;
+        assert!(describe_device(&device).contains("not grabbed"));
+        device.error = Some("reopening after I/O error, attempt 1 of 5".into());
+        assert!(describe_device(&device).contains("not grabbed (reopening after I/O error, attempt 1 of 5)"));
+    }
+
+    #[test]
+    fn locale_line_marks_active_and_shows_where_from() {
+        let mut locale = LocaleInfo {
+            name: "fr".into(),
+            source: LocaleSource::Builtin,
+            path: None,
+            description: None,
+            active: true,
+        };
+        assert_eq!(describe_locale(&locale, 4), "* fr    builtin");
+        locale.source = LocaleSource::File;
+        locale.path = Some("/usr/share/accentd/locales/fr.toml".into());
+        locale.description = Some("Français".into());
+        locale.active = false;
+        assert_eq!(describe_locale(&locale, 2), "  fr  /usr/share/accentd/locales/fr.toml  (Français)");
+    }
+}
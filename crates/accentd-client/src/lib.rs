@@ -0,0 +1,346 @@
+//! Async client for the accentd daemon socket.
+//!
+//! [`Client`] is one connection with typed send/receive; [`Subscription`]
+//! keeps a status or popup feed alive across daemon restarts.
+//!
+//! ```no_run
+//! # async fn demo() -> anyhow::Result<()> {
+//! use accentd_client::Client;
+//! use accentd_core::ipc::ClientMsg;
+//!
+//! let mut client = Client::connect().await?;
+//! let reply = client.request(&ClientMsg::GetStatus).await?;
+//! println!("{:?}", reply);
+//! # Ok(())
+//! # }
+//! ```
+
+use accentd_core::config;
+use accentd_core::ipc::{self, ClientMsg, DaemonMsg};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+
+/// How long a [`Subscription`] waits before reconnecting to a stopped daemon.
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// One connection to the daemon.
+pub struct Client {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl Client {
+    /// Connect to the daemon at [`config::socket_path`].
+    pub async fn connect() -> Result<Self> {
+        Self::connect_to(&config::socket_path()).await
+    }
+
+    pub async fn connect_to(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .await
+            .with_context(|| format!("connecting to accentd at {}", path.display()))?;
+        let (reader, writer) = stream.into_split();
+        Ok(Self {
+            lines: BufReader::new(reader).lines(),
+            writer,
+        })
+    }
+
+    /// Connect and register as a popup client, waiting for the daemon's ack.
+    /// ShowPopup/HidePopup then arrive via [`Client::recv`].
+    pub async fn register_popup(path: &Path) -> Result<Self> {
+        let mut client = Self::connect_to(path).await?;
+        match client.request(&ClientMsg::RegisterPopup).await? {
+            DaemonMsg::Ack { ok: true, .. } => Ok(client),
+            other => anyhow::bail!("daemon refused popup registration: {:?}", other),
+        }
+    }
+
+    pub async fn send(&mut self, msg: &ClientMsg) -> Result<()> {
+        self.writer
+            .write_all(ipc::encode(msg).as_bytes())
+            .await
+            .context("sending to accentd")
+    }
+
+    /// Next message from the daemon; `None` once it closes the connection.
+    /// Lines that don't decode (e.g. from a newer daemon) are skipped.
+    pub async fn recv(&mut self) -> Result<Option<DaemonMsg>> {
+        while let Some(line) = self.lines.next_line().await.context("reading from accentd")? {
+            if let Some(msg) = ipc::decode_daemon(&line) {
+                return Ok(Some(msg));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Send a message and wait for its reply (a Status, Ack, Stats,
+    /// LocaleMap, Locales or Calibration).
+    /// Popup messages arriving in between are dropped.
+    pub async fn request(&mut self, msg: &ClientMsg) -> Result<DaemonMsg> {
+        self.send(msg).await?;
+        loop {
+            match self.recv().await? {
+                Some(
+                    reply @ (DaemonMsg::Status { .. }
+                    | DaemonMsg::Ack { .. }
+                    | DaemonMsg::Stats { .. }
+                    | DaemonMsg::LocaleMap { .. }
+                    | DaemonMsg::Locales { .. }
+                    | DaemonMsg::Calibration { .. }),
+                ) => return Ok(reply),
+                Some(_) => continue,
+                None => anyhow::bail!("accentd closed the connection without replying"),
+            }
+        }
+    }
+}
+
+/// What a [`Subscription`] yields.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// (Re)connected and registered.
+    Connected,
+    Message(DaemonMsg),
+    /// The daemon is gone; reported once per outage.
+    Disconnected,
+}
+
+/// What to register as on every (re)connect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feed {
+    /// A Status now and on every change, plus popup shows and hides.
+    Status,
+    /// ShowPopup/HidePopup.
+    Popup,
+}
+
+/// A feed from the daemon that reconnects on its own.
+pub struct Subscription {
+    path: PathBuf,
+    feed: Feed,
+    client: Option<Client>,
+    /// Whether the current outage has been reported yet.
+    offline_reported: bool,
+    reconnect_delay: Duration,
+}
+
+impl Subscription {
+    pub fn new(feed: Feed) -> Self {
+        Self::with_path(config::socket_path(), feed)
+    }
+
+    pub fn with_path(path: PathBuf, feed: Feed) -> Self {
+        Self {
+            path,
+            feed,
+            client: None,
+            offline_reported: false,
+            reconnect_delay: RECONNECT_DELAY,
+        }
+    }
+
+    pub fn reconnect_delay(mut self, delay: Duration) -> Self {
+        self.reconnect_delay = delay;
+        self
+    }
+
+    /// Wait for the next event. Never ends: a stopped daemon is retried
+    /// every `reconnect_delay`. Cancel-safe, so it can be raced against
+    /// [`send`](Self::send) in a `select!`.
+    pub async fn next(&mut self) -> Event {
+        loop {
+            if let Some(client) = &mut self.client {
+                match client.recv().await {
+                    Ok(Some(msg)) => return Event::Message(msg),
+                    Ok(None) | Err(_) => self.client = None,
+                }
+                continue;
+            }
+
+            match self.open().await {
+                Ok(client) => {
+                    self.client = Some(client);
+                    self.offline_reported = false;
+                    return Event::Connected;
+                }
+                Err(_) if !self.offline_reported => {
+                    self.offline_reported = true;
+                    return Event::Disconnected;
+                }
+                Err(_) => tokio::time::sleep(self.reconnect_delay).await,
+            }
+        }
+    }
+
+    /// Send on the current connection. Fails while disconnected; nothing
+    /// is queued for the next one.
+    pub async fn send(&mut self, msg: &ClientMsg) -> Result<()> {
+        match &mut self.client {
+            Some(client) => client.send(msg).await,
+            None => anyhow::bail!("not connected to accentd"),
+        }
+    }
+
+    async fn open(&self) -> Result<Client> {
+        match self.feed {
+            Feed::Status => {
+                let mut client = Client::connect_to(&self.path).await?;
+                client.send(&ClientMsg::Subscribe).await?;
+                Ok(client)
+            }
+            Feed::Popup => Client::register_popup(&self.path).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use accentd_core::ipc::HideReason;
+    use tokio::net::UnixListener;
+
+    fn status(locale: &str) -> DaemonMsg {
+        DaemonMsg::Status {
+            enabled: true,
+            locale: locale.into(),
+            version: "0.0.0".into(),
+            suspended: false,
+            suspended_by: Vec::new(),
+            uptime_secs: 0,
+            backend: "uinput".into(),
+            devices: Vec::new(),
+            popup_clients: 0,
+        }
+    }
+
+    /// Accept one connection, check the first line, then write `replies`
+    /// and close.
+    async fn serve_once(listener: &UnixListener, expect: ClientMsg, replies: Vec<String>) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let first = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(first, ipc::encode(&expect).trim_end());
+        for reply in replies {
+            writer.write_all(reply.as_bytes()).await.unwrap();
+        }
+    }
+
+    // --- spec: typed request/reply ---
+
+    #[tokio::test]
+    async fn request_returns_reply_skipping_junk_and_popup_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let replies = vec![
+            "not json\n".to_string(),
+            ipc::encode(&DaemonMsg::HidePopup { reason: HideReason::Timeout }),
+            ipc::encode(&status("fr")),
+        ];
+        let server = serve_once(&listener, ClientMsg::GetStatus, replies);
+        let client = async {
+            let mut client = Client::connect_to(&path).await.unwrap();
+            client.request(&ClientMsg::GetStatus).await.unwrap()
+        };
+        let ((), got) = tokio::join!(server, client);
+        assert_eq!(got, status("fr"));
+    }
+
+    #[tokio::test]
+    async fn request_fails_when_daemon_hangs_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = serve_once(&listener, ClientMsg::Toggle, vec![]);
+        let client = async {
+            let mut client = Client::connect_to(&path).await.unwrap();
+            client.request(&ClientMsg::Toggle).await
+        };
+        let ((), got) = tokio::join!(server, client);
+        assert!(got.is_err());
+    }
+
+    #[tokio::test]
+    async fn register_popup_requires_ack() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let nack = ipc::encode(&DaemonMsg::Ack { ok: false, message: "no".into() });
+        let server = serve_once(&listener, ClientMsg::RegisterPopup, vec![nack]);
+        let ((), got) = tokio::join!(server, Client::register_popup(&path));
+        assert!(got.is_err());
+    }
+
+    // --- spec: subscription reconnects ---
+
+    #[tokio::test]
+    async fn subscription_reports_outage_once_and_reconnects() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sock");
+        let mut sub = Subscription::with_path(path.clone(), Feed::Status)
+            .reconnect_delay(Duration::from_millis(10));
+
+        // No daemon yet
+        assert_eq!(sub.next().await, Event::Disconnected);
+
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = serve_once(&listener, ClientMsg::Subscribe, vec![ipc::encode(&status("it"))]);
+        let client = async {
+            let mut events = Vec::new();
+            for _ in 0..2 {
+                events.push(sub.next().await);
+            }
+            events
+        };
+        let ((), events) = tokio::join!(server, client);
+        assert_eq!(events, vec![Event::Connected, Event::Message(status("it"))]);
+
+        // Daemon went away after the first status
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(sub.next().await, Event::Disconnected);
+
+        // And came back
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = serve_once(&listener, ClientMsg::Subscribe, vec![ipc::encode(&status("de"))]);
+        let client = async { (sub.next().await, sub.next().await) };
+        let ((), events) = tokio::join!(server, client);
+        assert_eq!(events, (Event::Connected, Event::Message(status("de"))));
+    }
+
+    #[tokio::test]
+    async fn subscription_sends_on_current_connection_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sock");
+        let mut sub = Subscription::with_path(path.clone(), Feed::Status);
+        assert!(sub.send(&ClientMsg::Dismiss).await.is_err());
+
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut lines = BufReader::new(stream).lines();
+            let mut got = Vec::new();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                got.push(line);
+            }
+            got
+        };
+        let client = async {
+            assert_eq!(sub.next().await, Event::Connected);
+            sub.send(&ClientMsg::Dismiss).await.unwrap();
+            drop(sub);
+        };
+        let (got, ()) = tokio::join!(server, client);
+        let expected: Vec<String> = [ClientMsg::Subscribe, ClientMsg::Dismiss]
+            .iter()
+            .map(|m| ipc::encode(m).trim_end().to_string())
+            .collect();
+        assert_eq!(got, expected);
+    }
+}
@@ -0,0 +1,138 @@
+//! Hold-threshold calibration.
+//!
+//! While calibrating, the daemon feeds every key event to a [`Calibration`],
+//! which measures how long letters stay down in normal typing. The suggested
+//! threshold sits a margin above the 99th percentile, so ordinary keystrokes
+//! never open a popup.
+
+use crate::charmap;
+use crate::state_machine::RawEvent;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Fewer holds than this give no suggestion.
+pub const MIN_SAMPLES: usize = 50;
+
+/// Added on top of the 99th percentile.
+const MARGIN_MS: u64 = 40;
+
+/// Holds this long are deliberate (an accent popup, autorepeat), not typing.
+const DELIBERATE_HOLD_MS: u64 = 1000;
+
+/// Hold durations collected so far.
+#[derive(Debug, Default)]
+pub struct Calibration {
+    pressed: HashMap<u16, Instant>,
+    holds_ms: Vec<u64>,
+}
+
+impl Calibration {
+    /// Feed one input event seen at `at`. Only letter presses and releases count.
+    pub fn record(&mut self, event: &RawEvent, at: Instant) {
+        if !event.is_key() || charmap::keycode_to_base(event.code).is_none() {
+            return;
+        }
+        match event.value {
+            1 => {
+                self.pressed.insert(event.code, at);
+            }
+            0 => {
+                if let Some(down) = self.pressed.remove(&event.code) {
+                    let held = at.saturating_duration_since(down).as_millis() as u64;
+                    if held < DELIBERATE_HOLD_MS {
+                        self.holds_ms.push(held);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Holds measured.
+    pub fn samples(&self) -> usize {
+        self.holds_ms.len()
+    }
+
+    /// The 99th percentile hold in milliseconds (nearest rank).
+    pub fn p99_ms(&self) -> Option<u64> {
+        let mut holds = self.holds_ms.clone();
+        holds.sort_unstable();
+        let rank = (holds.len() * 99).div_ceil(100);
+        holds.get(rank.checked_sub(1)?).copied()
+    }
+
+    /// A threshold safely above the 99th percentile, rounded up to 10 ms and
+    /// kept within the range accepted for derived thresholds. `None` until
+    /// [`MIN_SAMPLES`] holds are in.
+    pub fn suggest(&self) -> Option<u64> {
+        if self.samples() < MIN_SAMPLES {
+            return None;
+        }
+        let ms = (self.p99_ms()? + MARGIN_MS).div_ceil(10) * 10;
+        Some(ms.clamp(150, 2000))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const KEY_E: u16 = 18;
+    const KEY_1: u16 = 2;
+
+    /// Type `n` letters, each held for `hold(i)` ms.
+    fn typed(n: usize, hold: impl Fn(usize) -> u64) -> Calibration {
+        let mut cal = Calibration::default();
+        let mut t = Instant::now();
+        for i in 0..n {
+            cal.record(&RawEvent::key(KEY_E, 1), t);
+            t += Duration::from_millis(hold(i));
+            cal.record(&RawEvent::key(KEY_E, 0), t);
+            t += Duration::from_millis(100);
+        }
+        cal
+    }
+
+    // --- spec: holds are measured from letter press to release ---
+
+    #[test]
+    fn measures_letter_holds() {
+        let cal = typed(3, |i| 80 + i as u64 * 10);
+        assert_eq!(cal.samples(), 3);
+        assert_eq!(cal.p99_ms(), Some(100));
+    }
+
+    #[test]
+    fn ignores_non_letters_and_deliberate_holds() {
+        let mut cal = Calibration::default();
+        let t = Instant::now();
+        cal.record(&RawEvent::key(KEY_1, 1), t);
+        cal.record(&RawEvent::key(KEY_1, 0), t + Duration::from_millis(90));
+        cal.record(&RawEvent::key(KEY_E, 1), t);
+        cal.record(&RawEvent::key(KEY_E, 2), t + Duration::from_millis(600));
+        cal.record(&RawEvent::key(KEY_E, 0), t + Duration::from_millis(1500));
+        assert_eq!(cal.samples(), 0);
+    }
+
+    // --- spec: suggestion sits above the 99th percentile ---
+
+    #[test]
+    fn suggests_above_p99() {
+        // 199 quick taps and one slow outlier, which the percentile skips
+        let cal = typed(200, |i| if i == 0 { 900 } else { 60 + (i % 50) as u64 * 2 });
+        assert_eq!(cal.p99_ms(), Some(158));
+        assert_eq!(cal.suggest(), Some(200));
+    }
+
+    #[test]
+    fn no_suggestion_without_enough_samples() {
+        assert_eq!(typed(MIN_SAMPLES - 1, |_| 100).suggest(), None);
+        assert_eq!(Calibration::default().p99_ms(), None);
+    }
+
+    #[test]
+    fn suggestion_never_below_floor() {
+        assert_eq!(typed(MIN_SAMPLES, |_| 30).suggest(), Some(150));
+    }
+}
@@ -1,18 +1,208 @@
-use std::collections::HashMap;
+use icu_casemap::CaseMapper;
+use icu_locid::LanguageIdentifier;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// Base letter (lowercase) → accented variants, in popup order.
+pub type LocaleMap = HashMap<String, Vec<String>>;
+
+/// Accented variants of one base letter, shared rather than cloned.
+pub type Candidates = Arc<[Arc<str>]>;
+
+/// A [`LocaleMap`] prepared for the key-event hot path: candidates are
+/// interned once with their uppercase forms precomputed, and clones share the
+/// table (one per daemon, not one per keyboard).
+#[derive(Debug, Clone, Default)]
+pub struct AccentTable {
+    entries: Arc<HashMap<String, CaseForms>>,
+    selection: Selection,
+    language: String,
+    upper: Arc<UpperMap>,
+    descriptions: Arc<DescriptionMap>,
+}
+
+/// Candidate → its uppercase form, where a locale wants something other
+/// than [`uppercase`] gives (`ß` → `SS` rather than `ẞ`).
+pub type UpperMap = BTreeMap<String, String>;
+
+/// Candidate → how many characters picking it replaces, where that isn't
+/// just the held letter: `"œ" = 2` after typing `o`, holding `e`.
+pub type EraseMap = BTreeMap<String, usize>;
+
+/// Candidate → what to call it in tooltips and to screen readers, e.g.
+/// `"è" = "e grave"`.
+pub type DescriptionMap = BTreeMap<String, String>;
+
+/// What the digits mean while a popup is open; set per locale.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Selection {
+    /// Digit N picks the Nth candidate shown.
+    #[default]
+    Position,
+    /// Candidates are tones 1-4 in order and never reordered, so digits
+    /// always mean the same tone; 0 is the neutral tone and keeps the letter
+    /// unmarked (pinyin).
+    Tones,
+}
+
+#[derive(Debug)]
+struct CaseForms {
+    /// As the locale lists them, for uppercasing again per language.
+    listed: Candidates,
+    lower: Candidates,
+    upper: Candidates,
+}
+
+impl CaseForms {
+    /// A candidate the key already types in that case is left out, so a
+    /// locale can list `i` for the sake of its uppercase: Turkish `ı`, `i`
+    /// offer `ı` without Shift and `İ` with it (the key types `I` itself).
+    fn new(base: &str, listed: Candidates, language: &str, explicit: &UpperMap) -> Self {
+        let typed_upper = base.to_uppercase();
+        let lower = listed.iter().filter(|c| ***c != *base).cloned().collect();
+        let upper = listed
+            .iter()
+            .map(|c| explicit.get(&**c).cloned().unwrap_or_else(|| uppercase(c, language)))
+            .filter(|c| *c != typed_upper)
+            .map(Arc::from)
+            .collect();
+        Self { listed, lower, upper }
+    }
+}
+
+impl AccentTable {
+    pub fn new(locale_map: &LocaleMap) -> Self {
+        let entries = locale_map
+            .iter()
+            .map(|(base, accents)| {
+                let base = base.to_lowercase();
+                let listed: Candidates = accents.iter().map(|s| Arc::from(s.as_str())).collect();
+                let forms = CaseForms::new(&base, listed, "", &UpperMap::new());
+                (base, forms)
+            })
+            .collect();
+        Self { entries: Arc::new(entries), ..Self::default() }
+    }
+
+    /// Uppercase candidates by the rules of `language` (a BCP 47 tag).
+    pub fn with_language(mut self, language: &str) -> Self {
+        self.language = language.to_string();
+        self.recase()
+    }
+
+    /// Uppercase the candidates in `upper` to the forms given there rather
+    /// than by the language's rules.
+    pub fn with_uppercase(mut self, upper: &UpperMap) -> Self {
+        self.upper = Arc::new(upper.clone());
+        self.recase()
+    }
+
+    /// Describe the candidates in `descriptions`, in either case.
+    pub fn with_descriptions(mut self, descriptions: &DescriptionMap) -> Self {
+        self.descriptions = Arc::new(descriptions.clone());
+        self
+    }
+
+    /// What `candidate` is called, as shown or as the locale lists it.
+    pub fn description(&self, candidate: &str) -> Option<&str> {
+        let described = self.descriptions.get(candidate);
+        described.or_else(|| self.descriptions.get(&lowercase(candidate, &self.language))).map(String::as_str)
+    }
+
+    fn recase(mut self) -> Self {
+        let entries = self
+            .entries
+            .iter()
+            .map(|(base, forms)| (base.clone(), CaseForms::new(base, forms.listed.clone(), &self.language, &self.upper)))
+            .collect();
+        self.entries = Arc::new(entries);
+        self
+    }
+
+    pub fn with_selection(mut self, selection: Selection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    pub fn selection(&self) -> Selection {
+        self.selection
+    }
+
+    /// Same as [`resolve_accents`], without allocating.
+    pub fn get(&self, base: &str, shift: bool) -> Option<&Candidates> {
+        let forms = self.entries.get(base)?;
+        Some(if shift { &forms.upper } else { &forms.lower })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl From<&LocaleMap> for AccentTable {
+    fn from(map: &LocaleMap) -> Self {
+        Self::new(map)
+    }
+}
+
+impl From<LocaleMap> for AccentTable {
+    fn from(map: LocaleMap) -> Self {
+        Self::new(&map)
+    }
+}
+
+/// Names [`builtin_locale`] knows, aliases included.
+pub const BUILTIN_LOCALES: [&str; 26] = [
+    "it", "es", "fr", "de", "pt", "pl", "cs", "sk", "tr", "da", "no", "sv", "is", "eo", "cy", "hr", "sr-latn", "ca",
+    "vi", "ru", "uk", "grc", "el-poly", "ipa", "math", "pinyin",
+];
 
 /// Return the built-in accent map for a locale.
-pub fn builtin_locale(name: &str) -> HashMap<String, Vec<String>> {
+pub fn builtin_locale(name: &str) -> LocaleMap {
     match name {
         "it" => locale_it(),
         "es" => locale_es(),
         "fr" => locale_fr(),
         "de" => locale_de(),
         "pt" => locale_pt(),
+        "pl" => locale_pl(),
+        "cs" => locale_cs(),
+        "sk" => locale_sk(),
+        "tr" => locale_tr(),
+        "da" => locale_da(),
+        "no" => locale_no(),
+        "sv" => locale_sv(),
+        "is" => locale_is(),
+        "eo" => locale_eo(),
+        "cy" => locale_cy(),
+        "hr" | "sr-latn" => locale_hr(),
+        "ca" => locale_ca(),
+        "vi" => locale_vi(),
+        "ru" => locale_ru(),
+        "uk" => locale_uk(),
+        "grc" | "el-poly" => locale_grc(),
+        "ipa" => locale_ipa(),
+        "math" => locale_math(),
+        "pinyin" => locale_pinyin(),
         _ => HashMap::new(),
     }
 }
 
-fn locale_it() -> HashMap<String, Vec<String>> {
+/// How digits select in a built-in locale.
+pub fn builtin_selection(name: &str) -> Selection {
+    match name {
+        "pinyin" => Selection::Tones,
+        _ => Selection::Position,
+    }
+}
+
+fn locale_it() -> LocaleMap {
     HashMap::from([
         ("a".into(), vec!["à", "á", "â", "ã", "ä"].into_iter().map(Into::into).collect()),
         ("e".into(), vec!["è", "é", "ê", "ë"].into_iter().map(Into::into).collect()),
@@ -24,7 +214,7 @@ fn locale_it() -> HashMap<String, Vec<String>> {
     ])
 }
 
-fn locale_es() -> HashMap<String, Vec<String>> {
+fn locale_es() -> LocaleMap {
     HashMap::from([
         ("a".into(), vec!["á", "à", "â", "ä"].into_iter().map(Into::into).collect()),
         ("e".into(), vec!["é", "è", "ê", "ë"].into_iter().map(Into::into).collect()),
@@ -36,7 +226,7 @@ fn locale_es() -> HashMap<String, Vec<String>> {
     ])
 }
 
-fn locale_fr() -> HashMap<String, Vec<String>> {
+fn locale_fr() -> LocaleMap {
     HashMap::from([
         ("a".into(), vec!["à", "â", "æ", "á", "ä"].into_iter().map(Into::into).collect()),
         ("e".into(), vec!["è", "é", "ê", "ë", "æ"].into_iter().map(Into::into).collect()),
@@ -48,7 +238,7 @@ fn locale_fr() -> HashMap<String, Vec<String>> {
     ])
 }
 
-fn locale_de() -> HashMap<String, Vec<String>> {
+fn locale_de() -> LocaleMap {
     HashMap::from([
         ("a".into(), vec!["ä", "à", "á", "â"].into_iter().map(Into::into).collect()),
         ("e".into(), vec!["ë", "è", "é", "ê"].into_iter().map(Into::into).collect()),
@@ -59,7 +249,7 @@ fn locale_de() -> HashMap<String, Vec<String>> {
     ])
 }
 
-fn locale_pt() -> HashMap<String, Vec<String>> {
+fn locale_pt() -> LocaleMap {
     HashMap::from([
         ("a".into(), vec!["ã", "á", "à", "â", "ä"].into_iter().map(Into::into).collect()),
         ("e".into(), vec!["é", "è", "ê", "ë"].into_iter().map(Into::into).collect()),
@@ -70,10 +260,349 @@ fn locale_pt() -> HashMap<String, Vec<String>> {
     ])
 }
 
+/// Polish has one diacritic letter per base except `z`, where the
+/// commoner `ż` goes first.
+fn locale_pl() -> LocaleMap {
+    HashMap::from([
+        ("a".into(), vec!["ą"].into_iter().map(Into::into).collect()),
+        ("c".into(), vec!["ć"].into_iter().map(Into::into).collect()),
+        ("e".into(), vec!["ę"].into_iter().map(Into::into).collect()),
+        ("l".into(), vec!["ł"].into_iter().map(Into::into).collect()),
+        ("n".into(), vec!["ń"].into_iter().map(Into::into).collect()),
+        ("o".into(), vec!["ó"].into_iter().map(Into::into).collect()),
+        ("s".into(), vec!["ś"].into_iter().map(Into::into).collect()),
+        ("z".into(), vec!["ż", "ź"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Czech: háček and čárka, with `ě` and `ů`, the commoner of their pairs,
+/// first.
+fn locale_cs() -> LocaleMap {
+    HashMap::from([
+        ("a".into(), vec!["á"].into_iter().map(Into::into).collect()),
+        ("c".into(), vec!["č"].into_iter().map(Into::into).collect()),
+        ("d".into(), vec!["ď"].into_iter().map(Into::into).collect()),
+        ("e".into(), vec!["ě", "é"].into_iter().map(Into::into).collect()),
+        ("i".into(), vec!["í"].into_iter().map(Into::into).collect()),
+        ("n".into(), vec!["ň"].into_iter().map(Into::into).collect()),
+        ("o".into(), vec!["ó"].into_iter().map(Into::into).collect()),
+        ("r".into(), vec!["ř"].into_iter().map(Into::into).collect()),
+        ("s".into(), vec!["š"].into_iter().map(Into::into).collect()),
+        ("t".into(), vec!["ť"].into_iter().map(Into::into).collect()),
+        ("u".into(), vec!["ů", "ú"].into_iter().map(Into::into).collect()),
+        ("y".into(), vec!["ý"].into_iter().map(Into::into).collect()),
+        ("z".into(), vec!["ž"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Slovak: Czech's háček and čárka minus `ě ř ů`, plus `ä ľ ĺ ŕ ô`.
+fn locale_sk() -> LocaleMap {
+    HashMap::from([
+        ("a".into(), vec!["á", "ä"].into_iter().map(Into::into).collect()),
+        ("c".into(), vec!["č"].into_iter().map(Into::into).collect()),
+        ("d".into(), vec!["ď"].into_iter().map(Into::into).collect()),
+        ("e".into(), vec!["é"].into_iter().map(Into::into).collect()),
+        ("i".into(), vec!["í"].into_iter().map(Into::into).collect()),
+        ("l".into(), vec!["ľ", "ĺ"].into_iter().map(Into::into).collect()),
+        ("n".into(), vec!["ň"].into_iter().map(Into::into).collect()),
+        ("o".into(), vec!["ô", "ó"].into_iter().map(Into::into).collect()),
+        ("r".into(), vec!["ŕ"].into_iter().map(Into::into).collect()),
+        ("s".into(), vec!["š"].into_iter().map(Into::into).collect()),
+        ("t".into(), vec!["ť"].into_iter().map(Into::into).collect()),
+        ("u".into(), vec!["ú"].into_iter().map(Into::into).collect()),
+        ("y".into(), vec!["ý"].into_iter().map(Into::into).collect()),
+        ("z".into(), vec!["ž"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Turkish. `i` is listed for its uppercase: Shift+I types a dotless `I`,
+/// so the popup offers `İ` (see [`CaseForms`]); the circumflexes are for
+/// loanwords (`kâr`, `millî`).
+fn locale_tr() -> LocaleMap {
+    HashMap::from([
+        ("a".into(), vec!["â"].into_iter().map(Into::into).collect()),
+        ("c".into(), vec!["ç"].into_iter().map(Into::into).collect()),
+        ("g".into(), vec!["ğ"].into_iter().map(Into::into).collect()),
+        ("i".into(), vec!["ı", "i", "î"].into_iter().map(Into::into).collect()),
+        ("o".into(), vec!["ö"].into_iter().map(Into::into).collect()),
+        ("s".into(), vec!["ş"].into_iter().map(Into::into).collect()),
+        ("u".into(), vec!["ü", "û"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Danish: `æ` before `å`, the commoner in running text.
+fn locale_da() -> LocaleMap {
+    HashMap::from([
+        ("a".into(), vec!["æ", "å"].into_iter().map(Into::into).collect()),
+        ("e".into(), vec!["é"].into_iter().map(Into::into).collect()),
+        ("o".into(), vec!["ø"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Norwegian: Danish's letters with `å` first.
+fn locale_no() -> LocaleMap {
+    HashMap::from([
+        ("a".into(), vec!["å", "æ"].into_iter().map(Into::into).collect()),
+        ("e".into(), vec!["é"].into_iter().map(Into::into).collect()),
+        ("o".into(), vec!["ø", "ô", "ó"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Swedish: `ä` before `å`.
+fn locale_sv() -> LocaleMap {
+    HashMap::from([
+        ("a".into(), vec!["ä", "å"].into_iter().map(Into::into).collect()),
+        ("e".into(), vec!["é"].into_iter().map(Into::into).collect()),
+        ("o".into(), vec!["ö"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Icelandic. `þ` sits on `t` and `ð` on `d`, the letters they're
+/// transliterated with.
+fn locale_is() -> LocaleMap {
+    HashMap::from([
+        ("a".into(), vec!["á", "æ"].into_iter().map(Into::into).collect()),
+        ("d".into(), vec!["ð"].into_iter().map(Into::into).collect()),
+        ("e".into(), vec!["é"].into_iter().map(Into::into).collect()),
+        ("i".into(), vec!["í"].into_iter().map(Into::into).collect()),
+        ("o".into(), vec!["ó", "ö"].into_iter().map(Into::into).collect()),
+        ("t".into(), vec!["þ"].into_iter().map(Into::into).collect()),
+        ("u".into(), vec!["ú"].into_iter().map(Into::into).collect()),
+        ("y".into(), vec!["ý"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Esperanto: the circumflexed consonants and `ŭ`.
+fn locale_eo() -> LocaleMap {
+    HashMap::from([
+        ("c".into(), vec!["ĉ"].into_iter().map(Into::into).collect()),
+        ("g".into(), vec!["ĝ"].into_iter().map(Into::into).collect()),
+        ("h".into(), vec!["ĥ"].into_iter().map(Into::into).collect()),
+        ("j".into(), vec!["ĵ"].into_iter().map(Into::into).collect()),
+        ("s".into(), vec!["ŝ"].into_iter().map(Into::into).collect()),
+        ("u".into(), vec!["ŭ"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Welsh: the circumflex (to bach) first on every vowel, `w` and `y`
+/// included.
+fn locale_cy() -> LocaleMap {
+    HashMap::from([
+        ("a".into(), vec!["â"].into_iter().map(Into::into).collect()),
+        ("e".into(), vec!["ê"].into_iter().map(Into::into).collect()),
+        ("i".into(), vec!["î"].into_iter().map(Into::into).collect()),
+        ("o".into(), vec!["ô"].into_iter().map(Into::into).collect()),
+        ("u".into(), vec!["û"].into_iter().map(Into::into).collect()),
+        ("w".into(), vec!["ŵ"].into_iter().map(Into::into).collect()),
+        ("y".into(), vec!["ŷ"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Croatian, also Serbian in Latin script (`sr-latn`): the same five letters.
+fn locale_hr() -> LocaleMap {
+    HashMap::from([
+        ("c".into(), vec!["č", "ć"].into_iter().map(Into::into).collect()),
+        ("d".into(), vec!["đ"].into_iter().map(Into::into).collect()),
+        ("s".into(), vec!["š"].into_iter().map(Into::into).collect()),
+        ("z".into(), vec!["ž"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Catalan. `l` offers the geminated `l·l` with a middle dot, and the
+/// precomposed `ŀl` some older fonts and documents use.
+fn locale_ca() -> LocaleMap {
+    HashMap::from([
+        ("a".into(), vec!["à"].into_iter().map(Into::into).collect()),
+        ("c".into(), vec!["ç"].into_iter().map(Into::into).collect()),
+        ("e".into(), vec!["è", "é"].into_iter().map(Into::into).collect()),
+        ("i".into(), vec!["í", "ï"].into_iter().map(Into::into).collect()),
+        ("l".into(), vec!["l·l", "ŀl"].into_iter().map(Into::into).collect()),
+        ("o".into(), vec!["ò", "ó"].into_iter().map(Into::into).collect()),
+        ("u".into(), vec!["ú", "ü"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Vietnamese. Each vowel offers its tones in VNI order (sắc, huyền, hỏi,
+/// ngã, nặng), then each modified vowel followed by its tones; lists longer
+/// than nine page with Up and Down.
+fn locale_vi() -> LocaleMap {
+    let tones = |vowels: &[&str]| vowels.concat().chars().map(String::from).collect();
+    HashMap::from([
+        ("a".into(), tones(&["áàảãạ", "ăắằẳẵặ", "âấầẩẫậ"])),
+        ("d".into(), tones(&["đ"])),
+        ("e".into(), tones(&["éèẻẽẹ", "êếềểễệ"])),
+        ("i".into(), tones(&["íìỉĩị"])),
+        ("o".into(), tones(&["óòỏõọ", "ôốồổỗộ", "ơớờởỡợ"])),
+        ("u".into(), tones(&["úùủũụ", "ưứừửữự"])),
+        ("y".into(), tones(&["ýỳỷỹỵ"])),
+    ])
+}
+
+/// Russian, for Cyrillic layouts that put `ё` out of reach or, like the
+/// phonetic ones, leave `й` and `ъ` without a key of their own.
+fn locale_ru() -> LocaleMap {
+    HashMap::from([
+        ("е".into(), vec!["ё"].into_iter().map(Into::into).collect()),
+        ("и".into(), vec!["й"].into_iter().map(Into::into).collect()),
+        ("ь".into(), vec!["ъ"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Ukrainian, for typing it on a Russian layout: `є`, `і`, `ї` and `ґ`.
+/// Merged with `ru` it gives `е` → `ё є` and `и` → `й і ї`.
+fn locale_uk() -> LocaleMap {
+    HashMap::from([
+        ("г".into(), vec!["ґ"].into_iter().map(Into::into).collect()),
+        ("е".into(), vec!["є"].into_iter().map(Into::into).collect()),
+        ("и".into(), vec!["і", "ї", "й"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Polytonic Greek on the Latin letters that transliterate its vowels: `h`
+/// for eta, `w` for omega. Each offers the bare vowel and its acute, grave
+/// and circumflex, then the same with smooth and rough breathing, then with
+/// diaeresis or iota subscript; lists longer than nine page.
+fn locale_grc() -> LocaleMap {
+    let forms = |groups: &[&str]| groups.concat().chars().map(String::from).collect();
+    HashMap::from([
+        ("a".into(), forms(&["αάὰᾶ", "ἀἄἂἆ", "ἁἅἃἇ", "ᾳᾴᾲᾷ", "ᾀᾄᾂᾆ", "ᾁᾅᾃᾇ"])),
+        ("e".into(), forms(&["εέὲ", "ἐἔἒ", "ἑἕἓ"])),
+        ("h".into(), forms(&["ηήὴῆ", "ἠἤἢἦ", "ἡἥἣἧ", "ῃῄῂῇ", "ᾐᾔᾒᾖ", "ᾑᾕᾓᾗ"])),
+        ("i".into(), forms(&["ιίὶῖ", "ἰἴἲἶ", "ἱἵἳἷ", "ϊΐῒῗ"])),
+        ("o".into(), forms(&["οόὸ", "ὀὄὂ", "ὁὅὃ"])),
+        ("u".into(), forms(&["υύὺῦ", "ὐὔὒὖ", "ὑὕὓὗ", "ϋΰῢῧ"])),
+        ("w".into(), forms(&["ωώὼῶ", "ὠὤὢὦ", "ὡὥὣὧ", "ῳῴῲῷ", "ᾠᾤᾢᾦ", "ᾡᾥᾣᾧ"])),
+    ])
+}
+
+/// The International Phonetic Alphabet by mnemonic: schwa on `e`, esh on
+/// `s`, ezh on `z`, eng on `n`, theta on `t` and eth on `d`, the commoner
+/// symbol first. Stress marks are on `'`, length on `:` and the glottal
+/// stop on `?`.
+fn locale_ipa() -> LocaleMap {
+    HashMap::from([
+        ("a".into(), vec!["ɑ", "æ", "ɐ", "ʌ", "ɶ"].into_iter().map(Into::into).collect()),
+        ("c".into(), vec!["ç", "ɕ"].into_iter().map(Into::into).collect()),
+        ("d".into(), vec!["ð", "ɖ", "ɗ", "ʤ"].into_iter().map(Into::into).collect()),
+        ("e".into(), vec!["ə", "ɛ", "ɜ", "ɘ", "ɚ", "ɝ"].into_iter().map(Into::into).collect()),
+        ("g".into(), vec!["ɡ", "ɣ", "ɢ", "ɠ"].into_iter().map(Into::into).collect()),
+        ("h".into(), vec!["ʰ", "ħ", "ɦ", "ʜ"].into_iter().map(Into::into).collect()),
+        ("i".into(), vec!["ɪ", "ɨ"].into_iter().map(Into::into).collect()),
+        ("j".into(), vec!["ʝ", "ʲ", "ɟ"].into_iter().map(Into::into).collect()),
+        ("l".into(), vec!["ɫ", "ɬ", "ɮ", "ʎ", "ɭ", "ʟ"].into_iter().map(Into::into).collect()),
+        ("n".into(), vec!["ŋ", "ɲ", "ɳ", "ɴ"].into_iter().map(Into::into).collect()),
+        ("o".into(), vec!["ɔ", "ø", "œ", "ɒ", "ɵ", "ɤ"].into_iter().map(Into::into).collect()),
+        ("r".into(), vec!["ɹ", "ɾ", "ʁ", "ʀ", "ɻ", "ɽ"].into_iter().map(Into::into).collect()),
+        ("s".into(), vec!["ʃ", "ʂ"].into_iter().map(Into::into).collect()),
+        ("t".into(), vec!["θ", "ʈ", "ʧ"].into_iter().map(Into::into).collect()),
+        ("u".into(), vec!["ʊ", "ʉ", "ɯ"].into_iter().map(Into::into).collect()),
+        ("v".into(), vec!["ʋ", "ⱱ"].into_iter().map(Into::into).collect()),
+        ("w".into(), vec!["ʍ", "ɰ", "ʷ"].into_iter().map(Into::into).collect()),
+        ("y".into(), vec!["ʏ", "ɥ"].into_iter().map(Into::into).collect()),
+        ("z".into(), vec!["ʒ", "ʐ", "ʑ"].into_iter().map(Into::into).collect()),
+        ("'".into(), vec!["ˈ", "ˌ"].into_iter().map(Into::into).collect()),
+        (":".into(), vec!["ː", "ˑ"].into_iter().map(Into::into).collect()),
+        ("?".into(), vec!["ʔ", "ʕ"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Mathematical operators on the keys they look like, to use alone or
+/// merged with a language. `8` for `∞` needs `[layers] digits`.
+fn locale_math() -> LocaleMap {
+    HashMap::from([
+        ("x".into(), vec!["×", "·", "⋅"].into_iter().map(Into::into).collect()),
+        ("-".into(), vec!["−", "±", "∓"].into_iter().map(Into::into).collect()),
+        ("=".into(), vec!["≈", "≠", "≡"].into_iter().map(Into::into).collect()),
+        ("<".into(), vec!["≤", "≪"].into_iter().map(Into::into).collect()),
+        (">".into(), vec!["≥", "≫"].into_iter().map(Into::into).collect()),
+        ("/".into(), vec!["÷"].into_iter().map(Into::into).collect()),
+        ("8".into(), vec!["∞"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Tones 1-4 (flat, rising, dipping, falling); `v` stands in for `ü` as in
+/// pinyin input methods, and both offer the bare `ü` fifth.
+fn locale_pinyin() -> LocaleMap {
+    HashMap::from([
+        ("a".into(), vec!["ā", "á", "ǎ", "à"].into_iter().map(Into::into).collect()),
+        ("e".into(), vec!["ē", "é", "ě", "è"].into_iter().map(Into::into).collect()),
+        ("i".into(), vec!["ī", "í", "ǐ", "ì"].into_iter().map(Into::into).collect()),
+        ("o".into(), vec!["ō", "ó", "ǒ", "ò"].into_iter().map(Into::into).collect()),
+        ("u".into(), vec!["ū", "ú", "ǔ", "ù", "ü"].into_iter().map(Into::into).collect()),
+        ("v".into(), vec!["ǖ", "ǘ", "ǚ", "ǜ", "ü"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// The digit layer: superscripts, subscripts and vulgar fractions on the
+/// number row, by numerator (with `[layers] digits`).
+pub fn digit_layer() -> LocaleMap {
+    HashMap::from([
+        ("0".into(), vec!["⁰", "₀", "°"].into_iter().map(Into::into).collect()),
+        ("1".into(), vec!["¹", "₁", "½", "⅓", "¼", "⅕", "⅙", "⅛", "⅒"].into_iter().map(Into::into).collect()),
+        ("2".into(), vec!["²", "₂", "⅔", "⅖"].into_iter().map(Into::into).collect()),
+        ("3".into(), vec!["³", "₃", "¾", "⅗", "⅜"].into_iter().map(Into::into).collect()),
+        ("4".into(), vec!["⁴", "₄", "⅘"].into_iter().map(Into::into).collect()),
+        ("5".into(), vec!["⁵", "₅", "⅚", "⅝"].into_iter().map(Into::into).collect()),
+        ("6".into(), vec!["⁶", "₆"].into_iter().map(Into::into).collect()),
+        ("7".into(), vec!["⁷", "₇", "⅞"].into_iter().map(Into::into).collect()),
+        ("8".into(), vec!["⁸", "₈"].into_iter().map(Into::into).collect()),
+        ("9".into(), vec!["⁹", "₉"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Built-in punctuation layer: dashes on `-`, quotes on `'` and `"`
+/// (Shift+`'`), the ellipsis on `.`. Keyed by the symbol typed, like
+/// snippets, so only layouts with [`Layout::symbol`] entries get it.
+pub fn punctuation_layer() -> LocaleMap {
+    HashMap::from([
+        ("-".into(), vec!["–", "—", "−"].into_iter().map(Into::into).collect()),
+        ("'".into(), vec!["‘", "’", "´", "`"].into_iter().map(Into::into).collect()),
+        ("\"".into(), vec!["“", "”", "„"].into_iter().map(Into::into).collect()),
+        (".".into(), vec!["…"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Built-in symbols layer: currencies on Shift+4 (`$` on US and UK), with
+/// per mille and multiplication signs beside them. Keyed by position so
+/// every layout gets it; a locale's `"$"` entry wins on US and UK.
+pub fn symbols_layer() -> LocaleMap {
+    HashMap::from([
+        ("shift+4".into(), vec!["€", "£", "¥", "¢", "₿"].into_iter().map(Into::into).collect()),
+        ("shift+5".into(), vec!["‰", "‱"].into_iter().map(Into::into).collect()),
+        ("shift+8".into(), vec!["×", "·", "•"].into_iter().map(Into::into).collect()),
+    ])
+}
+
+/// Whether a candidate is a multi-character snippet rather than one
+/// character. An emoji with its variation selector, skin tone or
+/// zero-width-joined parts counts as one.
+pub fn is_snippet(candidate: &str) -> bool {
+    let mut count = 0;
+    let mut joined = false;
+    for c in candidate.chars() {
+        match c {
+            '\u{200d}' => joined = true,
+            '\u{fe0e}' | '\u{fe0f}' | '\u{20e3}' | '\u{1f3fb}'..='\u{1f3ff}' => {}
+            _ => {
+                if !joined {
+                    count += 1;
+                }
+                joined = false;
+            }
+        }
+    }
+    count > 1
+}
+
+/// Whether a locale map key is a number-row digit rather than a letter.
+pub fn is_digit_base(base: &str) -> bool {
+    base.len() == 1 && base.as_bytes()[0].is_ascii_digit()
+}
+
 /// Given a lowercase base char and shift state, return the accented variants.
 /// If shift is true, returns uppercase variants.
 pub fn resolve_accents(
-    locale_map: &HashMap<String, Vec<String>>,
+    locale_map: &LocaleMap,
     base: &str,
     shift: bool,
 ) -> Option<Vec<String>> {
@@ -83,7 +612,7 @@ pub fn resolve_accents(
         Some(
             accents
                 .iter()
-                .map(|s| s.to_uppercase())
+                .map(|s| uppercase(s, ""))
                 .collect(),
         )
     } else {
@@ -91,25 +620,228 @@ pub fn resolve_accents(
     }
 }
 
-/// Map evdev key codes to base letter names.
-/// Returns None for keys that are not accent-eligible.
-/// NOTE: These keycodes assume a QWERTY physical layout. Non-QWERTY layouts
-/// (Dvorak, AZERTY, Colemak) will map to wrong letters.
-pub fn keycode_to_base(code: u16) -> Option<&'static str> {
-    match code {
-        30 => Some("a"),
-        46 => Some("c"),
-        18 => Some("e"),
-        23 => Some("i"),
-        49 => Some("n"),
-        24 => Some("o"),
-        31 => Some("s"),
-        22 => Some("u"),
-        21 => Some("y"),
-        _ => None,
+/// Uppercase one candidate by the rules of `language` (a BCP 47 tag; empty
+/// or unknown tags get the language-neutral rules), so Turkish `i` becomes
+/// `İ` and `ı` becomes `I`. A single letter stays a single letter: `ß`
+/// becomes `ẞ` rather than `SS`. Greek keeps its accents, which full Greek
+/// uppercasing drops but a popup exists to type.
+pub fn uppercase(candidate: &str, language: &str) -> String {
+    let mapper = CaseMapper::new();
+    let langid = language
+        .parse::<LanguageIdentifier>()
+        .ok()
+        .filter(|id| id.language.as_str() != "el")
+        .unwrap_or_default();
+    let upper = mapper.uppercase_to_string(candidate, &langid);
+    let mut chars = candidate.chars();
+    match (chars.next(), chars.next()) {
+        (Some('ß'), None) => "ẞ".into(),
+        (Some(c), None) if upper.chars().count() > 1 => mapper.simple_uppercase(c).into(),
+        _ => upper,
+    }
+}
+
+/// Lowercase one candidate by the rules of `language`, the inverse of
+/// [`uppercase`]: Turkish `İ` becomes `i` (not `i̇`) and `I` becomes `ı`.
+pub fn lowercase(candidate: &str, language: &str) -> String {
+    let langid = language.parse::<LanguageIdentifier>().unwrap_or_default();
+    CaseMapper::new().lowercase_to_string(candidate, &langid)
+}
+
+/// Whether `letter` is one that can open a popup.
+pub fn is_base(letter: &str) -> bool {
+    BASES.contains(&letter)
+}
+
+/// Whether a locale key names a key that can be held: a base letter, a
+/// number-row digit, `shift+<digit>`, or a symbol a punctuation key types on
+/// a layout that has them.
+pub fn is_holdable(key: &str) -> bool {
+    let symbol = |code| [Layout::Us, Layout::Uk].iter().any(|l| [false, true].map(|s| l.symbol(code, s)).contains(&Some(key)));
+    is_base(key) || is_digit_base(key) || (0..128).any(|code| keycode_to_shifted_digit_base(code) == Some(key) || symbol(code))
+}
+
+/// Letters that can open a popup; the locales only have entries for these.
+/// No built-in [`Layout`] types the Cyrillic ones: they come from `[keymap]`
+/// or `[devices.xkb]`.
+const BASES: [&str; 24] = [
+    "a", "c", "d", "e", "g", "h", "i", "j", "l", "n", "o", "r", "s", "t", "u", "w", "y", "v", "x", "z",
+    "г", "е", "и", "ь",
+];
+
+/// Which letter and symbol each physical key types, set per keyboard in
+/// `[devices]`. Digit and selection keys are the number row on every layout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// ANSI US QWERTY.
+    #[default]
+    Us,
+    /// ISO UK QWERTY: US letters, `"` on Shift+2, `@` on Shift+', `#` left of Enter.
+    Uk,
+    /// German QWERTZ (letters only).
+    De,
+    /// French AZERTY (letters only).
+    Fr,
+    /// US Dvorak (letters only).
+    Dvorak,
+    /// Colemak (letters only).
+    Colemak,
+}
+
+impl Layout {
+    /// The letter a key types, lowercase, if it's a letter key.
+    fn letter(self, code: u16) -> Option<&'static str> {
+        const QWERTY: [(u16, &str); 26] = [
+            (16, "q"), (17, "w"), (18, "e"), (19, "r"), (20, "t"), (21, "y"), (22, "u"), (23, "i"),
+            (24, "o"), (25, "p"), (30, "a"), (31, "s"), (32, "d"), (33, "f"), (34, "g"), (35, "h"),
+            (36, "j"), (37, "k"), (38, "l"), (44, "z"), (45, "x"), (46, "c"), (47, "v"), (48, "b"),
+            (49, "n"), (50, "m"),
+        ];
+        let remapped: &[(u16, &str)] = match self {
+            Layout::Us | Layout::Uk => &[],
+            Layout::De => &[(21, "z"), (44, "y")],
+            Layout::Fr => &[(16, "a"), (17, "z"), (30, "q"), (39, "m"), (44, "w"), (50, "")],
+            Layout::Dvorak => &[
+                (16, ""), (17, ""), (18, ""), (19, "p"), (20, "y"), (21, "f"), (22, "g"), (23, "c"),
+                (24, "r"), (25, "l"), (30, "a"), (31, "o"), (32, "e"), (33, "u"), (34, "i"), (35, "d"),
+                (36, "h"), (37, "t"), (38, "n"), (39, "s"), (44, ""), (45, "q"), (46, "j"), (47, "k"),
+                (48, "x"), (49, "b"), (50, "m"), (51, "w"), (52, "v"), (53, "z"),
+            ],
+            Layout::Colemak => &[
+                (18, "f"), (19, "p"), (20, "g"), (21, "j"), (22, "l"), (23, "u"), (24, "y"), (25, ""),
+                (31, "r"), (32, "s"), (33, "t"), (34, "d"), (36, "n"), (37, "e"), (38, "i"), (39, "o"),
+                (49, "k"),
+            ],
+        };
+        let lookup = |table: &[(u16, &'static str)]| table.iter().find(|(c, _)| *c == code).map(|(_, l)| *l);
+        // An empty entry marks a key that types punctuation on this layout
+        lookup(remapped).or_else(|| lookup(&QWERTY)).filter(|l| !l.is_empty())
+    }
+
+    /// Whether the number row types digits only with Shift, as on AZERTY.
+    /// Popup selection goes by key position either way.
+    pub fn shifted_digits(self) -> bool {
+        self == Layout::Fr
+    }
+
+    /// The accent-eligible base letter a key types, if any.
+    pub fn base(self, code: u16) -> Option<&'static str> {
+        self.letter(code).filter(|l| BASES.contains(l))
+    }
+
+    /// The symbol a punctuation key types, with or without Shift, for
+    /// snippet entries like `"@" = [...]`. Only US and UK have symbols.
+    pub fn symbol(self, code: u16, shift: bool) -> Option<&'static str> {
+        let (plain, shifted) = match (self, code) {
+            (Layout::Us | Layout::Uk, 2) => (None, "!"),
+            (Layout::Us, 3) => (None, "@"),
+            (Layout::Uk, 3) => (None, "\""),
+            (Layout::Us, 4) => (None, "#"),
+            (Layout::Uk, 4) => (None, "£"),
+            (Layout::Us | Layout::Uk, 5) => (None, "$"),
+            (Layout::Us | Layout::Uk, 6) => (None, "%"),
+            (Layout::Us | Layout::Uk, 7) => (None, "^"),
+            (Layout::Us | Layout::Uk, 8) => (None, "&"),
+            (Layout::Us | Layout::Uk, 9) => (None, "*"),
+            (Layout::Us | Layout::Uk, 10) => (None, "("),
+            (Layout::Us | Layout::Uk, 11) => (None, ")"),
+            (Layout::Us | Layout::Uk, 12) => (Some("-"), "_"),
+            (Layout::Us | Layout::Uk, 13) => (Some("="), "+"),
+            (Layout::Us | Layout::Uk, 26) => (Some("["), "{"),
+            (Layout::Us | Layout::Uk, 27) => (Some("]"), "}"),
+            (Layout::Us | Layout::Uk, 39) => (Some(";"), ":"),
+            (Layout::Us, 40) => (Some("'"), "\""),
+            (Layout::Uk, 40) => (Some("'"), "@"),
+            (Layout::Us, 41) => (Some("`"), "~"),
+            (Layout::Uk, 41) => (Some("`"), "¬"),
+            (Layout::Us, 43) => (Some("\\"), "|"),
+            (Layout::Uk, 43) => (Some("#"), "~"),
+            (Layout::Us | Layout::Uk, 51) => (Some(","), "<"),
+            (Layout::Us | Layout::Uk, 52) => (Some("."), ">"),
+            (Layout::Us | Layout::Uk, 53) => (Some("/"), "?"),
+            (Layout::Uk, 86) => (Some("\\"), "|"), // KEY_102ND, the extra ISO key
+            _ => return None,
+        };
+        if shift { Some(shifted) } else { plain }
+    }
+}
+
+/// Per-key overrides of the letters a layout types, from `[keymap]`: evdev
+/// key code to the base letter its key types, for layouts (or remapped
+/// keys) that no [`Layout`] covers. `""` makes a key type no letter.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "BTreeMap<String, String>", into = "BTreeMap<String, String>")]
+pub struct Keymap(BTreeMap<u16, &'static str>);
+
+impl Keymap {
+    /// The accent-eligible base letter a key types: the override if there
+    /// is one, otherwise what `layout` says.
+    pub fn base(&self, layout: Layout, code: u16) -> Option<&'static str> {
+        match self.0.get(&code) {
+            Some(letter) => Some(*letter).filter(|l| BASES.contains(l)),
+            None => layout.base(code),
+        }
+    }
+}
+
+impl TryFrom<BTreeMap<String, String>> for Keymap {
+    type Error = String;
+
+    fn try_from(entries: BTreeMap<String, String>) -> Result<Self, String> {
+        let mut map = BTreeMap::new();
+        for (code, letter) in entries {
+            let code: u16 = code.parse().map_err(|_| format!("[keymap]: {:?} is not a key code", code))?;
+            let letter = letter.to_lowercase();
+            let mut chars = letter.chars();
+            match (chars.next(), chars.next()) {
+                (None, _) => {}
+                (Some(c), None) if c.is_alphabetic() => {}
+                _ => return Err(format!("[keymap]: key {} maps to {:?}, not a single letter", code, letter)),
+            }
+            // Only the bases can open a popup; any other letter is as good as none
+            map.insert(code, BASES.iter().copied().find(|b| *b == letter).unwrap_or(""));
+        }
+        Ok(Self(map))
+    }
+}
+
+impl From<Keymap> for BTreeMap<String, String> {
+    fn from(keymap: Keymap) -> Self {
+        keymap.0.into_iter().map(|(code, letter)| (code.to_string(), letter.to_string())).collect()
     }
 }
 
+/// Map evdev key codes to base letter names on a US layout.
+/// Returns None for keys that are not accent-eligible. Other layouts go
+/// through [`Layout::base`].
+pub fn keycode_to_base(code: u16) -> Option<&'static str> {
+    Layout::Us.base(code)
+}
+
+/// Map punctuation keys to the symbol they type on a US layout; see
+/// [`Layout::symbol`].
+pub fn keycode_to_symbol(code: u16, shift: bool) -> Option<&'static str> {
+    Layout::Us.symbol(code, shift)
+}
+
+/// Map number-row key codes to digit bases for the digit layer. While a
+/// popup is open the same keys select candidates instead
+/// ([`keycode_to_digit`]).
+pub fn keycode_to_digit_base(code: u16) -> Option<&'static str> {
+    const DIGITS: [&str; 10] = ["1", "2", "3", "4", "5", "6", "7", "8", "9", "0"];
+    // KEY_1 (2) through KEY_0 (11)
+    code.checked_sub(2).and_then(|i| DIGITS.get(i as usize)).copied()
+}
+
+/// Map number-row key codes to `shift+<digit>` bases: Shift and a digit key
+/// by position, whatever symbol the layout puts there.
+pub fn keycode_to_shifted_digit_base(code: u16) -> Option<&'static str> {
+    const SHIFTED: [&str; 10] =
+        ["shift+1", "shift+2", "shift+3", "shift+4", "shift+5", "shift+6", "shift+7", "shift+8", "shift+9", "shift+0"];
+    code.checked_sub(2).and_then(|i| SHIFTED.get(i as usize)).copied()
+}
+
 /// Check if a keycode maps to a digit 1-9 (for accent selection).
 /// Returns the 1-indexed number, or None.
 pub fn keycode_to_digit(code: u16) -> Option<u8> {
@@ -131,11 +863,12 @@ pub fn keycode_to_digit(code: u16) -> Option<u8> {
 mod tests {
     use super::*;
 
-    // --- spec: only a, e, i, o, u, n, c, s, y are accent-eligible ---
+    // --- spec: only a, c, d, e, g, i, l, n, o, r, s, t, u, v, y, z are accent-eligible ---
 
     #[test]
     fn accent_eligible_keys_map_to_correct_base() {
-        // evdev keycodes: A=30, C=46, E=18, I=23, N=49, O=24, S=31, U=22, Y=21
+        // evdev keycodes: A=30, C=46, E=18, I=23, N=49, O=24, S=31, U=22, Y=21, L=38, Z=44,
+        // D=32, R=19, T=20, G=34
         assert_eq!(keycode_to_base(30), Some("a"));
         assert_eq!(keycode_to_base(46), Some("c"));
         assert_eq!(keycode_to_base(18), Some("e"));
@@ -145,12 +878,18 @@ mod tests {
         assert_eq!(keycode_to_base(31), Some("s"));
         assert_eq!(keycode_to_base(22), Some("u"));
         assert_eq!(keycode_to_base(21), Some("y"));
+        assert_eq!(keycode_to_base(38), Some("l"));
+        assert_eq!(keycode_to_base(44), Some("z"));
+        assert_eq!(keycode_to_base(32), Some("d"));
+        assert_eq!(keycode_to_base(19), Some("r"));
+        assert_eq!(keycode_to_base(20), Some("t"));
+        assert_eq!(keycode_to_base(34), Some("g"));
     }
 
     #[test]
     fn non_accent_keys_return_none() {
-        // b=48, d=32, f=33, g=34, h=35, j=36, k=37, l=38
-        for code in [48, 32, 33, 34, 35, 36, 37, 38, 0, 255] {
+        // b=48, f=33, k=37
+        for code in [48, 33, 37, 0, 255] {
             assert_eq!(keycode_to_base(code), None, "keycode {} should not be accent-eligible", code);
         }
     }
@@ -233,16 +972,23 @@ mod tests {
         assert_eq!(de["u"][0], "ü");
     }
 
-    // --- spec: all 5 locales exist ---
+    // --- spec: all 13 locales exist ---
 
     #[test]
-    fn all_five_locales_exist_and_are_non_empty() {
-        for name in ["it", "es", "fr", "de", "pt"] {
+    fn all_twenty_three_locales_exist_and_are_non_empty() {
+        for name in ["it", "es", "fr", "de", "pt", "pl", "cs", "sk", "tr", "da", "no", "sv", "is", "eo", "cy", "hr", "ca", "vi", "grc", "ru", "uk", "ipa", "math"] {
             let locale = builtin_locale(name);
             assert!(!locale.is_empty(), "locale '{}' should not be empty", name);
         }
     }
 
+    #[test]
+    fn builtin_locales_names_every_builtin() {
+        for name in BUILTIN_LOCALES {
+            assert!(!builtin_locale(name).is_empty(), "{}", name);
+        }
+    }
+
     #[test]
     fn unknown_locale_returns_empty() {
         assert!(builtin_locale("zz").is_empty());
@@ -267,6 +1013,26 @@ mod tests {
         assert_eq!(accents[1], "É");
     }
 
+    #[test]
+    fn accent_table_matches_resolve_accents() {
+        let it = builtin_locale("it");
+        let table = AccentTable::new(&it);
+        for base in ["a", "e", "i", "o", "u", "z"] {
+            for shift in [false, true] {
+                let expected = resolve_accents(&it, base, shift);
+                let got = table.get(base, shift).map(|c| c.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+                assert_eq!(got, expected, "base {base} shift {shift}");
+            }
+        }
+    }
+
+    #[test]
+    fn accent_table_clones_share_candidates() {
+        let table = AccentTable::new(&builtin_locale("it"));
+        let copy = table.clone();
+        assert!(Arc::ptr_eq(table.get("e", false).unwrap(), copy.get("e", false).unwrap()));
+    }
+
     #[test]
     fn resolve_accents_returns_none_for_unknown_base() {
         let it = builtin_locale("it");
@@ -274,6 +1040,63 @@ mod tests {
         assert!(resolve_accents(&it, "b", false).is_none());
     }
 
+    // --- spec: uppercase follows the locale's language ---
+
+    #[test]
+    fn turkish_dotted_and_dotless_i() {
+        assert_eq!(uppercase("i", "tr"), "İ");
+        assert_eq!(uppercase("ı", "tr"), "I");
+        assert_eq!(uppercase("i", "az-Latn"), "İ");
+        assert_eq!(uppercase("i", "it"), "I");
+    }
+
+    #[test]
+    fn eszett_stays_one_letter() {
+        assert_eq!(uppercase("ß", "de"), "ẞ");
+        assert_eq!(uppercase("ß", ""), "ẞ");
+        assert_eq!(uppercase("straße", "de"), "STRASSE");
+    }
+
+    #[test]
+    fn greek_keeps_accents() {
+        assert_eq!(uppercase("έ", "el"), "Έ");
+        assert_eq!(uppercase("ά", "el-GR"), "Ά");
+    }
+
+    #[test]
+    fn invalid_language_uses_neutral_rules() {
+        assert_eq!(uppercase("i", "not a tag"), "I");
+    }
+
+    #[test]
+    fn accent_table_uppercases_per_language() {
+        let map = LocaleMap::from([("i".to_string(), vec!["ı".to_string(), "i".to_string()])]);
+        let table = AccentTable::new(&map).with_language("tr");
+        // `I` and `i` are what the key types anyway
+        let upper: Vec<_> = table.get("i", true).unwrap().iter().map(|s| s.to_string()).collect();
+        assert_eq!(upper, ["İ"]);
+        let lower: Vec<_> = table.get("i", false).unwrap().iter().map(|s| s.to_string()).collect();
+        assert_eq!(lower, ["ı"]);
+    }
+
+    #[test]
+    fn lowercase_undoes_turkish_uppercase() {
+        assert_eq!(lowercase("İ", "tr"), "i");
+        assert_eq!(lowercase("I", "tr"), "ı");
+        assert_eq!(lowercase("I", "it"), "i");
+    }
+
+    // --- spec: Turkish ---
+
+    #[test]
+    fn turkish_shift_i_offers_dotted_capital() {
+        let table = AccentTable::new(&builtin_locale("tr")).with_language("tr");
+        assert_eq!(table.get("i", true).unwrap()[0].as_ref(), "İ");
+        assert_eq!(table.get("i", false).unwrap()[0].as_ref(), "ı");
+        assert_eq!(table.get("g", true).unwrap()[0].as_ref(), "Ğ");
+        assert_eq!(table.get("s", false).unwrap()[0].as_ref(), "ş");
+    }
+
     // --- spec: Spanish has ñ and ý ---
 
     #[test]
@@ -307,4 +1130,262 @@ mod tests {
         let pt = builtin_locale("pt");
         assert_eq!(pt["o"][0], "õ");
     }
+
+    // --- spec: Polish covers ł and both z accents ---
+
+    #[test]
+    fn polish_l_and_z_open_popups() {
+        let pl = builtin_locale("pl");
+        assert_eq!(pl["l"], vec!["ł"]);
+        assert_eq!(pl["z"], vec!["ż", "ź"]);
+        let table = AccentTable::new(&pl);
+        assert_eq!(table.get("l", true).map(|c| c[0].to_string()), Some("Ł".to_string()));
+    }
+
+    // --- spec: Czech and Slovak háček and čárka ---
+
+    #[test]
+    fn czech_has_hacek_letters_on_their_keys() {
+        let cs = builtin_locale("cs");
+        for (base, first) in [("r", "ř"), ("t", "ť"), ("d", "ď"), ("z", "ž"), ("e", "ě"), ("u", "ů")] {
+            assert_eq!(cs[base][0], first);
+        }
+    }
+
+    #[test]
+    fn slovak_has_its_own_letters_and_no_czech_ones() {
+        let sk = builtin_locale("sk");
+        assert_eq!(sk["l"], vec!["ľ", "ĺ"]);
+        assert_eq!(sk["r"], vec!["ŕ"]);
+        assert_eq!(sk["o"][0], "ô");
+        assert!(!sk["e"].contains(&"ě".to_string()));
+        assert!(!sk["u"].contains(&"ů".to_string()));
+    }
+
+    // --- spec: Scandinavian letters on a and o ---
+
+    #[test]
+    fn scandinavian_locales_order_a_and_o_their_own_way() {
+        assert_eq!(builtin_locale("da")["a"], vec!["æ", "å"]);
+        assert_eq!(builtin_locale("no")["a"], vec!["å", "æ"]);
+        assert_eq!(builtin_locale("sv")["a"], vec!["ä", "å"]);
+        assert_eq!(builtin_locale("da")["o"][0], "ø");
+        assert_eq!(builtin_locale("no")["o"][0], "ø");
+        assert_eq!(builtin_locale("sv")["o"][0], "ö");
+    }
+
+    // --- spec: Icelandic thorn and eth ---
+
+    #[test]
+    fn icelandic_thorn_on_t_and_eth_on_d() {
+        let table = AccentTable::new(&builtin_locale("is")).with_language("is");
+        let upper = |base| table.get(base, true).unwrap().iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(upper("t"), ["Þ"]);
+        assert_eq!(upper("d"), ["Ð"]);
+        assert_eq!(builtin_locale("is")["o"], vec!["ó", "ö"]);
+    }
+
+    // --- spec: Esperanto on h and j ---
+
+    #[test]
+    fn esperanto_accents_h_and_j() {
+        assert_eq!(Layout::Us.base(35), Some("h"));
+        assert_eq!(Layout::Us.base(36), Some("j"));
+        let table = AccentTable::new(&builtin_locale("eo")).with_language("eo");
+        assert_eq!(table.get("h", false).unwrap()[0].as_ref(), "ĥ");
+        assert_eq!(table.get("j", true).unwrap()[0].as_ref(), "Ĵ");
+    }
+
+    // --- spec: Welsh on w ---
+
+    #[test]
+    fn welsh_accents_w_and_y() {
+        assert_eq!(Layout::Us.base(17), Some("w"));
+        assert_eq!(Layout::Fr.base(44), Some("w"));
+        let table = AccentTable::new(&builtin_locale("cy")).with_language("cy");
+        assert_eq!(table.get("w", true).unwrap()[0].as_ref(), "Ŵ");
+        assert_eq!(table.get("y", false).unwrap()[0].as_ref(), "ŷ");
+    }
+
+    // --- spec: Croatian and Serbian Latin ---
+
+    #[test]
+    fn serbian_latin_is_croatian() {
+        assert_eq!(builtin_locale("sr-latn"), builtin_locale("hr"));
+        assert_eq!(builtin_locale("hr")["c"], vec!["č", "ć"]);
+    }
+
+    // --- spec: Cyrillic ---
+
+    #[test]
+    fn cyrillic_letters_are_bases_through_the_keymap() {
+        let keymap = Keymap::try_from(BTreeMap::from([("20".to_string(), "Е".to_string())])).unwrap();
+        assert_eq!(keymap.base(Layout::Us, 20), Some("е"));
+        assert_eq!(Layout::Us.base(20), Some("t"));
+        let table = AccentTable::new(&builtin_locale("ru")).with_language("ru");
+        assert_eq!(table.get("е", true).unwrap()[0].as_ref(), "Ё");
+        assert_eq!(builtin_locale("uk")["и"], vec!["і", "ї", "й"]);
+    }
+
+    // --- spec: IPA ---
+
+    #[test]
+    fn ipa_symbols_on_mnemonic_keys() {
+        let ipa = builtin_locale("ipa");
+        for (base, symbol) in [("e", "ə"), ("s", "ʃ"), ("z", "ʒ"), ("n", "ŋ"), ("t", "θ"), ("d", "ð"), ("e", "ɛ"), ("o", "ɔ")] {
+            assert!(ipa[base].iter().any(|c| c == symbol), "{} on {}", symbol, base);
+        }
+        assert_eq!(ipa["e"][0], "ə");
+        assert_eq!(ipa[":"][0], "ː");
+        let typed = |base: &str| (0..128).any(|code| [false, true].map(|shift| Layout::Us.symbol(code, shift)).contains(&Some(base)));
+        assert!(ipa.keys().all(|base| is_base(base) || typed(base)), "every key can be held on US");
+    }
+
+    // --- spec: math ---
+
+    #[test]
+    fn math_operators_on_lookalike_keys() {
+        let math = builtin_locale("math");
+        assert_eq!(Layout::Us.base(45), Some("x"));
+        assert_eq!(math["x"], vec!["×", "·", "⋅"]);
+        assert_eq!(math["-"], vec!["−", "±", "∓"]);
+        assert_eq!(math["="], vec!["≈", "≠", "≡"]);
+        assert_eq!(math["8"], vec!["∞"]);
+    }
+
+    // --- spec: polytonic Greek ---
+
+    #[test]
+    fn polytonic_greek_on_transliterated_vowels() {
+        assert_eq!(builtin_locale("el-poly"), builtin_locale("grc"));
+        let table = AccentTable::new(&builtin_locale("grc")).with_language("grc");
+        let eta: Vec<_> = table.get("h", false).unwrap().iter().map(|s| s.to_string()).collect();
+        assert_eq!(eta[..5], ["η", "ή", "ὴ", "ῆ", "ἠ"]);
+        assert_eq!(table.get("w", true).unwrap()[8].as_ref(), "Ὡ", "rough breathing survives uppercasing");
+        assert_eq!(table.get("a", true).unwrap()[12].as_ref(), "ᾼ", "iota subscript stays one letter");
+        for candidates in builtin_locale("grc").values() {
+            assert!(candidates.iter().all(|c| c.chars().count() == 1));
+        }
+    }
+
+    // --- spec: Catalan l·l ---
+
+    #[test]
+    fn catalan_l_offers_the_geminate() {
+        let table = AccentTable::new(&builtin_locale("ca")).with_language("ca");
+        let lower: Vec<_> = table.get("l", false).unwrap().iter().map(|s| s.to_string()).collect();
+        assert_eq!(lower, ["l·l", "ŀl"]);
+        let upper: Vec<_> = table.get("l", true).unwrap().iter().map(|s| s.to_string()).collect();
+        assert_eq!(upper, ["L·L", "ĿL"]);
+    }
+
+    #[test]
+    fn vietnamese_offers_every_tone_and_d_stroke() {
+        let table = AccentTable::new(&builtin_locale("vi")).with_language("vi");
+        assert_eq!(table.get("a", false).unwrap().len(), 17);
+        assert_eq!(table.get("o", false).unwrap().len(), 17);
+        let upper: Vec<_> = table.get("d", true).unwrap().iter().map(|s| s.to_string()).collect();
+        assert_eq!(upper, ["Đ"]);
+    }
+
+    // --- spec: emoji are one character, snippets several ---
+
+    #[test]
+    fn emoji_sequences_are_not_snippets() {
+        for emoji in ["é", "😀", "❤️", "👍🏽", "👩‍💻", "1️⃣"] {
+            assert!(!is_snippet(emoji), "{}", emoji);
+        }
+        assert!(is_snippet("etc."));
+        assert!(is_snippet("😀😀"));
+    }
+
+    // --- spec: digit layer ---
+
+    #[test]
+    fn number_row_maps_to_digit_bases() {
+        assert_eq!(keycode_to_digit_base(2), Some("1"));
+        assert_eq!(keycode_to_digit_base(10), Some("9"));
+        assert_eq!(keycode_to_digit_base(11), Some("0"));
+        assert_eq!(keycode_to_digit_base(12), None); // KEY_MINUS
+        assert_eq!(keycode_to_digit_base(1), None); // KEY_ESC
+    }
+
+    #[test]
+    fn digit_layer_covers_every_digit_within_nine_candidates() {
+        let layer = digit_layer();
+        for digit in ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"] {
+            assert!(is_digit_base(digit));
+            let candidates = &layer[digit];
+            assert!(!candidates.is_empty() && candidates.len() <= 9, "{digit}");
+        }
+        assert_eq!(layer["2"][0], "²");
+        assert!(!is_digit_base("e"));
+    }
+
+    // --- spec: snippet symbols ---
+
+    #[test]
+    fn shifted_digits_are_named_by_position() {
+        assert_eq!(keycode_to_shifted_digit_base(5), Some("shift+4"));
+        assert_eq!(keycode_to_shifted_digit_base(11), Some("shift+0"));
+        assert_eq!(keycode_to_shifted_digit_base(12), None);
+    }
+
+    #[test]
+    fn shifted_number_row_types_symbols() {
+        assert_eq!(keycode_to_symbol(3, true), Some("@"));
+        assert_eq!(keycode_to_symbol(10, true), Some("("));
+        assert_eq!(keycode_to_symbol(3, false), None); // plain 2 is a digit
+    }
+
+    #[test]
+    fn punctuation_types_both_symbols() {
+        assert_eq!(keycode_to_symbol(52, false), Some("."));
+        assert_eq!(keycode_to_symbol(52, true), Some(">"));
+        assert_eq!(keycode_to_symbol(43, false), Some("\\"));
+        assert_eq!(keycode_to_symbol(18, false), None); // KEY_E
+    }
+
+    // --- spec: per-keyboard layouts ---
+
+    #[test]
+    fn layouts_move_letters() {
+        assert_eq!(Layout::De.base(21), Some("z")); // z on QWERTZ
+        assert_eq!(Layout::De.base(44), Some("y"));
+        assert_eq!(Layout::Fr.base(16), Some("a"));
+        assert_eq!(Layout::Fr.base(30), None); // q on AZERTY
+        assert_eq!(Layout::Dvorak.base(32), Some("e"));
+        assert_eq!(Layout::Dvorak.base(18), None); // "." on Dvorak
+        assert_eq!(Layout::Colemak.base(37), Some("e"));
+        assert_eq!(Layout::Colemak.base(31), Some("r")); // r on Colemak
+        assert_eq!(Layout::Colemak.base(33), Some("t")); // f on QWERTY
+    }
+
+    #[test]
+    fn only_azerty_shifts_for_digits() {
+        assert!(Layout::Fr.shifted_digits());
+        assert!(!Layout::De.shifted_digits());
+        assert!(!Layout::Us.shifted_digits());
+    }
+
+    #[test]
+    fn every_layout_reaches_every_base() {
+        for layout in [Layout::Us, Layout::Uk, Layout::De, Layout::Fr, Layout::Dvorak, Layout::Colemak] {
+            let mut bases: Vec<&str> = (0..128).filter_map(|code| layout.base(code)).collect();
+            bases.sort_unstable();
+            let mut expected: Vec<&str> = BASES.iter().copied().filter(|b| b.is_ascii()).collect();
+            expected.sort_unstable();
+            assert_eq!(bases, expected, "{:?}", layout);
+        }
+    }
+
+    #[test]
+    fn uk_symbols_differ_from_us() {
+        assert_eq!(Layout::Uk.symbol(3, true), Some("\""));
+        assert_eq!(Layout::Uk.symbol(40, true), Some("@"));
+        assert_eq!(Layout::Uk.symbol(43, false), Some("#"));
+        assert_eq!(Layout::Uk.symbol(86, false), Some("\\"));
+        assert_eq!(Layout::Us.symbol(86, false), None);
+        assert_eq!(Layout::De.symbol(3, true), None);
+    }
 }
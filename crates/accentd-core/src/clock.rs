@@ -0,0 +1,97 @@
+//! Time source for the state machine.
+//!
+//! Hold thresholds and popup timeouts are measured against a [`Clock`] rather
+//! than `Instant::now()` directly, so embedders and tests can drive time
+//! themselves with [`MockClock`].
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Source of the current time.
+pub trait Clock {
+    fn now(&self) -> Instant;
+
+    /// How long until `deadline`; zero if it has already passed. Main loops
+    /// sleep this long before calling `check_timer`.
+    fn time_until(&self, deadline: Instant) -> Duration {
+        deadline.saturating_duration_since(self.now())
+    }
+}
+
+/// The real monotonic clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time, so a
+/// test can keep one handle and give another to the state machine.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self { now: Arc::new(Mutex::new(Instant::now())) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+
+    /// Jump to a point in time (e.g. a deadline the state machine reported).
+    pub fn set(&self, now: Instant) {
+        *self.now.lock().unwrap() = now;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_only_moves_when_advanced() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::from_millis(300));
+        assert_eq!(clock.now() - start, Duration::from_millis(300));
+    }
+
+    #[test]
+    fn time_until_counts_down_to_zero() {
+        let clock = MockClock::new();
+        let deadline = clock.now() + Duration::from_millis(300);
+        assert_eq!(clock.time_until(deadline), Duration::from_millis(300));
+        clock.advance(Duration::from_millis(200));
+        assert_eq!(clock.time_until(deadline), Duration::from_millis(100));
+        clock.advance(Duration::from_millis(200));
+        assert_eq!(clock.time_until(deadline), Duration::ZERO);
+    }
+
+    #[test]
+    fn mock_clock_clones_share_time() {
+        let clock = MockClock::new();
+        let other = clock.clone();
+        let start = other.now();
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(other.now() - start, Duration::from_secs(1));
+    }
+}
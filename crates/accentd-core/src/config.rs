@@ -1,6 +1,9 @@
+use crate::charmap::{DescriptionMap, EraseMap, Keymap, Layout, LocaleMap, Selection, UpperMap};
+use crate::ipc::{LocaleInfo, LocaleSource};
+use crate::locale::{Locale, LocaleFile};
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -11,26 +14,88 @@ pub struct Config {
     pub popup: PopupConfig,
     #[serde(default)]
     pub locale: LocaleConfig,
+    #[serde(default)]
+    pub compositor: CompositorConfig,
+    #[serde(default)]
+    pub devices: DevicesConfig,
+    #[serde(default)]
+    pub layers: LayersConfig,
+    #[serde(default)]
+    pub compose: ComposeConfig,
+    /// Whole-string candidates added to every locale, e.g.
+    /// `"@" = ["me@example.com"]`. Appended after the locale's own.
+    #[serde(default)]
+    pub snippets: LocaleMap,
+    #[serde(default)]
+    pub emoji: EmojiConfig,
+    /// Key code to base letter overrides for layouts `[devices] layout`
+    /// doesn't cover, e.g. `18 = "e"`. Apply to every keyboard.
+    #[serde(default)]
+    pub keymap: Keymap,
 }
 
+/// Hold threshold used when neither the config nor the system provides one.
+pub const DEFAULT_THRESHOLD_MS: u64 = 300;
+
+/// How far past the autorepeat delay the popup should appear.
+const REPEAT_DELAY_MARGIN_MS: u64 = 50;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GeneralConfig {
-    #[serde(default = "GeneralConfig::default_threshold")]
-    pub threshold_ms: u64,
+    /// Unset means "derive from the system key-repeat delay".
+    #[serde(default)]
+    pub threshold_ms: Option<u64>,
     #[serde(default = "GeneralConfig::default_enabled")]
     pub enabled: bool,
+    /// Pure relay while the session is locked or idle (via logind).
+    #[serde(default = "GeneralConfig::default_suspend_on_lock")]
+    pub suspend_on_lock: bool,
+    /// Let `accentctl monitor` stream every key event. Off by default: the
+    /// stream is a keylogger for anyone who can reach the socket.
+    #[serde(default)]
+    pub allow_monitor: bool,
+    /// Grab keyboards exclusively. Off: only watch them and type picks over
+    /// what the hold already typed, without suppressing autorepeat.
+    #[serde(default = "GeneralConfig::default_grab")]
+    pub grab: bool,
+    /// Let go of the keyboards while disabled, so accentd is out of the
+    /// input path entirely; they are grabbed again on enable.
+    #[serde(default)]
+    pub ungrab_when_disabled: bool,
+    /// Hold back the held letter until the hold is decided: a short press
+    /// types it on release, a pick types only the candidate, with no
+    /// BackSpace for the app to see. Ignored without `grab`.
+    #[serde(default)]
+    pub defer_base: bool,
 }
 
 impl GeneralConfig {
-    fn default_threshold() -> u64 { 300 }
     fn default_enabled() -> bool { true }
+    fn default_grab() -> bool { true }
+    fn default_suspend_on_lock() -> bool { true }
+
+    /// The effective hold threshold in milliseconds.
+    pub fn threshold(&self) -> u64 {
+        self.threshold_ms.unwrap_or(DEFAULT_THRESHOLD_MS)
+    }
+}
+
+/// Pick a hold threshold slightly above the autorepeat delay, so the popup
+/// appears right when autorepeat would have started.
+pub fn threshold_from_repeat_delay(delay_ms: u64) -> u64 {
+    (delay_ms + REPEAT_DELAY_MARGIN_MS).clamp(150, 2000)
 }
 
 impl Default for GeneralConfig {
     fn default() -> Self {
         Self {
-            threshold_ms: 300,
+            threshold_ms: None,
             enabled: true,
+            suspend_on_lock: true,
+            allow_monitor: false,
+            grab: true,
+            ungrab_when_disabled: false,
+            defer_base: false,
         }
     }
 }
@@ -43,12 +108,36 @@ pub struct PopupConfig {
     pub timeout_ms: u64,
     #[serde(default = "PopupConfig::default_keep_open")]
     pub keep_open: bool,
+    /// With no popup client connected, show the candidates as a desktop
+    /// notification (they are always logged).
+    #[serde(default = "PopupConfig::default_notify_fallback")]
+    pub notify_fallback: bool,
+    #[serde(default)]
+    pub order: CandidateOrder,
+    /// Releasing the held key picks the highlighted candidate (moved with
+    /// the arrows or Tab), like a phone's long-press.
+    #[serde(default)]
+    pub release_to_select: bool,
+}
+
+/// How popup candidates are ordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CandidateOrder {
+    /// As listed in the locale.
+    #[default]
+    Locale,
+    /// Most-picked first, learning from every pick.
+    Adaptive,
+    /// Most-picked first, by the counts learned so far; picks are no longer counted.
+    Frozen,
 }
 
 impl PopupConfig {
     fn default_font_size() -> u32 { 24 }
     fn default_timeout() -> u64 { 5000 }
     fn default_keep_open() -> bool { true }
+    fn default_notify_fallback() -> bool { true }
 }
 
 impl Default for PopupConfig {
@@ -57,6 +146,9 @@ impl Default for PopupConfig {
             font_size: 24,
             timeout_ms: 5000,
             keep_open: true,
+            notify_fallback: true,
+            order: CandidateOrder::Locale,
+            release_to_select: false,
         }
     }
 }
@@ -64,24 +156,455 @@ impl Default for PopupConfig {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LocaleConfig {
     #[serde(default = "LocaleConfig::default_active")]
-    pub active: String,
+    pub active: ActiveLocales,
+    /// How the candidates of several active locales are combined.
+    #[serde(default)]
+    pub merge: MergeOrder,
+    /// Offer a candidate only once when several active locales list it.
+    #[serde(default = "LocaleConfig::default_dedupe")]
+    pub dedupe: bool,
+    /// `[locale.overrides]`: per-key tweaks on top of the active locale.
+    #[serde(default)]
+    pub overrides: BTreeMap<String, KeyOverride>,
+    /// `[locale.upper]`: uppercase forms that override the language's rules.
+    #[serde(default)]
+    pub upper: UpperMap,
+    /// `[locale.erase]`: characters a pick replaces, per candidate.
+    #[serde(default)]
+    pub erase: EraseMap,
+    /// `[locale.descriptions]`: names for candidates, on top of the
+    /// locale file's.
+    #[serde(default)]
+    pub descriptions: DescriptionMap,
     #[serde(flatten)]
-    pub locales: HashMap<String, HashMap<String, Vec<String>>>,
+    pub locales: HashMap<String, LocaleMap>,
 }
 
 impl LocaleConfig {
-    fn default_active() -> String { "it".into() }
+    fn default_active() -> ActiveLocales { "it".into() }
+    fn default_dedupe() -> bool { true }
 }
 
 impl Default for LocaleConfig {
     fn default() -> Self {
         Self {
             active: "it".into(),
+            merge: MergeOrder::Sequential,
+            dedupe: true,
+            overrides: BTreeMap::new(),
+            upper: UpperMap::new(),
+            erase: EraseMap::new(),
+            descriptions: DescriptionMap::new(),
             locales: HashMap::new(),
         }
     }
 }
 
+/// `[locale] active`: one locale, or several merged under one hold. Written
+/// `"it"` or `["it", "de"]` in the config; comma-separated (`it,de`) over
+/// IPC, in status and in event logs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "OneOrMany", into = "OneOrMany")]
+pub struct ActiveLocales(Vec<String>);
+
+impl ActiveLocales {
+    pub fn names(&self) -> &[String] {
+        &self.0
+    }
+}
+
+impl From<&str> for ActiveLocales {
+    fn from(names: &str) -> Self {
+        Self(split_names(names).map(String::from).collect())
+    }
+}
+
+impl From<String> for ActiveLocales {
+    fn from(names: String) -> Self {
+        names.as_str().into()
+    }
+}
+
+impl PartialEq<&str> for ActiveLocales {
+    fn eq(&self, names: &&str) -> bool {
+        self.0.iter().map(String::as_str).eq(split_names(names))
+    }
+}
+
+impl std::fmt::Display for ActiveLocales {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0.join(","))
+    }
+}
+
+fn split_names(names: &str) -> impl Iterator<Item = &str> {
+    names.split(',').map(str::trim).filter(|n| !n.is_empty())
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<OneOrMany> for ActiveLocales {
+    fn from(names: OneOrMany) -> Self {
+        match names {
+            OneOrMany::One(name) => name.into(),
+            OneOrMany::Many(names) => Self(names),
+        }
+    }
+}
+
+impl From<ActiveLocales> for OneOrMany {
+    fn from(active: ActiveLocales) -> Self {
+        match <[String; 1]>::try_from(active.0) {
+            Ok([name]) => Self::One(name),
+            Err(names) => Self::Many(names),
+        }
+    }
+}
+
+/// `[locale.overrides.<key>]`: changes to one key's candidates, so a locale
+/// can be tweaked without copying it whole.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyOverride {
+    /// Candidates to drop.
+    #[serde(default)]
+    pub remove: Vec<String>,
+    /// Candidates to append, unless already offered.
+    #[serde(default)]
+    pub add: Vec<String>,
+    /// Candidates to move to the front in this order, added if missing.
+    #[serde(default)]
+    pub first: Vec<String>,
+}
+
+impl KeyOverride {
+    pub fn apply(&self, candidates: &mut Vec<String>) {
+        candidates.retain(|c| !self.remove.contains(c));
+        for added in &self.add {
+            if !candidates.contains(added) {
+                candidates.push(added.clone());
+            }
+        }
+        for first in self.first.iter().rev() {
+            candidates.retain(|c| c != first);
+            candidates.insert(0, first.clone());
+        }
+    }
+}
+
+/// Order of a key's candidates when several locales are active.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeOrder {
+    /// All of the first locale's candidates, then the next locale's.
+    #[default]
+    Sequential,
+    /// Each locale's first candidate, then each one's second, and so on.
+    Interleave,
+}
+
+/// Which compositor to ask about the focused window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FocusProvider {
+    /// Use the first compositor found running; KWin only in a Plasma session.
+    #[default]
+    Auto,
+    Sway,
+    Hyprland,
+    Kwin,
+    X11,
+    /// Don't track the focused window.
+    None,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompositorConfig {
+    #[serde(default)]
+    pub provider: FocusProvider,
+    /// Gaming mode: pure relay while the focused window is fullscreen.
+    #[serde(default = "CompositorConfig::default_suspend_on_fullscreen")]
+    pub suspend_on_fullscreen: bool,
+}
+
+impl CompositorConfig {
+    fn default_suspend_on_fullscreen() -> bool { true }
+}
+
+impl Default for CompositorConfig {
+    fn default() -> Self {
+        Self {
+            provider: FocusProvider::Auto,
+            suspend_on_fullscreen: true,
+        }
+    }
+}
+
+/// Which input devices to grab.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct DevicesConfig {
+    /// Names of remapper virtual keyboards (keyd, kanata) to read from instead
+    /// of the physical keyboards they already grab. Empty: grab physical keyboards.
+    #[serde(default)]
+    pub upstream: Vec<String>,
+    /// Extra device names to never grab (case-insensitive substring match).
+    /// On-screen keyboards are always skipped.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Device names to grab even though they don't look enough like a
+    /// keyboard (case-insensitive substring match). `exclude` still wins.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// How keyboards are opened.
+    #[serde(default)]
+    pub access: DeviceAccess,
+    /// Give each grabbed keyboard its own virtual keyboard with its name and
+    /// ids, instead of one shared "accentd virtual keyboard".
+    #[serde(default)]
+    pub per_keyboard_output: bool,
+    /// Physical layout of keyboards not listed in `layouts`.
+    #[serde(default)]
+    pub layout: Layout,
+    /// Per-keyboard layouts, keyed by device name (case-insensitive
+    /// substring) or `vendor:product` id in hex, e.g. `"046d:c31c" = "uk"`.
+    #[serde(default)]
+    pub layouts: BTreeMap<String, Layout>,
+    /// The compositor's layouts in switching order, e.g. `["us", "de"]`.
+    /// When it switches (sway and Hyprland report it), every keyboard
+    /// follows. Empty: switches only matter to `[devices.xkb]`.
+    #[serde(default)]
+    pub layout_groups: Vec<Layout>,
+    /// Take every keyboard's letters from an XKB layout instead.
+    #[serde(default)]
+    pub xkb: XkbConfig,
+}
+
+/// `[devices.xkb]`: resolve keys through libxkbcommon (the daemon's `xkb`
+/// build feature). Names left empty come from the X server's keymap when
+/// there is a `$DISPLAY`, then from libxkbcommon's defaults
+/// (`$XKB_DEFAULT_LAYOUT` and friends, else `us`).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct XkbConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub rules: String,
+    #[serde(default)]
+    pub model: String,
+    /// e.g. `"fr"`, or `"us,it"` for two groups; the compositor's active
+    /// group is followed where it reports one, else the first is used.
+    #[serde(default)]
+    pub layout: String,
+    #[serde(default)]
+    pub variant: String,
+    #[serde(default)]
+    pub options: String,
+}
+
+/// How the daemon gets at /dev/input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceAccess {
+    /// Open the device nodes directly (root, the `input` group or a udev rule).
+    #[default]
+    Direct,
+    /// Ask logind for them (TakeDevice), as the session's controller; for
+    /// running as a plain user service.
+    Logind,
+}
+
+impl DevicesConfig {
+    /// The layout for a keyboard: an id match wins over a name match, then
+    /// the default `layout`.
+    pub fn layout_for(&self, name: &str, id: Option<(u16, u16)>) -> Layout {
+        let id = id.map(|(vendor, product)| format!("{:04x}:{:04x}", vendor, product));
+        let name = name.to_lowercase();
+        self.layouts
+            .iter()
+            .find(|(key, _)| id.as_deref() == Some(key.to_lowercase().as_str()))
+            .or_else(|| self.layouts.iter().find(|(key, _)| name.contains(&key.to_lowercase())))
+            .map_or(self.layout, |(_, layout)| *layout)
+    }
+}
+
+/// Which keys can open a popup.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LayersConfig {
+    /// Letters and symbols, with the active locale's candidates.
+    #[serde(default = "LayersConfig::default_letters")]
+    pub letters: bool,
+    /// Number-row digits, with superscripts, subscripts and fractions.
+    /// Digits still select candidates while a popup is open.
+    #[serde(default)]
+    pub digits: bool,
+    /// Dashes, quotes and the ellipsis on their punctuation keys. Symbols
+    /// the locale or `[snippets]` list work without it.
+    #[serde(default)]
+    pub punctuation: bool,
+    /// Currencies and a few other symbols on shifted digits.
+    #[serde(default)]
+    pub symbols: bool,
+}
+
+impl LayersConfig {
+    fn default_letters() -> bool { true }
+
+    /// `map` restricted to the enabled layers, with the built-in digit,
+    /// punctuation and symbols layers added when enabled. Entries the locale defines
+    /// itself win.
+    pub fn apply(&self, mut map: LocaleMap) -> LocaleMap {
+        map.retain(|base, _| {
+            if crate::charmap::is_digit_base(base) { self.digits } else { self.letters }
+        });
+        if self.digits {
+            for (digit, candidates) in crate::charmap::digit_layer() {
+                map.entry(digit).or_insert(candidates);
+            }
+        }
+        if self.punctuation {
+            for (symbol, candidates) in crate::charmap::punctuation_layer() {
+                map.entry(symbol).or_insert(candidates);
+            }
+        }
+        if self.symbols {
+            for (symbol, candidates) in crate::charmap::symbols_layer() {
+                map.entry(symbol).or_insert(candidates);
+            }
+        }
+        map
+    }
+}
+
+impl Default for LayersConfig {
+    fn default() -> Self {
+        Self { letters: true, digits: false, punctuation: false, symbols: false }
+    }
+}
+
+/// `[compose]`: how picked candidates are typed.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ComposeConfig {
+    #[serde(default)]
+    pub backend: ComposeBackend,
+    /// Per-app backends, keyed by Wayland app_id or X11 WM_CLASS
+    /// (case-insensitive substring), e.g. `"Slack" = "clipboard"`. Needs
+    /// `[compositor]` focus tracking.
+    #[serde(default)]
+    pub apps: BTreeMap<String, ComposeBackend>,
+    /// Ctrl+Shift+U: the fewest hex digits a code point is typed with,
+    /// zero-padded; code points outside the BMP take five or six whatever
+    /// this says. 6 or 8 for input methods that want every code the same
+    /// length.
+    #[serde(default)]
+    pub hex_digits: HexDigits,
+}
+
+impl ComposeConfig {
+    /// The backend for picks in `app`: a whole-name match wins over a
+    /// substring one, then the default `backend`.
+    pub fn backend_for(&self, app: Option<&str>) -> ComposeBackend {
+        let Some(app) = app.map(str::to_lowercase) else {
+            return self.backend;
+        };
+        self.apps
+            .iter()
+            .find(|(key, _)| key.to_lowercase() == app)
+            .or_else(|| self.apps.iter().find(|(key, _)| app.contains(&key.to_lowercase())))
+            .map_or(self.backend, |(_, backend)| *backend)
+    }
+}
+
+/// `[compose] hex_digits`: 4, GTK's own padding, to 8, the most GTK and
+/// IBus take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "usize", into = "usize")]
+pub struct HexDigits(usize);
+
+impl HexDigits {
+    pub fn get(self) -> usize {
+        self.0
+    }
+}
+
+impl Default for HexDigits {
+    fn default() -> Self {
+        Self(4)
+    }
+}
+
+impl TryFrom<usize> for HexDigits {
+    type Error = String;
+
+    fn try_from(digits: usize) -> Result<Self, String> {
+        if !(4..=8).contains(&digits) {
+            return Err(format!("[compose] hex_digits: {} is not between 4 and 8", digits));
+        }
+        Ok(Self(digits))
+    }
+}
+
+impl From<HexDigits> for usize {
+    fn from(digits: HexDigits) -> Self {
+        digits.0
+    }
+}
+
+/// What types a pick. Keys are always relayed through uinput; backends
+/// other than it fall back to it when they can't type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ComposeBackend {
+    /// `xtest` in X11 sessions (`XDG_SESSION_TYPE=x11`), else
+    /// `input-method` where the compositor has it, else `uinput`.
+    #[default]
+    Auto,
+    /// Ctrl+Shift+U and the hex code point on the uinput keyboard (GTK, Qt).
+    Uinput,
+    /// Handed to the focused field as an input method would
+    /// (zwp_input_method_v2): no BackSpace, no hex code. Fields with
+    /// text-input-v3 on wlroots compositors and KWin.
+    InputMethod,
+    /// A Wayland virtual keyboard (zwp_virtual_keyboard_v1) whose keymap
+    /// has the candidate on a key of its own: one keypress, any toolkit.
+    /// wlroots compositors (sway, Hyprland, river); not GNOME.
+    WaylandVk,
+    /// Put on the clipboard and pasted with Ctrl+V (Shift+Insert in
+    /// terminals), then the clipboard is put back. Electron apps and
+    /// terminals, where Ctrl+Shift+U fails. Needs wl-clipboard.
+    Clipboard,
+    /// Sent to the X server with XTEST, each character as its keysym, put
+    /// on an unused keycode if the keyboard mapping lacks it. X11 only.
+    Xtest,
+}
+
+/// `[emoji]`: favorite emoji on a key of their own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmojiConfig {
+    /// The key to hold, as a locale key: a letter, a symbol (`"."`, `":"`)
+    /// or a Shift chord (`"shift+1"`). Unset turns the layer off.
+    #[serde(default)]
+    pub key: Option<String>,
+    /// Offered in this order, replacing whatever else the key has.
+    #[serde(default = "EmojiConfig::default_favorites")]
+    pub favorites: Vec<String>,
+}
+
+impl EmojiConfig {
+    fn default_favorites() -> Vec<String> {
+        ["👍", "😂", "❤️", "🙏", "😊", "🎉", "😅", "🤔", "👀"].map(Into::into).to_vec()
+    }
+}
+
+impl Default for EmojiConfig {
+    fn default() -> Self {
+        Self { key: None, favorites: Self::default_favorites() }
+    }
+}
+
 impl Config {
     pub fn config_dir() -> PathBuf {
         dirs::config_dir()
@@ -108,35 +631,258 @@ impl Config {
         toml::from_str(&contents).with_context(|| "parsing config TOML")
     }
 
-    pub fn load_locale_map(&self) -> Result<HashMap<String, Vec<String>>> {
+    /// The active locale's map, restricted to the enabled `[layers]`.
+    pub fn load_locale_map(&self) -> Result<LocaleMap> {
+        self.load_locale().map(|locale| locale.map)
+    }
+
+    /// The active locale, its map restricted to the enabled `[layers]`,
+    /// tweaked by `[locale.overrides]`, extended with `[snippets]` and the
+    /// `[emoji]` key, with `[locale.upper]` on top of its uppercase forms.
+    pub fn load_locale(&self) -> Result<Locale> {
+        let Locale { name, map, selection, language, mut upper, mut erase, mut descriptions, meta, extends } =
+            self.load_active_locale()?;
+        upper.extend(self.locale.upper.clone());
+        erase.extend(self.locale.erase.clone());
+        descriptions.extend(self.locale.descriptions.clone());
+        let mut map = self.layers.apply(map);
+        for (base, tweak) in &self.locale.overrides {
+            let candidates = map.entry(base.clone()).or_default();
+            tweak.apply(candidates);
+            if candidates.is_empty() {
+                map.remove(base);
+            }
+        }
+        for (base, snippets) in &self.snippets {
+            let candidates = map.entry(base.clone()).or_default();
+            for snippet in snippets {
+                if !candidates.contains(snippet) {
+                    candidates.push(snippet.clone());
+                }
+            }
+        }
+        if let Some(key) = &self.emoji.key {
+            if !self.emoji.favorites.is_empty() {
+                map.insert(key.clone(), self.emoji.favorites.clone());
+            }
+        }
+        Ok(Locale { name, map, selection, language, upper, erase, descriptions, meta, extends })
+    }
+
+    /// The active locales merged in `[locale] merge` order. The first
+    /// decides selection and language.
+    fn load_active_locale(&self) -> Result<Locale> {
+        let mut names = self.locale.active.names().iter();
+        let first = names.next().context("[locale] active names no locale")?;
+        let mut active = self.load_named_locale(first)?;
+        let mut loaded = vec![active.name.clone()];
+        for name in names {
+            let next = self.load_named_locale(name)?;
+            loaded.push(next.name.clone());
+            for (base, theirs) in next.map {
+                let ours = active.map.entry(base).or_default();
+                *ours = merge_candidates(std::mem::take(ours), theirs, self.locale.merge, self.locale.dedupe);
+            }
+            for (candidate, upper) in next.upper {
+                active.upper.entry(candidate).or_insert(upper);
+            }
+            for (candidate, erase) in next.erase {
+                active.erase.entry(candidate).or_insert(erase);
+            }
+            for (candidate, description) in next.descriptions {
+                active.descriptions.entry(candidate).or_insert(description);
+            }
+            active.extends.extend(next.extends);
+        }
+        active.name = loaded.join(",");
+        Ok(active)
+    }
+
+    fn load_named_locale(&self, name: &str) -> Result<Locale> {
+        if name == "auto" {
+            return Ok(self.load_system_locale());
+        }
+        self.load_locale_from(&Self::locale_dirs(), name, 0, &mut Vec::new())
+    }
+
+    /// `name` from the first place that has it, starting at `from` (0 for
+    /// inline tables, then each of `dirs`, then the built-in locales), on
+    /// top of what its file `extends`. A file extending its own name
+    /// extends the next one down. `chain` is the files being resolved, to
+    /// catch cycles.
+    fn load_locale_from(&self, dirs: &[PathBuf], name: &str, from: usize, chain: &mut Vec<PathBuf>) -> Result<Locale> {
         // Inline locales from config file
-        if let Some(locale_map) = self.locale.locales.get(&self.locale.active) {
+        if let Some(locale_map) = self.locale.locales.get(name).filter(|_| from == 0) {
             if !locale_map.is_empty() {
-                return Ok(locale_map.clone());
+                return Ok(Locale {
+                    name: name.to_string(),
+                    map: locale_map.clone(),
+                    selection: Selection::Position,
+                    language: name.to_string(),
+                    ..Default::default()
+                });
             }
         }
 
         // Runtime locale files
-        for dir in &[
-            Self::config_dir().join("locales"),
-            PathBuf::from("/usr/share/accentd/locales"),
-        ] {
-            let path = dir.join(format!("{}.toml", self.locale.active));
+        for (i, dir) in dirs.iter().enumerate().skip(from.saturating_sub(1)) {
+            let path = dir.join(format!("{}.toml", name));
             if path.exists() {
-                let contents = std::fs::read_to_string(&path)
-                    .with_context(|| format!("reading locale {}", path.display()))?;
-                return toml::from_str(&contents).with_context(|| "parsing locale TOML");
+                if chain.contains(&path) {
+                    let cycle: Vec<String> = chain.iter().chain([&path]).map(|p| p.display().to_string()).collect();
+                    anyhow::bail!("locales extend each other in a cycle: {}", cycle.join(" -> "));
+                }
+                let mut file = LocaleFile::load_from(&path)?;
+                for key in file.keys.keys().filter(|key| !crate::charmap::is_holdable(&key.to_lowercase())) {
+                    tracing::warn!(path = %path.display(), key, "locale key can't be held; try accentctl validate-locale");
+                }
+                let Some(parent) = file.extends.take() else {
+                    return Ok(Locale::from_file(name, file));
+                };
+                chain.push(path.clone());
+                let start = if parent == name { i + 2 } else { 0 };
+                let base = self
+                    .load_locale_from(dirs, &parent, start, chain)
+                    .with_context(|| format!("{} extends '{}'", path.display(), parent))?;
+                return Ok(base.extended_by(name, file));
             }
         }
 
-        // Built-in
-        let builtin = crate::charmap::builtin_locale(&self.locale.active);
-        if !builtin.is_empty() {
-            return Ok(builtin);
+        Locale::builtin(name).with_context(|| format!("locale '{}' not found", name))
+    }
+
+    /// Where locale files are looked for, the user's first.
+    fn locale_dirs() -> [PathBuf; 2] {
+        [Self::config_dir().join("locales"), PathBuf::from("/usr/share/accentd/locales")]
+    }
+
+    /// Every locale `active` (or `accentctl set-locale`) accepts, sorted by
+    /// name, each from where [`Config::load_locale`] would load it: inline
+    /// tables first, then locale files, the user's before the system's,
+    /// then the built-in ones.
+    pub fn list_locales(&self) -> Vec<LocaleInfo> {
+        let active = self.locale.active.names();
+        let mut found: BTreeMap<String, LocaleInfo> = BTreeMap::new();
+        let mut add = |name: &str, source, path: Option<&Path>, description: Option<String>| {
+            found.entry(name.to_string()).or_insert_with(|| LocaleInfo {
+                name: name.to_string(),
+                source,
+                path: path.map(|p| p.display().to_string()),
+                description,
+                active: active.iter().any(|a| a == name),
+            });
+        };
+        for (name, map) in &self.locale.locales {
+            if !map.is_empty() {
+                add(name, LocaleSource::Config, None, None);
+            }
+        }
+        for dir in Self::locale_dirs() {
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            let mut paths: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+            paths.sort();
+            for path in paths.iter().filter(|p| p.extension().is_some_and(|ext| ext == "toml")) {
+                let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                    continue;
+                };
+                let description = match LocaleFile::load_from(path) {
+                    Ok(file) => file.meta.map(|meta| match meta.description {
+                        Some(description) => format!("{}: {}", meta.name, description),
+                        None => meta.name,
+                    }),
+                    Err(e) => Some(format!("doesn't load: {:#}", e)),
+                };
+                add(name, LocaleSource::File, Some(path), description);
+            }
+        }
+        for name in crate::charmap::BUILTIN_LOCALES {
+            add(name, LocaleSource::Builtin, None, None);
+        }
+        found.into_values().collect()
+    }
+
+    /// `active = "auto"`: the first locale the environment names that
+    /// loads, else the default.
+    fn load_system_locale(&self) -> Locale {
+        let names = system_locale_names(|var| std::env::var(var).ok());
+        for name in &names {
+            if let Ok(locale) = self.load_named_locale(name) {
+                tracing::info!(locale = %name, "locale from the environment");
+                return locale;
+            }
+        }
+        let default = LocaleConfig::default_active().to_string();
+        tracing::warn!(tried = ?names, default = %default, "no locale for the environment, using the default");
+        self.load_named_locale(&default).unwrap_or_default()
+    }
+}
+
+/// Locale names the environment suggests, most preferred first, the way
+/// gettext picks a language: the locale is the first of `$LC_ALL`,
+/// `$LC_MESSAGES` and `$LANG` that is set, and `$LANGUAGE` (a `:` list)
+/// comes before it unless it is `C` or `POSIX`, which suggest nothing.
+/// Each name is tried whole, then without its territory (`pt_BR.UTF-8`
+/// gives `pt-br` then `pt`). `@latin` becomes `-latn`; Norwegian Bokmål
+/// and Nynorsk are `no`.
+pub fn system_locale_names(var: impl Fn(&str) -> Option<String>) -> Vec<String> {
+    let set = |name| var(name).filter(|value| !value.is_empty());
+    let locale = set("LC_ALL").or_else(|| set("LC_MESSAGES")).or_else(|| set("LANG")).unwrap_or_default();
+    if matches!(locale.split('.').next().unwrap_or_default(), "" | "C" | "POSIX") {
+        return Vec::new();
+    }
+    let language = var("LANGUAGE").unwrap_or_default();
+    let values = language.split(':').map(String::from).chain([locale]);
+    let mut names = Vec::new();
+    for value in values {
+        let (value, modifier) = value.split_once('@').unwrap_or((&value, ""));
+        let value = value.split('.').next().unwrap_or_default().to_lowercase();
+        if value.is_empty() || value == "c" || value == "posix" {
+            continue;
+        }
+        let (lang, territory) = value.split_once('_').unwrap_or((&value, ""));
+        let lang = if matches!(lang, "nb" | "nn") { "no" } else { lang };
+        let script = if modifier == "latin" { "-latn" } else { "" };
+        let mut tried = vec![format!("{}{}", lang, script)];
+        if !territory.is_empty() {
+            tried.insert(0, format!("{}-{}{}", lang, territory, script));
         }
+        for name in tried {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
 
-        anyhow::bail!("locale '{}' not found", self.locale.active)
+/// One key's candidates from two locales, `ours` first.
+fn merge_candidates(ours: Vec<String>, theirs: Vec<String>, order: MergeOrder, dedupe: bool) -> Vec<String> {
+    let merged: Vec<String> = match order {
+        MergeOrder::Sequential => ours.into_iter().chain(theirs).collect(),
+        MergeOrder::Interleave => {
+            let (mut ours, mut theirs) = (ours.into_iter(), theirs.into_iter());
+            let mut merged = Vec::new();
+            loop {
+                let (a, b) = (ours.next(), theirs.next());
+                if a.is_none() && b.is_none() {
+                    break merged;
+                }
+                merged.extend(a.into_iter().chain(b));
+            }
+        }
+    };
+    if !dedupe {
+        return merged;
     }
+    let mut unique = Vec::new();
+    for candidate in merged {
+        if !unique.contains(&candidate) {
+            unique.push(candidate);
+        }
+    }
+    unique
 }
 
 pub fn socket_path() -> PathBuf {
@@ -157,7 +903,13 @@ mod tests {
     #[test]
     fn default_threshold_is_300ms() {
         let config = Config::default();
-        assert_eq!(config.general.threshold_ms, 300);
+        assert_eq!(config.general.threshold(), 300);
+    }
+
+    #[test]
+    fn default_threshold_is_unset() {
+        let config = Config::default();
+        assert_eq!(config.general.threshold_ms, None);
     }
 
     #[test]
@@ -166,6 +918,48 @@ mod tests {
         assert!(config.general.enabled);
     }
 
+    #[test]
+    fn default_suspends_on_lock() {
+        let config = Config::default();
+        assert!(config.general.suspend_on_lock);
+    }
+
+    #[test]
+    fn compose_backend_defaults_to_auto() {
+        assert_eq!(Config::default().compose.backend, ComposeBackend::Auto);
+        let config: Config = toml::from_str("[compose]\nbackend = \"wayland-vk\"\n").unwrap();
+        assert_eq!(config.compose.backend, ComposeBackend::WaylandVk);
+        let config: Config = toml::from_str("[compose]\nbackend = \"clipboard\"\n").unwrap();
+        assert_eq!(config.compose.backend, ComposeBackend::Clipboard);
+        let config: Config = toml::from_str("[compose]\nbackend = \"xtest\"\n").unwrap();
+        assert_eq!(config.compose.backend, ComposeBackend::Xtest);
+        assert_eq!(config.compose.hex_digits.get(), 4);
+    }
+
+    #[test]
+    fn hex_digits_outside_4_to_8_are_errors() {
+        let parse = |digits: usize| toml::from_str::<Config>(&format!("[compose]\nhex_digits = {}\n", digits));
+        assert_eq!(parse(6).unwrap().compose.hex_digits.get(), 6);
+        assert_eq!(parse(8).unwrap().compose.hex_digits.get(), 8);
+        assert!(parse(0).is_err());
+        assert!(parse(3).unwrap_err().to_string().contains("not between 4 and 8"));
+        assert!(parse(9).is_err());
+    }
+
+    #[test]
+    fn compose_backends_per_app_match_whole_names_then_substrings() {
+        let config: Config = toml::from_str(
+            "[compose]\nbackend = \"input-method\"\n[compose.apps]\nslack = \"clipboard\"\n\"code\" = \"uinput\"\n\"code-url-handler\" = \"xtest\"\n",
+        )
+        .unwrap();
+        let compose = &config.compose;
+        assert_eq!(compose.backend_for(Some("Slack")), ComposeBackend::Clipboard);
+        assert_eq!(compose.backend_for(Some("code-url-handler")), ComposeBackend::Xtest);
+        assert_eq!(compose.backend_for(Some("code-oss")), ComposeBackend::Uinput);
+        assert_eq!(compose.backend_for(Some("firefox")), ComposeBackend::InputMethod);
+        assert_eq!(compose.backend_for(None), ComposeBackend::InputMethod);
+    }
+
     #[test]
     fn default_locale_is_italian() {
         let config = Config::default();
@@ -190,6 +984,18 @@ mod tests {
         assert!(config.popup.keep_open);
     }
 
+    #[test]
+    fn default_popup_notify_fallback_is_true() {
+        let config = Config::default();
+        assert!(config.popup.notify_fallback);
+    }
+
+    #[test]
+    fn default_candidate_order_is_locale() {
+        let config = Config::default();
+        assert_eq!(config.popup.order, CandidateOrder::Locale);
+    }
+
     // --- spec: TOML parsing ---
 
     #[test]
@@ -197,7 +1003,8 @@ mod tests {
         let toml = "";
         let config: Config = toml::from_str(toml).unwrap();
         // All defaults should apply
-        assert_eq!(config.general.threshold_ms, 300);
+        assert_eq!(config.general.threshold_ms, None);
+        assert_eq!(config.general.threshold(), 300);
         assert!(config.general.enabled);
         assert_eq!(config.locale.active, "it");
     }
@@ -209,7 +1016,8 @@ mod tests {
 threshold_ms = 500
 "#;
         let config: Config = toml::from_str(toml).unwrap();
-        assert_eq!(config.general.threshold_ms, 500);
+        assert_eq!(config.general.threshold_ms, Some(500));
+        assert_eq!(config.general.threshold(), 500);
         // Other fields should still be defaults
         assert!(config.general.enabled);
     }
@@ -234,6 +1042,123 @@ active = "fr"
         assert_eq!(config.locale.active, "fr");
     }
 
+    #[test]
+    fn parse_notify_fallback_off() {
+        let toml = r#"
+[popup]
+notify_fallback = false
+"#;
+        let config: Config = toml::from_str(toml).unwrap();
+        assert!(!config.popup.notify_fallback);
+        assert!(config.popup.keep_open);
+    }
+
+    #[test]
+    fn parse_candidate_order() {
+        let toml = r#"
+[popup]
+order = "frozen"
+"#;
+        let config: Config = toml::from_str(toml).unwrap();
+        assert_eq!(config.popup.order, CandidateOrder::Frozen);
+    }
+
+    // --- spec: compositor integration ---
+
+    #[test]
+    fn default_focus_provider_is_auto() {
+        let config = Config::default();
+        assert_eq!(config.compositor.provider, FocusProvider::Auto);
+    }
+
+    #[test]
+    fn default_suspends_on_fullscreen() {
+        let config = Config::default();
+        assert!(config.compositor.suspend_on_fullscreen);
+    }
+
+    #[test]
+    fn parse_focus_provider() {
+        let toml = r#"
+[compositor]
+provider = "none"
+"#;
+        let config: Config = toml::from_str(toml).unwrap();
+        assert_eq!(config.compositor.provider, FocusProvider::None);
+    }
+
+    // --- spec: remapper interop ---
+
+    #[test]
+    fn default_has_no_upstream_devices() {
+        let config = Config::default();
+        assert!(config.devices.upstream.is_empty());
+    }
+
+    #[test]
+    fn parse_upstream_devices() {
+        let toml = r#"
+[devices]
+upstream = ["keyd virtual keyboard"]
+"#;
+        let config: Config = toml::from_str(toml).unwrap();
+        assert_eq!(config.devices.upstream, vec!["keyd virtual keyboard"]);
+    }
+
+    #[test]
+    fn parse_excluded_devices() {
+        let toml = r#"
+[devices]
+exclude = ["Yubico"]
+"#;
+        let config: Config = toml::from_str(toml).unwrap();
+        assert_eq!(config.devices.exclude, vec!["Yubico"]);
+        assert!(config.devices.upstream.is_empty());
+    }
+
+    #[test]
+    fn parse_included_devices() {
+        let toml = r#"
+[devices]
+include = ["ErgoDox"]
+"#;
+        let config: Config = toml::from_str(toml).unwrap();
+        assert_eq!(config.devices.include, vec!["ErgoDox"]);
+        assert!(config.devices.exclude.is_empty());
+    }
+
+    #[test]
+    fn device_access_defaults_to_direct() {
+        assert_eq!(Config::default().devices.access, DeviceAccess::Direct);
+        let toml = r#"
+[devices]
+access = "logind"
+"#;
+        let config: Config = toml::from_str(toml).unwrap();
+        assert_eq!(config.devices.access, DeviceAccess::Logind);
+    }
+
+    #[test]
+    fn per_keyboard_output_is_opt_in() {
+        assert!(!Config::default().devices.per_keyboard_output);
+        let config: Config = toml::from_str("[devices]\nper_keyboard_output = true\n").unwrap();
+        assert!(config.devices.per_keyboard_output);
+    }
+
+    // --- spec: threshold derived from key-repeat delay ---
+
+    #[test]
+    fn threshold_sits_just_above_repeat_delay() {
+        assert_eq!(threshold_from_repeat_delay(500), 550);
+        assert_eq!(threshold_from_repeat_delay(250), 300);
+    }
+
+    #[test]
+    fn threshold_from_repeat_delay_is_clamped() {
+        assert_eq!(threshold_from_repeat_delay(0), 150);
+        assert_eq!(threshold_from_repeat_delay(10_000), 2000);
+    }
+
     // --- spec: load_locale_map falls back to built-in ---
 
     #[test]
@@ -244,6 +1169,118 @@ active = "fr"
         assert_eq!(map["e"][0], "è");
     }
 
+    #[test]
+    fn builtin_pinyin_selects_by_tone() {
+        let mut config = Config::default();
+        config.locale.active = "pinyin".into();
+        let locale = config.load_locale().unwrap();
+        assert_eq!(locale.map["a"], vec!["ā", "á", "ǎ", "à"]);
+        assert_eq!(locale.selection, Selection::Tones);
+        assert_eq!(locale.language, "pinyin");
+    }
+
+    // --- spec: locale from the environment ---
+
+    #[test]
+    fn system_locale_names_follow_gettext() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| vars.iter().find(|(k, _)| *k == name).map(|(_, v)| v.to_string())
+        };
+        assert_eq!(system_locale_names(env(&[("LANG", "it_IT.UTF-8")])), ["it-it", "it"]);
+        assert!(system_locale_names(env(&[("LC_ALL", "C"), ("LANG", "de_DE.UTF-8")])).is_empty(), "LC_ALL overrides");
+        assert_eq!(system_locale_names(env(&[("LC_MESSAGES", "fr_FR.UTF-8"), ("LANG", "de_DE.UTF-8")])), ["fr-fr", "fr"]);
+        assert_eq!(system_locale_names(env(&[("LC_ALL", ""), ("LANG", "de_DE.UTF-8")])), ["de-de", "de"], "empty is unset");
+        assert!(system_locale_names(env(&[("LANGUAGE", "fr"), ("LANG", "C.UTF-8")])).is_empty(), "LANGUAGE needs a locale");
+        assert_eq!(system_locale_names(env(&[("LANGUAGE", "fr:es"), ("LANG", "en_US.UTF-8")]))[..2], ["fr", "es"]);
+        assert_eq!(system_locale_names(env(&[("LANG", "sr_RS.UTF-8@latin")])), ["sr-rs-latn", "sr-latn"]);
+        assert_eq!(system_locale_names(env(&[("LANG", "nb_NO.UTF-8")])), ["no-no", "no"]);
+        assert!(system_locale_names(env(&[("LANG", "POSIX")])).is_empty());
+    }
+
+    #[test]
+    fn auto_falls_back_to_the_default_locale() {
+        let mut config = Config::default();
+        config.locale.active = "auto".into();
+        assert!(config.load_locale_map().unwrap().contains_key("e"));
+    }
+
+    // --- spec: several active locales ---
+
+    #[test]
+    fn active_takes_one_name_or_a_list() {
+        let one: Config = toml::from_str("[locale]\nactive = \"fr\"\n").unwrap();
+        assert_eq!(one.locale.active.names(), ["fr"]);
+        let two: Config = toml::from_str("[locale]\nactive = [\"it\", \"de\"]\n").unwrap();
+        assert_eq!(two.locale.active.names(), ["it", "de"]);
+        assert_eq!(two.locale.active.to_string(), "it,de");
+        assert_eq!(ActiveLocales::from("it, de"), two.locale.active);
+    }
+
+    #[test]
+    fn active_locales_merge_in_order() {
+        let mut config: Config = toml::from_str("[locale]\nactive = [\"it\", \"de\"]\n").unwrap();
+        let locale = config.load_locale().unwrap();
+        assert_eq!(locale.map["a"], vec!["à", "á", "â", "ã", "ä"]);
+        assert_eq!(locale.map["s"], vec!["ß"]);
+        assert_eq!(locale.language, "it");
+        config.locale.merge = MergeOrder::Interleave;
+        assert_eq!(config.load_locale().unwrap().map["a"], vec!["à", "ä", "á", "â", "ã"]);
+    }
+
+    #[test]
+    fn interleave_alternates_and_dedupe_drops_repeats() {
+        let ours = vec!["è".to_string(), "é".to_string()];
+        let theirs = vec!["é".to_string(), "ê".to_string(), "ë".to_string()];
+        assert_eq!(merge_candidates(ours.clone(), theirs.clone(), MergeOrder::Interleave, true), ["è", "é", "ê", "ë"]);
+        assert_eq!(merge_candidates(ours.clone(), theirs.clone(), MergeOrder::Sequential, true), ["è", "é", "ê", "ë"]);
+        assert_eq!(merge_candidates(ours, theirs, MergeOrder::Interleave, false), ["è", "é", "é", "ê", "ë"]);
+    }
+
+    // --- spec: explicit uppercase forms ---
+
+    #[test]
+    fn locale_upper_overrides_the_language_rules() {
+        let config: Config = toml::from_str("[locale]\nactive = \"de\"\n[locale.upper]\n\"ß\" = \"SS\"\n").unwrap();
+        let locale = config.load_locale().unwrap();
+        let table = crate::charmap::AccentTable::new(&locale.map).with_language(&locale.language).with_uppercase(&locale.upper);
+        assert_eq!(&*table.get("s", true).unwrap()[0], "SS");
+        assert_eq!(&*table.get("a", true).unwrap()[0], "Ä");
+    }
+
+    #[test]
+    fn config_descriptions_add_to_the_locale_files() {
+        let config: Config =
+            toml::from_str("[locale]\nactive = \"fr\"\n[locale.descriptions]\n\"é\" = \"e acute\"\n").unwrap();
+        let locale = config.load_locale().unwrap();
+        let table = crate::charmap::AccentTable::new(&locale.map).with_descriptions(&locale.descriptions);
+        assert_eq!(table.description("É"), Some("e acute"));
+        assert_eq!(table.description("è"), None);
+    }
+
+    #[test]
+    fn erase_counts_apply_to_both_cases() {
+        let config: Config =
+            toml::from_str("[locale]\nactive = \"fr\"\n[locale.erase]\n\"œ\" = 2\n").unwrap();
+        let locale = config.load_locale().unwrap();
+        assert_eq!((locale.erase("œ"), locale.erase("Œ"), locale.erase("è")), (2, 2, 1));
+    }
+
+    // --- spec: per-key overrides ---
+
+    #[test]
+    fn overrides_add_reorder_and_remove_on_top_of_the_locale() {
+        let config: Config = toml::from_str(
+            "[locale]\nactive = \"it\"\n[locale.overrides.e]\nremove = [\"ë\"]\nfirst = [\"é\"]\n\
+             [locale.overrides.y]\nadd = [\"ÿ\"]\n[locale.overrides.n]\nremove = [\"ñ\"]\n",
+        )
+        .unwrap();
+        let map = config.load_locale_map().unwrap();
+        assert_eq!(map["e"], vec!["é", "è", "ê"]);
+        assert_eq!(map["y"], vec!["ÿ"]);
+        assert!(!map.contains_key("n"));
+        assert_eq!(map["a"][0], "à", "untouched keys keep the locale's list");
+    }
+
     #[test]
     fn load_locale_map_fails_for_unknown_locale() {
         let mut config = Config::default();
@@ -251,6 +1288,188 @@ active = "fr"
         assert!(config.load_locale_map().is_err());
     }
 
+    // --- spec: layers ---
+
+    #[test]
+    fn digit_layer_is_off_by_default() {
+        let map = Config::default().load_locale_map().unwrap();
+        assert!(map.contains_key("e"));
+        assert!(!map.contains_key("2"));
+    }
+
+    #[test]
+    fn layers_parse_and_filter() {
+        let config: Config = toml::from_str("[layers]\nletters = false\ndigits = true\n").unwrap();
+        let map = config.load_locale_map().unwrap();
+        assert!(!map.contains_key("e"));
+        assert_eq!(map["2"][0], "²");
+    }
+
+    #[test]
+    fn locale_digits_override_builtin_layer() {
+        let layers = LayersConfig { letters: true, digits: true, punctuation: false, symbols: false };
+        let map = layers.apply(LocaleMap::from([("2".to_string(), vec!["₂".to_string()])]));
+        assert_eq!(map["2"], vec!["₂"]);
+        assert_eq!(map["3"][0], "³");
+    }
+
+    #[test]
+    fn locale_digits_dropped_without_digit_layer() {
+        let map = LayersConfig::default().apply(LocaleMap::from([
+            ("2".to_string(), vec!["₂".to_string()]),
+            ("e".to_string(), vec!["é".to_string()]),
+        ]));
+        assert_eq!(map.keys().collect::<Vec<_>>(), vec!["e"]);
+    }
+
+    #[test]
+    fn punctuation_layer_is_opt_in_and_yields_to_the_locale() {
+        assert!(!Config::default().load_locale_map().unwrap().contains_key("-"));
+        let layers = LayersConfig { punctuation: true, ..Default::default() };
+        let map = layers.apply(LocaleMap::from([("-".to_string(), vec!["‐".to_string()])]));
+        assert_eq!(map["-"], vec!["‐"]);
+        assert_eq!(map["\""], vec!["“", "”", "„"]);
+        assert_eq!(map["."], vec!["…"]);
+    }
+
+    #[test]
+    fn symbols_layer_puts_currencies_on_shift_4() {
+        let config: Config = toml::from_str("[layers]\nsymbols = true\n").unwrap();
+        assert_eq!(config.load_locale_map().unwrap()["shift+4"], vec!["€", "£", "¥", "¢", "₿"]);
+    }
+
+    // --- spec: per-keyboard layouts ---
+
+    #[test]
+    fn layouts_match_by_id_then_name() {
+        let config: Config = toml::from_str(
+            "[devices]\nlayout = \"us\"\n[devices.layouts]\n\"046D:C31C\" = \"uk\"\nkeychron = \"de\"\n",
+        )
+        .unwrap();
+        let devices = &config.devices;
+        assert_eq!(devices.layout_for("Logitech USB Keyboard", Some((0x046d, 0xc31c))), Layout::Uk);
+        assert_eq!(devices.layout_for("Keychron K2", Some((0x05ac, 0x024f))), Layout::De);
+        assert_eq!(devices.layout_for("AT Translated Set 2 keyboard", None), Layout::Us);
+    }
+
+    // --- spec: keymap overrides ---
+
+    #[test]
+    fn keymap_overrides_the_layout_letters() {
+        let config: Config = toml::from_str("[keymap]\n32 = \"E\"\n18 = \"\"\n").unwrap();
+        assert_eq!(config.keymap.base(Layout::Us, 32), Some("e"));
+        assert_eq!(config.keymap.base(Layout::Us, 18), None);
+        assert_eq!(config.keymap.base(Layout::Us, 30), Some("a"));
+    }
+
+    #[test]
+    fn keymap_rejects_bad_entries() {
+        assert!(toml::from_str::<Config>("[keymap]\nKEY_E = \"e\"\n").is_err());
+        assert!(toml::from_str::<Config>("[keymap]\n18 = \"ee\"\n").is_err());
+    }
+
+    // --- spec: snippets ---
+
+    #[test]
+    fn snippets_extend_the_active_locale() {
+        let config: Config = toml::from_str(
+            "[snippets]\n\"@\" = [\"me@example.com\"]\ne = [\"etc.\", \"è\"]\n",
+        )
+        .unwrap();
+        let map = config.load_locale_map().unwrap();
+        assert_eq!(map["@"], vec!["me@example.com"]);
+        assert_eq!(map["e"].last().unwrap(), "etc.");
+        assert_eq!(map["e"].iter().filter(|c| *c == "è").count(), 1);
+    }
+
+    // --- spec: locales extend others ---
+
+    fn locale_dirs_with(files: &[(&str, &str, &str)]) -> (tempfile::TempDir, Vec<PathBuf>) {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = vec![root.path().join("user"), root.path().join("system")];
+        for dir in &dirs {
+            std::fs::create_dir(dir).unwrap();
+        }
+        for (dir, name, text) in files {
+            std::fs::write(root.path().join(dir).join(format!("{}.toml", name)), text).unwrap();
+        }
+        (root, dirs)
+    }
+
+    #[test]
+    fn extends_resolves_through_files_to_a_builtin() {
+        let (_root, dirs) = locale_dirs_with(&[
+            ("user", "mine", "extends = \"fr-ca\"\no = [\"ø\"]\n"),
+            ("system", "fr-ca", "extends = \"fr\"\ne = [\"é\", \"è\"]\n"),
+        ]);
+        let locale = Config::default().load_locale_from(&dirs, "mine", 0, &mut Vec::new()).unwrap();
+        assert_eq!((locale.map["o"].clone(), locale.map["e"].clone()), (vec!["ø".into()], vec!["é".into(), "è".into()]));
+        assert_eq!(locale.map["c"], ["ç"]);
+        assert_eq!(locale.extends, ["fr-ca", "fr"]);
+        assert_eq!(locale.language, "fr", "the language comes from the root without a [meta]");
+    }
+
+    #[test]
+    fn a_file_extending_its_own_name_extends_the_next_one_down() {
+        let (_root, dirs) = locale_dirs_with(&[
+            ("user", "fr", "extends = \"fr\"\na = [\"à\"]\n"),
+            ("system", "fr", "extends = \"fr\"\ne = [\"é\"]\n"),
+        ]);
+        let locale = Config::default().load_locale_from(&dirs, "fr", 0, &mut Vec::new()).unwrap();
+        assert_eq!((locale.map["a"].len(), locale.map["e"].len()), (1, 1));
+        assert_eq!(locale.map["c"], ["ç"], "the built-in fr is underneath");
+    }
+
+    #[test]
+    fn extends_cycles_are_errors() {
+        let (_root, dirs) = locale_dirs_with(&[
+            ("user", "a", "extends = \"b\"\ne = [\"é\"]\n"),
+            ("user", "b", "extends = \"a\"\ne = [\"è\"]\n"),
+        ]);
+        let err = Config::default().load_locale_from(&dirs, "a", 0, &mut Vec::new()).unwrap_err();
+        assert!(format!("{:#}", err).contains("cycle"), "{:#}", err);
+        let err = Config::default().load_locale_from(&dirs, "c", 0, &mut Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("not found"));
+    }
+
+    // --- spec: listing locales ---
+
+    #[test]
+    fn list_locales_marks_active_and_inline_ones() {
+        let config: Config =
+            toml::from_str("[locale]\nactive = [\"fr\", \"mine\"]\n[locale.mine]\ne = [\"ẽ\"]\n").unwrap();
+        let locales = config.list_locales();
+        let find = |name: &str| locales.iter().find(|l| l.name == name).unwrap();
+        assert_eq!((find("mine").source, find("mine").active), (LocaleSource::Config, true));
+        assert!(find("fr").active);
+        assert!(!find("de").active);
+        assert_eq!(find("sr-latn").source, LocaleSource::Builtin);
+        assert!(locales.windows(2).all(|w| w[0].name < w[1].name), "sorted, no repeats");
+    }
+
+    // --- spec: symbol locales merge with languages ---
+
+    #[test]
+    fn math_merges_with_a_language() {
+        let config: Config = toml::from_str("[locale]\nactive = [\"fr\", \"math\"]\n[layers]\ndigits = true\n").unwrap();
+        let map = config.load_locale_map().unwrap();
+        assert_eq!(map["e"][0], "è");
+        assert_eq!(map["x"][0], "×");
+        assert_eq!(map["8"], vec!["∞"], "the locale's digit wins over the digit layer");
+    }
+
+    // --- spec: emoji ---
+
+    #[test]
+    fn emoji_key_shows_the_favorites_instead() {
+        let config: Config = toml::from_str("[layers]\npunctuation = true\n[emoji]\nkey = \".\"\n").unwrap();
+        let map = config.load_locale_map().unwrap();
+        assert_eq!(map["."], EmojiConfig::default_favorites());
+        let config: Config = toml::from_str("[emoji]\nkey = \"shift+1\"\nfavorites = [\"🦀\"]\n").unwrap();
+        assert_eq!(config.load_locale_map().unwrap()["shift+1"], vec!["🦀"]);
+        assert!(!Config::default().load_locale_map().unwrap().contains_key("."));
+    }
+
     // --- spec: socket path ---
 
     #[test]
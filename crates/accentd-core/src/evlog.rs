@@ -0,0 +1,343 @@
+//! Event logs: keyboard input recorded by `accentd --record` and replayed
+//! through fresh state machines by `accentd --replay --simulate`, so "the
+//! popup didn't appear" can be reproduced from a bug report.
+//!
+//! A log is plain text. Lines starting with `#` are comments, except
+//! `# device <index> <name>`, which names a keyboard, and the settings it
+//! was recorded with: `# layout <index> <layout>` per keyboard, `# locale
+//! <names>` (what `auto` stood for, not `auto`), `# threshold_ms <ms>`,
+//! `# order <popup.order>`, `# keymap <json>` and, unless candidates kept
+//! the locale's order, `# usage <json>`, the stats they were ranked by.
+//! Every other line is one event: `<microseconds since start> <device
+//! index> <type> <code> <value>`.
+
+use crate::charmap::{AccentTable, Keymap, Layout};
+use crate::clock::{Clock, MockClock};
+use crate::config::{CandidateOrder, Config};
+use crate::locale::Locale;
+use crate::state_machine::{self, Actions, RawEvent, StateMachine, EV_SYN};
+use crate::usage::UsageStats;
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// First line of every log.
+pub const HEADER: &str = "# accentd event log v1";
+
+/// One recorded event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    /// Since the recording started.
+    pub at: Duration,
+    pub device: usize,
+    pub event: RawEvent,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let e = &self.event;
+        write!(f, "{} {} {} {} {}", self.at.as_micros(), self.device, e.event_type, e.code, e.value)
+    }
+}
+
+/// The `# device` line naming keyboard `device`.
+pub fn device_line(device: usize, name: &str) -> String {
+    format!("# device {} {}", device, name)
+}
+
+/// The `# layout` line for keyboard `device`.
+pub fn layout_line(device: usize, layout: Layout) -> String {
+    format!("# layout {} {}", device, word(layout))
+}
+
+/// The settings lines after [`HEADER`]: what decides the candidates and
+/// their order, so a replay elsewhere picks the same ones.
+pub fn settings_lines(config: &Config, locale: &Locale, keymap: &Keymap, usage: &UsageStats) -> Vec<String> {
+    let order = config.popup.order;
+    let mut lines = vec![
+        format!("# locale {}", locale.name),
+        format!("# threshold_ms {}", config.general.threshold()),
+        format!("# order {}", word(order)),
+        format!("# keymap {}", serde_json::to_string(keymap).unwrap_or_default()),
+    ];
+    if order != CandidateOrder::Locale {
+        lines.push(format!("# usage {}", serde_json::to_string(usage).unwrap_or_default()));
+    }
+    lines
+}
+
+/// A unit enum variant as config files spell it.
+fn word(value: impl Serialize) -> String {
+    serde_json::to_value(value).ok().and_then(|v| v.as_str().map(String::from)).unwrap_or_default()
+}
+
+fn parse_word<T: DeserializeOwned>(word: &str) -> Result<T> {
+    Ok(serde_json::from_value(serde_json::Value::String(word.to_string()))?)
+}
+
+/// A parsed log.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EventLog {
+    /// Keyboard names by device index.
+    pub devices: BTreeMap<usize, String>,
+    /// Keyboard layouts by device index.
+    pub layouts: BTreeMap<usize, Layout>,
+    /// The active locale while recording.
+    pub locale: Option<String>,
+    /// The hold threshold while recording.
+    pub threshold_ms: Option<u64>,
+    /// `popup.order` while recording.
+    pub order: Option<CandidateOrder>,
+    /// The key code to letter overrides in effect.
+    pub keymap: Option<Keymap>,
+    /// The usage stats candidates were ranked by.
+    pub usage: Option<UsageStats>,
+    pub records: Vec<Record>,
+}
+
+impl EventLog {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut log = Self::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if let Some(comment) = line.strip_prefix('#') {
+                let comment = comment.trim();
+                let bad = |what: &str| format!("line {}: bad {}", n + 1, what);
+                // JSON may have spaces in it
+                if let Some(json) = comment.strip_prefix("keymap ") {
+                    log.keymap = Some(serde_json::from_str(json).with_context(|| bad("keymap"))?);
+                    continue;
+                }
+                if let Some(json) = comment.strip_prefix("usage ") {
+                    log.usage = Some(UsageStats::from_json(json).with_context(|| bad("usage"))?);
+                    continue;
+                }
+                let mut words = comment.splitn(3, ' ');
+                match (words.next(), words.next(), words.next()) {
+                    (Some("device"), Some(index), Some(name)) => {
+                        let index = index.parse().with_context(|| bad("device index"))?;
+                        log.devices.insert(index, name.to_string());
+                    }
+                    (Some("layout"), Some(index), Some(layout)) => {
+                        let index = index.parse().with_context(|| bad("device index"))?;
+                        log.layouts.insert(index, parse_word(layout).with_context(|| bad("layout"))?);
+                    }
+                    (Some("locale"), Some(name), None) => log.locale = Some(name.to_string()),
+                    (Some("threshold_ms"), Some(ms), None) => {
+                        log.threshold_ms = Some(ms.parse().with_context(|| bad("threshold"))?);
+                    }
+                    (Some("order"), Some(order), None) => log.order = Some(parse_word(order).with_context(|| bad("order"))?),
+                    _ => {}
+                }
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            let record = parse_record(line).with_context(|| format!("line {}: {:?}", n + 1, line))?;
+            log.records.push(record);
+        }
+        Ok(log)
+    }
+}
+
+fn parse_record(line: &str) -> Result<Record> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [at, device, event_type, code, value] = fields[..] else {
+        anyhow::bail!("expected 5 fields, got {}", fields.len());
+    };
+    Ok(Record {
+        at: Duration::from_micros(at.parse().context("time")?),
+        device: device.parse().context("device")?,
+        event: RawEvent::new(event_type.parse().context("type")?, code.parse().context("code")?, value.parse().context("value")?),
+    })
+}
+
+/// What a keyboard's state machine did with one key event, or with a timer
+/// (`event` is `None`), during a replay.
+#[derive(Debug, Clone)]
+pub struct Replayed {
+    pub at: Duration,
+    pub device: usize,
+    pub event: Option<RawEvent>,
+    pub actions: Actions,
+    /// State afterwards.
+    pub state: &'static str,
+}
+
+impl fmt::Display for Replayed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>9.3}s #{}: ", self.at.as_secs_f64(), self.device)?;
+        match self.event {
+            Some(event) => {
+                let what = match event.value {
+                    0 => "release",
+                    1 => "press",
+                    _ => "repeat",
+                };
+                write!(f, "key {} {}", event.code, what)?;
+            }
+            None => write!(f, "timer")?,
+        }
+        write!(f, " -> {} [{}]", state_machine::describe_actions(&self.actions), self.state)
+    }
+}
+
+/// Run `log` through one state machine per recorded keyboard on a mock
+/// clock, the way the daemon would have: a frame at a time, timers firing
+/// at their deadlines. Timers still pending at the end fire too.
+pub fn replay(config: &Config, accents: AccentTable, log: &EventLog) -> Vec<Replayed> {
+    let clock = MockClock::new();
+    let start = clock.now();
+    let count = log.records.iter().map(|r| r.device + 1).max().unwrap_or(0);
+    let mut sms: Vec<StateMachine<MockClock>> = (0..count)
+        .map(|device| {
+            let mut sm = StateMachine::with_clock(config, accents.clone(), clock.clone());
+            match (log.layouts.get(&device), log.devices.get(&device)) {
+                (Some(&layout), _) => sm.set_layout(layout),
+                (None, Some(name)) => sm.set_layout(config.devices.layout_for(name, None)),
+                (None, None) => {}
+            }
+            sm
+        })
+        .collect();
+    let mut frames = vec![Vec::new(); count];
+    let mut out = Vec::new();
+
+    for record in &log.records {
+        let at = start + record.at;
+        fire_timers(&mut sms, &clock, start, Some(at), &mut out);
+        clock.set(at);
+        let frame = &mut frames[record.device];
+        frame.push(record.event);
+        if record.event.event_type != EV_SYN || record.event.code != 0 {
+            continue;
+        }
+        let frame = std::mem::take(frame);
+        let sm = &mut sms[record.device];
+        sm.begin_frame(&frame);
+        for event in frame.into_iter().filter(RawEvent::is_key) {
+            let actions = sm.process_event_at(event, at);
+            out.push(Replayed { at: record.at, device: record.device, event: Some(event), actions, state: sm.state_name() });
+        }
+    }
+    fire_timers(&mut sms, &clock, start, None, &mut out);
+    out
+}
+
+/// Fire every timer due by `end` (all of them if `None`), earliest first.
+fn fire_timers(
+    sms: &mut [StateMachine<MockClock>],
+    clock: &MockClock,
+    start: Instant,
+    end: Option<Instant>,
+    out: &mut Vec<Replayed>,
+) {
+    loop {
+        let next = sms.iter().enumerate().filter_map(|(device, sm)| sm.next_deadline().map(|d| (d, device))).min();
+        let Some((deadline, device)) = next else {
+            return;
+        };
+        if end.is_some_and(|end| deadline > end) {
+            return;
+        }
+        clock.set(deadline);
+        let sm = &mut sms[device];
+        let actions = sm.check_timer();
+        if actions.is_empty() {
+            return;
+        }
+        out.push(Replayed { at: deadline - start, device, event: None, actions, state: sm.state_name() });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::charmap::builtin_locale;
+
+    const KEY_E: u16 = 18;
+    const KEY_1: u16 = 2;
+
+    fn record(ms: u64, event: RawEvent) -> Record {
+        Record { at: Duration::from_millis(ms), device: 0, event }
+    }
+
+    fn syn(ms: u64) -> Record {
+        record(ms, RawEvent::new(EV_SYN, 0, 0))
+    }
+
+    // --- spec: log format ---
+
+    #[test]
+    fn records_round_trip_through_text() {
+        let records = vec![record(0, RawEvent::key(KEY_E, 1)), syn(0), record(350, RawEvent::key(KEY_E, 0))];
+        let mut text = format!("{}\n# locale it\n# threshold_ms 550\n", HEADER);
+        text += &format!("{}\n", device_line(0, "AT Translated Set 2 keyboard"));
+        for r in &records {
+            text += &format!("{}\n", r);
+        }
+        let log = EventLog::parse(&text).unwrap();
+        assert_eq!(log.records, records);
+        assert_eq!(log.devices[&0], "AT Translated Set 2 keyboard");
+        assert_eq!((log.locale.as_deref(), log.threshold_ms), (Some("it"), Some(550)));
+    }
+
+    #[test]
+    fn settings_round_trip_through_text() {
+        let mut config = Config::default();
+        config.popup.order = CandidateOrder::Frozen;
+        let locale = Locale { name: "it,de".into(), ..Default::default() };
+        let keymap: Keymap = toml::from_str("16 = \"a\"\n50 = \"\"\n").unwrap();
+        let mut usage = UsageStats::default();
+        usage.record("e", "é");
+        let mut lines = vec![HEADER.to_string()];
+        lines.extend(settings_lines(&config, &locale, &keymap, &usage));
+        lines.push(device_line(0, "AT Translated Set 2 keyboard"));
+        lines.push(layout_line(0, Layout::Fr));
+        let log = EventLog::parse(&lines.join("\n")).unwrap();
+        assert_eq!(log.locale.as_deref(), Some("it,de"));
+        assert_eq!(log.order, Some(CandidateOrder::Frozen));
+        assert_eq!(log.keymap, Some(keymap));
+        assert_eq!(log.usage.map(|u| u.count("e", "é")), Some(1));
+        assert_eq!(log.layouts[&0], Layout::Fr);
+    }
+
+    #[test]
+    fn usage_is_only_logged_when_it_ranks_candidates() {
+        let lines = settings_lines(&Config::default(), &Locale::default(), &Keymap::default(), &UsageStats::default());
+        assert!(lines.contains(&"# order locale".to_string()), "{:?}", lines);
+        assert!(!lines.iter().any(|l| l.starts_with("# usage")), "{:?}", lines);
+    }
+
+    #[test]
+    fn bad_lines_name_their_line_number() {
+        let err = EventLog::parse("# accentd event log v1\n0 0 1 18\n").unwrap_err();
+        assert!(format!("{:#}", err).contains("line 2"), "{:#}", err);
+    }
+
+    // --- spec: replay ---
+
+    #[test]
+    fn replay_reproduces_a_hold_and_pick() {
+        let mut config = Config::default();
+        config.general.threshold_ms = Some(300);
+        let log = EventLog {
+            records: vec![
+                record(0, RawEvent::key(KEY_E, 1)),
+                syn(0),
+                record(400, RawEvent::key(KEY_E, 0)),
+                syn(400),
+                record(600, RawEvent::key(KEY_1, 1)),
+                syn(600),
+            ],
+            ..Default::default()
+        };
+        let lines: Vec<String> = replay(&config, builtin_locale("it").into(), &log).iter().map(|r| r.to_string()).collect();
+        assert!(lines[1].starts_with("    0.300s #0: timer -> relay, show popup e:"), "{:?}", lines);
+        assert!(lines.iter().any(|l| l.contains("key 2 press") && l.contains("emit è")), "{:?}", lines);
+    }
+}
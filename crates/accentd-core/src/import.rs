@@ -0,0 +1,205 @@
+//! Accent definitions kept for other systems, turned into locale maps for
+//! `accentctl locale import-*`.
+
+use crate::charmap::{self, LocaleMap};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Candidates per base letter from the single dead-key sequences of an
+/// XCompose file (`<dead_acute> <e> : "é" eacute`), in file order. Uppercase
+/// bases are left out, since Shift uppercases the lowercase ones, and so
+/// are `<Multi_key>` and longer sequences and keys that can't be held.
+/// Also returns the file's `include` arguments, unexpanded.
+pub fn xcompose(text: &str) -> (LocaleMap, Vec<String>) {
+    let mut map = LocaleMap::new();
+    let mut includes = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(rest) = line.strip_prefix("include") {
+            if let Some(path) = quoted(rest.trim()).map(|(path, _)| path) {
+                includes.push(path);
+            }
+            continue;
+        }
+        let Some((keys, result)) = line.split_once(':') else {
+            continue;
+        };
+        let keys: Vec<&str> = keys.split_whitespace().collect();
+        let [dead, letter] = keys[..] else {
+            continue;
+        };
+        let base = letter.strip_prefix('<').and_then(|l| l.strip_suffix('>'));
+        let (true, Some(base)) = (dead.starts_with("<dead_"), base) else {
+            continue;
+        };
+        let Some((candidate, _)) = quoted(result.trim()) else {
+            continue;
+        };
+        if !charmap::is_base(base) || candidate == base || candidate.is_empty() {
+            continue;
+        }
+        let candidates = map.entry(base.to_string()).or_default();
+        if !candidates.contains(&candidate) {
+            candidates.push(candidate);
+        }
+    }
+    (map, includes)
+}
+
+/// [`xcompose`] on the file at `path` and, recursively, the files it
+/// includes, with `%H` as `$HOME` and `%S` as the system Compose directory.
+/// `%L` (the system file for the current locale) is skipped: it holds
+/// thousands of sequences rather than the user's own; import it by path to
+/// get it. Earlier definitions come first.
+pub fn load_xcompose(path: &Path) -> Result<LocaleMap> {
+    let mut map = LocaleMap::new();
+    load_xcompose_into(path, &mut map, 0)?;
+    Ok(map)
+}
+
+fn load_xcompose_into(path: &Path, map: &mut LocaleMap, depth: usize) -> Result<()> {
+    anyhow::ensure!(depth < 8, "includes nested too deep at {}", path.display());
+    let text = std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let (own, includes) = xcompose(&text);
+    for include in includes {
+        if include.contains("%L") {
+            continue;
+        }
+        let home = std::env::var("HOME").unwrap_or_default();
+        let expanded = include.replace("%H", &home).replace("%S", "/usr/share/X11/locale");
+        load_xcompose_into(&PathBuf::from(expanded), map, depth + 1)?;
+    }
+    for (base, candidates) in own {
+        let merged = map.entry(base).or_default();
+        for candidate in candidates {
+            if !merged.contains(&candidate) {
+                merged.push(candidate);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Candidates per base letter from a macOS press-and-hold definition
+/// (`PressAndHold.app/Contents/Resources/Keyboard-*.plist`, XML or binary),
+/// in the order macOS numbers them. Each `Roman-Accent-<letter>` entry's
+/// `Strings` holds the letter itself and then its candidates. As with
+/// [`xcompose`], only lowercase letters accentd can hold are kept.
+pub fn press_and_hold(bytes: &[u8]) -> Result<LocaleMap> {
+    #[derive(Deserialize)]
+    struct Entry {
+        #[serde(rename = "Strings")]
+        strings: String,
+    }
+    let entries: HashMap<String, Entry> = plist::from_bytes(bytes).context("parsing plist")?;
+    let mut map = LocaleMap::new();
+    for (key, entry) in entries {
+        let Some(base) = key.strip_prefix("Roman-Accent-").filter(|base| charmap::is_base(base)) else {
+            continue;
+        };
+        let mut candidates: Vec<String> = Vec::new();
+        for candidate in entry.strings.split_whitespace().filter(|&c| c != base) {
+            if !candidates.iter().any(|c| c == candidate) {
+                candidates.push(candidate.to_string());
+            }
+        }
+        if !candidates.is_empty() {
+            map.insert(base.to_string(), candidates);
+        }
+    }
+    Ok(map)
+}
+
+/// The double-quoted string at the start of `s` (with `\"` and `\\`
+/// escapes), and what follows it.
+fn quoted(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut out = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((out, &s[i + 2..])),
+            '\\' => out.push(chars.next()?.1),
+            c => out.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // --- spec: XCompose ---
+
+    #[test]
+    fn dead_key_sequences_become_candidates() {
+        let text = r#"
+include "%L"
+<dead_grave> <e> : "è" egrave # E WITH GRAVE
+<dead_acute> <e> : "é" eacute
+<dead_acute> <E> : "É" Eacute
+<Multi_key> <apostrophe> <a> : "á" aacute
+<dead_acute> <dead_acute> <e> : "é"
+<dead_cedilla> <c> : "ç"
+<dead_acute> <b> : "ḃ"
+<dead_macron> <space> : "¯"
+"#;
+        let (map, includes) = xcompose(text);
+        assert_eq!(map["e"], vec!["è", "é"]);
+        assert_eq!(map["c"], vec!["ç"]);
+        assert_eq!(map.len(), 2, "{:?}", map);
+        assert_eq!(includes, ["%L"]);
+    }
+
+    #[test]
+    fn quoted_strings_unescape() {
+        assert_eq!(quoted(r#""\"x\\" rest"#), Some(("\"x\\".to_string(), " rest")));
+        assert_eq!(quoted("unquoted"), None);
+    }
+
+    #[test]
+    fn includes_come_before_the_file_itself() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base");
+        std::fs::write(&base, "<dead_acute> <e> : \"é\"\n").unwrap();
+        let own = dir.path().join("XCompose");
+        std::fs::write(&own, format!("include \"{}\"\n<dead_grave> <e> : \"è\"\n", base.display())).unwrap();
+        assert_eq!(load_xcompose(&own).unwrap()["e"], vec!["é", "è"]);
+    }
+
+    // --- spec: macOS press-and-hold ---
+
+    #[test]
+    fn press_and_hold_keeps_the_macos_order() {
+        let plist = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>Roman-Accent-e</key>
+	<dict>
+		<key>Direction</key>
+		<string>right</string>
+		<key>Keycaps</key>
+		<string>e è é ê ë ē ė ę</string>
+		<key>Strings</key>
+		<string>e è é ê ë ē ė ę</string>
+	</dict>
+	<key>Roman-Accent-E</key>
+	<dict>
+		<key>Strings</key>
+		<string>E È É Ê Ë Ē Ė Ę</string>
+	</dict>
+	<key>Roman-Accent-b</key>
+	<dict>
+		<key>Strings</key>
+		<string>b ḃ</string>
+	</dict>
+</dict>
+</plist>"#;
+        let map = press_and_hold(plist.as_bytes()).unwrap();
+        assert_eq!(map["e"], vec!["è", "é", "ê", "ë", "ē", "ė", "ę"]);
+        assert_eq!(map.len(), 1, "{:?}", map);
+    }
+}
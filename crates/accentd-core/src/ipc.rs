@@ -1,7 +1,10 @@
+use crate::charmap::LocaleMap;
+use crate::usage::UsageStats;
 use serde::{Deserialize, Serialize};
 
 /// Messages from daemon to popup/clients (JSON-lines over Unix socket).
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(tag = "type")]
 pub enum DaemonMsg {
     /// Show the accent popup for the given base character.
@@ -11,24 +14,175 @@ pub enum DaemonMsg {
         accents: Vec<String>,
         /// 1-indexed labels for display
         labels: Vec<u8>,
+        /// What each candidate is called (`"e grave"`), `""` if the locale
+        /// doesn't say; empty when it describes none of them.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        descriptions: Vec<String>,
     },
-    /// Hide the popup (user released key or pressed ESC).
+    /// Highlight one candidate of the open popup (1-indexed label).
+    #[serde(rename = "highlight")]
+    Highlight { index: u8 },
+    /// Hide the popup.
     #[serde(rename = "hide_popup")]
-    HidePopup,
+    HidePopup {
+        /// Why it closed; older daemons don't send one.
+        #[serde(default)]
+        reason: HideReason,
+    },
     /// Status response.
     #[serde(rename = "status")]
     Status {
         enabled: bool,
         locale: String,
         version: String,
+        /// Hold detection is temporarily off (e.g. fullscreen window).
+        #[serde(default)]
+        suspended: bool,
+        /// Why it is off; older daemons don't say.
+        #[serde(default)]
+        suspended_by: Vec<SuspendReason>,
+        /// Seconds since the daemon started.
+        #[serde(default)]
+        uptime_secs: u64,
+        /// The emission backend in use, e.g. "uinput".
+        #[serde(default)]
+        backend: String,
+        /// The keyboards found at startup, in grab order.
+        #[serde(default)]
+        devices: Vec<DeviceStatus>,
+        /// Popup clients currently connected.
+        #[serde(default)]
+        popup_clients: usize,
     },
     /// Acknowledgement for commands.
     #[serde(rename = "ack")]
     Ack { ok: bool, message: String },
+    /// Reply to ExportStats.
+    #[serde(rename = "stats")]
+    Stats { stats: UsageStats },
+    /// Reply to GetLocaleMap: every key and its candidates, unranked.
+    #[serde(rename = "locale_map")]
+    LocaleMap { locale: String, keys: LocaleMap },
+    /// Reply to ListLocales: every locale SetLocale accepts, by name.
+    #[serde(rename = "locales")]
+    Locales { locales: Vec<LocaleInfo> },
+    /// Show the cheat sheet (to popup clients, on ShowCheatSheet).
+    #[serde(rename = "show_cheat_sheet")]
+    ShowCheatSheet,
+    /// Reply to Calibrate, once the recording period is over.
+    #[serde(rename = "calibration")]
+    Calibration {
+        /// Letter holds measured.
+        samples: usize,
+        p99_ms: Option<u64>,
+        /// `None` with too few samples.
+        suggested_ms: Option<u64>,
+        /// The threshold in effect before calibrating.
+        current_ms: u64,
+        /// The suggestion is now in effect (until restart).
+        applied: bool,
+    },
+    /// One key event and what the state machine made of it (to Monitor
+    /// clients, only with `allow_monitor`).
+    #[serde(rename = "monitor")]
+    Monitor {
+        /// The keyboard's name.
+        device: String,
+        /// Key code and value (0 release, 1 press, 2 repeat); no code for a
+        /// timer firing (hold threshold, popup timeout).
+        code: Option<u16>,
+        value: i32,
+        /// The base the key would offer accents for, if any.
+        base: Option<String>,
+        /// The decision, e.g. "relay" or "suppress, emit è".
+        actions: String,
+        /// State afterwards: "idle", "holding", "popup", "disabled" or "suspended".
+        state: String,
+    },
+}
+
+/// One keyboard in a Status.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DeviceStatus {
+    pub name: String,
+    pub path: String,
+    /// Grabbed and still delivering events.
+    pub grabbed: bool,
+    /// Hold detection is on for this keyboard.
+    pub enabled: bool,
+    /// Input events read from it since it was grabbed.
+    pub events: u64,
+    /// Why it stopped delivering events, e.g. an I/O error it is being
+    /// reopened after.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// One locale in a Locales reply.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct LocaleInfo {
+    pub name: String,
+    /// Where it loads from; the first place with the name wins.
+    pub source: LocaleSource,
+    /// The locale file, for `file` locales.
+    #[serde(default)]
+    pub path: Option<String>,
+    /// The file's `[meta]` name and description.
+    #[serde(default)]
+    pub description: Option<String>,
+    /// One of the locales in use.
+    pub active: bool,
+}
+
+/// Where a locale comes from, in the order they are looked up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum LocaleSource {
+    /// An inline `[locale.NAME]` table in config.toml.
+    Config,
+    /// A file in `~/.config/accentd/locales` or `/usr/share/accentd/locales`.
+    File,
+    /// Compiled into accentd.
+    Builtin,
+}
+
+/// Why the popup closed, so clients can animate or hint accordingly.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum HideReason {
+    /// An accent was picked.
+    Selected,
+    /// Nothing was picked within `popup.timeout_ms`.
+    Timeout,
+    /// Closed from outside: a client's Dismiss, or focus moved elsewhere.
+    #[default]
+    Dismissed,
+    /// The user backed out: ESC, another key, or releasing the held key.
+    Cancelled,
+    /// accentd was disabled or suspended while it was open.
+    Disabled,
+}
+
+/// Why hold detection is temporarily off, independent of the user's toggle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum SuspendReason {
+    /// The focused window is fullscreen (gaming mode).
+    Fullscreen,
+    /// The session is locked.
+    Locked,
+    /// logind reports the session idle.
+    Idle,
 }
 
 /// Messages from popup/clients to daemon.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(tag = "type")]
 pub enum ClientMsg {
     /// User selected an accent variant (1-indexed).
@@ -55,6 +209,61 @@ pub enum ClientMsg {
     /// Popup client announcing itself (for routing ShowPopup/HidePopup).
     #[serde(rename = "register_popup")]
     RegisterPopup,
+    /// Receive a Status now and again on every change (status bars), and
+    /// every ShowPopup/HidePopup.
+    #[serde(rename = "subscribe")]
+    Subscribe,
+    /// Request the learned candidate usage stats.
+    #[serde(rename = "export_stats")]
+    ExportStats,
+    /// Replace the usage stats, or add to them with `merge`.
+    #[serde(rename = "import_stats")]
+    ImportStats {
+        stats: UsageStats,
+        #[serde(default)]
+        merge: bool,
+    },
+    /// Request the active locale's full map.
+    #[serde(rename = "get_locale_map")]
+    GetLocaleMap,
+    /// Request the locales SetLocale accepts.
+    #[serde(rename = "list_locales")]
+    ListLocales,
+    /// Ask popup clients to show the active locale as a cheat sheet.
+    #[serde(rename = "show_cheat_sheet")]
+    ShowCheatSheet,
+    /// Measure hold durations during normal typing for `secs` seconds, then
+    /// reply with a suggested threshold, applying it with `apply`.
+    #[serde(rename = "calibrate")]
+    Calibrate {
+        #[serde(default = "default_calibration_secs")]
+        secs: u64,
+        #[serde(default)]
+        apply: bool,
+    },
+    /// Stream a Monitor message per key event. Refused unless the daemon's
+    /// config sets `allow_monitor`.
+    #[serde(rename = "monitor")]
+    Monitor,
+    /// Look for keyboards again: grab new ones and let go of those gone,
+    /// e.g. after docking.
+    #[serde(rename = "rescan")]
+    Rescan,
+}
+
+fn default_calibration_secs() -> u64 {
+    60
+}
+
+/// JSON Schemas for both directions of the protocol, keyed by message type
+/// name, with the crate version they describe.
+#[cfg(feature = "schema")]
+pub fn schemas() -> serde_json::Value {
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "ClientMsg": schemars::schema_for!(ClientMsg),
+        "DaemonMsg": schemars::schema_for!(DaemonMsg),
+    })
 }
 
 /// Serialize a message as a JSON line (with trailing newline).
@@ -89,7 +298,7 @@ mod tests {
 
     #[test]
     fn encode_produces_trailing_newline() {
-        let msg = DaemonMsg::HidePopup;
+        let msg = DaemonMsg::HidePopup { reason: HideReason::Selected };
         let encoded = encode(&msg);
         assert!(encoded.ends_with('\n'));
     }
@@ -100,6 +309,7 @@ mod tests {
             base: "e".into(),
             accents: vec!["è".into(), "é".into()],
             labels: vec![1, 2],
+            descriptions: vec![],
         };
         let encoded = encode(&msg);
         // Should be exactly one newline at the end
@@ -110,10 +320,22 @@ mod tests {
 
     #[test]
     fn daemon_msg_hide_popup_round_trips() {
-        let msg = DaemonMsg::HidePopup;
+        let msg = DaemonMsg::HidePopup { reason: HideReason::Timeout };
         let encoded = encode(&msg);
         let decoded = decode_daemon(&encoded).expect("should decode");
-        assert!(matches!(decoded, DaemonMsg::HidePopup));
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn hide_popup_reason_is_snake_case() {
+        let encoded = encode(&DaemonMsg::HidePopup { reason: HideReason::Timeout });
+        assert_eq!(encoded, "{\"type\":\"hide_popup\",\"reason\":\"timeout\"}\n");
+    }
+
+    #[test]
+    fn hide_popup_without_reason_decodes_as_dismissed() {
+        let decoded = decode_daemon(r#"{"type":"hide_popup"}"#).expect("should decode");
+        assert_eq!(decoded, DaemonMsg::HidePopup { reason: HideReason::Dismissed });
     }
 
     #[test]
@@ -122,33 +344,63 @@ mod tests {
             base: "e".into(),
             accents: vec!["è".into(), "é".into(), "ê".into()],
             labels: vec![1, 2, 3],
+            descriptions: vec!["e grave".into(), "".into(), "".into()],
         };
         let encoded = encode(&msg);
         let decoded = decode_daemon(&encoded).expect("should decode");
         match decoded {
-            DaemonMsg::ShowPopup { base, accents, labels } => {
+            DaemonMsg::ShowPopup { base, accents, labels, descriptions } => {
                 assert_eq!(base, "e");
                 assert_eq!(accents, vec!["è", "é", "ê"]);
                 assert_eq!(labels, vec![1, 2, 3]);
+                assert_eq!(descriptions, vec!["e grave", "", ""]);
             }
             _ => panic!("expected ShowPopup"),
         }
     }
 
+    #[test]
+    fn show_popup_without_descriptions_leaves_them_out() {
+        let msg = DaemonMsg::ShowPopup { base: "e".into(), accents: vec!["è".into()], labels: vec![1], descriptions: vec![] };
+        let encoded = encode(&msg);
+        assert!(!encoded.contains("descriptions"));
+        assert_eq!(decode_daemon(&encoded).expect("should decode"), msg);
+    }
+
     #[test]
     fn daemon_msg_status_round_trips() {
         let msg = DaemonMsg::Status {
             enabled: true,
             locale: "it".into(),
             version: "0.1.0".into(),
+            suspended: true,
+            suspended_by: vec![SuspendReason::Locked, SuspendReason::Idle],
+            uptime_secs: 90,
+            backend: "uinput".into(),
+            devices: vec![DeviceStatus {
+                name: "AT Translated Set 2 keyboard".into(),
+                path: "/dev/input/event3".into(),
+                grabbed: true,
+                enabled: true,
+                events: 1234,
+                error: None,
+            }],
+            popup_clients: 1,
         };
         let encoded = encode(&msg);
         let decoded = decode_daemon(&encoded).expect("should decode");
-        match decoded {
-            DaemonMsg::Status { enabled, locale, version } => {
-                assert!(enabled);
-                assert_eq!(locale, "it");
-                assert_eq!(version, "0.1.0");
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn status_without_suspended_decodes() {
+        let line = r#"{"type":"status","enabled":true,"locale":"it","version":"0.1.0"}"#;
+        match decode_daemon(line).expect("should decode") {
+            DaemonMsg::Status { suspended, suspended_by, devices, popup_clients, .. } => {
+                assert!(!suspended);
+                assert!(suspended_by.is_empty());
+                assert!(devices.is_empty());
+                assert_eq!(popup_clients, 0);
             }
             _ => panic!("expected Status"),
         }
@@ -168,6 +420,37 @@ mod tests {
         }
     }
 
+    #[test]
+    fn daemon_msg_locale_map_round_trips() {
+        let keys = LocaleMap::from([("e".to_string(), vec!["è".to_string(), "é".to_string()])]);
+        let msg = DaemonMsg::LocaleMap { locale: "it".into(), keys };
+        assert_eq!(decode_daemon(&encode(&msg)), Some(msg));
+    }
+
+    #[test]
+    fn daemon_msg_locales_round_trips() {
+        let locales = vec![
+            LocaleInfo { name: "fr".into(), source: LocaleSource::Builtin, path: None, description: None, active: true },
+            LocaleInfo {
+                name: "mine".into(),
+                source: LocaleSource::File,
+                path: Some("/home/me/.config/accentd/locales/mine.toml".into()),
+                description: Some("Mine".into()),
+                active: false,
+            },
+        ];
+        let msg = DaemonMsg::Locales { locales };
+        let encoded = encode(&msg);
+        assert!(encoded.contains(r#""source":"builtin""#));
+        assert_eq!(decode_daemon(&encoded), Some(msg));
+    }
+
+    #[test]
+    fn calibrate_defaults_to_a_minute_without_applying() {
+        let msg = decode_client(r#"{"type":"calibrate"}"#).expect("should decode");
+        assert_eq!(msg, ClientMsg::Calibrate { secs: 60, apply: false });
+    }
+
     #[test]
     fn client_msg_select_round_trips() {
         let msg = ClientMsg::Select { index: 3 };
@@ -199,12 +482,61 @@ mod tests {
             ClientMsg::Disable,
             ClientMsg::GetStatus,
             ClientMsg::RegisterPopup,
+            ClientMsg::Subscribe,
+            ClientMsg::ExportStats,
+            ClientMsg::ImportStats { stats: UsageStats::default(), merge: true },
+            ClientMsg::GetLocaleMap,
+            ClientMsg::ListLocales,
+            ClientMsg::ShowCheatSheet,
+            ClientMsg::Calibrate { secs: 60, apply: false },
+            ClientMsg::Monitor,
+            ClientMsg::Rescan,
         ] {
             let encoded = encode(&msg);
             assert!(decode_client(&encoded).is_some(), "failed to round-trip: {:?}", msg);
         }
     }
 
+    // --- spec: JSON Schema ---
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn schemas_cover_every_message_type() {
+        let schemas = schemas().to_string();
+        for tag in [
+            "show_popup", "hide_popup", "status", "ack",
+            "select", "dismiss", "toggle", "enable", "disable",
+            "set_locale", "get_status", "register_popup", "subscribe",
+            "stats", "export_stats", "import_stats",
+            "locale_map", "get_locale_map", "show_cheat_sheet",
+            "locales", "list_locales",
+            "calibration", "calibrate", "monitor", "highlight", "rescan",
+        ] {
+            assert!(schemas.contains(&format!("\"{}\"", tag)), "missing {}", tag);
+        }
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn schema_marks_suspended_optional() {
+        let schema = serde_json::to_value(schemars::schema_for!(DaemonMsg)).unwrap();
+        let status = schema["oneOf"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|v| v["properties"]["type"]["const"] == "status")
+            .expect("status variant");
+        let required: Vec<&str> = status["required"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|v| v.as_str())
+            .collect();
+        assert!(required.contains(&"locale"));
+        assert!(!required.contains(&"suspended"));
+        assert!(!required.contains(&"suspended_by"));
+    }
+
     // --- spec: empty/whitespace input → None ---
 
     #[test]
@@ -238,7 +570,7 @@ mod tests {
 
     #[test]
     fn encoded_messages_contain_type_field() {
-        let encoded = encode(&DaemonMsg::HidePopup);
+        let encoded = encode(&DaemonMsg::HidePopup { reason: HideReason::Cancelled });
         assert!(encoded.contains("\"type\""));
 
         let encoded = encode(&ClientMsg::Toggle);
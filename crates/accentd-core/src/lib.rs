@@ -1,3 +1,45 @@
+//! Core of accentd: the press-and-hold accent logic and everything the daemon,
+//! popup and CLI share.
+//!
+//! The pieces are usable on their own, e.g. to embed accent popups in an
+//! input method or editor plugin without running the daemon:
+//!
+//! - [`state_machine`]: hold detection. Key events in, [`state_machine::Action`]s
+//!   out, no I/O and an injectable [`clock::Clock`].
+//! - [`sim`]: scripted runs of the state machine on a mock clock.
+//! - [`evlog`]: recorded keyboard input and its replay.
+//! - [`locale`]: the [`locale::Locale`] type a state machine is given, and
+//!   locale file parsing and validation.
+//! - [`charmap`]: built-in locale maps, the popup's [`charmap::AccentTable`]
+//!   and keycode helpers.
+//! - [`import`]: locale maps from XCompose files and other systems.
+//! - [`config`]: the `config.toml` schema.
+//! - [`ipc`]: JSON-lines messages spoken over the daemon socket.
+//! - [`usage`]: per-candidate pick counts for frequency-ranked popups.
+//! - [`calibration`]: hold-duration sampling behind `accentctl calibrate`.
+//!
+//! ```
+//! use accentd_core::{config::Config, locale::Locale, state_machine::{RawEvent, StateMachine}};
+//!
+//! let italian = Locale::builtin("it").unwrap();
+//! let mut sm = StateMachine::new(&Config::default(), &italian);
+//! let actions = sm.process_event(RawEvent::key(18, 1)); // KEY_E pressed
+//! assert_eq!(actions.len(), 1); // relayed right away
+//! assert!(sm.next_deadline().is_some()); // popup once the threshold passes
+//! ```
+//!
+//! [`config::Config::load_locale`] resolves a locale the way the daemon
+//! does: config-inline maps, locale files and merges included. Its
+//! [`locale::Locale::table`] is what the popup shows.
+
+pub mod calibration;
 pub mod charmap;
+pub mod clock;
 pub mod config;
+pub mod evlog;
+pub mod import;
 pub mod ipc;
+pub mod locale;
+pub mod sim;
+pub mod state_machine;
+pub mod usage;
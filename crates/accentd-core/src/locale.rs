@@ -0,0 +1,674 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::charmap::{self, AccentTable, DescriptionMap, EraseMap, LocaleMap, Selection, UpperMap};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+/// Optional `[meta]` header of a locale file (format v2).
+///
+/// v1 files are a flat `key = [candidates]` table; v2 files add this header so
+/// shared locales are self-describing. Both formats load the same way.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LocaleMeta {
+    /// Human-readable name, e.g. "Italiano".
+    pub name: String,
+    /// BCP 47 language tag, e.g. "it" or "fr-CA".
+    pub language: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub author: Option<String>,
+    /// Version of the locale file itself.
+    #[serde(default)]
+    pub version: Option<String>,
+    /// Oldest accentd release able to load this file.
+    #[serde(default)]
+    pub min_accentd_version: Option<String>,
+    /// What digits mean in the popup: "position" (default) or "tones".
+    #[serde(default)]
+    pub selection: Selection,
+}
+
+/// A parsed locale file: optional metadata plus the accent map.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LocaleFile {
+    /// `extends = "fr"`: the locale this file only changes. Its keys
+    /// replace the parent's (an empty list removes one), and its `[upper]`,
+    /// `[erase]` and `[descriptions]` go on top of the parent's.
+    pub extends: Option<String>,
+    pub meta: Option<LocaleMeta>,
+    pub keys: LocaleMap,
+    /// `[upper]`: uppercase forms that differ from the language's rules.
+    pub upper: UpperMap,
+    /// `[erase]`: how many characters a pick of the candidate replaces.
+    pub erase: EraseMap,
+    /// `[descriptions]`: names for candidates, for tooltips and screen readers.
+    pub descriptions: DescriptionMap,
+}
+
+impl LocaleFile {
+    /// How digits select; files without a header select by position.
+    pub fn selection(&self) -> Selection {
+        self.meta.as_ref().map(|m| m.selection).unwrap_or_default()
+    }
+}
+
+/// On-disk layout. v1 puts candidates at the top level; v2 puts them under
+/// `[keys]` so the `[meta]` header can come first.
+#[derive(Deserialize)]
+struct RawLocaleFile {
+    #[serde(default)]
+    extends: Option<String>,
+    #[serde(default)]
+    meta: Option<LocaleMeta>,
+    #[serde(default)]
+    keys: Option<LocaleMap>,
+    #[serde(default)]
+    upper: UpperMap,
+    #[serde(default)]
+    erase: EraseMap,
+    #[serde(default)]
+    descriptions: DescriptionMap,
+    #[serde(flatten)]
+    flat: LocaleMap,
+}
+
+impl LocaleFile {
+    /// Parse and validate a locale file from TOML text.
+    pub fn parse(contents: &str) -> Result<Self> {
+        let raw: RawLocaleFile = toml::from_str(contents).context("parsing locale TOML")?;
+        let keys = match raw.keys {
+            Some(keys) if raw.flat.is_empty() => keys,
+            Some(_) => anyhow::bail!("candidates must be either all under [keys] or all top-level"),
+            None => raw.flat,
+        };
+        let file = Self {
+            extends: raw.extends,
+            meta: raw.meta,
+            keys,
+            upper: raw.upper,
+            erase: raw.erase,
+            descriptions: raw.descriptions,
+        };
+        file.validate()?;
+        Ok(file)
+    }
+
+    /// The file as v2 TOML, keys sorted.
+    pub fn to_toml(&self) -> Result<String> {
+        #[derive(Serialize)]
+        struct Out<'a> {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            extends: &'a Option<String>,
+            meta: &'a Option<LocaleMeta>,
+            keys: BTreeMap<&'a String, &'a Vec<String>>,
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            upper: &'a UpperMap,
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            erase: &'a EraseMap,
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            descriptions: &'a DescriptionMap,
+        }
+        let out = Out {
+            extends: &self.extends,
+            meta: &self.meta,
+            keys: self.keys.iter().collect(),
+            upper: &self.upper,
+            erase: &self.erase,
+            descriptions: &self.descriptions,
+        };
+        toml::to_string(&out).context("writing locale TOML")
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("reading locale {}", path.display()))?;
+        Self::parse(&contents).with_context(|| format!("loading locale {}", path.display()))
+    }
+
+    /// Check the header against this build. v1 files (no header) always pass.
+    pub fn validate(&self) -> Result<()> {
+        if self.extends.as_deref().is_some_and(|parent| parent.trim().is_empty()) {
+            anyhow::bail!("extends must name a locale");
+        }
+        let Some(meta) = &self.meta else {
+            return Ok(());
+        };
+
+        if meta.name.trim().is_empty() {
+            anyhow::bail!("meta.name must not be empty");
+        }
+        if !is_valid_language_tag(&meta.language) {
+            anyhow::bail!("meta.language '{}' is not a valid BCP 47 language tag", meta.language);
+        }
+        if let Some(version) = &meta.version {
+            parse_version(version)
+                .with_context(|| format!("meta.version '{}' is not a valid version", version))?;
+        }
+        if let Some(min) = &meta.min_accentd_version {
+            let required = parse_version(min).with_context(|| {
+                format!("meta.min_accentd_version '{}' is not a valid version", min)
+            })?;
+            let current = parse_version(env!("CARGO_PKG_VERSION")).expect("crate version");
+            if required > current {
+                anyhow::bail!(
+                    "locale requires accentd >= {}, this is {}",
+                    min,
+                    env!("CARGO_PKG_VERSION")
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A locale ready for a [`StateMachine`](crate::state_machine::StateMachine):
+/// what each key offers and how to treat it. Built in ([`Locale::builtin`]),
+/// from a file ([`Locale::load`]), or resolved from the config, merges and
+/// tweaks included ([`Config::load_locale`](crate::config::Config::load_locale)).
+///
+/// ```
+/// use accentd_core::{config::Config, locale::Locale, state_machine::StateMachine};
+///
+/// let locale = Locale::builtin("fr").unwrap();
+/// assert_eq!(locale.map["c"], ["ç"]);
+/// let sm = StateMachine::new(&Config::default(), &locale);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Locale {
+    /// What it was loaded as: `"fr"`, `"mine"`, or `"it,de"` when merged;
+    /// `auto` gives the name of the locale it found.
+    pub name: String,
+    /// Candidates per key, lowercase.
+    pub map: LocaleMap,
+    /// What digits mean in its popup.
+    pub selection: Selection,
+    /// BCP 47 tag whose case rules uppercase the candidates: the locale
+    /// file's `[meta] language`, otherwise the locale's name.
+    pub language: String,
+    /// Uppercase forms that override the language's rules: the locale
+    /// file's `[upper]`, then `[locale.upper]`.
+    pub upper: UpperMap,
+    /// Characters replaced by picks that replace more than the held letter:
+    /// the locale file's `[erase]`, then `[locale.erase]`.
+    pub erase: EraseMap,
+    /// Names for candidates: the locale file's `[descriptions]`, then
+    /// `[locale.descriptions]`.
+    pub descriptions: DescriptionMap,
+    /// The file's `[meta]` header, if it came from a v2 file.
+    pub meta: Option<LocaleMeta>,
+    /// The locales it's built on through `extends`, nearest first.
+    pub extends: Vec<String>,
+}
+
+impl Locale {
+    /// One of [`charmap::BUILTIN_LOCALES`].
+    pub fn builtin(name: &str) -> Option<Self> {
+        let map = charmap::builtin_locale(name);
+        if map.is_empty() {
+            return None;
+        }
+        Some(Self {
+            name: name.to_string(),
+            map,
+            selection: charmap::builtin_selection(name),
+            language: name.to_string(),
+            ..Default::default()
+        })
+    }
+
+    /// A parsed locale file, known as `name`.
+    pub fn from_file(name: &str, file: LocaleFile) -> Self {
+        let selection = file.selection();
+        let language = match &file.meta {
+            Some(meta) if !meta.language.is_empty() => meta.language.clone(),
+            _ => name.to_string(),
+        };
+        Self {
+            name: name.to_string(),
+            map: file.keys,
+            selection,
+            language,
+            upper: file.upper,
+            erase: file.erase,
+            descriptions: file.descriptions,
+            meta: file.meta,
+            extends: Vec::new(),
+        }
+    }
+
+    /// `file`, known as `name`, on top of this locale, for files that
+    /// declare `extends`. The file's `[meta]` (language, selection) wins
+    /// when it has one.
+    pub fn extended_by(mut self, name: &str, file: LocaleFile) -> Self {
+        for (key, candidates) in file.keys {
+            if candidates.is_empty() {
+                self.map.remove(&key);
+            } else {
+                self.map.insert(key, candidates);
+            }
+        }
+        self.upper.extend(file.upper);
+        self.erase.extend(file.erase);
+        self.descriptions.extend(file.descriptions);
+        if let Some(meta) = file.meta {
+            if !meta.language.is_empty() {
+                self.language = meta.language.clone();
+            }
+            self.selection = meta.selection;
+            self.meta = Some(meta);
+        }
+        self.extends.insert(0, std::mem::replace(&mut self.name, name.to_string()));
+        self
+    }
+
+    /// The locale file at `path`, named after its stem.
+    pub fn load(path: &Path) -> Result<Self> {
+        let name = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+        Ok(Self::from_file(name, LocaleFile::load_from(path)?))
+    }
+
+    /// How many characters picking `candidate` (in either case) replaces.
+    pub fn erase(&self, candidate: &str) -> usize {
+        let listed = || charmap::lowercase(candidate, &self.language);
+        self.erase.get(candidate).or_else(|| self.erase.get(&listed())).copied().unwrap_or(1)
+    }
+
+    /// The popup candidates, in the locale's order.
+    pub fn table(&self) -> AccentTable {
+        AccentTable::new(&self.map)
+            .with_selection(self.selection)
+            .with_language(&self.language)
+            .with_uppercase(&self.upper)
+            .with_descriptions(&self.descriptions)
+    }
+}
+
+impl From<&Locale> for AccentTable {
+    fn from(locale: &Locale) -> Self {
+        locale.table()
+    }
+}
+
+impl From<Locale> for AccentTable {
+    fn from(locale: Locale) -> Self {
+        locale.table()
+    }
+}
+
+/// How bad a [`Problem`] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The file doesn't load, or part of it can never show.
+    Error,
+    /// Loads, but probably not as meant.
+    Warning,
+}
+
+/// Something [`lint`] found, at a 1-based line and column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub severity: Severity,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}:{}: {}: {}", self.line, self.column, severity, self.message)
+    }
+}
+
+/// Check the locale file `text` more closely than loading it does, for
+/// `accentctl validate-locale`: keys that can't be held, empty or repeated
+/// candidates, lists that page, and `[upper]`, `[erase]` and
+/// `[descriptions]` entries for candidates the file doesn't list.
+pub fn lint(text: &str) -> Vec<Problem> {
+    let file = match LocaleFile::parse(text) {
+        Ok(file) => file,
+        Err(e) => {
+            let problem = match e.chain().find_map(|cause| cause.downcast_ref::<toml::de::Error>()) {
+                Some(toml) => {
+                    let (line, column) = toml.span().map_or((1, 1), |span| position(text, span.start));
+                    Problem { severity: Severity::Error, line, column, message: toml.message().trim().to_string() }
+                }
+                None => {
+                    let (line, column) = find_key(text, "[meta]");
+                    Problem { severity: Severity::Error, line, column, message: e.to_string() }
+                }
+            };
+            return vec![problem];
+        }
+    };
+
+    let mut problems = Vec::new();
+    let mut report = |severity, key: &str, message: String| {
+        let (line, column) = find_key(text, key);
+        problems.push(Problem { severity, line, column, message });
+    };
+    let mut keys: Vec<_> = file.keys.iter().collect();
+    keys.sort();
+    for (key, candidates) in keys {
+        if !charmap::is_holdable(&key.to_lowercase()) {
+            report(Severity::Error, key, format!("{:?} is not a key accentd can hold, so it never shows a popup", key));
+        }
+        if candidates.is_empty() && file.extends.is_none() {
+            report(Severity::Warning, key, format!("{:?} has no candidates", key));
+        }
+        for (i, candidate) in candidates.iter().enumerate() {
+            if candidate.trim().is_empty() {
+                report(Severity::Error, key, format!("{:?}: candidate {} is empty", key, i + 1));
+            } else if candidates[..i].contains(candidate) {
+                report(Severity::Warning, key, format!("{:?}: {:?} is listed twice", key, candidate));
+            }
+        }
+        if candidates.len() > 9 {
+            let message = format!("{:?} has {} candidates; past the ninth they page with Up and Down", key, candidates.len());
+            report(Severity::Warning, key, message);
+        }
+    }
+    // an extending file's tables may name its parent's candidates
+    let listed = |candidate: &String| file.extends.is_some() || file.keys.values().flatten().any(|c| c == candidate);
+    let tables: [(&str, Vec<&String>); 3] = [
+        ("upper", file.upper.keys().collect()),
+        ("erase", file.erase.keys().collect()),
+        ("descriptions", file.descriptions.keys().collect()),
+    ];
+    for (table, candidates) in tables {
+        for candidate in candidates.into_iter().filter(|c| !listed(c)) {
+            report(Severity::Warning, candidate, format!("[{}] {:?} is not a candidate of any key", table, candidate));
+        }
+    }
+    problems
+}
+
+/// Line and column of byte `offset` in `text`.
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+/// Where the entry for `key` (or the line `key` itself, for a header)
+/// starts, or 1:1.
+fn find_key(text: &str, key: &str) -> (usize, usize) {
+    let forms = [key.to_string(), format!("{:?}", key), format!("'{}'", key)];
+    for (i, line) in text.lines().enumerate() {
+        let rest = line.trim_start();
+        let entry = forms.iter().any(|form| {
+            rest.strip_prefix(form.as_str()).is_some_and(|after| after.is_empty() || after.trim_start().starts_with('='))
+        });
+        if entry {
+            return (i + 1, line.len() - rest.len() + 1);
+        }
+    }
+    (1, 1)
+}
+
+/// Loose BCP 47 check: a 2-3 letter primary language subtag followed by
+/// alphanumeric subtags of 1-8 characters.
+fn is_valid_language_tag(tag: &str) -> bool {
+    let mut parts = tag.split('-');
+    let Some(primary) = parts.next() else {
+        return false;
+    };
+    (2..=3).contains(&primary.len())
+        && primary.chars().all(|c| c.is_ascii_alphabetic())
+        && parts.all(|p| (1..=8).contains(&p.len()) && p.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+/// Parse "MAJOR.MINOR.PATCH" (minor/patch optional) into a comparable tuple.
+fn parse_version(version: &str) -> Result<(u64, u64, u64)> {
+    let mut nums = version.trim().splitn(3, '.').map(|p| p.parse::<u64>());
+    let major = nums.next().context("missing major version")??;
+    let minor = nums.next().transpose()?.unwrap_or(0);
+    let patch = nums.next().transpose()?.unwrap_or(0);
+    Ok((major, minor, patch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // --- spec: v1 files (flat map, no header) still load ---
+
+    #[test]
+    fn v1_file_parses_without_meta() {
+        let file = LocaleFile::parse(r#"e = ["è", "é"]"#).unwrap();
+        assert!(file.meta.is_none());
+        assert_eq!(file.keys["e"], vec!["è", "é"]);
+    }
+
+    // --- spec: v2 header is parsed and kept out of the key map ---
+
+    #[test]
+    fn v2_file_parses_meta_and_keys() {
+        let toml = r#"
+[meta]
+name = "Italiano"
+language = "it"
+author = "someone"
+version = "1.2"
+
+[keys]
+a = ["à"]
+e = ["è", "é"]
+"#;
+        let file = LocaleFile::parse(toml).unwrap();
+        let meta = file.meta.unwrap();
+        assert_eq!(meta.name, "Italiano");
+        assert_eq!(meta.language, "it");
+        assert_eq!(meta.author.as_deref(), Some("someone"));
+        assert_eq!(file.keys.len(), 2);
+        assert_eq!(file.keys["e"], vec!["è", "é"]);
+    }
+
+    #[test]
+    fn upper_table_gives_explicit_uppercase_forms() {
+        let toml = "[meta]\nname = \"x\"\nlanguage = \"nl\"\n[keys]\ni = [\"ĳ\"]\n[upper]\n\"ĳ\" = \"IJ\"\n";
+        let file = LocaleFile::parse(toml).unwrap();
+        assert_eq!(file.upper["ĳ"], "IJ");
+        assert_eq!(file.keys["i"], vec!["ĳ"]);
+    }
+
+    #[test]
+    fn erase_table_counts_characters_replaced() {
+        let file = LocaleFile::parse("o = [\"œ\"]\n[erase]\n\"œ\" = 2\n").unwrap();
+        assert_eq!(file.erase["œ"], 2);
+        assert_eq!(file.keys["o"], vec!["œ"]);
+    }
+
+    #[test]
+    fn to_toml_round_trips() {
+        let meta = LocaleMeta { name: "x".into(), language: "und".into(), ..Default::default() };
+        let file = LocaleFile {
+            extends: Some("fr".into()),
+            meta: Some(meta),
+            keys: LocaleMap::from([("e".to_string(), vec!["è".to_string(), "\"".to_string()])]),
+            descriptions: DescriptionMap::from([("è".to_string(), "e grave".to_string())]),
+            ..Default::default()
+        };
+        assert_eq!(LocaleFile::parse(&file.to_toml().unwrap()).unwrap(), file);
+    }
+
+    #[test]
+    fn mixing_keys_table_and_top_level_candidates_is_rejected() {
+        let toml = "a = [\"à\"]\n[keys]\ne = [\"è\"]\n";
+        assert!(LocaleFile::parse(toml).is_err());
+    }
+
+    // --- spec: header is validated on load ---
+
+    #[test]
+    fn empty_name_is_rejected() {
+        let toml = "[meta]\nname = \"\"\nlanguage = \"it\"\n";
+        assert!(LocaleFile::parse(toml).is_err());
+    }
+
+    #[test]
+    fn invalid_language_tag_is_rejected() {
+        for tag in ["", "i", "italian", "it_IT", "fr-"] {
+            let toml = format!("[meta]\nname = \"x\"\nlanguage = \"{}\"\n", tag);
+            assert!(LocaleFile::parse(&toml).is_err(), "tag '{}' should be rejected", tag);
+        }
+    }
+
+    #[test]
+    fn valid_language_tags_are_accepted() {
+        for tag in ["it", "fr-CA", "sr-Latn", "zh-Hans-CN"] {
+            assert!(is_valid_language_tag(tag), "tag '{}' should be accepted", tag);
+        }
+    }
+
+    #[test]
+    fn min_version_newer_than_build_is_rejected() {
+        let toml = "[meta]\nname = \"x\"\nlanguage = \"it\"\nmin_accentd_version = \"999.0.0\"\n";
+        let err = LocaleFile::parse(toml).unwrap_err();
+        assert!(format!("{:#}", err).contains("requires accentd"));
+    }
+
+    #[test]
+    fn min_version_older_than_build_is_accepted() {
+        let toml = "[meta]\nname = \"x\"\nlanguage = \"it\"\nmin_accentd_version = \"0.1\"\n";
+        assert!(LocaleFile::parse(toml).is_ok());
+    }
+
+    #[test]
+    fn malformed_version_is_rejected() {
+        let toml = "[meta]\nname = \"x\"\nlanguage = \"it\"\nversion = \"one\"\n";
+        assert!(LocaleFile::parse(toml).is_err());
+    }
+
+    // --- spec: the Locale type ---
+
+    #[test]
+    fn builtin_locale_knows_its_selection_and_casing() {
+        let pinyin = Locale::builtin("pinyin").unwrap();
+        assert_eq!((pinyin.name.as_str(), pinyin.selection), ("pinyin", Selection::Tones));
+        let tr = Locale::builtin("tr").unwrap();
+        assert_eq!(tr.table().get("i", true).unwrap()[0].as_ref(), "İ");
+        assert!(Locale::builtin("xx").is_none());
+    }
+
+    #[test]
+    fn locale_from_a_file_keeps_its_header() {
+        let file = LocaleFile::parse(
+            "[meta]\nname = \"Latvian\"\nlanguage = \"lv\"\n[keys]\na = [\"ā\"]\n[erase]\n\"ā\" = 2\n",
+        )
+        .unwrap();
+        let locale = Locale::from_file("mine", file);
+        assert_eq!((locale.name.as_str(), locale.language.as_str()), ("mine", "lv"));
+        assert_eq!(locale.meta.as_ref().unwrap().name, "Latvian");
+        assert_eq!((locale.erase("Ā"), locale.erase("x")), (2, 1));
+    }
+
+    #[test]
+    fn extending_replaces_keys_and_keeps_the_rest() {
+        let file = LocaleFile::parse(
+            "extends = \"fr\"\n[meta]\nname = \"Québécois\"\nlanguage = \"fr-CA\"\n[keys]\ne = [\"é\", \"è\"]\ny = []\n",
+        )
+        .unwrap();
+        let locale = Locale::builtin("fr").unwrap().extended_by("fr-ca", file);
+        assert_eq!(locale.map["e"], ["é", "è"]);
+        assert_eq!(locale.map["c"], ["ç"], "untouched keys come from fr");
+        assert!(!locale.map.contains_key("y"), "an empty list removes the key");
+        assert_eq!((locale.name.as_str(), locale.language.as_str()), ("fr-ca", "fr-CA"));
+        assert_eq!(locale.extends, ["fr"]);
+    }
+
+    // --- spec: lint ---
+
+    #[test]
+    fn lint_reports_toml_errors_with_their_position() {
+        let problems = lint("[keys]\ne = [\"è\"\na = [\"à\"]\n");
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert_eq!(problems[0].severity, Severity::Error);
+        assert_eq!(problems[0].line, 3);
+        let problems = lint("[keys]\n\ne = \"è\"\n");
+        assert_eq!((problems[0].line, problems[0].column), (3, 5), "{}", problems[0]);
+    }
+
+    #[test]
+    fn lint_finds_unholdable_keys_and_bad_candidates() {
+        let text = "[keys]\ne = [\"è\", \"è\", \"\"]\nk = [\"ķ\"]\n\"@\" = [\"me@example.com\"]\n[upper]\n\"ŧ\" = \"Ŧ\"\n";
+        let problems: Vec<String> = lint(text).iter().map(ToString::to_string).collect();
+        assert_eq!(
+            problems,
+            [
+                "2:1: warning: \"e\": \"è\" is listed twice",
+                "2:1: error: \"e\": candidate 3 is empty",
+                "3:1: error: \"k\" is not a key accentd can hold, so it never shows a popup",
+                "6:1: warning: [upper] \"ŧ\" is not a candidate of any key",
+            ]
+        );
+    }
+
+    #[test]
+    fn lint_passes_shipped_locales_except_for_paging() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../data/locales");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let text = std::fs::read_to_string(&path).unwrap();
+            let problems: Vec<_> = lint(&text).into_iter().filter(|p| !p.message.contains("page with Up")).collect();
+            assert!(problems.is_empty(), "{}: {:?}", path.display(), problems);
+        }
+    }
+
+    // --- spec: shipped locale files are valid v2 ---
+
+    #[test]
+    fn shipped_locale_files_have_valid_meta() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../data/locales");
+        for name in ["it", "es", "fr", "de", "pt", "pl", "cs", "sk", "tr", "da", "no", "sv", "is", "eo", "cy", "hr", "sr-latn", "ca", "vi", "grc", "ru", "uk"] {
+            let file = LocaleFile::load_from(&dir.join(format!("{}.toml", name))).unwrap();
+            let meta = file.meta.expect("shipped locales carry a [meta] header");
+            assert_eq!(meta.language, name);
+            assert!(!file.keys.is_empty());
+        }
+    }
+
+    #[test]
+    fn shipped_pinyin_matches_builtin_and_selects_tones() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../data/locales");
+        let file = LocaleFile::load_from(&dir.join("pinyin.toml")).unwrap();
+        assert_eq!(file.selection(), Selection::Tones);
+        assert_eq!(file.meta.as_ref().unwrap().language, "zh");
+        assert_eq!(file.keys, crate::charmap::builtin_locale("pinyin"));
+    }
+
+    #[test]
+    fn shipped_ipa_matches_builtin() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../data/locales");
+        let file = LocaleFile::load_from(&dir.join("ipa.toml")).unwrap();
+        assert_eq!(file.meta.as_ref().unwrap().language, "und-fonipa");
+        assert_eq!(file.keys, crate::charmap::builtin_locale("ipa"));
+    }
+
+    #[test]
+    fn shipped_math_matches_builtin() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../data/locales");
+        let file = LocaleFile::load_from(&dir.join("math.toml")).unwrap();
+        assert_eq!(file.keys, crate::charmap::builtin_locale("math"));
+    }
+
+    #[test]
+    fn shipped_polytonic_greek_matches_builtin() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../data/locales");
+        let file = LocaleFile::load_from(&dir.join("grc.toml")).unwrap();
+        assert_eq!(file.keys, crate::charmap::builtin_locale("grc"));
+    }
+
+    #[test]
+    fn selection_defaults_to_position() {
+        let v1 = LocaleFile::parse("a = [\"ā\"]\n").unwrap();
+        assert_eq!(v1.selection(), Selection::Position);
+        let v2 = LocaleFile::parse("[meta]\nname = \"x\"\nlanguage = \"it\"\n[keys]\na = [\"à\"]\n").unwrap();
+        assert_eq!(v2.selection(), Selection::Position);
+    }
+}
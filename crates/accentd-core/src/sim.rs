@@ -0,0 +1,273 @@
+//! Deterministic simulation of the state machine.
+//!
+//! Runs a [`StateMachine`] against a [`MockClock`] and a scripted sequence of
+//! key events and clock advances, firing timers exactly when a real main loop
+//! would. Used by the tests here and handy for embedders checking their own
+//! configurations.
+
+use crate::charmap::LocaleMap;
+use crate::clock::{Clock, MockClock};
+use crate::config::Config;
+use crate::state_machine::{Action, Actions, RawEvent, StateMachine};
+use std::time::Duration;
+
+/// One scripted input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Press(u16),
+    Release(u16),
+    Repeat(u16),
+    /// Let time pass, firing any timers that come due on the way.
+    Advance(Duration),
+    /// Popup click on the 1-indexed candidate.
+    Select(u8),
+    /// Popup closed by the user.
+    Dismiss,
+}
+
+/// A state machine on a mock clock, recording every action it produces.
+pub struct Simulation {
+    sm: StateMachine<MockClock>,
+    clock: MockClock,
+    log: Vec<Action>,
+}
+
+impl Simulation {
+    pub fn new(config: &Config, locale_map: LocaleMap) -> Self {
+        let clock = MockClock::new();
+        Self {
+            sm: StateMachine::with_clock(config, locale_map, clock.clone()),
+            clock,
+            log: Vec::new(),
+        }
+    }
+
+    pub fn state_machine(&mut self) -> &mut StateMachine<MockClock> {
+        &mut self.sm
+    }
+
+    /// Every action produced so far, in order.
+    pub fn log(&self) -> &[Action] {
+        &self.log
+    }
+
+    /// Apply one step and return the actions it produced.
+    pub fn step(&mut self, step: Step) -> Actions {
+        let actions = match step {
+            Step::Press(code) => self.sm.process_event(RawEvent::key(code, 1)),
+            Step::Release(code) => self.sm.process_event(RawEvent::key(code, 0)),
+            Step::Repeat(code) => self.sm.process_event(RawEvent::key(code, 2)),
+            Step::Advance(by) => self.advance(by),
+            Step::Select(index) => self.sm.ipc_select(index),
+            Step::Dismiss => self.sm.ipc_dismiss(),
+        };
+        self.log.extend(actions.iter().cloned());
+        actions
+    }
+
+    /// Apply steps in order and return all actions they produced.
+    pub fn run(&mut self, steps: &[Step]) -> Vec<Action> {
+        steps.iter().flat_map(|&step| self.step(step)).collect()
+    }
+
+    /// Move the clock forward, stopping at each deadline on the way.
+    fn advance(&mut self, by: Duration) -> Actions {
+        let end = self.clock.now() + by;
+        let mut actions = Actions::new();
+        while let Some(deadline) = self.sm.next_deadline().filter(|&d| d <= end) {
+            self.clock.set(deadline);
+            actions.extend(self.sm.check_timer());
+        }
+        self.clock.set(end);
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::charmap::builtin_locale;
+    use crate::ipc::DaemonMsg;
+    use proptest::prelude::*;
+    use std::collections::BTreeSet;
+
+    const KEY_1: u16 = 2;
+    const KEY_ESC: u16 = 1;
+    const KEY_E: u16 = 18;
+    const KEY_A: u16 = 30;
+    const KEY_F: u16 = 33;
+    const KEY_LEFTCTRL: u16 = 29;
+    const KEY_LEFTSHIFT: u16 = 42;
+
+    fn ms(n: u64) -> Step {
+        Step::Advance(Duration::from_millis(n))
+    }
+
+    fn sim(keep_open: bool, defer_base: bool) -> Simulation {
+        let mut config = Config::default();
+        config.general.threshold_ms = Some(300);
+        config.general.defer_base = defer_base;
+        config.popup.keep_open = keep_open;
+        Simulation::new(&config, builtin_locale("it"))
+    }
+
+    fn emitted(actions: &[Action]) -> Vec<&str> {
+        actions
+            .iter()
+            .filter_map(|a| match a {
+                Action::EmitAccent { accent, .. } => Some(&**accent),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // --- spec: scripted scenarios ---
+
+    #[test]
+    fn hold_and_pick_emits_accent() {
+        let mut sim = sim(true, false);
+        let actions = sim.run(&[Step::Press(KEY_E), ms(350), Step::Release(KEY_E), Step::Press(KEY_1)]);
+        assert_eq!(emitted(&actions), vec!["è"]);
+    }
+
+    #[test]
+    fn timer_fires_exactly_at_threshold() {
+        let mut sim = sim(true, false);
+        sim.step(Step::Press(KEY_E));
+        assert!(sim.step(ms(299)).is_empty());
+        let actions = sim.step(ms(1));
+        assert!(actions.iter().any(|a| matches!(a, Action::SendPopup(DaemonMsg::ShowPopup { .. }))));
+    }
+
+    #[test]
+    fn long_advance_opens_then_times_out_popup() {
+        let mut sim = sim(true, false);
+        sim.step(Step::Press(KEY_E));
+        let actions = sim.step(ms(10_000));
+        let popups: Vec<_> = actions
+            .iter()
+            .filter_map(|a| match a {
+                Action::SendPopup(msg) => Some(msg),
+                _ => None,
+            })
+            .collect();
+        assert!(matches!(popups[..], [DaemonMsg::ShowPopup { .. }, DaemonMsg::HidePopup { .. }]));
+    }
+
+    // --- spec: invariants over random keystroke streams ---
+
+    /// Keys that exercise every path: accent-eligible, plain, digits, ESC and modifiers.
+    const KEYS: &[u16] = &[KEY_E, KEY_A, KEY_F, KEY_1, 3, KEY_ESC, KEY_LEFTSHIFT, KEY_LEFTCTRL];
+
+    #[derive(Debug, Clone)]
+    enum Input {
+        Toggle(u16),
+        Repeat(u16),
+        Wait(u64),
+        Select(u8),
+        Dismiss,
+    }
+
+    fn input() -> impl Strategy<Value = Input> {
+        prop_oneof![
+            4 => prop::sample::select(KEYS).prop_map(Input::Toggle),
+            1 => prop::sample::select(KEYS).prop_map(Input::Repeat),
+            3 => (0u64..800).prop_map(Input::Wait),
+            1 => (1u8..6).prop_map(Input::Select),
+            1 => Just(Input::Dismiss),
+        ]
+    }
+
+    /// Turn random inputs into a physically possible stream: presses and
+    /// releases alternate per key, repeats only while held, and everything is
+    /// released at the end.
+    fn script(inputs: &[Input]) -> Vec<Step> {
+        let mut held = BTreeSet::new();
+        let mut steps = Vec::new();
+        for input in inputs {
+            match *input {
+                Input::Toggle(code) if held.remove(&code) => steps.push(Step::Release(code)),
+                Input::Toggle(code) => {
+                    held.insert(code);
+                    steps.push(Step::Press(code));
+                }
+                Input::Repeat(code) if held.contains(&code) => steps.push(Step::Repeat(code)),
+                Input::Repeat(_) => {}
+                Input::Wait(n) => steps.push(ms(n)),
+                Input::Select(i) => steps.push(Step::Select(i)),
+                Input::Dismiss => steps.push(Step::Dismiss),
+            }
+        }
+        steps.extend(held.into_iter().map(Step::Release));
+        steps.push(ms(60_000));
+        steps
+    }
+
+    /// Replay relayed key events and check the output never presses a key
+    /// that's already down, releases or repeats one that's up, nor leaves one
+    /// down at the end.
+    fn check_output(log: &[Action]) -> Result<(), String> {
+        let mut down = BTreeSet::new();
+        for action in log {
+            let Action::Relay(event) = action else { continue };
+            if !event.is_key() {
+                continue;
+            }
+            match event.value {
+                1 if !down.insert(event.code) => {
+                    return Err(format!("key {} pressed twice without release", event.code));
+                }
+                0 if !down.remove(&event.code) => {
+                    return Err(format!("key {} released while up", event.code));
+                }
+                2 if !down.contains(&event.code) => {
+                    return Err(format!("key {} repeated while up", event.code));
+                }
+                _ => {}
+            }
+        }
+        if down.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("keys stuck down: {:?}", down))
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn random_streams_leave_no_stuck_keys(
+            inputs in prop::collection::vec(input(), 0..60),
+            keep_open in any::<bool>(),
+            defer_base in any::<bool>(),
+        ) {
+            let mut sim = sim(keep_open, defer_base);
+            sim.run(&script(&inputs));
+            prop_assert_eq!(check_output(sim.log()), Ok(()));
+            prop_assert!(sim.state_machine().next_deadline().is_none(), "settles back to idle");
+        }
+
+        #[test]
+        fn every_accent_comes_from_an_open_popup(
+            inputs in prop::collection::vec(input(), 0..60),
+            defer_base in any::<bool>(),
+        ) {
+            let mut sim = sim(true, defer_base);
+            let mut open = false;
+            for step in script(&inputs) {
+                // Whether this step closed a popup that was open
+                let mut closed = false;
+                for action in sim.step(step) {
+                    match action {
+                        Action::SendPopup(DaemonMsg::ShowPopup { .. }) => open = true,
+                        Action::SendPopup(DaemonMsg::HidePopup { .. }) => {
+                            closed = open;
+                            open = false;
+                        }
+                        Action::EmitAccent { .. } => prop_assert!(closed, "accent without a popup being picked from"),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,2083 @@
+//! The press-and-hold detection logic, independent of any input backend.
+//!
+//! Feed it key events with [`StateMachine::process_event`] (or a report's
+//! worth at a time with [`StateMachine::process_frame`]), call
+//! [`StateMachine::check_timer`] whenever [`StateMachine::next_deadline`]
+//! passes, and carry out the returned [`Action`]s. Keycodes are Linux
+//! input-event codes (`KEY_A` = 30, ...), whatever the platform.
+
+use crate::charmap::{self, AccentTable, Candidates, Keymap, Layout, Selection};
+use crate::clock::{Clock, SystemClock};
+use crate::config::Config;
+use crate::ipc::{DaemonMsg, HideReason};
+use smallvec::{smallvec, SmallVec};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Linux `EV_SYN` event type.
+pub const EV_SYN: u16 = 0x00;
+/// Linux `EV_KEY` event type.
+pub const EV_KEY: u16 = 0x01;
+
+const SYN_REPORT: u16 = 0;
+
+const KEY_ESC: u16 = 1;
+const KEY_0: u16 = 11;
+const KEY_TAB: u16 = 15;
+const KEY_ENTER: u16 = 28;
+const KEY_UP: u16 = 103;
+const KEY_LEFT: u16 = 105;
+const KEY_RIGHT: u16 = 106;
+const KEY_DOWN: u16 = 108;
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTSHIFT: u16 = 54;
+const KEY_LEFTALT: u16 = 56;
+const KEY_CAPSLOCK: u16 = 58;
+const KEY_RIGHTCTRL: u16 = 97;
+const KEY_RIGHTALT: u16 = 100;
+const KEY_LEFTMETA: u16 = 125;
+const KEY_RIGHTMETA: u16 = 126;
+
+/// An input event in Linux evdev terms: type, code and value.
+///
+/// For key events the value is 0 for release, 1 for press and 2 for
+/// autorepeat. Events of other types are passed through untouched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawEvent {
+    pub event_type: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl RawEvent {
+    pub fn new(event_type: u16, code: u16, value: i32) -> Self {
+        Self { event_type, code, value }
+    }
+
+    /// A key event.
+    pub fn key(code: u16, value: i32) -> Self {
+        Self::new(EV_KEY, code, value)
+    }
+
+    pub fn is_key(&self) -> bool {
+        self.event_type == EV_KEY
+    }
+}
+
+fn is_modifier(code: u16) -> bool {
+    matches!(
+        code,
+        KEY_LEFTCTRL
+            | KEY_RIGHTCTRL
+            | KEY_LEFTALT
+            | KEY_RIGHTALT
+            | KEY_LEFTMETA
+            | KEY_RIGHTMETA
+            | KEY_LEFTSHIFT
+            | KEY_RIGHTSHIFT
+            | KEY_CAPSLOCK
+    )
+}
+
+/// Per-device state machine states.
+#[derive(Debug, Clone, PartialEq)]
+enum State {
+    /// Normal passthrough mode.
+    Idle,
+    /// An accent-eligible key was pressed; waiting for threshold.
+    Holding {
+        base: &'static str,
+        accents: Candidates,
+        key_code: u16,
+        shift: bool,
+        started: Instant,
+        /// The press reached the output; with `defer_base` it waits for the
+        /// hold to be decided.
+        typed: bool,
+        /// The press's frame hasn't ended yet: modifiers pressed in it go
+        /// with the key (see `begin_frame`) instead of cancelling the hold.
+        first_frame: bool,
+    },
+    /// Popup is shown, awaiting number selection or dismiss.
+    Popup {
+        base: &'static str,
+        accents: Candidates,
+        key_code: u16,
+        started: Instant,
+        /// Index moved with the arrows or Tab; Enter (or releasing the
+        /// held key, with `release_to_select`) picks it.
+        highlighted: Option<usize>,
+        /// Which [`PAGE_SIZE`] candidates are shown and numbered.
+        page: usize,
+        /// The held letter was typed, and a pick replaces it.
+        typed: bool,
+    },
+}
+
+/// Candidates per popup page, one per digit key. Longer lists (Vietnamese)
+/// are paged with the arrows, and Up and Down.
+const PAGE_SIZE: usize = 9;
+
+/// The popup for page `page` of `accents`, numbered from 1 for the digits,
+/// with the descriptions `table` has for them.
+fn show_page(base: &str, accents: &Candidates, page: usize, table: &AccentTable) -> DaemonMsg {
+    let accents: Vec<String> = accents.iter().skip(page * PAGE_SIZE).take(PAGE_SIZE).map(|s| s.to_string()).collect();
+    let labels = (1..=accents.len() as u8).collect();
+    let mut descriptions: Vec<String> =
+        accents.iter().map(|a| table.description(a).unwrap_or_default().to_string()).collect();
+    if descriptions.iter().all(String::is_empty) {
+        descriptions.clear();
+    }
+    DaemonMsg::ShowPopup { base: base.to_string(), accents, labels, descriptions }
+}
+
+/// The held key's press, late: in a frame of its own, so apps see it before
+/// whatever comes next.
+fn late_press(code: u16) -> [Action; 2] {
+    [Action::Relay(RawEvent::key(code, 1)), Action::Relay(RawEvent::new(EV_SYN, SYN_REPORT, 0))]
+}
+
+/// The held key tapped late, for a popup closed without a pick: the letter
+/// the hold held back.
+fn late_tap(code: u16) -> [Action; 4] {
+    let [press, syn] = late_press(code);
+    [press, syn.clone(), Action::Relay(RawEvent::key(code, 0)), syn]
+}
+
+/// Actions that the state machine wants the caller to perform.
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Relay event to the output device unchanged.
+    Relay(RawEvent),
+    /// Send a message to the popup UI.
+    SendPopup(DaemonMsg),
+    /// Emit an accented character (backspace + char), picked from the popup
+    /// for `base`. `base_typed`: the held letter reached the output and is
+    /// to be erased; with `defer_base` it usually didn't.
+    EmitAccent { base: &'static str, accent: Arc<str>, base_typed: bool },
+    /// Suppress this event (don't relay).
+    Suppress,
+}
+
+/// What one call into the state machine returns. Two actions at most,
+/// unless a timer comes due with an event, so this nearly always stays on
+/// the stack.
+pub type Actions = SmallVec<[Action; 2]>;
+
+/// One-line summary of what the state machine decided, for diagnostics,
+/// e.g. "relay" or "suppress, emit è, hide popup (selected)".
+pub fn describe_actions(actions: &[Action]) -> String {
+    if actions.is_empty() {
+        return "nothing".into();
+    }
+    let parts: Vec<String> = actions
+        .iter()
+        .map(|action| match action {
+            Action::Relay(_) => "relay".into(),
+            Action::Suppress => "suppress".into(),
+            Action::EmitAccent { accent, .. } => format!("emit {}", accent),
+            Action::SendPopup(DaemonMsg::ShowPopup { base, accents, .. }) => {
+                format!("show popup {}: {}", base, accents.join(" "))
+            }
+            Action::SendPopup(DaemonMsg::HidePopup { reason }) => {
+                format!("hide popup ({:?})", reason).to_lowercase()
+            }
+            Action::SendPopup(DaemonMsg::Highlight { index }) => format!("highlight {}", index),
+            Action::SendPopup(_) => "send popup".into(),
+        })
+        .collect();
+    parts.join(", ")
+}
+
+/// Per-keyboard hold detector. Generic over the [`Clock`] so embedders and
+/// tests can control time; the daemon uses [`SystemClock`].
+pub struct StateMachine<C: Clock = SystemClock> {
+    clock: C,
+    state: State,
+    accents: AccentTable,
+    /// What the keyboard's keys type; US unless configured.
+    layout: Layout,
+    /// `[keymap]` overrides of the layout's letters.
+    keymap: Keymap,
+    threshold_ms: u64,
+    popup_timeout_ms: u64,
+    keep_open: bool,
+    release_to_select: bool,
+    /// Hold back the held key's press until the hold is decided.
+    defer_base: bool,
+    enabled: bool,
+    /// Temporarily off for reasons outside the user's toggle (e.g. fullscreen game).
+    suspended: bool,
+    /// Track modifier state.
+    ctrl_held: bool,
+    alt_held: bool,
+    super_held: bool,
+    shift_held: bool,
+    caps_lock: bool,
+    /// Between `begin_frame` and the SYN_REPORT ending that frame.
+    in_frame: bool,
+    /// Keys still physically down whose release must not reach the output:
+    /// the held key (its release went out when the popup opened) and keys
+    /// the popup consumed (ESC, digits). Their real releases and repeats are
+    /// swallowed whichever way the popup closed.
+    swallow_release: SmallVec<[u16; 4]>,
+}
+
+impl StateMachine {
+    pub fn new(config: &Config, accents: impl Into<AccentTable>) -> Self {
+        Self::with_clock(config, accents, SystemClock)
+    }
+}
+
+impl<C: Clock> StateMachine<C> {
+    pub fn with_clock(config: &Config, accents: impl Into<AccentTable>, clock: C) -> Self {
+        Self {
+            clock,
+            state: State::Idle,
+            accents: accents.into(),
+            layout: Layout::Us,
+            keymap: config.keymap.clone(),
+            threshold_ms: config.general.threshold(),
+            popup_timeout_ms: config.popup.timeout_ms,
+            keep_open: config.popup.keep_open,
+            release_to_select: config.popup.release_to_select,
+            // without the grab the letter reaches apps whatever we relay
+            defer_base: config.general.defer_base && config.general.grab,
+            enabled: config.general.enabled,
+            suspended: false,
+            ctrl_held: false,
+            alt_held: false,
+            super_held: false,
+            shift_held: false,
+            caps_lock: false,
+            in_frame: false,
+            swallow_release: SmallVec::new(),
+        }
+    }
+
+    /// Enable or disable. Disabling drops any hold in progress and hides an
+    /// open popup.
+    pub fn set_enabled(&mut self, enabled: bool) -> Actions {
+        self.enabled = enabled;
+        if enabled {
+            return Actions::new();
+        }
+        self.drop_hold()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Short name of the current state, for diagnostics: "disabled",
+    /// "suspended", "idle", "holding" or "popup".
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            _ if !self.enabled => "disabled",
+            _ if self.suspended => "suspended",
+            State::Idle => "idle",
+            State::Holding { .. } => "holding",
+            State::Popup { .. } => "popup",
+        }
+    }
+
+    /// The clock this state machine measures holds against.
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Suspend or resume hold detection without touching the enabled state.
+    /// Suspending drops any hold in progress and hides an open popup.
+    pub fn set_suspended(&mut self, suspended: bool) -> Actions {
+        self.suspended = suspended;
+        if !suspended {
+            return Actions::new();
+        }
+        self.drop_hold()
+    }
+
+    /// Go idle, hiding an open popup, and type a held-back letter.
+    fn drop_hold(&mut self) -> Actions {
+        match self.state {
+            State::Idle => Actions::new(),
+            State::Holding { key_code, typed, .. } => {
+                self.state = State::Idle;
+                if typed {
+                    Actions::new()
+                } else {
+                    late_press(key_code).into_iter().collect()
+                }
+            }
+            State::Popup { .. } => self.close(HideReason::Disabled),
+        }
+    }
+
+    /// Start from the keyboard's actual state (keys down and Caps Lock at
+    /// grab time) rather than assuming nothing is held.
+    pub fn seed_key_state(&mut self, held: &[u16], caps_lock: bool) {
+        self.ctrl_held = held.iter().any(|c| matches!(*c, KEY_LEFTCTRL | KEY_RIGHTCTRL));
+        self.alt_held = held.iter().any(|c| matches!(*c, KEY_LEFTALT | KEY_RIGHTALT));
+        self.super_held = held.iter().any(|c| matches!(*c, KEY_LEFTMETA | KEY_RIGHTMETA));
+        self.shift_held = held.iter().any(|c| matches!(*c, KEY_LEFTSHIFT | KEY_RIGHTSHIFT));
+        self.caps_lock = caps_lock;
+    }
+
+    /// Set the keyboard's physical layout (default US).
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// The base letter a key types on this keyboard, after `[keymap]`.
+    pub fn base(&self, code: u16) -> Option<&'static str> {
+        self.keymap.base(self.layout, code)
+    }
+
+    /// Replace the `[keymap]` overrides, e.g. after the config changed.
+    pub fn set_keymap(&mut self, keymap: Keymap) {
+        self.keymap = keymap;
+    }
+
+    /// Change the hold threshold, e.g. after calibration.
+    pub fn set_threshold(&mut self, threshold_ms: u64) {
+        self.threshold_ms = threshold_ms;
+    }
+
+    /// Switch candidates. A hold in progress ends: its popup is hidden and
+    /// a held-back letter typed.
+    pub fn set_locale_map(&mut self, accents: impl Into<AccentTable>) -> Actions {
+        self.accents = accents.into();
+        self.drop_hold()
+    }
+
+    /// Check if we're in HOLDING state and the threshold has elapsed,
+    /// or in Popup state and the timeout has elapsed.
+    pub fn check_timer(&mut self) -> Actions {
+        self.check_timer_at(self.clock.now())
+    }
+
+    /// [`check_timer`](Self::check_timer) as of `now`.
+    pub fn check_timer_at(&mut self, now: Instant) -> Actions {
+        match &self.state {
+            State::Holding {
+                base,
+                accents,
+                key_code,
+                started,
+                typed,
+                ..
+            } => {
+                let reached = *started + Duration::from_millis(self.threshold_ms);
+                if now >= reached {
+                    debug!(base = %base, "hold threshold reached, showing popup");
+
+                    self.swallow_release.push(*key_code);
+                    let mut actions = Actions::new();
+                    if *typed {
+                        // Stop its autorepeat downstream
+                        actions.push(Action::Relay(RawEvent::key(*key_code, 0)));
+                    }
+                    actions.push(Action::SendPopup(show_page(base, accents, 0, &self.accents)));
+                    self.state = State::Popup {
+                        base,
+                        accents: Arc::clone(accents),
+                        key_code: *key_code,
+                        // When the threshold passed, however late we got here
+                        started: reached,
+                        highlighted: None,
+                        page: 0,
+                        typed: *typed,
+                    };
+                    actions
+                } else {
+                    Actions::new()
+                }
+            }
+            State::Popup { started, .. } => {
+                if now.saturating_duration_since(*started).as_millis() as u64 >= self.popup_timeout_ms {
+                    debug!("popup timed out");
+                    self.close(HideReason::Timeout)
+                } else {
+                    Actions::new()
+                }
+            }
+            State::Idle => Actions::new(),
+        }
+    }
+
+    /// Return the next `Instant` at which `check_timer()` needs to run,
+    /// or `None` if idle (no timer needed).
+    pub fn next_deadline(&self) -> Option<Instant> {
+        match &self.state {
+            State::Holding { started, .. } => {
+                Some(*started + Duration::from_millis(self.threshold_ms))
+            }
+            State::Popup { started, .. } => {
+                Some(*started + Duration::from_millis(self.popup_timeout_ms))
+            }
+            State::Idle => None,
+        }
+    }
+
+    /// IPC: select accent by its label on the page shown. Returns actions if
+    /// in Popup state.
+    pub fn ipc_select(&mut self, index: u8) -> Actions {
+        if let State::Popup { base, ref accents, page, typed, .. } = self.state {
+            let shown = index.checked_sub(1).filter(|&i| (i as usize) < PAGE_SIZE);
+            if let Some(accent) = shown.and_then(|i| accents.get(page * PAGE_SIZE + i as usize)) {
+                return self.pick(base, Arc::clone(accent), typed);
+            }
+        }
+        Actions::new()
+    }
+
+    /// Close the popup with `accent` chosen.
+    fn pick(&mut self, base: &'static str, accent: Arc<str>, base_typed: bool) -> Actions {
+        debug!(accent = %accent, "accent selected");
+        self.state = State::Idle;
+        let mut actions: Actions = smallvec![
+            Action::SendPopup(DaemonMsg::HidePopup { reason: HideReason::Selected }),
+            Action::EmitAccent { base, accent, base_typed },
+        ];
+        actions.extend(self.late_shift());
+        actions
+    }
+
+    /// Close the popup without a pick, typing the held letter if it was
+    /// held back.
+    fn close(&mut self, reason: HideReason) -> Actions {
+        let untyped = match self.state {
+            State::Popup { key_code, typed, .. } => (!typed).then_some(key_code),
+            _ => None,
+        };
+        self.state = State::Idle;
+        let mut actions: Actions = smallvec![Action::SendPopup(DaemonMsg::HidePopup { reason })];
+        actions.extend(untyped.into_iter().flat_map(late_tap));
+        actions.extend(self.late_shift());
+        actions
+    }
+
+    /// Shift pressed in the popup was kept from the apps. Still held as the
+    /// popup closes, it goes out late, with its release no longer swallowed:
+    /// otherwise Shift+T after the popup would type `t`, and lowercase on
+    /// until Shift was pressed again.
+    fn late_shift(&mut self) -> Actions {
+        let mut actions = Actions::new();
+        self.swallow_release.retain(|code| {
+            let shift = matches!(*code, KEY_LEFTSHIFT | KEY_RIGHTSHIFT);
+            if shift {
+                actions.extend(late_press(*code));
+            }
+            !shift
+        });
+        actions
+    }
+
+    /// IPC: dismiss popup. Returns actions if in Popup state.
+    pub fn ipc_dismiss(&mut self) -> Actions {
+        if matches!(self.state, State::Popup { .. }) {
+            return self.close(HideReason::Dismissed);
+        }
+        Actions::new()
+    }
+
+    /// Process an input event, returning actions for the caller.
+    pub fn process_event(&mut self, event: RawEvent) -> Actions {
+        self.process_event_at(event, self.clock.now())
+    }
+
+    /// Process an input event the kernel timestamped `at`. A threshold or
+    /// timeout that passed by then takes effect first, even if the timer
+    /// hasn't fired yet: a key held past the threshold but read late still
+    /// shows the popup.
+    pub fn process_event_at(&mut self, event: RawEvent, at: Instant) -> Actions {
+        let mut actions = match self.next_deadline() {
+            Some(deadline) if deadline <= at => self.check_timer_at(at),
+            _ => Actions::new(),
+        };
+        actions.extend(self.handle_event(event, at));
+        actions
+    }
+
+    /// Process a whole input frame seen `at`: [`begin_frame`](Self::begin_frame),
+    /// then each event in the order it came.
+    pub fn process_frame(&mut self, frame: &[RawEvent], at: Instant) -> Actions {
+        self.begin_frame(frame);
+        frame.iter().flat_map(|&event| self.process_event_at(event, at)).collect()
+    }
+
+    /// Take in the modifier changes of an input frame (its events up to the
+    /// SYN_REPORT) before any of its keys. A frame is the keyboard's state at
+    /// its end: Shift let go in the report that presses `e` doesn't shift
+    /// it, and Shift pressed in it does, whichever the device listed first.
+    /// The events are still to be processed, and relayed, in the order they
+    /// came, each MSC_SCAN next to its key.
+    pub fn begin_frame(&mut self, frame: &[RawEvent]) {
+        self.in_frame = true;
+        // Caps Lock toggles on its press, which must count once
+        let modifiers = frame.iter().filter(|e| e.is_key() && is_modifier(e.code) && e.code != KEY_CAPSLOCK);
+        for event in modifiers {
+            self.update_modifiers(event);
+        }
+    }
+
+    fn handle_event(&mut self, event: RawEvent, at: Instant) -> Actions {
+        // Non-key events: always relay
+        if !event.is_key() {
+            if event.event_type == EV_SYN && event.code == SYN_REPORT {
+                self.in_frame = false;
+                if let State::Holding { first_frame, .. } = &mut self.state {
+                    *first_frame = false;
+                }
+            }
+            return smallvec![Action::Relay(event)];
+        }
+
+        // Track modifier state for all key events
+        self.update_modifiers(&event);
+
+        let code = event.code;
+        let value = event.value; // 0=release, 1=press, 2=repeat
+
+        // Keys whose press never reached the output, or whose release already
+        // did. Checked before enabled/suspended: closing the popup by
+        // disabling must not let the release through either.
+        if let Some(pos) = self.swallow_release.iter().position(|&c| c == code) {
+            if !self.is_popup_for(code) {
+                match value {
+                    0 => {
+                        self.swallow_release.swap_remove(pos);
+                        return smallvec![Action::Suppress];
+                    }
+                    2 => return smallvec![Action::Suppress],
+                    // Pressed again: its release got lost somewhere; it's a new press
+                    _ => {
+                        self.swallow_release.swap_remove(pos);
+                    }
+                }
+            }
+        }
+
+        if !self.enabled || self.suspended {
+            return smallvec![Action::Relay(event)];
+        }
+
+        match &self.state {
+            State::Idle => self.handle_idle(event, code, value, at),
+            State::Holding { .. } => self.handle_holding(event, code, value),
+            State::Popup { .. } => self.handle_popup(event, code, value),
+        }
+    }
+
+    fn handle_idle(&mut self, event: RawEvent, code: u16, value: i32, at: Instant) -> Actions {
+        // Only interested in key press (value=1)
+        if value != 1 {
+            return smallvec![Action::Relay(event)];
+        }
+
+        // If a modifier is held, don't start accent detection
+        if self.ctrl_held || self.alt_held || self.super_held {
+            return smallvec![Action::Relay(event)];
+        }
+
+        // Check if this is an accent-eligible key: a letter (Caps Lock inverts
+        // Shift), a digit (Shift+digit on AZERTY), the symbol a punctuation key types
+        // (Shift+2 is "@"), or a shifted digit by position ("shift+2"), in
+        // that order. Symbol and digit candidates are never uppercased.
+        let letter = self.base(code).map(|base| (base, self.shift_held != self.caps_lock));
+        let digit = charmap::keycode_to_digit_base(code)
+            .filter(|_| self.shift_held == self.layout.shifted_digits())
+            .map(|base| (base, false));
+        let symbol = self.layout.symbol(code, self.shift_held).map(|base| (base, false));
+        let shifted_digit =
+            charmap::keycode_to_shifted_digit_base(code).filter(|_| self.shift_held).map(|base| (base, false));
+        let found = [letter, digit, symbol, shifted_digit].into_iter().flatten().find_map(|(base, shift)| {
+            let accents = self.accents.get(base, shift).filter(|accents| !accents.is_empty())?;
+            Some((base, shift, Arc::clone(accents)))
+        });
+        if let Some((base, shift, accents)) = found {
+            debug!(base = %base, shift, "starting hold timer");
+            self.state = State::Holding {
+                base,
+                accents,
+                key_code: code,
+                shift,
+                started: at,
+                typed: !self.defer_base,
+                first_frame: self.in_frame,
+            };
+            if self.defer_base {
+                return smallvec![Action::Suppress];
+            }
+            // Emit the base key immediately (zero latency)
+            return smallvec![Action::Relay(event)];
+        }
+
+        // Not accent-eligible, relay normally
+        smallvec![Action::Relay(event)]
+    }
+
+    fn handle_holding(&mut self, event: RawEvent, code: u16, value: i32) -> Actions {
+        let (held_code, held_base, typed, first_frame) = match &self.state {
+            State::Holding {
+                key_code, base, typed, first_frame, ..
+            } => (*key_code, *base, *typed, *first_frame),
+            _ => unreachable!(),
+        };
+
+        // Key repeat of the held key: suppress (we already emitted the first press)
+        if code == held_code && value == 2 {
+            return smallvec![Action::Suppress];
+        }
+
+        // A held-back press goes out before a press or the held key's
+        // release, and before a modifier's release so that a Shift let go
+        // meanwhile still applies to the letter
+        let mut actions = Actions::new();
+        if !typed && (value == 1 || code == held_code || is_modifier(code)) {
+            actions.extend(late_press(held_code));
+            if let State::Holding { typed, .. } = &mut self.state {
+                *typed = true;
+            }
+        }
+
+        // Release of the held key: cancel timer, go idle
+        if code == held_code && value == 0 {
+            debug!(base = %held_base, "hold cancelled: key released before threshold");
+            self.state = State::Idle;
+        }
+
+        // Any other key press: cancel timer, relay both. Not a modifier
+        // pressed in the held key's frame: it applies to the key (see
+        // begin_frame)
+        if value == 1 && !(first_frame && is_modifier(code)) {
+            debug!(base = %held_base, other_key = code, "hold cancelled: another key pressed");
+            self.state = State::Idle;
+        }
+
+        // Other events: relay
+        actions.push(Action::Relay(event));
+        actions
+    }
+
+    fn handle_popup(&mut self, event: RawEvent, code: u16, value: i32) -> Actions {
+        let (popup_base, popup_accents, popup_code, highlighted, page, typed) = match &self.state {
+            State::Popup { base, accents, key_code, highlighted, page, typed, .. } => {
+                (*base, Arc::clone(accents), *key_code, *highlighted, *page, *typed)
+            }
+            _ => unreachable!(),
+        };
+
+        // Key repeat of the held key: suppress
+        if code == popup_code && value == 2 {
+            return smallvec![Action::Suppress];
+        }
+
+        // Release of the held key (its release went out when the popup opened)
+        if code == popup_code && value == 0 {
+            self.swallow_release.retain(|c| *c != code);
+            if let Some(i) = highlighted.filter(|_| self.release_to_select) {
+                return self.pick(popup_base, Arc::clone(&popup_accents[i]), typed);
+            }
+            if self.keep_open {
+                // macOS style: popup stays open, suppress the release
+                return smallvec![Action::Suppress];
+            }
+            debug!("popup dismissed: held key released");
+            let mut actions = self.close(HideReason::Cancelled);
+            actions.push(Action::Suppress); // don't relay the release
+            return actions;
+        }
+
+        // ESC press: dismiss popup
+        if code == KEY_ESC && value == 1 {
+            debug!("popup dismissed: ESC pressed");
+            self.swallow_release.push(code);
+            let mut actions = self.close(HideReason::Cancelled);
+            actions.push(Action::Suppress);
+            return actions;
+        }
+
+        // Neutral tone: keep the letter as typed
+        if code == KEY_0 && value == 1 && self.accents.selection() == Selection::Tones {
+            debug!("neutral tone selected");
+            self.swallow_release.push(code);
+            let mut actions = self.close(HideReason::Selected);
+            actions.push(Action::Suppress);
+            return actions;
+        }
+
+        // Arrows and Tab (Shift+Tab back) move the highlight, wrapping and
+        // turning the page as needed; Up and Down turn it outright
+        let len = popup_accents.len();
+        let pages = len.div_ceil(PAGE_SIZE);
+        let turn = pages > 1 && matches!(code, KEY_UP | KEY_DOWN);
+        if value == 1 && (turn || matches!(code, KEY_LEFT | KEY_RIGHT | KEY_TAB)) {
+            let back = code == KEY_LEFT || code == KEY_UP || (code == KEY_TAB && self.shift_held);
+            let next = match (turn, highlighted, back) {
+                (true, _, false) => (page + 1) % pages * PAGE_SIZE,
+                (true, _, true) => (page + pages - 1) % pages * PAGE_SIZE,
+                (false, None, false) => page * PAGE_SIZE,
+                (false, None, true) => len - 1,
+                (false, Some(i), false) => (i + 1) % len,
+                (false, Some(i), true) => (i + len - 1) % len,
+            };
+            let mut actions = Actions::new();
+            if next / PAGE_SIZE != page {
+                actions.push(Action::SendPopup(show_page(popup_base, &popup_accents, next / PAGE_SIZE, &self.accents)));
+            }
+            actions.push(Action::SendPopup(DaemonMsg::Highlight { index: (next % PAGE_SIZE) as u8 + 1 }));
+            if let State::Popup { highlighted, page, .. } = &mut self.state {
+                *highlighted = Some(next);
+                *page = next / PAGE_SIZE;
+            }
+            self.swallow_release.push(code);
+            return actions;
+        }
+
+        // Shift neither dismisses nor recases: AZERTY needs it for digits.
+        // The apps don't see it while the popup is open (see `late_shift`)
+        if matches!(code, KEY_LEFTSHIFT | KEY_RIGHTSHIFT) && value != 0 {
+            if value == 1 {
+                self.swallow_release.push(code);
+            }
+            return smallvec![Action::Suppress];
+        }
+
+        // Enter picks the highlighted candidate
+        if let Some(i) = highlighted.filter(|_| code == KEY_ENTER && value == 1) {
+            self.swallow_release.push(code);
+            return self.pick(popup_base, Arc::clone(&popup_accents[i]), typed);
+        }
+
+        // Number key press: select accent
+        if value == 1 {
+            if let Some(digit) = charmap::keycode_to_digit(code) {
+                let idx = page * PAGE_SIZE + (digit - 1) as usize;
+                if idx < popup_accents.len() {
+                    self.swallow_release.push(code);
+                    return self.pick(popup_base, Arc::clone(&popup_accents[idx]), typed);
+                }
+            }
+        }
+
+        // Any other key: dismiss popup and relay
+        if value == 1 {
+            debug!(code, "popup dismissed: unrelated key pressed");
+            let mut actions = self.close(HideReason::Cancelled);
+            actions.push(Action::Relay(event));
+            return actions;
+        }
+
+        // Release of a key pressed before the popup opened (Shift, a fast
+        // typist's previous key): relay it, or it stays down downstream
+        if value == 0 {
+            return smallvec![Action::Relay(event)];
+        }
+
+        smallvec![Action::Suppress]
+    }
+
+    fn is_popup_for(&self, code: u16) -> bool {
+        matches!(self.state, State::Popup { key_code, .. } if key_code == code)
+    }
+
+    #[cfg(test)]
+    fn is_idle(&self) -> bool {
+        self.state == State::Idle
+    }
+
+    fn update_modifiers(&mut self, event: &RawEvent) {
+        let pressed = event.value == 1;
+        let released = event.value == 0;
+
+        if event.code == KEY_CAPSLOCK && pressed {
+            self.caps_lock = !self.caps_lock;
+            return;
+        }
+
+        let held = match event.code {
+            KEY_LEFTCTRL | KEY_RIGHTCTRL => &mut self.ctrl_held,
+            KEY_LEFTALT | KEY_RIGHTALT => &mut self.alt_held,
+            KEY_LEFTMETA | KEY_RIGHTMETA => &mut self.super_held,
+            KEY_LEFTSHIFT | KEY_RIGHTSHIFT => &mut self.shift_held,
+            _ => return,
+        };
+        if pressed {
+            *held = true;
+        } else if released {
+            *held = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::charmap::builtin_locale;
+    use crate::clock::MockClock;
+
+    // Helpers to create events without caring about implementation details.
+    // evdev keycodes from Linux input-event-codes.h:
+    const KEY_A: u16 = 30;
+    const KEY_E: u16 = 18;
+    const KEY_F: u16 = 33; // not accent-eligible
+    const KEY_T: u16 = 20;
+    const KEY_1: u16 = 2;
+    const KEY_2: u16 = 3;
+    const KEY_3: u16 = 4;
+    const KEY_9: u16 = 10;
+    const KEY_V: u16 = 47;
+    const EV_MSC: u16 = 4;
+    const MSC_SCAN: u16 = 4;
+
+    fn key_press(code: u16) -> RawEvent {
+        RawEvent::key(code, 1)
+    }
+    fn key_release(code: u16) -> RawEvent {
+        RawEvent::key(code, 0)
+    }
+    fn key_repeat(code: u16) -> RawEvent {
+        RawEvent::key(code, 2)
+    }
+
+    fn make_sm() -> StateMachine<MockClock> {
+        let config = Config::default(); // threshold=300ms, enabled=true, locale=it
+        let locale_map = builtin_locale("it");
+        StateMachine::with_clock(&config, locale_map, MockClock::new())
+    }
+
+    fn has_relay(actions: &[Action]) -> bool {
+        actions.iter().any(|a| matches!(a, Action::Relay(_)))
+    }
+    fn has_suppress(actions: &[Action]) -> bool {
+        actions.iter().any(|a| matches!(a, Action::Suppress))
+    }
+    fn has_emit_accent(actions: &[Action]) -> Option<&str> {
+        actions.iter().find_map(|a| match a {
+            Action::EmitAccent { accent, .. } => Some(&**accent),
+            _ => None,
+        })
+    }
+    fn has_show_popup(actions: &[Action]) -> bool {
+        actions.iter().any(|a| matches!(a, Action::SendPopup(DaemonMsg::ShowPopup { .. })))
+    }
+    fn has_hide_popup(actions: &[Action]) -> bool {
+        actions.iter().any(|a| matches!(a, Action::SendPopup(DaemonMsg::HidePopup { .. })))
+    }
+
+    // === SPEC: Press accent key → emit immediately (zero latency) ===
+
+    #[test]
+    fn press_accent_key_relays_immediately() {
+        let mut sm = make_sm();
+        let actions = sm.process_event(key_press(KEY_E));
+        assert!(has_relay(&actions), "pressing 'e' should relay immediately");
+    }
+
+    // === SPEC: Press non-accent key → relay normally, no hold ===
+
+    #[test]
+    fn press_non_accent_key_relays_and_stays_idle() {
+        let mut sm = make_sm();
+        let actions = sm.process_event(key_press(KEY_F));
+        assert!(has_relay(&actions));
+        assert!(sm.is_idle());
+    }
+
+    // === SPEC: Release before threshold → cancel, base char stays ===
+
+    #[test]
+    fn release_before_threshold_cancels_hold() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_E));
+        let actions = sm.process_event(key_release(KEY_E));
+        assert!(has_relay(&actions), "release should be relayed");
+        assert!(sm.is_idle(), "should return to idle");
+    }
+
+    // === SPEC: Another key before threshold → cancel hold ===
+
+    #[test]
+    fn another_key_before_threshold_cancels_hold() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_E));
+        // Type another key quickly (fast typist)
+        let actions = sm.process_event(key_press(KEY_F));
+        assert!(has_relay(&actions), "other key should be relayed");
+        assert!(sm.is_idle(), "should return to idle");
+    }
+
+    #[test]
+    fn modifier_pressed_after_the_frame_cancels_hold() {
+        for modifier in [KEY_LEFTCTRL, KEY_LEFTALT, KEY_LEFTMETA, KEY_LEFTSHIFT] {
+            let mut sm = make_sm();
+            let now = sm.clock().now();
+            let syn = RawEvent::new(EV_SYN, 0, 0);
+            sm.process_frame(&[key_press(KEY_E), syn], now);
+            sm.clock().advance(Duration::from_millis(200));
+            let actions = sm.process_frame(&[key_press(modifier), syn], sm.clock().now());
+            assert!(has_relay(&actions), "modifier {} should be relayed", modifier);
+            assert!(sm.is_idle(), "modifier {} should cancel the hold", modifier);
+        }
+    }
+
+    // === SPEC: Key repeat while holding → suppressed ===
+
+    #[test]
+    fn repeat_during_hold_is_suppressed() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_E));
+        let actions = sm.process_event(key_repeat(KEY_E));
+        assert!(has_suppress(&actions), "repeat should be suppressed");
+        assert!(!has_relay(&actions), "repeat should NOT be relayed");
+    }
+
+    // === SPEC: Hold past threshold → popup appears ===
+
+    #[test]
+    fn hold_past_threshold_shows_popup() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        let actions = sm.check_timer();
+        assert!(has_show_popup(&actions), "popup should appear after threshold");
+        assert!(has_relay(&actions), "should emit synthetic key release to stop autorepeat");
+        assert!(!sm.is_idle(), "should not be idle (should be in popup state)");
+    }
+
+    // === SPEC: Timer doesn't fire before threshold ===
+
+    #[test]
+    fn timer_does_not_fire_before_threshold() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_E));
+        let actions = sm.check_timer();
+        assert!(actions.is_empty(), "timer should not fire immediately");
+    }
+
+    // === SPEC: Holds are timed from the kernel's event timestamps ===
+
+    #[test]
+    fn hold_counts_from_event_time_not_processing_time() {
+        let mut sm = make_sm();
+        let pressed = sm.clock().now();
+        sm.clock().advance(Duration::from_millis(250));
+        sm.process_event_at(key_press(KEY_E), pressed);
+        sm.clock().advance(Duration::from_millis(60));
+        assert!(has_show_popup(&sm.check_timer()), "310ms since the press, past the threshold");
+    }
+
+    #[test]
+    fn release_read_after_threshold_still_shows_popup() {
+        let mut sm = make_sm();
+        let pressed = sm.clock().now();
+        sm.process_event_at(key_press(KEY_E), pressed);
+        // The timer never got to run before the release was read
+        let actions = sm.process_event_at(key_release(KEY_E), pressed + Duration::from_millis(350));
+        assert!(has_show_popup(&actions));
+        assert!(!sm.is_idle(), "popup stays open after the held key's release");
+    }
+
+    // === SPEC: Threshold transition must release key to stop display server autorepeat ===
+
+    #[test]
+    fn threshold_emits_key_release_to_stop_autorepeat() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        let actions = sm.check_timer();
+
+        // Must contain a release event (value=0) for KEY_E before the ShowPopup
+        let release_idx = actions.iter().position(|a| match a {
+            Action::Relay(ev) => ev.code == KEY_E && ev.value == 0,
+            _ => false,
+        });
+        let popup_idx = actions.iter().position(|a| matches!(a, Action::SendPopup(DaemonMsg::ShowPopup { .. })));
+
+        assert!(release_idx.is_some(), "must emit key release to stop autorepeat");
+        assert!(popup_idx.is_some(), "must show popup");
+        assert!(release_idx.unwrap() < popup_idx.unwrap(), "release must come before popup");
+    }
+
+    // === SPEC: In popup, press digit → select accent ===
+
+    #[test]
+    fn popup_digit_selects_accent() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        sm.check_timer(); // triggers popup
+
+        // Press '1' to select first accent (è for Italian 'e')
+        let actions = sm.process_event(key_press(KEY_1));
+        assert!(has_hide_popup(&actions), "popup should hide");
+        assert_eq!(has_emit_accent(&actions), Some("è"), "should emit first Italian 'e' accent");
+        assert!(sm.is_idle());
+    }
+
+    #[test]
+    fn popup_digit_2_selects_second_accent() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        sm.check_timer();
+
+        let actions = sm.process_event(key_press(KEY_2));
+        assert_eq!(has_emit_accent(&actions), Some("é"), "should emit second Italian 'e' accent");
+    }
+
+    // === SPEC: In popup, out-of-range digit → no accent emitted ===
+
+    #[test]
+    fn popup_out_of_range_digit_dismisses() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        sm.check_timer();
+
+        // Italian 'e' has 4 accents, press 9
+        let actions = sm.process_event(key_press(KEY_9));
+        assert!(has_emit_accent(&actions).is_none(), "should not emit accent for out-of-range digit");
+        assert!(has_hide_popup(&actions), "popup should dismiss");
+    }
+
+    // === SPEC: In popup, Shift+digit selects too (AZERTY) ===
+
+    #[test]
+    fn shift_in_popup_neither_dismisses_nor_recases() {
+        let mut sm = make_sm();
+        sm.set_layout(crate::charmap::Layout::Fr);
+        enter_popup(&mut sm);
+        assert!(matches!(sm.process_event(key_press(KEY_LEFTSHIFT))[..], [Action::Suppress]));
+        let actions = sm.process_event(key_press(KEY_2));
+        assert_eq!(has_emit_accent(&actions), Some("é"));
+        // Still held after the pick: the apps get it now
+        assert!(actions.iter().any(|a| matches!(a, Action::Relay(e) if *e == key_press(KEY_LEFTSHIFT))));
+        assert!(matches!(sm.process_event(key_release(KEY_LEFTSHIFT))[..], [Action::Relay(_)]));
+    }
+
+    #[test]
+    fn shift_held_in_popup_reaches_the_next_letter() {
+        let mut sm = make_sm();
+        enter_popup(&mut sm);
+        sm.process_event(key_press(KEY_LEFTSHIFT));
+        let actions = sm.process_event(key_press(KEY_T));
+        assert!(has_hide_popup(&actions));
+        let relayed: Vec<RawEvent> = actions
+            .iter()
+            .filter_map(|a| match a {
+                Action::Relay(event) if event.is_key() => Some(*event),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(relayed, [key_press(KEY_LEFTSHIFT), key_press(KEY_T)], "T, not t");
+        assert!(has_relay(&sm.process_event(key_release(KEY_LEFTSHIFT))));
+    }
+
+    // === SPEC: In popup, ESC → dismiss, base char stays ===
+
+    #[test]
+    fn popup_esc_dismisses() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        sm.check_timer();
+
+        let actions = sm.process_event(key_press(KEY_ESC));
+        assert!(has_hide_popup(&actions));
+        assert!(has_emit_accent(&actions).is_none(), "ESC should not emit any accent");
+        assert!(sm.is_idle());
+    }
+
+    // === SPEC: In popup, release held key → keep_open=true keeps popup, keep_open=false dismisses ===
+
+    fn make_sm_keep_open(keep_open: bool) -> StateMachine<MockClock> {
+        let mut config = Config::default();
+        config.popup.keep_open = keep_open;
+        let locale_map = builtin_locale("it");
+        StateMachine::with_clock(&config, locale_map, MockClock::new())
+    }
+
+    #[test]
+    fn popup_release_held_key_dismisses_when_keep_open_false() {
+        let mut sm = make_sm_keep_open(false);
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        sm.check_timer();
+
+        let actions = sm.process_event(key_release(KEY_E));
+        assert!(has_hide_popup(&actions));
+        assert!(has_emit_accent(&actions).is_none());
+        assert!(sm.is_idle());
+    }
+
+    #[test]
+    fn popup_release_held_key_keeps_popup_when_keep_open_true() {
+        let mut sm = make_sm_keep_open(true);
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        sm.check_timer();
+
+        let actions = sm.process_event(key_release(KEY_E));
+        assert!(has_suppress(&actions), "release should be suppressed");
+        assert!(!has_hide_popup(&actions), "popup should stay open");
+        assert!(!sm.is_idle(), "should still be in popup state");
+    }
+
+    #[test]
+    fn popup_keep_open_then_select_accent() {
+        let mut sm = make_sm_keep_open(true);
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        sm.check_timer();
+
+        // Release the key — popup stays
+        sm.process_event(key_release(KEY_E));
+
+        // Now press a digit to select
+        let actions = sm.process_event(key_press(KEY_1));
+        assert!(has_hide_popup(&actions));
+        assert_eq!(has_emit_accent(&actions), Some("è"));
+        assert!(sm.is_idle());
+    }
+
+    #[test]
+    fn popup_keep_open_then_esc_dismisses() {
+        let mut sm = make_sm_keep_open(true);
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        sm.check_timer();
+
+        // Release the key — popup stays
+        sm.process_event(key_release(KEY_E));
+
+        // ESC dismisses
+        let actions = sm.process_event(key_press(KEY_ESC));
+        assert!(has_hide_popup(&actions));
+        assert!(sm.is_idle());
+    }
+
+    // === SPEC: In popup, repeat of held key → suppressed ===
+
+    #[test]
+    fn popup_repeat_held_key_is_suppressed() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        sm.check_timer();
+
+        let actions = sm.process_event(key_repeat(KEY_E));
+        assert!(has_suppress(&actions));
+        assert!(!has_relay(&actions));
+    }
+
+    // === SPEC: In popup, release of an earlier key → relayed (no stuck keys) ===
+
+    #[test]
+    fn popup_relays_release_of_shift_pressed_before_it() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_LEFTSHIFT));
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        sm.check_timer();
+
+        let actions = sm.process_event(key_release(KEY_LEFTSHIFT));
+        assert!(has_relay(&actions), "Shift release must reach the output");
+        assert!(!sm.is_idle(), "popup stays open");
+    }
+
+    // === SPEC: Ctrl/Alt/Super + letter → no hold, relay as-is ===
+
+    #[test]
+    fn ctrl_plus_letter_does_not_start_hold() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_LEFTCTRL));
+        sm.process_event(key_press(KEY_A));
+        assert!(sm.is_idle(), "Ctrl+A should not start hold detection");
+    }
+
+    #[test]
+    fn alt_plus_letter_does_not_start_hold() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_LEFTALT));
+        sm.process_event(key_press(KEY_E));
+        assert!(sm.is_idle());
+    }
+
+    #[test]
+    fn super_plus_letter_does_not_start_hold() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_LEFTMETA));
+        sm.process_event(key_press(KEY_E));
+        assert!(sm.is_idle());
+    }
+
+    // === SPEC: Shift + letter → uppercase accents ===
+
+    #[test]
+    fn shift_plus_letter_produces_uppercase_accents() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_LEFTSHIFT));
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        let timer_actions = sm.check_timer();
+        assert!(has_show_popup(&timer_actions));
+
+        // Check the popup contains uppercase accents
+        let show = timer_actions.iter().find_map(|a| match a {
+            Action::SendPopup(DaemonMsg::ShowPopup { accents, .. }) => Some(accents),
+            _ => None,
+        }).expect("should have ShowPopup");
+        assert_eq!(show[0], "È");
+        assert_eq!(show[1], "É");
+    }
+
+    // === SPEC: A modifier in the same frame as a key applies to it ===
+
+    #[test]
+    fn shift_after_letter_in_one_frame_still_shifts() {
+        let mut sm = make_sm();
+        let now = sm.clock().now();
+        let syn = RawEvent::new(EV_SYN, 0, 0);
+        sm.process_frame(&[key_press(KEY_E), key_press(KEY_LEFTSHIFT), syn], now);
+        assert_eq!(popup_accents(&mut sm)[0], "È");
+    }
+
+    #[test]
+    fn shift_released_with_letter_in_one_frame_no_longer_shifts() {
+        let mut sm = make_sm();
+        let now = sm.clock().now();
+        sm.seed_key_state(&[KEY_LEFTSHIFT], false);
+        let msc = |scan| RawEvent::new(EV_MSC, MSC_SCAN, scan);
+        let syn = RawEvent::new(EV_SYN, 0, 0);
+        let frame = [msc(0x700e1), key_release(KEY_LEFTSHIFT), msc(0x70008), key_press(KEY_E), syn];
+        let relayed: Vec<RawEvent> = sm
+            .process_frame(&frame, now)
+            .into_iter()
+            .filter_map(|a| match a {
+                Action::Relay(event) => Some(event),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(relayed, frame, "relayed as the kernel ordered it, scancodes by their keys");
+        assert_eq!(popup_accents(&mut sm)[0], "è", "Hello, not HEllo");
+    }
+
+    fn popup_accents(sm: &mut StateMachine<MockClock>) -> Vec<String> {
+        sm.clock().advance(Duration::from_millis(350));
+        sm.check_timer().into_iter().find_map(|a| match a {
+            Action::SendPopup(DaemonMsg::ShowPopup { accents, .. }) => Some(accents),
+            _ => None,
+        }).expect("should have ShowPopup")
+    }
+
+    // === SPEC: Caps Lock → uppercase accents, Shift inverts it ===
+
+    #[test]
+    fn caps_lock_produces_uppercase_accents() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_CAPSLOCK));
+        sm.process_event(key_release(KEY_CAPSLOCK));
+        sm.process_event(key_press(KEY_E));
+        assert_eq!(popup_accents(&mut sm)[0], "È");
+    }
+
+    #[test]
+    fn shift_with_caps_lock_gives_lowercase() {
+        let mut sm = make_sm();
+        sm.seed_key_state(&[KEY_LEFTSHIFT], true);
+        sm.process_event(key_press(KEY_E));
+        assert_eq!(popup_accents(&mut sm)[0], "è");
+    }
+
+    // === SPEC: Modifiers held at grab time are known ===
+
+    #[test]
+    fn seeded_ctrl_blocks_hold() {
+        let mut sm = make_sm();
+        sm.seed_key_state(&[KEY_LEFTCTRL], false);
+        sm.process_event(key_press(KEY_E));
+        assert!(sm.is_idle());
+        // Releasing it is seen like any other release
+        sm.process_event(key_release(KEY_LEFTCTRL));
+        sm.process_event(key_press(KEY_E));
+        assert!(!sm.is_idle());
+    }
+
+    #[test]
+    fn seeded_shift_gives_uppercase() {
+        let mut sm = make_sm();
+        sm.seed_key_state(&[KEY_RIGHTSHIFT, KEY_A], false);
+        sm.process_event(key_press(KEY_E));
+        assert_eq!(popup_accents(&mut sm)[0], "È");
+    }
+
+    // === SPEC: Disabled → all keys relayed, no hold ===
+
+    #[test]
+    fn disabled_relays_everything() {
+        let mut sm = make_sm();
+        sm.set_enabled(false);
+        let actions = sm.process_event(key_press(KEY_E));
+        assert!(has_relay(&actions));
+        assert!(sm.is_idle());
+    }
+
+    // === SPEC: Suspended (e.g. fullscreen game) → pure relay, popup hidden ===
+
+    #[test]
+    fn suspended_relays_everything() {
+        let mut sm = make_sm();
+        sm.set_suspended(true);
+        let actions = sm.process_event(key_press(KEY_E));
+        assert!(has_relay(&actions));
+        assert!(sm.is_idle());
+        assert!(sm.is_enabled(), "suspending must not flip the user's toggle");
+    }
+
+    #[test]
+    fn suspending_during_popup_hides_it() {
+        let mut sm = make_sm();
+        enter_popup(&mut sm);
+        let actions = sm.set_suspended(true);
+        assert!(has_hide_popup(&actions));
+        assert!(sm.is_idle());
+    }
+
+    #[test]
+    fn resuming_allows_hold_again() {
+        let mut sm = make_sm();
+        sm.set_suspended(true);
+        sm.set_suspended(false);
+        sm.process_event(key_press(KEY_E));
+        assert!(!sm.is_idle());
+    }
+
+    // === SPEC: Non-key events always relayed ===
+
+    #[test]
+    fn non_key_events_always_relayed() {
+        let mut sm = make_sm();
+        // EV_SYN event
+        let syn = RawEvent::new(EV_SYN, 0, 0);
+        let actions = sm.process_event(syn);
+        assert!(has_relay(&actions));
+    }
+
+    // === SPEC: ShowPopup labels are 1-indexed ===
+
+    #[test]
+    fn show_popup_labels_are_1_indexed() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        let actions = sm.check_timer();
+        let labels = actions.iter().find_map(|a| match a {
+            Action::SendPopup(DaemonMsg::ShowPopup { labels, .. }) => Some(labels),
+            _ => None,
+        }).expect("should have ShowPopup");
+        assert_eq!(labels[0], 1);
+        assert_eq!(*labels.last().unwrap(), labels.len() as u8);
+    }
+
+    // === SPEC: ShowPopup carries candidate descriptions ===
+
+    #[test]
+    fn show_popup_describes_candidates_in_either_case() {
+        let map = crate::charmap::LocaleMap::from([("e".to_string(), vec!["è".to_string(), "é".to_string()])]);
+        let descriptions = crate::charmap::DescriptionMap::from([("è".to_string(), "e grave".to_string())]);
+        let table = AccentTable::new(&map).with_descriptions(&descriptions);
+        for shift in [false, true] {
+            let mut sm = StateMachine::with_clock(&Config::default(), table.clone(), MockClock::new());
+            if shift {
+                sm.process_event(key_press(KEY_LEFTSHIFT));
+            }
+            sm.process_event(key_press(KEY_E));
+            sm.clock().advance(Duration::from_millis(350));
+            let actions = sm.check_timer();
+            let descriptions = actions.iter().find_map(|a| match a {
+                Action::SendPopup(DaemonMsg::ShowPopup { descriptions, .. }) => Some(descriptions.clone()),
+                _ => None,
+            }).expect("should have ShowPopup");
+            assert_eq!(descriptions, ["e grave", ""], "shift {}", shift);
+        }
+    }
+
+    // === SPEC: More than nine candidates page ===
+
+    fn make_sm_with_many() -> StateMachine<MockClock> {
+        let letters: Vec<String> = "ēéěèêëẽẹẻềếểễệ".chars().map(String::from).collect();
+        let map = crate::charmap::LocaleMap::from([("e".to_string(), letters)]);
+        StateMachine::with_clock(&Config::default(), map, MockClock::new())
+    }
+
+    fn shown(actions: &[Action]) -> Option<Vec<String>> {
+        actions.iter().find_map(|a| match a {
+            Action::SendPopup(DaemonMsg::ShowPopup { accents, .. }) => Some(accents.clone()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn long_lists_show_nine_at_a_time() {
+        let mut sm = make_sm_with_many();
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        assert_eq!(shown(&sm.check_timer()).unwrap().len(), 9);
+    }
+
+    #[test]
+    fn down_turns_the_page_and_digits_pick_from_it() {
+        let mut sm = make_sm_with_many();
+        enter_popup(&mut sm);
+        let actions = sm.process_event(key_press(KEY_DOWN));
+        assert_eq!(shown(&actions).unwrap(), ["ề", "ế", "ể", "ễ", "ệ"]);
+        let actions = sm.process_event(key_press(KEY_1 + 1));
+        assert_eq!(has_emit_accent(&actions), Some("ế"));
+    }
+
+    #[test]
+    fn right_past_the_ninth_turns_the_page() {
+        let mut sm = make_sm_with_many();
+        enter_popup(&mut sm);
+        for _ in 0..9 {
+            assert!(shown(&sm.process_event(key_press(KEY_RIGHT))).is_none());
+        }
+        let actions = sm.process_event(key_press(KEY_RIGHT));
+        assert_eq!(shown(&actions).unwrap()[0], "ề");
+        assert!(actions.iter().any(|a| matches!(a, Action::SendPopup(DaemonMsg::Highlight { index: 1 }))));
+        assert_eq!(has_emit_accent(&sm.ipc_select(2)), Some("ế"));
+    }
+
+    // === SPEC: 'a' key with Italian locale has 5 accents ===
+
+    #[test]
+    fn italian_a_popup_has_5_accents() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_A));
+        sm.clock().advance(Duration::from_millis(350));
+        let actions = sm.check_timer();
+        let accents = actions.iter().find_map(|a| match a {
+            Action::SendPopup(DaemonMsg::ShowPopup { accents, .. }) => Some(accents),
+            _ => None,
+        }).expect("should have ShowPopup");
+        assert_eq!(accents.len(), 5);
+        assert_eq!(accents[0], "à");
+    }
+
+    // === SPEC: Modifier released → subsequent letters should hold ===
+
+    #[test]
+    fn modifier_release_allows_hold_again() {
+        let mut sm = make_sm();
+        // Press and release Ctrl
+        sm.process_event(key_press(KEY_LEFTCTRL));
+        sm.process_event(key_release(KEY_LEFTCTRL));
+        // Now press 'e' — should start hold
+        sm.process_event(key_press(KEY_E));
+        assert!(!sm.is_idle(), "should be in holding state after modifier released");
+    }
+
+    // === SPEC: IPC select → emit accent if in popup state ===
+
+    fn enter_popup(sm: &mut StateMachine<MockClock>) {
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        sm.check_timer();
+    }
+
+    #[test]
+    fn ipc_select_emits_accent_in_popup_state() {
+        let mut sm = make_sm();
+        enter_popup(&mut sm);
+
+        let actions = sm.ipc_select(1);
+        assert!(has_hide_popup(&actions));
+        assert_eq!(has_emit_accent(&actions), Some("è"));
+        assert!(sm.is_idle());
+    }
+
+    #[test]
+    fn emitted_accent_names_its_base_key() {
+        let mut sm = make_sm();
+        enter_popup(&mut sm);
+        let actions = sm.process_event(key_press(KEY_2));
+        let base = actions.iter().find_map(|a| match a {
+            Action::EmitAccent { base, .. } => Some(*base),
+            _ => None,
+        });
+        assert_eq!(base, Some("e"));
+    }
+
+    #[test]
+    fn ipc_select_zero_is_ignored() {
+        let mut sm = make_sm();
+        enter_popup(&mut sm);
+        assert!(sm.ipc_select(0).is_empty());
+        assert!(!sm.is_idle());
+    }
+
+    #[test]
+    fn ipc_select_returns_empty_when_idle() {
+        let mut sm = make_sm();
+        let actions = sm.ipc_select(1);
+        assert!(actions.is_empty());
+    }
+
+    // === SPEC: The held key's release goes out exactly once ===
+
+    #[test]
+    fn release_after_popup_timeout_is_not_relayed_again() {
+        let mut sm = make_sm();
+        enter_popup(&mut sm); // synthetic release of 'e' relayed here
+        sm.clock().advance(Duration::from_millis(6000));
+        assert!(has_hide_popup(&sm.check_timer()));
+
+        let actions = sm.process_event(key_release(KEY_E));
+        assert!(has_suppress(&actions));
+        assert!(!has_relay(&actions));
+    }
+
+    #[test]
+    fn release_after_ipc_select_is_not_relayed_again() {
+        let mut sm = make_sm();
+        enter_popup(&mut sm);
+        sm.ipc_select(1);
+        assert!(has_suppress(&sm.process_event(key_repeat(KEY_E))));
+        assert!(has_suppress(&sm.process_event(key_release(KEY_E))));
+    }
+
+    #[test]
+    fn release_after_disable_is_not_relayed_again() {
+        let mut sm = make_sm();
+        enter_popup(&mut sm);
+        sm.set_suspended(true);
+        assert!(has_suppress(&sm.process_event(key_release(KEY_E))));
+        // Later releases are ordinary again
+        sm.process_event(key_press(KEY_E));
+        assert!(has_relay(&sm.process_event(key_release(KEY_E))));
+    }
+
+    #[test]
+    fn releases_of_keys_the_popup_consumed_are_swallowed() {
+        let mut sm = make_sm();
+        enter_popup(&mut sm);
+        sm.process_event(key_press(KEY_ESC));
+        assert!(has_suppress(&sm.process_event(key_release(KEY_ESC))));
+
+        enter_popup(&mut sm);
+        sm.process_event(key_press(KEY_2));
+        assert!(has_suppress(&sm.process_event(key_release(KEY_2))));
+    }
+
+    #[test]
+    fn key_pressed_again_after_lost_release_is_a_new_press() {
+        let mut sm = make_sm();
+        enter_popup(&mut sm);
+        sm.ipc_dismiss();
+        // The release never came; the next press starts over
+        assert!(has_relay(&sm.process_event(key_press(KEY_E))));
+        assert!(has_relay(&sm.process_event(key_release(KEY_E))));
+    }
+
+    // === SPEC: IPC dismiss → hide popup if in popup state ===
+
+    #[test]
+    fn ipc_dismiss_hides_popup() {
+        let mut sm = make_sm();
+        enter_popup(&mut sm);
+
+        let actions = sm.ipc_dismiss();
+        assert!(has_hide_popup(&actions));
+        assert!(has_emit_accent(&actions).is_none());
+        assert!(sm.is_idle());
+    }
+
+    #[test]
+    fn ipc_dismiss_returns_empty_when_idle() {
+        let mut sm = make_sm();
+        let actions = sm.ipc_dismiss();
+        assert!(actions.is_empty());
+    }
+
+    // === SPEC: Popup auto-timeout ===
+
+    #[test]
+    fn popup_times_out_after_timeout_ms() {
+        let mut config = Config::default();
+        config.popup.timeout_ms = 50; // short timeout for test
+        let locale_map = builtin_locale("it");
+        let mut sm = StateMachine::with_clock(&config, locale_map, MockClock::new());
+
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        sm.check_timer(); // enters popup
+
+        sm.clock().advance(Duration::from_millis(60));
+        let actions = sm.check_timer();
+        assert!(has_hide_popup(&actions), "popup should auto-dismiss after timeout");
+        assert!(sm.is_idle());
+    }
+
+    // === SPEC: Digit layer → hold a digit for superscripts and fractions ===
+
+    fn make_digit_sm() -> StateMachine<MockClock> {
+        let config = Config::default();
+        let layers = crate::config::LayersConfig { letters: true, digits: true, punctuation: false, symbols: false };
+        let map = layers.apply(builtin_locale("it"));
+        StateMachine::with_clock(&config, map, MockClock::new())
+    }
+
+    #[test]
+    fn holding_digit_offers_superscripts() {
+        let mut sm = make_digit_sm();
+        assert!(has_relay(&sm.process_event(key_press(KEY_2))));
+        sm.clock().advance(Duration::from_millis(350));
+        let actions = sm.check_timer();
+        assert!(actions.iter().any(|a| matches!(
+            a,
+            Action::SendPopup(DaemonMsg::ShowPopup { base, accents, .. }) if base == "2" && accents[0] == "²"
+        )));
+
+        // Digits pick from the open popup
+        let actions = sm.process_event(key_press(KEY_1));
+        assert_eq!(has_emit_accent(&actions), Some("²"));
+    }
+
+    #[test]
+    fn azerty_holds_digits_with_shift() {
+        let mut sm = make_digit_sm();
+        sm.set_layout(crate::charmap::Layout::Fr);
+        sm.process_event(key_press(KEY_2));
+        assert!(sm.next_deadline().is_none(), "unshifted, the key types é");
+        sm.process_event(key_release(KEY_2));
+        sm.process_event(key_press(KEY_LEFTSHIFT));
+        sm.process_event(key_press(KEY_2));
+        sm.clock().advance(Duration::from_millis(350));
+        assert!(has_show_popup(&sm.check_timer()));
+    }
+
+    #[test]
+    fn shifted_digit_does_not_hold() {
+        let mut sm = make_digit_sm();
+        sm.process_event(key_press(KEY_RIGHTSHIFT));
+        sm.process_event(key_press(KEY_2));
+        sm.clock().advance(Duration::from_millis(350));
+        assert!(!has_show_popup(&sm.check_timer()));
+    }
+
+    #[test]
+    fn digits_do_not_hold_without_the_layer() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_2));
+        assert!(sm.is_idle());
+    }
+
+    // === SPEC: Tone selection (pinyin) → 1-4 are tones, 0 is neutral ===
+
+    fn make_pinyin_sm() -> StateMachine<MockClock> {
+        let accents = AccentTable::new(&builtin_locale("pinyin"))
+            .with_selection(charmap::builtin_selection("pinyin"));
+        StateMachine::with_clock(&Config::default(), accents, MockClock::new())
+    }
+
+    fn hold(sm: &mut StateMachine<MockClock>, code: u16) {
+        sm.process_event(key_press(code));
+        sm.clock().advance(Duration::from_millis(350));
+        assert!(has_show_popup(&sm.check_timer()));
+    }
+
+    #[test]
+    fn tone_digits_pick_tones() {
+        let mut sm = make_pinyin_sm();
+        hold(&mut sm, KEY_A);
+        assert_eq!(has_emit_accent(&sm.process_event(key_press(KEY_3))), Some("ǎ"));
+    }
+
+    #[test]
+    fn zero_is_neutral_tone_keeping_the_letter() {
+        let mut sm = make_pinyin_sm();
+        hold(&mut sm, KEY_A);
+        let actions = sm.process_event(key_press(KEY_0));
+        assert!(has_emit_accent(&actions).is_none());
+        assert!(has_suppress(&actions));
+        assert_eq!(hide_reason(&actions), Some(HideReason::Selected));
+        assert!(sm.is_idle());
+        // The 0's release doesn't leak either
+        assert!(has_suppress(&sm.process_event(key_release(KEY_0))));
+    }
+
+    #[test]
+    fn zero_cancels_in_positional_locales() {
+        let mut sm = make_sm();
+        hold(&mut sm, KEY_E);
+        let actions = sm.process_event(key_press(KEY_0));
+        assert_eq!(hide_reason(&actions), Some(HideReason::Cancelled));
+        assert!(has_relay(&actions));
+    }
+
+    #[test]
+    fn v_offers_u_umlaut_tones() {
+        let mut sm = make_pinyin_sm();
+        hold(&mut sm, KEY_V);
+        assert_eq!(has_emit_accent(&sm.process_event(key_press(KEY_2))), Some("ǘ"));
+    }
+
+    // === SPEC: Snippets → whole strings as candidates, on symbols too ===
+
+    #[test]
+    fn holding_shifted_symbol_offers_snippets() {
+        let map = crate::charmap::LocaleMap::from([("@".to_string(), vec!["me@example.com".to_string(), "work@example.com".to_string()])]);
+        let mut sm = StateMachine::with_clock(&Config::default(), map, MockClock::new());
+        sm.process_event(key_press(KEY_LEFTSHIFT));
+        sm.process_event(key_press(KEY_2));
+        sm.clock().advance(Duration::from_millis(350));
+        assert!(has_show_popup(&sm.check_timer()));
+        // Not uppercased although Shift is down
+        assert_eq!(has_emit_accent(&sm.process_event(key_press(KEY_2))), Some("work@example.com"));
+    }
+
+    #[test]
+    fn snippets_mix_with_single_characters() {
+        let map = crate::charmap::LocaleMap::from([("e".to_string(), vec!["é".to_string(), "etc.".to_string()])]);
+        let mut sm = StateMachine::with_clock(&Config::default(), map, MockClock::new());
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        sm.check_timer();
+        assert_eq!(has_emit_accent(&sm.process_event(key_press(KEY_2))), Some("etc."));
+    }
+
+    #[test]
+    fn apostrophe_key_offers_single_quotes_and_double_with_shift() {
+        const KEY_APOSTROPHE: u16 = 40;
+        let layers = crate::config::LayersConfig { letters: false, digits: false, punctuation: true, symbols: false };
+        let mut sm = StateMachine::with_clock(&Config::default(), layers.apply(crate::charmap::LocaleMap::new()), MockClock::new());
+        sm.process_event(key_press(KEY_APOSTROPHE));
+        sm.clock().advance(Duration::from_millis(350));
+        sm.check_timer();
+        assert_eq!(has_emit_accent(&sm.process_event(key_press(KEY_1 + 1))), Some("’"));
+        sm.process_event(key_release(KEY_APOSTROPHE));
+        sm.process_event(key_press(KEY_LEFTSHIFT));
+        sm.process_event(key_press(KEY_APOSTROPHE));
+        sm.clock().advance(Duration::from_millis(350));
+        sm.check_timer();
+        assert_eq!(has_emit_accent(&sm.process_event(key_press(KEY_1 + 2))), Some("„"));
+    }
+
+    #[test]
+    fn shifted_digit_falls_back_to_its_position_name() {
+        const KEY_4: u16 = 5;
+        let map = crate::charmap::LocaleMap::from([("shift+4".to_string(), vec!["€".to_string(), "£".to_string()])]);
+        let mut sm = StateMachine::with_clock(&Config::default(), map, MockClock::new());
+        sm.set_layout(crate::charmap::Layout::De);
+        sm.process_event(key_press(KEY_LEFTSHIFT));
+        sm.process_event(key_press(KEY_4));
+        sm.clock().advance(Duration::from_millis(350));
+        assert!(has_show_popup(&sm.check_timer()));
+        assert_eq!(has_emit_accent(&sm.process_event(key_press(KEY_1 + 1))), Some("£"));
+    }
+
+    // === SPEC: Highlight with arrows/Tab, pick with Enter or release ===
+
+    fn has_highlight(actions: &[Action]) -> Option<u8> {
+        actions.iter().find_map(|a| match a {
+            Action::SendPopup(DaemonMsg::Highlight { index }) => Some(*index),
+            _ => None,
+        })
+    }
+
+    fn make_sm_release_to_select() -> StateMachine<MockClock> {
+        let mut config = Config::default();
+        config.popup.release_to_select = true;
+        StateMachine::with_clock(&config, builtin_locale("it"), MockClock::new())
+    }
+
+    #[test]
+    fn arrows_move_highlight_and_wrap() {
+        let mut sm = make_sm();
+        enter_popup(&mut sm);
+        assert_eq!(has_highlight(&sm.process_event(key_press(KEY_RIGHT))), Some(1));
+        assert_eq!(has_highlight(&sm.process_event(key_press(KEY_LEFT))), Some(4));
+        assert!(has_suppress(&sm.process_event(key_release(KEY_LEFT))));
+        assert!(!sm.is_idle(), "moving the highlight keeps the popup open");
+    }
+
+    #[test]
+    fn tab_cycles_and_enter_picks() {
+        let mut sm = make_sm();
+        enter_popup(&mut sm);
+        sm.process_event(key_press(KEY_TAB));
+        sm.process_event(key_press(KEY_TAB));
+        let actions = sm.process_event(key_press(KEY_ENTER));
+        assert_eq!(has_emit_accent(&actions), Some("é"));
+        assert!(has_suppress(&sm.process_event(key_release(KEY_ENTER))));
+    }
+
+    #[test]
+    fn enter_without_highlight_dismisses_and_relays() {
+        let mut sm = make_sm();
+        enter_popup(&mut sm);
+        let actions = sm.process_event(key_press(KEY_ENTER));
+        assert!(has_hide_popup(&actions));
+        assert!(has_emit_accent(&actions).is_none());
+    }
+
+    #[test]
+    fn releasing_held_key_picks_highlight_in_release_to_select() {
+        let mut sm = make_sm_release_to_select();
+        enter_popup(&mut sm);
+        sm.process_event(key_press(KEY_RIGHT));
+        let actions = sm.process_event(key_release(KEY_E));
+        assert_eq!(has_emit_accent(&actions), Some("è"));
+        assert!(sm.is_idle());
+    }
+
+    #[test]
+    fn releasing_held_key_without_highlight_keeps_popup() {
+        let mut sm = make_sm_release_to_select();
+        enter_popup(&mut sm);
+        let actions = sm.process_event(key_release(KEY_E));
+        assert!(has_emit_accent(&actions).is_none());
+        assert!(!sm.is_idle());
+    }
+
+    // === SPEC: Per-keyboard layout picks the letter ===
+
+    #[test]
+    fn qwertz_keyboard_holds_y_on_its_own_key() {
+        const KEY_Y: u16 = 21;
+        const KEY_Z: u16 = 44;
+        let map = crate::charmap::LocaleMap::from([("y".to_string(), vec!["ý".to_string()])]);
+        let mut sm = StateMachine::with_clock(&Config::default(), map, MockClock::new());
+        sm.set_layout(Layout::De);
+        sm.process_event(key_press(KEY_Y));
+        assert!(sm.next_deadline().is_none(), "KEY_Y types z on QWERTZ");
+        sm.process_event(key_release(KEY_Y));
+        sm.process_event(key_press(KEY_Z));
+        sm.clock().advance(Duration::from_millis(350));
+        assert!(has_show_popup(&sm.check_timer()));
+    }
+
+    #[test]
+    fn keymap_moves_a_letter_to_another_key() {
+        // Workman-ish: the key in QWERTY's D position types e
+        const KEY_D: u16 = 32;
+        let config: Config = toml::from_str("[keymap]\n32 = \"e\"\n18 = \"\"\n").unwrap();
+        let map = crate::charmap::LocaleMap::from([("e".to_string(), vec!["é".to_string()])]);
+        let mut sm = StateMachine::with_clock(&config, map, MockClock::new());
+        sm.process_event(key_press(KEY_E));
+        assert!(sm.next_deadline().is_none(), "KEY_E types no letter here");
+        sm.process_event(key_release(KEY_E));
+        sm.process_event(key_press(KEY_D));
+        sm.clock().advance(Duration::from_millis(350));
+        assert!(has_show_popup(&sm.check_timer()));
+    }
+
+    // === SPEC: Monitor output names state and decisions ===
+
+    #[test]
+    fn state_name_follows_the_hold() {
+        let mut sm = make_sm();
+        assert_eq!(sm.state_name(), "idle");
+        let actions = sm.process_event(key_press(KEY_E));
+        assert_eq!(describe_actions(&actions), "relay");
+        assert_eq!(sm.state_name(), "holding");
+        sm.clock().advance(Duration::from_millis(350));
+        assert!(describe_actions(&sm.check_timer()).contains("show popup e: "));
+        assert_eq!(sm.state_name(), "popup");
+        sm.set_enabled(false);
+        assert_eq!(sm.state_name(), "disabled");
+    }
+
+    #[test]
+    fn describe_lists_every_action() {
+        let actions = [
+            Action::Suppress,
+            Action::EmitAccent { base: "e", accent: "è".into(), base_typed: true },
+            Action::SendPopup(DaemonMsg::HidePopup { reason: HideReason::Selected }),
+        ];
+        assert_eq!(describe_actions(&actions), "suppress, emit è, hide popup (selected)");
+        assert_eq!(describe_actions(&[]), "nothing");
+    }
+
+    // === SPEC: HidePopup says why the popup closed ===
+
+    fn hide_reason(actions: &[Action]) -> Option<HideReason> {
+        actions.iter().find_map(|a| match a {
+            Action::SendPopup(DaemonMsg::HidePopup { reason }) => Some(*reason),
+            _ => None,
+        })
+    }
+
+    fn open_popup() -> StateMachine<MockClock> {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        assert!(has_show_popup(&sm.check_timer()));
+        sm
+    }
+
+    #[test]
+    fn hide_reason_selected() {
+        assert_eq!(hide_reason(&open_popup().process_event(key_press(KEY_1))), Some(HideReason::Selected));
+        assert_eq!(hide_reason(&open_popup().ipc_select(2)), Some(HideReason::Selected));
+    }
+
+    #[test]
+    fn hide_reason_timeout() {
+        let mut sm = open_popup();
+        sm.clock().advance(Duration::from_millis(6000));
+        assert_eq!(hide_reason(&sm.check_timer()), Some(HideReason::Timeout));
+    }
+
+    #[test]
+    fn hide_reason_cancelled() {
+        assert_eq!(hide_reason(&open_popup().process_event(key_press(KEY_ESC))), Some(HideReason::Cancelled));
+        assert_eq!(hide_reason(&open_popup().process_event(key_press(KEY_F))), Some(HideReason::Cancelled));
+    }
+
+    #[test]
+    fn hide_reason_dismissed() {
+        assert_eq!(hide_reason(&open_popup().ipc_dismiss()), Some(HideReason::Dismissed));
+    }
+
+    #[test]
+    fn hide_reason_disabled() {
+        assert_eq!(hide_reason(&open_popup().set_enabled(false)), Some(HideReason::Disabled));
+        assert_eq!(hide_reason(&open_popup().set_suspended(true)), Some(HideReason::Disabled));
+    }
+
+    // === SPEC: defer_base holds the letter back until the hold is decided ===
+
+    fn make_sm_deferred() -> StateMachine<MockClock> {
+        let mut config = Config::default();
+        config.general.defer_base = true;
+        StateMachine::with_clock(&config, builtin_locale("it"), MockClock::new())
+    }
+
+    fn relayed(actions: &[Action]) -> Vec<(u16, i32)> {
+        actions
+            .iter()
+            .filter_map(|a| match a {
+                Action::Relay(event) if event.is_key() => Some((event.code, event.value)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn deferred_press_goes_out_with_a_quick_release() {
+        let mut sm = make_sm_deferred();
+        let actions = sm.process_event(key_press(KEY_E));
+        assert!(has_suppress(&actions) && !has_relay(&actions));
+        let actions = sm.process_event(key_release(KEY_E));
+        assert_eq!(relayed(&actions), [(KEY_E, 1), (KEY_E, 0)]);
+        assert!(sm.is_idle());
+    }
+
+    #[test]
+    fn deferred_press_goes_out_before_the_next_key() {
+        let mut sm = make_sm_deferred();
+        sm.process_event(key_press(KEY_E));
+        let actions = sm.process_event(key_press(KEY_F));
+        assert_eq!(relayed(&actions), [(KEY_E, 1), (KEY_F, 1)]);
+        assert!(matches!(actions[1], Action::Relay(RawEvent { event_type: EV_SYN, .. })), "in a frame of its own");
+        assert_eq!(relayed(&sm.process_event(key_release(KEY_E))), [(KEY_E, 0)]);
+    }
+
+    #[test]
+    fn deferred_press_goes_out_before_shift_is_let_go() {
+        let mut sm = make_sm_deferred();
+        sm.process_event(key_press(KEY_LEFTSHIFT));
+        sm.process_event(key_press(KEY_E));
+        let actions = sm.process_event(key_release(KEY_LEFTSHIFT));
+        assert_eq!(relayed(&actions), [(KEY_E, 1), (KEY_LEFTSHIFT, 0)], "E, not e");
+        assert_eq!(relayed(&sm.process_event(key_release(KEY_E))), [(KEY_E, 0)]);
+    }
+
+    #[test]
+    fn deferred_pick_types_only_the_accent() {
+        let mut sm = make_sm_deferred();
+        enter_popup(&mut sm);
+        let actions = sm.ipc_select(1);
+        assert!(actions.iter().any(|a| matches!(a, Action::EmitAccent { base_typed: false, .. })));
+        assert!(!has_relay(&actions));
+    }
+
+    #[test]
+    fn threshold_relays_no_release_for_a_deferred_press() {
+        let mut sm = make_sm_deferred();
+        sm.process_event(key_press(KEY_E));
+        sm.clock().advance(Duration::from_millis(350));
+        let actions = sm.check_timer();
+        assert!(has_show_popup(&actions));
+        assert!(!has_relay(&actions));
+    }
+
+    #[test]
+    fn dismissing_a_deferred_popup_types_the_letter() {
+        let mut sm = make_sm_deferred();
+        enter_popup(&mut sm);
+        let actions = sm.process_event(key_press(KEY_ESC));
+        assert!(has_hide_popup(&actions));
+        assert_eq!(relayed(&actions), [(KEY_E, 1), (KEY_E, 0)]);
+
+        enter_popup(&mut sm);
+        sm.process_event(key_release(KEY_E));
+        sm.clock().advance(Duration::from_secs(60));
+        assert_eq!(relayed(&sm.check_timer()), [(KEY_E, 1), (KEY_E, 0)], "timed out");
+    }
+
+    #[test]
+    fn reloading_the_locale_mid_hold_keeps_the_letter() {
+        let mut sm = make_sm_deferred();
+        sm.process_event(key_press(KEY_E));
+        let actions = sm.set_locale_map(builtin_locale("fr"));
+        assert_eq!(relayed(&actions), [(KEY_E, 1)], "the press goes out, its release follows");
+        assert_eq!(relayed(&sm.process_event(key_release(KEY_E))), [(KEY_E, 0)]);
+
+        enter_popup(&mut sm);
+        let actions = sm.set_locale_map(builtin_locale("it"));
+        assert!(has_hide_popup(&actions));
+        assert_eq!(relayed(&actions), [(KEY_E, 1), (KEY_E, 0)]);
+        assert!(sm.is_idle());
+    }
+
+    #[test]
+    fn defer_base_needs_the_grab() {
+        let mut config = Config::default();
+        config.general.defer_base = true;
+        config.general.grab = false;
+        let mut sm = StateMachine::with_clock(&config, builtin_locale("it"), MockClock::new());
+        assert!(has_relay(&sm.process_event(key_press(KEY_E))));
+    }
+
+    // === SPEC: next_deadline ===
+
+    #[test]
+    fn next_deadline_is_none_when_idle() {
+        let sm = make_sm();
+        assert!(sm.next_deadline().is_none());
+    }
+
+    #[test]
+    fn next_deadline_is_some_when_holding() {
+        let mut sm = make_sm();
+        sm.process_event(key_press(KEY_E));
+        assert!(sm.next_deadline().is_some());
+    }
+
+    #[test]
+    fn next_deadline_is_some_when_popup() {
+        let mut sm = make_sm();
+        enter_popup(&mut sm);
+        assert!(sm.next_deadline().is_some());
+    }
+}
+
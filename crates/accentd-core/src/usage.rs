@@ -0,0 +1,246 @@
+//! How often each candidate was picked, for frequency-ranked popups.
+
+use crate::charmap::LocaleMap;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Current version of the stats file format.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Pick counts per base key, persisted as `usage.json` in the state directory
+/// and used as-is by `accentctl stats export/import`:
+///
+/// ```json
+/// { "version": 1, "counts": { "e": { "é": 12, "è": 3 } }, "last": { "e": "é" } }
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UsageStats {
+    /// Format version; files from a newer accentd are rejected.
+    #[serde(default = "UsageStats::default_version")]
+    pub version: u32,
+    /// base key → accent → times picked. Both lowercase.
+    #[serde(default)]
+    pub counts: BTreeMap<String, BTreeMap<String, u64>>,
+    /// base key → the accent picked most recently. Both lowercase.
+    #[serde(default)]
+    pub last: BTreeMap<String, String>,
+}
+
+impl Default for UsageStats {
+    fn default() -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            counts: BTreeMap::new(),
+            last: BTreeMap::new(),
+        }
+    }
+}
+
+impl UsageStats {
+    fn default_version() -> u32 { FORMAT_VERSION }
+
+    /// Where the daemon keeps its stats: `$STATE_DIRECTORY` (set by systemd's
+    /// `StateDirectory=`), else the user state dir, else `/var/lib/accentd`.
+    pub fn path() -> PathBuf {
+        let dir = std::env::var_os("STATE_DIRECTORY")
+            .map(PathBuf::from)
+            .or_else(|| dirs::state_dir().map(|d| d.join("accentd")))
+            .unwrap_or_else(|| PathBuf::from("/var/lib/accentd"));
+        dir.join("usage.json")
+    }
+
+    /// Load from `path`; a missing file is empty stats.
+    pub fn load_from(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => Self::from_json(&contents)
+                .with_context(|| format!("parsing usage stats {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("reading usage stats {}", path.display())),
+        }
+    }
+
+    /// Parse a stats file, checking its format version.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let stats: Self = serde_json::from_str(json)?;
+        if stats.version > FORMAT_VERSION {
+            anyhow::bail!(
+                "usage stats format v{} is newer than this accentd supports (v{})",
+                stats.version,
+                FORMAT_VERSION
+            );
+        }
+        Ok(stats)
+    }
+
+    /// Add `other`'s counts to ours; its last choices win.
+    pub fn merge(&mut self, other: UsageStats) {
+        for (base, accents) in other.counts {
+            let ours = self.counts.entry(base).or_default();
+            for (accent, n) in accents {
+                *ours.entry(accent).or_default() += n;
+            }
+        }
+        self.last.extend(other.last);
+    }
+
+    /// Write to `path` atomically (temp file + rename).
+    pub fn save_to(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        let json = serde_json::to_string_pretty(self).expect("serialize usage stats");
+        std::fs::write(&tmp, json).with_context(|| format!("writing {}", tmp.display()))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("writing {}", path.display()))
+    }
+
+    /// Count one pick of `accent` from the popup for `base`. Case is folded,
+    /// so `È` counts towards `è`.
+    pub fn record(&mut self, base: &str, accent: &str) {
+        let (base, accent) = (base.to_lowercase(), accent.to_lowercase());
+        *self
+            .counts
+            .entry(base.clone())
+            .or_default()
+            .entry(accent.clone())
+            .or_default() += 1;
+        self.last.insert(base, accent);
+    }
+
+    pub fn count(&self, base: &str, accent: &str) -> u64 {
+        self.counts
+            .get(base)
+            .and_then(|c| c.get(accent))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// `map` with each key's candidates reordered most-picked first. Ties
+    /// (including never-picked candidates) keep the locale's order.
+    pub fn rank(&self, map: &LocaleMap) -> LocaleMap {
+        map.iter()
+            .map(|(base, accents)| {
+                let mut ranked = accents.clone();
+                ranked.sort_by_key(|a| std::cmp::Reverse(self.count(base, &a.to_lowercase())));
+                (base.clone(), ranked)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn map() -> LocaleMap {
+        HashMap::from([("e".to_string(), vec!["è".into(), "é".into(), "ê".into()])])
+    }
+
+    // --- spec: counting ---
+
+    #[test]
+    fn record_counts_per_base_and_folds_case() {
+        let mut stats = UsageStats::default();
+        stats.record("e", "é");
+        stats.record("e", "É");
+        assert_eq!(stats.count("e", "é"), 2);
+        assert_eq!(stats.count("e", "è"), 0);
+        assert_eq!(stats.count("a", "é"), 0);
+        assert_eq!(stats.last["e"], "é");
+    }
+
+    // --- spec: frequency ranking ---
+
+    #[test]
+    fn rank_moves_most_picked_first() {
+        let mut stats = UsageStats::default();
+        stats.record("e", "ê");
+        stats.record("e", "ê");
+        stats.record("e", "é");
+        assert_eq!(stats.rank(&map())["e"], vec!["ê", "é", "è"]);
+    }
+
+    #[test]
+    fn rank_keeps_locale_order_for_ties() {
+        let stats = UsageStats::default();
+        assert_eq!(stats.rank(&map()), map());
+    }
+
+    #[test]
+    fn rank_orders_merged_locales_as_one_list() {
+        let config: crate::config::Config =
+            toml::from_str("[locale]\nactive = [\"de\", \"it\"]\nmerge = \"interleave\"\n").unwrap();
+        let merged = config.load_locale_map().unwrap();
+        let mut stats = UsageStats::default();
+        for _ in 0..19 {
+            stats.record("e", "è");
+        }
+        stats.record("e", "é");
+        assert_eq!(stats.rank(&merged)["e"][..2], ["è", "é"]);
+        assert_eq!(stats.rank(&merged)["a"], merged["a"], "unpicked keys keep the merged order");
+    }
+
+    // --- spec: export/import ---
+
+    #[test]
+    fn merge_sums_counts_and_takes_last_choices() {
+        let mut ours = UsageStats::default();
+        ours.record("e", "é");
+        ours.record("a", "à");
+        let mut theirs = UsageStats::default();
+        theirs.record("e", "é");
+        theirs.record("e", "è");
+        ours.merge(theirs);
+        assert_eq!(ours.count("e", "é"), 2);
+        assert_eq!(ours.count("e", "è"), 1);
+        assert_eq!(ours.last["e"], "è");
+        assert_eq!(ours.last["a"], "à");
+    }
+
+    #[test]
+    fn documented_file_format_parses() {
+        let json = r#"{ "version": 1, "counts": { "e": { "é": 12, "è": 3 } }, "last": { "e": "é" } }"#;
+        let stats = UsageStats::from_json(json).unwrap();
+        assert_eq!(stats.count("e", "é"), 12);
+        assert_eq!(stats.last["e"], "é");
+    }
+
+    #[test]
+    fn newer_format_is_rejected() {
+        assert!(UsageStats::from_json(r#"{ "version": 99 }"#).is_err());
+    }
+
+    #[test]
+    fn unversioned_file_is_current_format() {
+        let stats = UsageStats::from_json(r#"{ "counts": {} }"#).unwrap();
+        assert_eq!(stats.version, FORMAT_VERSION);
+    }
+
+    // --- spec: persistence ---
+
+    #[test]
+    fn save_then_load_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join("usage.json");
+        let mut stats = UsageStats::default();
+        stats.record("e", "é");
+        stats.save_to(&path).unwrap();
+        assert_eq!(UsageStats::load_from(&path).unwrap(), stats);
+    }
+
+    #[test]
+    fn missing_file_is_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let stats = UsageStats::load_from(&dir.path().join("usage.json")).unwrap();
+        assert!(stats.counts.is_empty());
+    }
+
+    #[test]
+    fn path_is_usage_json() {
+        assert_eq!(UsageStats::path().file_name().unwrap(), "usage.json");
+    }
+}
@@ -0,0 +1,111 @@
+//! The cheat sheet: every key of the active locale with its candidates, as a
+//! quick reference. Opened via IPC (`accentctl cheat-sheet`), closed with Esc.
+
+use accentd_core::charmap::LocaleMap;
+use gtk4::glib;
+use gtk4::prelude::*;
+use tracing::debug;
+
+use crate::layer_shell;
+
+/// Keys per row of the grid.
+const COLUMNS: usize = 3;
+
+/// Build and show the cheat sheet for `keys`. Esc closes it.
+pub fn show(app: &gtk4::Application, locale: &str, keys: &LocaleMap) -> gtk4::Window {
+    let window = gtk4::Window::builder()
+        .application(app)
+        .title(format!("accentd: {}", locale))
+        .decorated(false)
+        .resizable(false)
+        .build();
+    window.add_css_class("cheat-sheet");
+    if layer_shell::try_init_layer_shell(&window) {
+        // Esc has to reach us
+        layer_shell::grab_keyboard(&window);
+    }
+
+    let entries = entries(keys);
+    let grid = gtk4::Grid::builder().column_homogeneous(true).build();
+    let title = gtk4::Label::new(Some(&format!("hold a key · {} · Esc closes", locale)));
+    grid.attach(&title, 0, 0, COLUMNS as i32, 1);
+    for (i, (base, candidates)) in entries.iter().enumerate() {
+        let label = gtk4::Label::new(None);
+        label.set_markup(&format!(
+            "<span color='#88aaff'>{}</span>  {}",
+            glib::markup_escape_text(base),
+            glib::markup_escape_text(candidates),
+        ));
+        label.set_halign(gtk4::Align::Start);
+        grid.attach(&label, (i % COLUMNS) as i32, (i / COLUMNS) as i32 + 1, 1, 1);
+    }
+    window.set_child(Some(&grid));
+
+    let esc = gtk4::EventControllerKey::new();
+    esc.connect_key_pressed(glib::clone!(
+        #[weak]
+        window,
+        #[upgrade_or]
+        glib::Propagation::Proceed,
+        move |_, key, _, _| {
+            if key == gtk4::gdk::Key::Escape {
+                window.close();
+                glib::Propagation::Stop
+            } else {
+                glib::Propagation::Proceed
+            }
+        }
+    ));
+    window.add_controller(esc);
+
+    window.present();
+    debug!(locale, keys = entries.len(), "cheat sheet shown");
+    window
+}
+
+/// One `(key, candidates)` pair per grid cell: letters first, then digits,
+/// then symbols, each in order.
+fn entries(keys: &LocaleMap) -> Vec<(String, String)> {
+    let mut entries: Vec<(String, String)> = keys
+        .iter()
+        .filter(|(_, candidates)| !candidates.is_empty())
+        .map(|(base, candidates)| (base.clone(), candidates.join(" ")))
+        .collect();
+    entries.sort_by_key(|(base, _)| {
+        let group = match base.chars().next() {
+            Some(c) if c.is_alphabetic() => 0,
+            Some(c) if c.is_ascii_digit() => 1,
+            _ => 2,
+        };
+        (group, base.clone())
+    });
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // --- spec: cheat sheet lists the whole locale ---
+
+    #[test]
+    fn letters_then_digits_then_symbols() {
+        let keys = LocaleMap::from([
+            ("@".to_string(), vec!["me@example.com".to_string()]),
+            ("2".to_string(), vec!["²".to_string()]),
+            ("e".to_string(), vec!["è".to_string(), "é".to_string()]),
+            ("a".to_string(), vec!["à".to_string()]),
+        ]);
+        let bases: Vec<String> = entries(&keys).into_iter().map(|(base, _)| base).collect();
+        assert_eq!(bases, ["a", "e", "2", "@"]);
+    }
+
+    #[test]
+    fn candidates_are_space_separated_and_empty_keys_skipped() {
+        let keys = LocaleMap::from([
+            ("e".to_string(), vec!["è".to_string(), "é".to_string()]),
+            ("x".to_string(), vec![]),
+        ]);
+        assert_eq!(entries(&keys), [("e".to_string(), "è é".to_string())]);
+    }
+}
@@ -0,0 +1,107 @@
+//! Waiting for the graphical session before GTK starts. As a user service the
+//! popup can be started before the compositor is up (or while it restarts);
+//! GTK would then fail to open a display, or open one without layer-shell.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::info;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Block until a Wayland socket or X display is available. If no display is
+/// set in the environment (service started before the session imported it),
+/// the first Wayland socket in the runtime dir is used.
+pub fn wait_for_session() {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut logged = false;
+    loop {
+        if session_ready() {
+            if logged {
+                info!("graphical session is up");
+            }
+            return;
+        }
+        if !logged {
+            info!("waiting for the compositor");
+            logged = true;
+        }
+        std::thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+fn session_ready() -> bool {
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from);
+    if let Some(display) = std::env::var_os("WAYLAND_DISPLAY") {
+        return wayland_socket(Path::new(&display), runtime_dir.as_deref()).is_some_and(|p| p.exists());
+    }
+    if std::env::var_os("DISPLAY").is_some() {
+        return true;
+    }
+    match runtime_dir.as_deref().and_then(find_wayland_socket) {
+        Some(name) => {
+            info!(display = %name, "WAYLAND_DISPLAY not set, using the session's socket");
+            std::env::set_var("WAYLAND_DISPLAY", name);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Where the socket named by `WAYLAND_DISPLAY` lives: absolute, or relative
+/// to the runtime dir.
+fn wayland_socket(display: &Path, runtime_dir: Option<&Path>) -> Option<PathBuf> {
+    if display.is_absolute() {
+        Some(display.to_path_buf())
+    } else {
+        runtime_dir.map(|dir| dir.join(display))
+    }
+}
+
+/// The lowest-numbered `wayland-N` socket in `dir`.
+fn find_wayland_socket(dir: &Path) -> Option<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .filter_map(|e| e.file_name().into_string().ok())
+        .filter(|n| n.strip_prefix("wayland-").is_some_and(|rest| rest.parse::<u32>().is_ok()))
+        .collect();
+    names.sort_by_key(|n| n["wayland-".len()..].parse::<u32>().unwrap_or(u32::MAX));
+    names.into_iter().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // --- spec: startup waits for the compositor ---
+
+    #[test]
+    fn relative_display_is_in_runtime_dir() {
+        let socket = wayland_socket(Path::new("wayland-1"), Some(Path::new("/run/user/1000")));
+        assert_eq!(socket, Some(PathBuf::from("/run/user/1000/wayland-1")));
+    }
+
+    #[test]
+    fn absolute_display_is_used_as_is() {
+        let socket = wayland_socket(Path::new("/tmp/wl"), None);
+        assert_eq!(socket, Some(PathBuf::from("/tmp/wl")));
+    }
+
+    #[test]
+    fn finds_lowest_socket_ignoring_locks() {
+        let dir = std::env::temp_dir().join(format!("accentd-popup-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["wayland-10", "wayland-2", "wayland-2.lock", "wayland-x"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        assert_eq!(find_wayland_socket(&dir).as_deref(), Some("wayland-2"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn no_socket_in_empty_dir() {
+        assert_eq!(find_wayland_socket(Path::new("/nonexistent")), None);
+    }
+}
@@ -1,64 +1,53 @@
-use accentd_core::config;
-use accentd_core::ipc::{self, ClientMsg, DaemonMsg};
+use accentd_client::{Event, Feed, Subscription};
+use accentd_core::ipc::{ClientMsg, DaemonMsg, HideReason};
 use anyhow::{Context, Result};
-use std::io::{BufRead, BufReader, Write};
-use std::os::unix::net::UnixStream;
 use std::sync::mpsc as std_mpsc;
-use tracing::{info, warn};
-
-fn setup_connection(stream: UnixStream) -> Result<(std_mpsc::Receiver<DaemonMsg>, UnixStream)> {
-    let mut write_stream = stream.try_clone().context("cloning stream")?;
-
-    let register = ipc::encode(&ClientMsg::RegisterPopup);
-    write_stream
-        .write_all(register.as_bytes())
-        .context("sending register")?;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
 
+/// Follow the daemon's popup feed on a background thread, reconnecting on
+/// its own. The GTK main loop polls the returned receiver, and sends the
+/// daemon messages (e.g. Dismiss) through the returned sender.
+pub fn spawn() -> Result<(std_mpsc::Receiver<DaemonMsg>, mpsc::UnboundedSender<ClientMsg>)> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("creating IPC runtime")?;
     let (tx, rx) = std_mpsc::channel();
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel();
 
-    let read_stream = stream;
     std::thread::spawn(move || {
-        let reader = BufReader::new(read_stream);
-        for line in reader.lines() {
-            match line {
-                Ok(line) => {
-                    if let Some(msg) = ipc::decode_daemon(&line) {
-                        if tx.send(msg).is_err() {
-                            break;
+        runtime.block_on(async move {
+            let mut sub = Subscription::new(Feed::Popup);
+            loop {
+                let event = tokio::select! {
+                    event = sub.next() => event,
+                    Some(msg) = out_rx.recv() => {
+                        if let Err(e) = sub.send(&msg).await {
+                            debug!(error = %e, "dropping message for daemon");
                         }
+                        continue;
                     }
-                }
-                Err(e) => {
-                    warn!(error = %e, "IPC read error");
+                };
+                let msg = match event {
+                    Event::Connected => {
+                        info!("connected to accentd daemon");
+                        continue;
+                    }
+                    Event::Message(msg) => msg,
+                    Event::Disconnected => {
+                        warn!("daemon not reachable, will keep retrying");
+                        // Don't leave a popup up for a daemon that's gone
+                        DaemonMsg::HidePopup { reason: HideReason::Dismissed }
+                    }
+                };
+                if tx.send(msg).is_err() {
                     break;
                 }
             }
-        }
-        info!("IPC reader thread exiting");
+        });
+        info!("IPC thread exiting");
     });
 
-    Ok((rx, write_stream))
-}
-
-/// Connect to the daemon with retries (for initial startup).
-pub fn connect() -> Result<(std_mpsc::Receiver<DaemonMsg>, UnixStream)> {
-    let socket_path = config::socket_path();
-    let stream = {
-        let mut result = UnixStream::connect(&socket_path);
-        for _ in 0..9 {
-            if result.is_ok() { break; }
-            std::thread::sleep(std::time::Duration::from_millis(500));
-            result = UnixStream::connect(&socket_path);
-        }
-        result.with_context(|| format!("connecting to {}", socket_path.display()))?
-    };
-    setup_connection(stream)
-}
-
-/// Try to connect once without retries (for reconnection).
-pub fn try_connect() -> Result<(std_mpsc::Receiver<DaemonMsg>, UnixStream)> {
-    let socket_path = config::socket_path();
-    let stream = UnixStream::connect(&socket_path)
-        .with_context(|| format!("connecting to {}", socket_path.display()))?;
-    setup_connection(stream)
+    Ok((rx, out_tx))
 }
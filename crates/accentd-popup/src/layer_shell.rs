@@ -27,3 +27,15 @@ pub fn try_init_layer_shell(window: &gtk4::Window) -> bool {
     info!("layer-shell initialized");
     true
 }
+
+/// Let a layer-shell window take keyboard input while shown, for windows the
+/// user closes with a key (the cheat sheet).
+pub fn grab_keyboard(window: &gtk4::Window) {
+    window.set_keyboard_mode(KeyboardMode::Exclusive);
+}
+
+/// Whether layer-shell is available now but `window` was built without it,
+/// e.g. the popup started while the compositor was still coming up.
+pub fn missing_layer_shell(window: &gtk4::Window) -> bool {
+    gtk4_layer_shell::is_supported() && !window.is_layer_window()
+}
@@ -1,23 +1,17 @@
+mod cheat_sheet;
+mod display;
 mod ipc_client;
 mod layer_shell;
 mod window;
 
 use accentd_core::config::Config;
-use accentd_core::ipc::DaemonMsg;
+use accentd_core::ipc::{ClientMsg, DaemonMsg};
 use gtk4::glib;
 use gtk4::prelude::*;
-use std::cell::RefCell;
-use std::os::unix::net::UnixStream;
-use std::rc::Rc;
 use std::sync::mpsc as std_mpsc;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 use tracing::{info, warn};
 
-struct IpcState {
-    rx: std_mpsc::Receiver<DaemonMsg>,
-    _stream: UnixStream,
-}
-
 fn main() {
     tracing_subscriber::fmt()
         .with_env_filter(
@@ -28,6 +22,8 @@ fn main() {
 
     info!("accentd-popup starting");
 
+    display::wait_for_session();
+
     let config = Config::load().unwrap_or_default();
     let font_size = config.popup.font_size;
 
@@ -36,66 +32,67 @@ fn main() {
         .build();
 
     app.connect_activate(move |app| {
-        let (popup_window, popup_label) = window::build_popup(app, font_size);
+        window::install_css(font_size);
+        let app = app.clone();
 
-        let initial = match ipc_client::connect() {
-            Ok((rx, stream)) => {
-                info!("connected to accentd daemon");
-                Some(IpcState { rx, _stream: stream })
-            }
+        let (rx, daemon_tx) = match ipc_client::spawn() {
+            Ok(channels) => channels,
             Err(e) => {
-                warn!(error = %e, "failed to connect to daemon, will retry");
-                None
+                warn!(error = %e, "failed to start IPC client");
+                return;
             }
         };
-
-        let ipc_state: Rc<RefCell<Option<IpcState>>> = Rc::new(RefCell::new(initial));
-        let last_reconnect: Rc<RefCell<Instant>> = Rc::new(RefCell::new(Instant::now()));
-
-        let popup_window = Rc::new(popup_window);
-        let popup_label = Rc::new(popup_label);
-
-        let pw = Rc::clone(&popup_window);
-        let pl = Rc::clone(&popup_label);
+        let build = {
+            let daemon_tx = daemon_tx.clone();
+            move |app: &gtk4::Application| {
+                let (window, label) = window::build_popup(app);
+                let daemon_tx = daemon_tx.clone();
+                window::connect_focus_lost(&window, move || {
+                    let _ = daemon_tx.send(ClientMsg::Dismiss);
+                });
+                (window, label)
+            }
+        };
+        let (mut popup_window, mut popup_label) = build(&app);
+        let mut cheat_sheet: Option<gtk4::Window> = None;
+        // The open popup's candidates, to redraw when the highlight moves
+        let mut shown: Option<(Vec<String>, Vec<u8>, Vec<String>)> = None;
 
         glib::timeout_add_local(Duration::from_millis(16), move || {
-            let mut state = ipc_state.borrow_mut();
-
-            if let Some(ref ipc) = *state {
-                loop {
-                    match ipc.rx.try_recv() {
-                        Ok(msg) => match msg {
-                            DaemonMsg::ShowPopup { accents, labels, .. } => {
-                                window::show_popup(&pw, &pl, &accents, &labels);
-                            }
-                            DaemonMsg::HidePopup => {
-                                window::hide_popup(&pw);
-                            }
-                            _ => {}
-                        },
-                        Err(std_mpsc::TryRecvError::Empty) => break,
-                        Err(std_mpsc::TryRecvError::Disconnected) => {
-                            warn!("daemon disconnected, will try to reconnect");
-                            *state = None;
-                            break;
+            loop {
+                match rx.try_recv() {
+                    Ok(DaemonMsg::ShowPopup { accents, labels, descriptions, .. }) => {
+                        if layer_shell::missing_layer_shell(&popup_window) {
+                            info!("layer-shell available now, rebuilding popup window");
+                            popup_window.destroy();
+                            (popup_window, popup_label) = build(&app);
                         }
+                        window::show_popup(&popup_window, &popup_label, &accents, &labels, &descriptions, None);
+                        shown = Some((accents, labels, descriptions));
                     }
-                }
-            } else {
-                // Try to reconnect every ~1 second
-                let mut last = last_reconnect.borrow_mut();
-                if last.elapsed() >= Duration::from_secs(1) {
-                    *last = Instant::now();
-                    match ipc_client::try_connect() {
-                        Ok((rx, stream)) => {
-                            info!("reconnected to accentd daemon");
-                            *state = Some(IpcState { rx, _stream: stream });
+                    Ok(DaemonMsg::Highlight { index }) => {
+                        if let Some((accents, labels, descriptions)) = &shown {
+                            window::show_popup(&popup_window, &popup_label, accents, labels, descriptions, Some(index));
+                        }
+                    }
+                    Ok(DaemonMsg::HidePopup { .. }) => {
+                        window::hide_popup(&popup_window);
+                        shown = None;
+                    }
+                    Ok(DaemonMsg::ShowCheatSheet) => {
+                        let _ = daemon_tx.send(ClientMsg::GetLocaleMap);
+                    }
+                    Ok(DaemonMsg::LocaleMap { locale, keys }) => {
+                        if let Some(old) = cheat_sheet.take() {
+                            old.destroy();
                         }
-                        Err(_) => {}
+                        cheat_sheet = Some(cheat_sheet::show(&app, &locale, &keys));
                     }
+                    Ok(_) => {}
+                    Err(std_mpsc::TryRecvError::Empty) => break,
+                    Err(std_mpsc::TryRecvError::Disconnected) => return glib::ControlFlow::Break,
                 }
             }
-
             glib::ControlFlow::Continue
         });
     });
@@ -4,7 +4,7 @@ use tracing::debug;
 use crate::layer_shell;
 
 /// Build the popup window. Returns the window and the label widget to update.
-pub fn build_popup(app: &gtk4::Application, font_size: u32) -> (gtk4::Window, gtk4::Label) {
+pub fn build_popup(app: &gtk4::Application) -> (gtk4::Window, gtk4::Label) {
     let window = gtk4::Window::builder()
         .application(app)
         .title("accentd")
@@ -28,6 +28,29 @@ pub fn build_popup(app: &gtk4::Application, font_size: u32) -> (gtk4::Window, gt
     label.set_halign(gtk4::Align::Center);
     label.set_valign(gtk4::Align::Center);
 
+    window.set_child(Some(&label));
+
+    // Start hidden
+    window.set_visible(false);
+
+    (window, label)
+}
+
+/// Call `dismiss` when the shown popup loses focus, e.g. the user clicked
+/// into another window. Layer-shell popups never take focus; the daemon's
+/// focus tracking covers those.
+pub fn connect_focus_lost(window: &gtk4::Window, dismiss: impl Fn() + 'static) {
+    window.connect_is_active_notify(move |window| {
+        // Hiding also drops focus; only react while still shown
+        if !window.is_active() && window.is_visible() {
+            debug!("popup lost focus");
+            dismiss();
+        }
+    });
+}
+
+/// Style popup windows. Once per display, so rebuilt windows don't stack providers.
+pub fn install_css(font_size: u32) {
     let css_provider = gtk4::CssProvider::new();
     css_provider.load_from_data(&format!(
         "
@@ -43,34 +66,65 @@ pub fn build_popup(app: &gtk4::Application, font_size: u32) -> (gtk4::Window, gt
             padding: 12px 20px;
             font-family: monospace;
         }}
+        window.cheat-sheet label {{
+            font-size: {}px;
+            padding: 4px 16px;
+        }}
         ",
         font_size,
+        (font_size * 2 / 3).max(12),
     ));
     gtk4::style_context_add_provider_for_display(
         &gtk4::gdk::Display::default().expect("display"),
         &css_provider,
         gtk4::STYLE_PROVIDER_PRIORITY_APPLICATION,
     );
-
-    window.set_child(Some(&label));
-
-    // Start hidden
-    window.set_visible(false);
-
-    (window, label)
 }
 
-/// Show the popup with the given accented characters.
-pub fn show_popup(window: &gtk4::Window, label: &gtk4::Label, accents: &[String], labels: &[u8]) {
+/// Show the popup with the given accented characters, `highlighted` (a
+/// label) marked. Their `descriptions`, if any, make up the tooltip, and the
+/// highlighted one's is what screen readers announce.
+pub fn show_popup(
+    window: &gtk4::Window,
+    label: &gtk4::Label,
+    accents: &[String],
+    labels: &[u8],
+    descriptions: &[String],
+    highlighted: Option<u8>,
+) {
     let parts: Vec<String> = accents
         .iter()
         .zip(labels.iter())
-        .map(|(accent, num)| format!("<span color='#88aaff'>{}</span>:{}", num, accent))
+        .map(|(accent, num)| {
+            let part = format!("<span color='#88aaff'>{}</span>:{}", num, gtk4::glib::markup_escape_text(accent));
+            if highlighted == Some(*num) {
+                format!("<span background='#3a5a9a'>{}</span>", part)
+            } else {
+                part
+            }
+        })
         .collect();
 
-    let markup = parts.join("  ");
+    // Snippets (multi-character candidates) read better one per line
+    let snippets = accents.iter().any(|a| accentd_core::charmap::is_snippet(a));
+    let markup = parts.join(if snippets { "\n" } else { "  " });
     label.set_markup(&markup);
 
+    let described: Vec<String> = accents
+        .iter()
+        .zip(labels)
+        .zip(descriptions)
+        .filter(|(_, description)| !description.is_empty())
+        .map(|((accent, num), description)| format!("{} {}  {}", num, accent, description))
+        .collect();
+    label.set_tooltip_text((!described.is_empty()).then(|| described.join("\n")).as_deref());
+    let announced = highlighted
+        .and_then(|num| labels.iter().position(|l| *l == num))
+        .and_then(|i| descriptions.get(i).filter(|d| !d.is_empty()).or(accents.get(i)));
+    if let Some(announced) = announced {
+        label.update_property(&[gtk4::accessible::Property::Description(announced)]);
+    }
+
     window.set_visible(true);
     debug!(count = accents.len(), "popup shown");
 }
@@ -0,0 +1,555 @@
+//! Seams between the daemon loop and the platform's input stack.
+//!
+//! Capture goes through [`EventSource`] and output through [`Emitter`]; the
+//! evdev/uinput implementations live in `grabber` and `uinput_emitter`. A new
+//! backend (libinput, a portal, another OS) implements these two traits and
+//! leaves the state machine and main loop alone.
+
+use accentd_core::charmap;
+use accentd_core::state_machine::{RawEvent, EV_KEY, EV_SYN};
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError};
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+/// One input frame from a grabbed keyboard, tagged with the device index:
+/// its events up to and including the SYN_REPORT, as one report from the
+/// device.
+#[derive(Debug, Clone)]
+pub struct DeviceEvent {
+    pub device_idx: usize,
+    pub frame: Vec<RawEvent>,
+    /// When the kernel saw it; holds are timed from this, not from when the
+    /// main loop gets to the frame.
+    pub time: Instant,
+}
+
+/// One keyboard, already opened and held exclusively.
+pub trait EventSource: Send + 'static {
+    /// Human-readable device name for logs.
+    fn name(&self) -> &str;
+
+    /// Wait for the next event and when it happened. An error ends the
+    /// source.
+    fn next_event(&mut self) -> impl Future<Output = Result<(RawEvent, Instant)>> + Send;
+
+    /// Vendor and product id, for sources that have one.
+    fn input_id(&self) -> Option<(u16, u16)> {
+        None
+    }
+
+    /// Keys down and Caps Lock as of opening the device, for sources that
+    /// can tell. Default: nothing held.
+    fn key_state(&self) -> KeyState {
+        KeyState::default()
+    }
+}
+
+/// A keyboard's state when it was opened.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyState {
+    pub held: Vec<u16>,
+    pub caps_lock: bool,
+}
+
+/// Where relayed keys and selected accents go.
+pub trait Emitter: Send {
+    /// Short name for status output, e.g. "uinput".
+    fn name(&self) -> &'static str;
+
+    /// Pass one input frame from keyboard `device` through unchanged,
+    /// terminated by a SYN_REPORT. `frame` holds the frame's events without
+    /// the SYN_REPORT itself.
+    fn relay(&mut self, device: usize, frame: &[RawEvent]) -> Result<()>;
+
+    /// Replace the last `erase` characters typed on keyboard `device`, the
+    /// base character and usually nothing more, with `text`. `app` is the
+    /// focused window's, when the compositor reports it.
+    fn emit_text(&mut self, device: usize, app: Option<&str>, text: &str, erase: usize) -> Result<()>;
+
+    /// Keyboard `device` was attached, as `name` with `input_id`. Outputs
+    /// with a virtual keyboard per physical one set it up here. Returns
+    /// whether a device was created, changing [`dev_nodes`](Self::dev_nodes);
+    /// outputs that create it later return false, and their `dev_nodes`
+    /// catches up by itself.
+    fn keyboard_attached(&mut self, _device: usize, _name: &str, _input_id: Option<(u16, u16)>) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// The /dev/input nodes of the output's own devices, never to be grabbed.
+    fn dev_nodes(&mut self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    /// Release every key the output still holds down. Called on shutdown so
+    /// the compositor isn't left with keys (modifiers) stuck down.
+    fn release_all(&mut self) -> Result<()>;
+}
+
+/// Output for `--dry-run`: the keyboards aren't grabbed, so their events
+/// already reach the apps. Only logs what would have been typed.
+pub struct DryRun;
+
+impl Emitter for DryRun {
+    fn name(&self) -> &'static str {
+        "dry-run"
+    }
+
+    fn relay(&mut self, _device: usize, _frame: &[RawEvent]) -> Result<()> {
+        Ok(())
+    }
+
+    fn emit_text(&mut self, _device: usize, _app: Option<&str>, text: &str, erase: usize) -> Result<()> {
+        info!(text, erase, "dry run: would type");
+        Ok(())
+    }
+
+    fn release_all(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// An [`Emitter`] on a thread of its own, so typing a pick (Ctrl+Shift+U
+/// sleeps between keys) doesn't hold up the main loop or other keyboards'
+/// events. Everything goes through one queue in order: keys typed after a
+/// pick are relayed after it. Relay, pick and attach errors are logged there.
+pub struct Queued {
+    name: &'static str,
+    jobs: std::sync::mpsc::Sender<Job>,
+    /// The output's device nodes, updated by the thread as keyboards attach:
+    /// asking it would wait out a pick being typed.
+    nodes: Arc<std::sync::Mutex<Vec<PathBuf>>>,
+}
+
+type Job = Box<dyn FnOnce(&mut dyn Emitter) + Send>;
+
+impl Queued {
+    pub fn spawn(mut emitter: Box<dyn Emitter>) -> Result<Self> {
+        let name = emitter.name();
+        let nodes = Arc::new(std::sync::Mutex::new(emitter.dev_nodes()));
+        let (jobs, queue) = std::sync::mpsc::channel::<Job>();
+        std::thread::Builder::new()
+            .name("emitter".into())
+            .spawn(move || {
+                for job in queue {
+                    job(emitter.as_mut());
+                }
+            })
+            .context("starting the emitter thread")?;
+        Ok(Self { name, jobs, nodes })
+    }
+
+    /// Queue `job` without waiting for it.
+    fn send(&self, job: impl FnOnce(&mut dyn Emitter) + Send + 'static) {
+        if self.jobs.send(Box::new(job)).is_err() {
+            warn!("the emitter thread is gone");
+        }
+    }
+
+    /// Run `job` after everything queued, and wait for it.
+    fn call<T: Send + 'static>(&self, job: impl FnOnce(&mut dyn Emitter) -> T + Send + 'static) -> Result<T> {
+        let (reply_tx, reply) = std::sync::mpsc::sync_channel(1);
+        self.send(move |emitter| {
+            let _ = reply_tx.send(job(emitter));
+        });
+        reply.recv().context("the emitter thread is gone")
+    }
+}
+
+impl Emitter for Queued {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn relay(&mut self, device: usize, frame: &[RawEvent]) -> Result<()> {
+        let frame = frame.to_vec();
+        self.send(move |emitter| {
+            if let Err(e) = emitter.relay(device, &frame) {
+                warn!(error = %e, "relay error");
+            }
+        });
+        Ok(())
+    }
+
+    fn emit_text(&mut self, device: usize, app: Option<&str>, text: &str, erase: usize) -> Result<()> {
+        let (app, text) = (app.map(str::to_string), text.to_string());
+        self.send(move |emitter| {
+            if let Err(e) = emitter.emit_text(device, app.as_deref(), &text, erase) {
+                warn!(error = %e, "emit accent error");
+            }
+        });
+        Ok(())
+    }
+
+    fn keyboard_attached(&mut self, device: usize, name: &str, input_id: Option<(u16, u16)>) -> Result<bool> {
+        let (name, nodes) = (name.to_string(), Arc::clone(&self.nodes));
+        self.send(move |emitter| match emitter.keyboard_attached(device, &name, input_id) {
+            Ok(true) => *nodes.lock().unwrap_or_else(PoisonError::into_inner) = emitter.dev_nodes(),
+            Ok(false) => {}
+            Err(e) => {
+                warn!(device = %name, error = %format!("{:#}", e), "no virtual keyboard of its own, using the shared one")
+            }
+        });
+        Ok(false)
+    }
+
+    fn dev_nodes(&mut self) -> Vec<PathBuf> {
+        self.nodes.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    fn release_all(&mut self) -> Result<()> {
+        self.call(|emitter| emitter.release_all())?
+    }
+}
+
+/// Keys an output currently holds down, as seen in the frames relayed to it.
+#[derive(Debug, Default)]
+pub struct DownKeys(BTreeSet<u16>);
+
+impl DownKeys {
+    pub fn track(&mut self, frame: &[RawEvent]) {
+        for event in frame.iter().filter(|e| e.event_type == EV_KEY) {
+            match event.value {
+                0 => {
+                    self.0.remove(&event.code);
+                }
+                1 => {
+                    self.0.insert(event.code);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Release events for every key down, forgetting them.
+    pub fn take_releases(&mut self) -> Vec<RawEvent> {
+        std::mem::take(&mut self.0).into_iter().map(|code| RawEvent::key(code, 0)).collect()
+    }
+}
+
+/// Collects relayed events until the frame's SYN_REPORT, so the output gets
+/// whole frames (KEY + MSC + SYN) in one write, as the kernel delivered them.
+#[derive(Debug, Default)]
+pub struct FrameBuffer {
+    events: Vec<RawEvent>,
+}
+
+impl FrameBuffer {
+    /// Queue an event relayed from keyboard `device`; a SYN_REPORT writes
+    /// out the frame.
+    pub fn relay(&mut self, event: RawEvent, device: usize, emitter: &mut dyn Emitter) -> Result<()> {
+        if event.event_type == EV_SYN && event.code == SYN_REPORT {
+            self.flush(device, emitter)
+        } else {
+            self.events.push(event);
+            Ok(())
+        }
+    }
+
+    /// Write out whatever is queued. Frames whose events were all suppressed
+    /// are dropped rather than sent as a lone SYN_REPORT.
+    pub fn flush(&mut self, device: usize, emitter: &mut dyn Emitter) -> Result<()> {
+        if self.events.is_empty() {
+            return Ok(());
+        }
+        let result = emitter.relay(device, &self.events);
+        self.events.clear();
+        result
+    }
+}
+
+const SYN_REPORT: u16 = 0;
+
+const KEY_BACKSPACE: u16 = 14;
+
+/// Without a grab (`grab = false`) apps get every key as it is typed, so a
+/// pick first erases what the hold typed: the held letter, its autorepeats,
+/// the digit or Enter that picked.
+#[derive(Debug, Default)]
+pub struct Echo {
+    typed: usize,
+}
+
+impl Echo {
+    /// Note an event the apps got too. `idle`: the keyboard's state machine
+    /// was idle before it, so a press may start a new hold.
+    pub fn saw(&mut self, event: &RawEvent, idle: bool) {
+        if !event.is_key() {
+            return;
+        }
+        if idle && event.value == 1 {
+            self.typed = 0;
+        }
+        if event.value != 0 && types_character(event.code) {
+            self.typed += 1;
+        }
+    }
+
+    /// BackSpace taps erasing what was typed since the hold began, one
+    /// frame per event.
+    pub fn take_erase(&mut self) -> Vec<RawEvent> {
+        let taps = std::mem::take(&mut self.typed);
+        (0..taps).flat_map(|_| [RawEvent::key(KEY_BACKSPACE, 1), RawEvent::key(KEY_BACKSPACE, 0)]).collect()
+    }
+}
+
+/// Keys that put a character in a text field (on a US layout; the others
+/// have the same keys in different places).
+fn types_character(code: u16) -> bool {
+    const KEY_TAB: u16 = 15;
+    const KEY_ENTER: u16 = 28;
+    const KEY_SPACE: u16 = 57;
+    charmap::keycode_to_base(code).is_some()
+        || charmap::keycode_to_digit_base(code).is_some()
+        || charmap::keycode_to_symbol(code, false).is_some()
+        || matches!(code, KEY_TAB | KEY_ENTER | KEY_SPACE)
+}
+
+/// Forward a source's events to the channel a frame at a time.
+/// Runs until the receiver is dropped or the source errors, returning the
+/// error.
+pub async fn forward(
+    mut source: impl EventSource,
+    device_idx: usize,
+    tx: mpsc::UnboundedSender<DeviceEvent>,
+) -> Result<()> {
+    info!(device = %source.name(), device_idx, "reading events");
+    let mut frame = Vec::new();
+    loop {
+        match source.next_event().await {
+            Ok((event, time)) => {
+                frame.push(event);
+                if !(event.event_type == EV_SYN && event.code == SYN_REPORT) {
+                    continue;
+                }
+                let frame = std::mem::take(&mut frame);
+                if tx.send(DeviceEvent { device_idx, frame, time }).is_err() {
+                    // Receiver dropped, shut down
+                    return Ok(());
+                }
+            }
+            Err(e) => {
+                warn!(device = %source.name(), error = %e, "device error, stopping grab");
+                return Err(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Plays back a fixed list of events, then fails like an unplugged device.
+    struct ScriptedSource(VecDeque<RawEvent>);
+
+    impl EventSource for ScriptedSource {
+        fn name(&self) -> &str {
+            "scripted"
+        }
+
+        async fn next_event(&mut self) -> Result<(RawEvent, Instant)> {
+            let event = self.0.pop_front().ok_or_else(|| anyhow::anyhow!("unplugged"))?;
+            Ok((event, Instant::now()))
+        }
+    }
+
+    /// Records each relayed frame of keyboard 0.
+    #[derive(Default)]
+    struct Recorder(Vec<Vec<RawEvent>>);
+
+    impl Emitter for Recorder {
+        fn name(&self) -> &'static str {
+            "recorder"
+        }
+
+        fn relay(&mut self, device: usize, frame: &[RawEvent]) -> Result<()> {
+            assert_eq!(device, 0);
+            self.0.push(frame.to_vec());
+            Ok(())
+        }
+
+        fn emit_text(&mut self, _device: usize, _app: Option<&str>, _text: &str, _erase: usize) -> Result<()> {
+            Ok(())
+        }
+
+        fn release_all(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Logs what it's asked to do, typing only once `typing` lets it.
+    struct Gated {
+        log: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+        typing: std::sync::mpsc::Receiver<()>,
+    }
+
+    impl Emitter for Gated {
+        fn name(&self) -> &'static str {
+            "gated"
+        }
+
+        fn relay(&mut self, device: usize, _frame: &[RawEvent]) -> Result<()> {
+            self.log.lock().unwrap().push(format!("relay {}", device));
+            Ok(())
+        }
+
+        fn emit_text(&mut self, _device: usize, _app: Option<&str>, text: &str, _erase: usize) -> Result<()> {
+            self.typing.recv()?;
+            self.log.lock().unwrap().push(format!("type {}", text));
+            Ok(())
+        }
+
+        fn keyboard_attached(&mut self, device: usize, _name: &str, _input_id: Option<(u16, u16)>) -> Result<bool> {
+            self.log.lock().unwrap().push(format!("attach {}", device));
+            Ok(true)
+        }
+
+        fn dev_nodes(&mut self) -> Vec<PathBuf> {
+            let log = self.log.lock().unwrap();
+            let attached = log.iter().filter_map(|line| line.strip_prefix("attach "));
+            attached.map(|device| PathBuf::from(format!("/dev/input/event{}", device))).collect()
+        }
+
+        fn release_all(&mut self) -> Result<()> {
+            self.log.lock().unwrap().push("release".to_string());
+            Ok(())
+        }
+    }
+
+    const MSC_SCAN: RawEvent = RawEvent { event_type: 0x04, code: 0x04, value: 0x70008 };
+    const SYN: RawEvent = RawEvent { event_type: EV_SYN, code: SYN_REPORT, value: 0 };
+
+    // --- spec: relays are written one frame at a time ---
+
+    #[test]
+    fn frame_is_written_at_syn_report() {
+        let mut out = Recorder::default();
+        let mut buf = FrameBuffer::default();
+        buf.relay(MSC_SCAN, 0, &mut out).unwrap();
+        buf.relay(RawEvent::key(18, 1), 0, &mut out).unwrap();
+        assert!(out.0.is_empty(), "nothing written mid-frame");
+        buf.relay(SYN, 0, &mut out).unwrap();
+        assert_eq!(out.0, vec![vec![MSC_SCAN, RawEvent::key(18, 1)]]);
+    }
+
+    #[test]
+    fn fully_suppressed_frame_writes_nothing() {
+        let mut out = Recorder::default();
+        let mut buf = FrameBuffer::default();
+        buf.relay(SYN, 0, &mut out).unwrap();
+        assert!(out.0.is_empty());
+    }
+
+    #[test]
+    fn flush_writes_events_without_syn() {
+        let mut out = Recorder::default();
+        let mut buf = FrameBuffer::default();
+        buf.relay(RawEvent::key(18, 0), 0, &mut out).unwrap();
+        buf.flush(0, &mut out).unwrap();
+        buf.flush(0, &mut out).unwrap();
+        assert_eq!(out.0, vec![vec![RawEvent::key(18, 0)]]);
+    }
+
+    // --- spec: picks are typed off the main loop, in order ---
+
+    #[test]
+    fn queued_picks_return_at_once_and_keep_their_place() {
+        let log = std::sync::Arc::default();
+        let (open, typing) = std::sync::mpsc::channel();
+        let mut queued = Queued::spawn(Box::new(Gated { log: std::sync::Arc::clone(&log), typing })).unwrap();
+        assert_eq!(queued.name(), "gated");
+        // the pick waits for the gate, so returning at all means not waiting
+        queued.emit_text(0, None, "è", 1).unwrap();
+        queued.relay(1, &[RawEvent::key(KEY_E, 1)]).unwrap();
+        assert!(log.lock().unwrap().is_empty(), "the relay waits behind the pick");
+        open.send(()).unwrap();
+        queued.release_all().unwrap();
+        assert_eq!(*log.lock().unwrap(), ["type è", "relay 1", "release"]);
+    }
+
+    #[test]
+    fn keyboards_attach_without_waiting_for_a_pick() {
+        let log = std::sync::Arc::default();
+        let (open, typing) = std::sync::mpsc::channel();
+        let mut queued = Queued::spawn(Box::new(Gated { log: std::sync::Arc::clone(&log), typing })).unwrap();
+        queued.emit_text(0, None, "è", 1).unwrap();
+        // the pick holds the thread; neither call may wait for it
+        queued.keyboard_attached(2, "Keychron K2", None).unwrap();
+        assert!(queued.dev_nodes().is_empty(), "not attached yet");
+        open.send(()).unwrap();
+        queued.release_all().unwrap();
+        assert_eq!(queued.dev_nodes(), [PathBuf::from("/dev/input/event2")]);
+    }
+
+    // --- spec: without a grab, picks erase what the hold typed ---
+
+    const KEY_E: u16 = 18;
+    const KEY_2: u16 = 3;
+    const KEY_LEFTSHIFT: u16 = 42;
+
+    #[test]
+    fn pick_erases_letter_repeats_and_digit() {
+        let mut echo = Echo::default();
+        echo.saw(&RawEvent::key(KEY_E, 1), true);
+        echo.saw(&RawEvent::key(KEY_E, 2), false);
+        echo.saw(&RawEvent::key(KEY_E, 2), false);
+        echo.saw(&RawEvent::key(KEY_2, 1), false);
+        let erase = echo.take_erase();
+        assert_eq!(erase.len(), 8);
+        assert_eq!(erase[..2], [RawEvent::key(KEY_BACKSPACE, 1), RawEvent::key(KEY_BACKSPACE, 0)]);
+        assert!(echo.take_erase().is_empty());
+    }
+
+    #[test]
+    fn new_hold_forgets_earlier_typing() {
+        let mut echo = Echo::default();
+        echo.saw(&RawEvent::key(KEY_E, 1), true);
+        echo.saw(&RawEvent::key(KEY_E, 0), false);
+        echo.saw(&RawEvent::key(KEY_LEFTSHIFT, 1), true);
+        echo.saw(&RawEvent::key(KEY_E, 1), true);
+        echo.saw(&RawEvent::new(EV_SYN, SYN_REPORT, 0), false);
+        assert_eq!(echo.take_erase().len(), 2);
+    }
+
+    // --- spec: held keys are released on shutdown ---
+
+    #[test]
+    fn down_keys_follow_presses_and_releases() {
+        let mut down = DownKeys::default();
+        down.track(&[RawEvent::key(42, 1), MSC_SCAN, RawEvent::key(18, 1)]);
+        down.track(&[RawEvent::key(18, 2)]);
+        down.track(&[RawEvent::key(18, 0)]);
+        assert_eq!(down.take_releases(), vec![RawEvent::key(42, 0)]);
+        assert!(down.take_releases().is_empty());
+    }
+
+    #[tokio::test]
+    async fn forward_tags_events_until_source_ends() {
+        let events = [RawEvent::key(18, 1), SYN, RawEvent::key(18, 0), SYN];
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let ended = forward(ScriptedSource(events.into_iter().collect()), 3, tx).await;
+        assert!(ended.is_err(), "the source's error is returned");
+
+        for expected in events.chunks(2) {
+            let got = rx.recv().await.unwrap();
+            assert_eq!(got.device_idx, 3);
+            assert_eq!(got.frame, expected);
+        }
+        assert!(rx.recv().await.is_none(), "channel closes when the source ends");
+    }
+
+    #[tokio::test]
+    async fn forward_sends_whole_frames_only() {
+        let events = [RawEvent::key(42, 1), RawEvent::key(18, 1), SYN, RawEvent::key(18, 0)];
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let _ = forward(ScriptedSource(events.into_iter().collect()), 0, tx).await;
+        assert_eq!(rx.recv().await.unwrap().frame, events[..3]);
+        assert!(rx.recv().await.is_none(), "the unfinished frame is dropped with the source");
+    }
+}
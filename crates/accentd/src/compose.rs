@@ -1,7 +1,160 @@
-use anyhow::Result;
+mod clipboard;
+mod input_method;
+mod wayland_vk;
+mod xtest;
+
+use crate::compositor;
+use accentd_core::config::{ComposeBackend, ComposeConfig};
+use anyhow::{Context, Result};
 use evdev::uinput::VirtualDevice;
 use evdev::{EventType, InputEvent, Key};
-use std::time::Duration;
+use std::collections::HashMap;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+use wayland_client::globals::{registry_queue_init, GlobalList, GlobalListContents};
+use wayland_client::protocol::wl_registry::WlRegistry;
+use wayland_client::{Connection, Dispatch, EventQueue};
+
+/// Types picks some other way than Ctrl+Shift+U on the uinput keyboard:
+/// the `[compose] backend`s other than `uinput`.
+pub trait TextBackend: Send {
+    /// Short name for status output, e.g. "wayland-vk".
+    fn name(&self) -> &'static str;
+
+    /// Replace the last `erase` characters typed with `text`, in `app` when
+    /// the focused window is known. `keyboard` is the uinput keyboard the
+    /// pick was made on. An error before anything was typed lets the caller
+    /// fall back to Ctrl+Shift+U.
+    fn type_text(&mut self, keyboard: &mut dyn EventEmitter, app: Option<&str>, text: &str, erase: usize)
+        -> Result<()>;
+}
+
+/// The `[compose]` backends, each made when an app first needs it, and
+/// which app gets which.
+pub struct Backends {
+    config: ComposeConfig,
+    /// Per backend after [`resolve`]; `None` for `uinput`.
+    made: HashMap<ComposeBackend, Option<Box<dyn TextBackend>>>,
+}
+
+impl Backends {
+    /// Makes the default backend right away, to report early whether it
+    /// works.
+    pub fn new(config: ComposeConfig) -> Self {
+        let mut backends = Self { config, made: HashMap::new() };
+        backends.for_app(None);
+        backends
+    }
+
+    /// The default backend's name, for status output.
+    pub fn name(&self) -> &'static str {
+        let made = self.made.get(&resolve(self.config.backend, x11_session())).and_then(Option::as_ref);
+        made.map_or("uinput", |backend| backend.name())
+    }
+
+    /// `[compose] hex_digits`, for picks typed with Ctrl+Shift+U.
+    pub fn hex_digits(&self) -> usize {
+        self.config.hex_digits.get()
+    }
+
+    /// The backend for picks in `app`; `None` for Ctrl+Shift+U.
+    pub fn for_app(&mut self, app: Option<&str>) -> Option<&mut (dyn TextBackend + 'static)> {
+        let backend = resolve(self.config.backend_for(app), x11_session());
+        self.made.entry(backend).or_insert_with(|| make(backend)).as_deref_mut()
+    }
+}
+
+/// What `auto` means in an X11 session (`x11`) or not: `xtest`, else
+/// `input-method`.
+fn resolve(backend: ComposeBackend, x11: bool) -> ComposeBackend {
+    match backend {
+        ComposeBackend::Auto if x11 => ComposeBackend::Xtest,
+        // the compositor may start after us (at boot, before login), so
+        // Wayland is looked for on each pick until found
+        ComposeBackend::Auto => ComposeBackend::InputMethod,
+        backend => backend,
+    }
+}
+
+fn make(backend: ComposeBackend) -> Option<Box<dyn TextBackend>> {
+    match backend {
+        ComposeBackend::Auto | ComposeBackend::InputMethod => Some(Box::new(input_method::InputMethod::new())),
+        ComposeBackend::Uinput => None,
+        ComposeBackend::WaylandVk => Some(Box::new(wayland_vk::WaylandVk::new())),
+        ComposeBackend::Clipboard => Some(Box::new(clipboard::Clipboard)),
+        ComposeBackend::Xtest => Some(Box::new(xtest::XTest::new())),
+    }
+}
+
+/// Whether we run in an X11 session, as logind's `XDG_SESSION_TYPE` says.
+fn x11_session() -> bool {
+    std::env::var("XDG_SESSION_TYPE").is_ok_and(|session| session == "x11")
+}
+
+/// After failing to reach the compositor, leave picks to Ctrl+Shift+U for
+/// this long before trying again.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// A backend's connection, opened again on a later pick when it couldn't
+/// be or was lost.
+struct Reconnecting<T> {
+    /// What fails to connect, for the log.
+    what: &'static str,
+    connect: fn() -> Result<T>,
+    conn: Option<T>,
+    retry_at: Option<Instant>,
+    /// Only the first failure is a warning; the compositor won't grow the
+    /// protocol in the meantime.
+    warned: bool,
+}
+
+impl<T> Reconnecting<T> {
+    /// Connects right away, to report early whether it works.
+    fn new(what: &'static str, connect: fn() -> Result<T>) -> Self {
+        let mut reconnecting = Self { what, connect, conn: None, retry_at: None, warned: false };
+        reconnecting.get();
+        reconnecting
+    }
+
+    /// The connection, if it's open or opens now.
+    fn get(&mut self) -> Option<&mut T> {
+        if self.conn.is_none() && self.retry_at.is_none_or(|at| Instant::now() >= at) {
+            match (self.connect)() {
+                Ok(conn) => self.conn = Some(conn),
+                Err(e) if !self.warned => {
+                    warn!(error = %format!("{:#}", e), "no {}, typing with Ctrl+Shift+U", self.what);
+                    self.warned = true;
+                    self.retry_at = Some(Instant::now() + RETRY_DELAY);
+                }
+                Err(e) => {
+                    debug!(error = %format!("{:#}", e), "still no {}", self.what);
+                    self.retry_at = Some(Instant::now() + RETRY_DELAY);
+                }
+            }
+        }
+        self.conn.as_mut()
+    }
+
+    /// The compositor went away or restarted: reconnect on the next pick.
+    fn lost(&mut self) {
+        self.conn = None;
+    }
+}
+
+/// A connection to the Wayland compositor, its globals and an event queue,
+/// and the socket it came through.
+fn connect_wayland<State>() -> Result<(GlobalList, EventQueue<State>, PathBuf)>
+where
+    State: Dispatch<WlRegistry, GlobalListContents> + 'static,
+{
+    let socket = compositor::find_wayland_socket().context("no Wayland compositor found")?;
+    let stream = UnixStream::connect(&socket).with_context(|| format!("connecting to {}", socket.display()))?;
+    let conn = Connection::from_socket(stream).context("opening the Wayland connection")?;
+    let (globals, queue) = registry_queue_init::<State>(&conn).context("listing Wayland globals")?;
+    Ok((globals, queue, socket))
+}
 
 pub trait EventEmitter {
     fn emit_events(&mut self, events: &[InputEvent]) -> Result<()>;
@@ -35,7 +188,7 @@ fn syn() -> InputEvent {
 
 /// Tap a key: press, sleep, release, sleep. Each half needs its own emit so the
 /// kernel processes the state change before the next event.
-fn tap_key(emitter: &mut impl EventEmitter, key: Key) -> Result<()> {
+fn tap_key(emitter: &mut (impl EventEmitter + ?Sized), key: Key) -> Result<()> {
     emitter.emit_events(&[InputEvent::new(EventType::KEY, key.code(), 1), syn()])?;
     std::thread::sleep(DELAY_BETWEEN_EMITS);
     emitter.emit_events(&[InputEvent::new(EventType::KEY, key.code(), 0), syn()])?;
@@ -44,7 +197,7 @@ fn tap_key(emitter: &mut impl EventEmitter, key: Key) -> Result<()> {
 }
 
 /// Press or release a single key + syn, then sleep.
-fn hold_key(emitter: &mut impl EventEmitter, key: Key, press: bool) -> Result<()> {
+fn hold_key(emitter: &mut (impl EventEmitter + ?Sized), key: Key, press: bool) -> Result<()> {
     let val = if press { 1 } else { 0 };
     emitter.emit_events(&[InputEvent::new(EventType::KEY, key.code(), val), syn()])?;
     std::thread::sleep(DELAY_BETWEEN_EMITS);
@@ -55,23 +208,45 @@ fn hold_key(emitter: &mut impl EventEmitter, key: Key, press: bool) -> Result<()
 /// via Ctrl+Shift+U hex sequence (GTK/Qt Unicode input method).
 ///
 /// The protocol has 4 phases:
-///   1. Backspace — delete the base character
+///   1. Backspace — delete the base character (`erase` of them)
 ///   2. Ctrl+Shift+U chord — enter Unicode hex input mode
 ///   3. Hex digits + Enter — type the codepoint and confirm
 ///
+/// Snippets (several characters) repeat phases 2 and 3 per character, and so
+/// do emoji with a variation selector or skin tone. Characters outside the
+/// BMP are typed as their full code point (`1f600`), not UTF-16 surrogates.
+/// Code points get at least `hex_digits` digits; see [`hex`].
+///
 /// NOTE: Ctrl+Shift+U works in GTK and Qt apps. It may fail in Electron apps,
 /// some terminal emulators, and other toolkits that don't support this input method.
-pub fn emit_accent(emitter: &mut impl EventEmitter, accent: &str) -> Result<()> {
-    let c = accent.chars().next().unwrap_or(' ');
-    let hex = format!("{:04x}", c as u32);
-
+///
+/// `erase` is usually 1, the base; a ligature like `œ` picked on `e` after
+/// typing `o` erases 2.
+pub fn emit_accent(
+    emitter: &mut (impl EventEmitter + ?Sized),
+    accent: &str,
+    erase: usize,
+    hex_digits: usize,
+) -> Result<()> {
     // Wait for popup to hide and focus to return
     std::thread::sleep(DELAY_POPUP_HIDE);
 
-    // Phase 1: delete the base character
-    tap_key(emitter, Key::KEY_BACKSPACE)?;
+    // Phase 1: delete the base character, and whatever else it replaces
+    for _ in 0..erase {
+        tap_key(emitter, Key::KEY_BACKSPACE)?;
+    }
     std::thread::sleep(DELAY_AFTER_BACKSPACE);
 
+    for c in accent.chars() {
+        emit_codepoint(emitter, c, hex_digits)?;
+    }
+    Ok(())
+}
+
+/// Phases 2 and 3 for one character.
+fn emit_codepoint(emitter: &mut (impl EventEmitter + ?Sized), c: char, hex_digits: usize) -> Result<()> {
+    let hex = hex(c, hex_digits);
+
     // Phase 2: Ctrl+Shift+U chord — each modifier and the U tap need separate
     // emits so the kernel registers the state changes in order
     hold_key(emitter, Key::KEY_LEFTCTRL, true)?;
@@ -100,6 +275,14 @@ pub fn emit_accent(emitter: &mut impl EventEmitter, accent: &str) -> Result<()>
     Ok(())
 }
 
+/// `c`'s code point in lowercase hex, zero-padded to `digits`: `hex('è', 4)`
+/// is `00e8`, `hex('😀', 4)` is `1f600`. The leading `1` of a plane-1 code
+/// point is a digit like any other; IBus, like GTK, reads the code point
+/// whole and needs nothing done about it.
+fn hex(c: char, digits: usize) -> String {
+    format!("{:0digits$x}", c as u32)
+}
+
 fn hex_char_to_key(c: char) -> Key {
     match c {
         '0' => Key::KEY_0,
@@ -165,7 +348,7 @@ mod tests {
     #[test]
     fn modifier_presses_are_separate_emits() {
         let mut mock = RecordingEmitter::new();
-        emit_accent(&mut mock, "è").unwrap();
+        emit_accent(&mut mock, "è", 1, 4).unwrap();
         // Batch 2 = Ctrl↓, batch 3 = Shift↓ (after BS↓, BS↑)
         assert_eq!(mock.key_events(2), vec![(Key::KEY_LEFTCTRL.code(), 1)]);
         assert_eq!(mock.key_events(3), vec![(Key::KEY_LEFTSHIFT.code(), 1)]);
@@ -174,7 +357,7 @@ mod tests {
     #[test]
     fn modifier_releases_are_separate_emits() {
         let mut mock = RecordingEmitter::new();
-        emit_accent(&mut mock, "è").unwrap();
+        emit_accent(&mut mock, "è", 1, 4).unwrap();
         // After U tap (batches 4,5), Shift↑ = batch 6, Ctrl↑ = batch 7
         assert_eq!(mock.key_events(6), vec![(Key::KEY_LEFTSHIFT.code(), 0)]);
         assert_eq!(mock.key_events(7), vec![(Key::KEY_LEFTCTRL.code(), 0)]);
@@ -183,7 +366,7 @@ mod tests {
     #[test]
     fn key_tap_is_two_emits() {
         let mut mock = RecordingEmitter::new();
-        emit_accent(&mut mock, "è").unwrap();
+        emit_accent(&mut mock, "è", 1, 4).unwrap();
         // Backspace tap = batches 0 (press) and 1 (release)
         assert_eq!(mock.key_events(0), vec![(Key::KEY_BACKSPACE.code(), 1)]);
         assert_eq!(mock.key_events(1), vec![(Key::KEY_BACKSPACE.code(), 0)]);
@@ -195,7 +378,7 @@ mod tests {
     #[test]
     fn hex_digits_are_one_emit() {
         let mut mock = RecordingEmitter::new();
-        emit_accent(&mut mock, "è").unwrap();
+        emit_accent(&mut mock, "è", 1, 4).unwrap();
         // Last batch (8) contains all hex digit press/release pairs + Enter
         let last = mock.batches.len() - 1;
         let key_events = mock.key_events(last);
@@ -206,7 +389,7 @@ mod tests {
     #[test]
     fn full_event_sequence() {
         let mut mock = RecordingEmitter::new();
-        emit_accent(&mut mock, "è").unwrap();
+        emit_accent(&mut mock, "è", 1, 4).unwrap();
         let events = mock.all_key_events();
         let expected = vec![
             // BS tap
@@ -237,7 +420,7 @@ mod tests {
     #[test]
     fn emit_accent_for_e_grave() {
         let mut mock = RecordingEmitter::new();
-        emit_accent(&mut mock, "è").unwrap();
+        emit_accent(&mut mock, "è", 1, 4).unwrap();
         // è = U+00E8 → hex digits in last batch should be KEY_0, KEY_0, KEY_E, KEY_8
         let last = mock.batches.len() - 1;
         let hex_keys: Vec<u16> = mock.key_events(last)
@@ -277,4 +460,68 @@ mod tests {
         assert_eq!(hex_char_to_key('e'), Key::KEY_E);
         assert_eq!(hex_char_to_key('f'), Key::KEY_F);
     }
+
+    #[test]
+    fn snippet_deletes_once_and_types_each_character() {
+        let mut mock = RecordingEmitter::new();
+        emit_accent(&mut mock, "a@b", 1, 4).unwrap();
+        let events = mock.all_key_events();
+        let taps = |key: Key| events.iter().filter(|&&e| e == (key.code(), 1)).count();
+        assert_eq!(taps(Key::KEY_BACKSPACE), 1);
+        assert_eq!(taps(Key::KEY_U), 3);
+        assert_eq!(taps(Key::KEY_ENTER), 3);
+    }
+
+    #[test]
+    fn ligature_erases_both_letters_it_replaces() {
+        let mut mock = RecordingEmitter::new();
+        emit_accent(&mut mock, "œ", 2, 4).unwrap();
+        let events = mock.all_key_events();
+        assert_eq!(events.iter().filter(|&&e| e == (Key::KEY_BACKSPACE.code(), 1)).count(), 2);
+        assert_eq!(events.iter().filter(|&&e| e == (Key::KEY_U.code(), 1)).count(), 1);
+    }
+
+    #[test]
+    fn emoji_outside_the_bmp_take_five_hex_digits() {
+        let mut mock = RecordingEmitter::new();
+        emit_accent(&mut mock, "😀", 1, 4).unwrap();
+        let last = mock.batches.len() - 1;
+        let hex_keys: Vec<u16> = mock.key_events(last).iter().filter(|(_, v)| *v == 1).map(|(c, _)| *c).collect();
+        // U+1F600, not its UTF-16 surrogates
+        let expected = [Key::KEY_1, Key::KEY_F, Key::KEY_6, Key::KEY_0, Key::KEY_0, Key::KEY_ENTER];
+        assert_eq!(hex_keys, expected.map(|k| k.code()));
+    }
+
+    // --- spec: auto follows the session ---
+
+    #[test]
+    fn auto_is_xtest_under_x11_and_input_method_elsewhere() {
+        assert_eq!(resolve(ComposeBackend::Auto, true), ComposeBackend::Xtest);
+        assert_eq!(resolve(ComposeBackend::Auto, false), ComposeBackend::InputMethod);
+        for backend in [ComposeBackend::Uinput, ComposeBackend::WaylandVk, ComposeBackend::Clipboard] {
+            assert_eq!(resolve(backend, true), backend, "chosen outright");
+            assert_eq!(resolve(backend, false), backend);
+        }
+    }
+
+    #[test]
+    fn hex_is_padded_to_the_configured_length_and_no_further() {
+        assert_eq!(hex('è', 4), "00e8");
+        assert_eq!(hex('è', 6), "0000e8");
+        assert_eq!(hex('😀', 4), "1f600");
+        assert_eq!(hex('😀', 6), "01f600");
+        assert_eq!(hex('\u{10fffd}', 4), "10fffd");
+        assert_eq!(hex('\u{10fffd}', 8), "0010fffd");
+    }
+
+    #[test]
+    fn longer_sequences_are_typed_in_full() {
+        let mut mock = RecordingEmitter::new();
+        emit_accent(&mut mock, "𓀀", 1, 8).unwrap();
+        let last = mock.batches.len() - 1;
+        let hex_keys: Vec<u16> = mock.key_events(last).iter().filter(|(_, v)| *v == 1).map(|(c, _)| *c).collect();
+        // U+13000, an Egyptian hieroglyph
+        let expected = [Key::KEY_0, Key::KEY_0, Key::KEY_0, Key::KEY_1, Key::KEY_3, Key::KEY_0, Key::KEY_0, Key::KEY_0];
+        assert_eq!(hex_keys, expected.into_iter().chain([Key::KEY_ENTER]).map(|k| k.code()).collect::<Vec<_>>());
+    }
 }
@@ -0,0 +1,244 @@
+//! `[compose] backend = "clipboard"`: the pick is put on the clipboard with
+//! wl-copy and pasted on the uinput keyboard, then the clipboard gets back
+//! what it held. Works wherever pasting does, Electron apps and terminals
+//! included.
+
+use super::{hold_key, tap_key, EventEmitter, TextBackend, DELAY_POPUP_HIDE};
+use crate::compositor;
+use anyhow::{bail, Context, Result};
+use evdev::Key;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How long the app has to fetch the pasted pick before the clipboard is
+/// put back.
+const RESTORE_DELAY: Duration = Duration::from_millis(150);
+
+/// wl-paste waits on the app that owns the clipboard; one that hangs mustn't
+/// hang the daemon.
+const TOOL_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How picks are offered, and the type saved when the clipboard has it.
+const TEXT: &str = "text/plain;charset=utf-8";
+
+/// Lowercase parts of terminals' app ids and WM_CLASSes.
+const TERMINALS: &[&str] =
+    &["term", "kitty", "alacritty", "foot", "konsole", "wezterm", "ghostty", "tilix", "urxvt", "console"];
+
+pub struct Clipboard;
+
+impl TextBackend for Clipboard {
+    fn name(&self) -> &'static str {
+        "clipboard"
+    }
+
+    fn type_text(
+        &mut self,
+        keyboard: &mut dyn EventEmitter,
+        app: Option<&str>,
+        text: &str,
+        erase: usize,
+    ) -> Result<()> {
+        let socket = compositor::find_wayland_socket().context("no Wayland compositor found")?;
+        let terminal = app.is_some_and(is_terminal);
+        // Shift+Insert pastes the primary selection in some terminals (kitty,
+        // foot, Alacritty) and the clipboard in others
+        let selections: &[Selection] =
+            if terminal { &[Selection::Clipboard, Selection::Primary] } else { &[Selection::Clipboard] };
+        let saved = selections.iter().map(|&selection| save(&socket, selection)).collect::<Result<Vec<_>>>()?;
+
+        let pick = Contents { mime: TEXT.to_string(), data: text.as_bytes().to_vec() };
+        let pasted =
+            selections.iter().try_for_each(|&selection| copy(&socket, selection, Some(&pick))).and_then(|()| {
+                std::thread::sleep(DELAY_POPUP_HIDE);
+                for _ in 0..erase {
+                    tap_key(keyboard, Key::KEY_BACKSPACE)?;
+                }
+                paste(keyboard, terminal)?;
+                std::thread::sleep(RESTORE_DELAY);
+                Ok(())
+            });
+        for (&selection, contents) in selections.iter().zip(&saved) {
+            if let Err(e) = copy(&socket, selection, contents.as_ref()) {
+                warn!(error = %format!("{:#}", e), "couldn't put the {} back", selection.name());
+            }
+        }
+        pasted
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Selection {
+    Clipboard,
+    Primary,
+}
+
+impl Selection {
+    fn name(self) -> &'static str {
+        match self {
+            Selection::Clipboard => "clipboard",
+            Selection::Primary => "primary selection",
+        }
+    }
+
+    /// wl-clipboard's arguments for it.
+    fn args(self) -> &'static [&'static str] {
+        match self {
+            Selection::Clipboard => &[],
+            Selection::Primary => &["--primary"],
+        }
+    }
+}
+
+/// What a selection held, in one of the types it was offered as.
+struct Contents {
+    mime: String,
+    data: Vec<u8>,
+}
+
+/// Whether `app` looks like a terminal, which pastes with Shift+Insert
+/// rather than Ctrl+V.
+fn is_terminal(app: &str) -> bool {
+    let app = app.to_lowercase();
+    TERMINALS.iter().any(|terminal| app.contains(terminal))
+}
+
+/// Ctrl+V, or Shift+Insert in terminals.
+fn paste_keys(terminal: bool) -> (Key, Key) {
+    if terminal {
+        (Key::KEY_LEFTSHIFT, Key::KEY_INSERT)
+    } else {
+        (Key::KEY_LEFTCTRL, Key::KEY_V)
+    }
+}
+
+fn paste(keyboard: &mut dyn EventEmitter, terminal: bool) -> Result<()> {
+    let (modifier, key) = paste_keys(terminal);
+    hold_key(keyboard, modifier, true)?;
+    tap_key(keyboard, key)?;
+    hold_key(keyboard, modifier, false)
+}
+
+/// What `selection` holds, as text when it's offered as text; `None` when
+/// it's empty.
+fn save(socket: &Path, selection: Selection) -> Result<Option<Contents>> {
+    let Some(types) = output(wl_clipboard("wl-paste", socket, selection).arg("--list-types"))? else {
+        return Ok(None);
+    };
+    let types = String::from_utf8_lossy(&types);
+    let Some(mime) = types.lines().find(|&mime| mime == TEXT).or_else(|| types.lines().next()) else {
+        return Ok(None);
+    };
+    let data = output(wl_clipboard("wl-paste", socket, selection).args(["--no-newline", "--type", mime]))?;
+    Ok(data.map(|data| Contents { mime: mime.to_string(), data }))
+}
+
+/// Put `contents` in `selection`, or clear it.
+fn copy(socket: &Path, selection: Selection, contents: Option<&Contents>) -> Result<()> {
+    let mut command = wl_clipboard("wl-copy", socket, selection);
+    let Some(contents) = contents else {
+        let status = wait(&mut spawn(command.arg("--clear"))?, "wl-copy")?;
+        anyhow::ensure!(status.success(), "wl-copy --clear failed: {}", status);
+        return Ok(());
+    };
+    let mut child = spawn(command.args(["--type", &contents.mime]).stdin(Stdio::piped()))?;
+    let written = child.stdin.take().context("wl-copy's stdin")?.write_all(&contents.data);
+    let status = wait(&mut child, "wl-copy")?;
+    written.context("writing to wl-copy")?;
+    anyhow::ensure!(status.success(), "wl-copy failed: {}", status);
+    Ok(())
+}
+
+/// `program` of wl-clipboard on `selection`, talking to the compositor at
+/// `socket` whatever our own environment says.
+fn wl_clipboard(program: &str, socket: &Path, selection: Selection) -> Command {
+    let mut command = Command::new(program);
+    command.args(selection.args()).env("WAYLAND_DISPLAY", socket);
+    if let Some(dir) = socket.parent() {
+        command.env("XDG_RUNTIME_DIR", dir);
+    }
+    // wl-copy stays in the background to serve the pick; it mustn't hold a
+    // pipe of ours open
+    command.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null());
+    command
+}
+
+fn spawn(command: &mut Command) -> Result<Child> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    command.spawn().with_context(|| format!("running {} (is wl-clipboard installed?)", program))
+}
+
+/// What `command` prints, `None` when it fails (wl-paste on an empty
+/// selection).
+fn output(command: &mut Command) -> Result<Option<Vec<u8>>> {
+    let mut child = spawn(command.stdout(Stdio::piped()))?;
+    let mut stdout = child.stdout.take().context("wl-paste's stdout")?;
+    // read while it runs: a big selection (an image) fills the pipe
+    let reader = std::thread::spawn(move || {
+        let mut data = Vec::new();
+        stdout.read_to_end(&mut data).map(|_| data)
+    });
+    let status = wait(&mut child, "wl-paste")?;
+    let data = reader.join().map_err(|_| anyhow::anyhow!("reading wl-paste panicked"))?.context("reading wl-paste")?;
+    Ok(status.success().then_some(data))
+}
+
+/// Wait up to [`TOOL_TIMEOUT`] for `child` to exit, killing it after that.
+fn wait(child: &mut Child, program: &str) -> Result<ExitStatus> {
+    let deadline = Instant::now() + TOOL_TIMEOUT;
+    loop {
+        if let Some(status) = child.try_wait().with_context(|| format!("waiting for {}", program))? {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("{} didn't finish in {:?}", program, TOOL_TIMEOUT);
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev::{EventType, InputEvent};
+
+    struct RecordingEmitter(Vec<(u16, i32)>);
+
+    impl EventEmitter for RecordingEmitter {
+        fn emit_events(&mut self, events: &[InputEvent]) -> Result<()> {
+            let keys = events.iter().filter(|e| e.event_type() == EventType::KEY);
+            self.0.extend(keys.map(|e| (e.code(), e.value())));
+            Ok(())
+        }
+    }
+
+    // --- spec: paste chord ---
+
+    #[test]
+    fn terminals_paste_with_shift_insert() {
+        assert!(is_terminal("org.gnome.Terminal"));
+        assert!(is_terminal("kitty"));
+        assert!(is_terminal("foot"));
+        assert!(is_terminal("XTerm"));
+        assert!(!is_terminal("code-url-handler"));
+        assert!(!is_terminal("firefox"));
+
+        let mut keyboard = RecordingEmitter(Vec::new());
+        paste(&mut keyboard, true).unwrap();
+        let (shift, insert) = (Key::KEY_LEFTSHIFT.code(), Key::KEY_INSERT.code());
+        assert_eq!(keyboard.0, [(shift, 1), (insert, 1), (insert, 0), (shift, 0)]);
+    }
+
+    #[test]
+    fn other_apps_paste_with_ctrl_v() {
+        let mut keyboard = RecordingEmitter(Vec::new());
+        paste(&mut keyboard, false).unwrap();
+        let (ctrl, v) = (Key::KEY_LEFTCTRL.code(), Key::KEY_V.code());
+        assert_eq!(keyboard.0, [(ctrl, 1), (v, 1), (v, 0), (ctrl, 0)]);
+    }
+}
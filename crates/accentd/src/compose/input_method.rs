@@ -0,0 +1,214 @@
+//! `[compose] backend = "input-method"`: picks handed to the focused text
+//! field as an input method would (zwp_input_method_v2): the base deleted
+//! with delete_surrounding_text and the pick inserted with commit_string.
+//! No BackSpace, no hex code, nothing a toolkit can get wrong.
+//!
+//! The input method only exists for the moment of a pick, so the seat's
+//! slot stays free for a real one (fcitx5, IBus) the rest of the time.
+//! Fields without text-input-v3 (XWayland, some toolkits) never activate
+//! it; their picks fall back to Ctrl+Shift+U.
+
+use super::{EventEmitter, Reconnecting, TextBackend, DELAY_POPUP_HIDE};
+use anyhow::{Context, Result};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use std::time::{Duration, Instant};
+use tracing::info;
+use wayland_client::globals::GlobalListContents;
+use wayland_client::protocol::wl_registry::WlRegistry;
+use wayland_client::protocol::wl_seat::WlSeat;
+use wayland_client::{delegate_noop, Connection, Dispatch, EventQueue, QueueHandle};
+use wayland_protocols_misc::zwp_input_method_v2::client::zwp_input_method_manager_v2::ZwpInputMethodManagerV2;
+use wayland_protocols_misc::zwp_input_method_v2::client::zwp_input_method_v2::{self, ZwpInputMethodV2};
+
+/// How long the focused field has to take the input method before the
+/// pick goes to Ctrl+Shift+U.
+const ACTIVATE_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub struct InputMethod(Reconnecting<Session>);
+
+impl InputMethod {
+    pub fn new() -> Self {
+        Self(Reconnecting::new("Wayland input method", Session::connect))
+    }
+}
+
+impl TextBackend for InputMethod {
+    fn name(&self) -> &'static str {
+        "input-method"
+    }
+
+    fn type_text(&mut self, _: &mut dyn EventEmitter, _: Option<&str>, text: &str, erase: usize) -> Result<()> {
+        let session = self.0.get().context("not connected to the compositor")?;
+        std::thread::sleep(DELAY_POPUP_HIDE);
+        let typed = session.type_text(text, erase);
+        if session.lost {
+            self.0.lost();
+        }
+        typed
+    }
+}
+
+/// The compositor's input-method manager, on its first seat.
+struct Session {
+    queue: EventQueue<Field>,
+    manager: ZwpInputMethodManagerV2,
+    seat: WlSeat,
+    /// The connection failed; open a new one for the next pick.
+    lost: bool,
+}
+
+impl Session {
+    fn connect() -> Result<Self> {
+        let (globals, queue, socket) = super::connect_wayland::<Field>()?;
+        let qh = queue.handle();
+        let seat: WlSeat = globals.bind(&qh, 1..=1, ()).context("the compositor has no seat")?;
+        let manager: ZwpInputMethodManagerV2 =
+            globals.bind(&qh, 1..=1, ()).context("the compositor doesn't offer zwp_input_method_v2")?;
+        info!(socket = %socket.display(), "typing picks as a Wayland input method where fields take one");
+        Ok(Self { queue, manager, seat, lost: false })
+    }
+
+    /// Become the seat's input method, replace the `erase` characters
+    /// before the cursor with `text` in the focused field, and step down.
+    fn type_text(&mut self, text: &str, erase: usize) -> Result<()> {
+        let qh = self.queue.handle();
+        let input_method = self.manager.get_input_method(&self.seat, &qh, ());
+        let mut field = Field::default();
+        let typed = self.wait_active(&mut field).and_then(|()| {
+            let before = field.bytes_before_cursor(erase)?;
+            input_method.delete_surrounding_text(before as u32, 0);
+            input_method.commit_string(text.to_string());
+            input_method.commit(field.done);
+            Ok(())
+        });
+        input_method.destroy();
+        // once committed the pick is typed, whatever happens to the connection
+        self.lost = self.queue.roundtrip(&mut field).is_err();
+        typed
+    }
+
+    /// Dispatch events until the focused field has activated the input
+    /// method, or it can't.
+    fn wait_active(&mut self, field: &mut Field) -> Result<()> {
+        let deadline = Instant::now() + ACTIVATE_TIMEOUT;
+        loop {
+            self.queue.dispatch_pending(field).context("reading Wayland events")?;
+            anyhow::ensure!(!field.unavailable, "another input method is running");
+            if field.active {
+                return Ok(());
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            anyhow::ensure!(!left.is_zero(), "the focused field takes no input method");
+            self.queue.flush().context("writing Wayland requests")?;
+            let Some(guard) = self.queue.prepare_read() else {
+                continue;
+            };
+            let mut fds = [PollFd::new(guard.connection_fd(), PollFlags::POLLIN)];
+            if poll(&mut fds, PollTimeout::try_from(left).unwrap_or(PollTimeout::MAX))? > 0 {
+                guard.read().context("reading Wayland events")?;
+            }
+        }
+    }
+}
+
+/// The focused text field, as the input method's events describe it.
+/// Values take effect on `done`.
+#[derive(Debug, Default)]
+struct Field {
+    active: bool,
+    unavailable: bool,
+    /// The text around the cursor and the cursor's byte offset in it.
+    surrounding: Option<(String, usize)>,
+    /// How many `done` events came, for `commit`.
+    done: u32,
+    pending_active: Option<bool>,
+    pending_surrounding: Option<(String, usize)>,
+}
+
+impl Field {
+    /// How many bytes the `erase` characters before the cursor take up,
+    /// from the surrounding text; without it, no way to know.
+    fn bytes_before_cursor(&self, erase: usize) -> Result<usize> {
+        if erase == 0 {
+            return Ok(0);
+        }
+        let (text, cursor) = self.surrounding.as_ref().context("the focused field doesn't tell its text")?;
+        let before = text.get(..*cursor).context("cursor outside the surrounding text")?;
+        let start =
+            before.char_indices().rev().nth(erase - 1).context("fewer characters before the cursor than to erase")?.0;
+        Ok(before.len() - start)
+    }
+}
+
+impl Dispatch<ZwpInputMethodV2, ()> for Field {
+    fn event(
+        field: &mut Self,
+        _: &ZwpInputMethodV2,
+        event: zwp_input_method_v2::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwp_input_method_v2::Event::Activate => {
+                field.pending_active = Some(true);
+                field.pending_surrounding = None;
+            }
+            zwp_input_method_v2::Event::Deactivate => field.pending_active = Some(false),
+            zwp_input_method_v2::Event::SurroundingText { text, cursor, .. } => {
+                field.pending_surrounding = Some((text, cursor as usize));
+            }
+            zwp_input_method_v2::Event::Done => {
+                field.done += 1;
+                if let Some(active) = field.pending_active.take() {
+                    field.active = active;
+                    field.surrounding = None;
+                }
+                if let Some(surrounding) = field.pending_surrounding.take() {
+                    field.surrounding = Some(surrounding);
+                }
+            }
+            zwp_input_method_v2::Event::Unavailable => field.unavailable = true,
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for Field {
+    fn event(
+        _: &mut Self,
+        _: &WlRegistry,
+        _: <WlRegistry as wayland_client::Proxy>::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+delegate_noop!(Field: ignore WlSeat);
+delegate_noop!(Field: ZwpInputMethodManagerV2);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // --- spec: the base is deleted by its size in bytes ---
+
+    #[test]
+    fn erase_counts_bytes_of_the_characters_before_the_cursor() {
+        let field = Field { surrounding: Some(("où e".to_string(), 5)), ..Default::default() };
+        assert_eq!(field.bytes_before_cursor(1).unwrap(), 1);
+        assert_eq!(field.bytes_before_cursor(3).unwrap(), 4, "e, the space and the 2-byte ù");
+        let cyrillic = Field { surrounding: Some(("е".to_string(), 2)), ..Default::default() };
+        assert_eq!(cyrillic.bytes_before_cursor(1).unwrap(), 2);
+        assert!(cyrillic.bytes_before_cursor(2).is_err());
+    }
+
+    #[test]
+    fn erase_needs_the_surrounding_text() {
+        let field = Field::default();
+        assert_eq!(field.bytes_before_cursor(0).unwrap(), 0);
+        assert!(field.bytes_before_cursor(1).is_err());
+    }
+}
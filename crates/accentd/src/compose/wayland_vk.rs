@@ -0,0 +1,167 @@
+//! `[compose] backend = "wayland-vk"`: picks typed by a Wayland virtual
+//! keyboard (zwp_virtual_keyboard_v1) whose keymap is made up for each pick,
+//! BackSpace and every character of the pick on a key of its own. One
+//! keypress per character, understood by every toolkit.
+
+use super::{EventEmitter, Reconnecting, TextBackend, DELAY_POPUP_HIDE};
+use anyhow::{Context, Result};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::os::fd::AsFd;
+use std::time::Instant;
+use tracing::info;
+use wayland_client::globals::GlobalListContents;
+use wayland_client::protocol::wl_keyboard::KeymapFormat;
+use wayland_client::protocol::wl_registry::WlRegistry;
+use wayland_client::protocol::wl_seat::WlSeat;
+use wayland_client::{delegate_noop, Connection, Dispatch, EventQueue, QueueHandle};
+use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::zwp_virtual_keyboard_manager_v1::ZwpVirtualKeyboardManagerV1;
+use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::zwp_virtual_keyboard_v1::ZwpVirtualKeyboardV1;
+
+/// Evdev code of BackSpace in our keymaps; the pick's characters follow.
+const BACKSPACE: u32 = 1;
+
+/// XKB keycodes are evdev's plus 8, and stop at 255.
+const MAX_KEYS: usize = 255 - 8;
+
+pub struct WaylandVk(Reconnecting<Keyboard>);
+
+impl WaylandVk {
+    pub fn new() -> Self {
+        Self(Reconnecting::new("Wayland virtual keyboard", Keyboard::connect))
+    }
+}
+
+impl TextBackend for WaylandVk {
+    fn name(&self) -> &'static str {
+        "wayland-vk"
+    }
+
+    fn type_text(&mut self, _: &mut dyn EventEmitter, _: Option<&str>, text: &str, erase: usize) -> Result<()> {
+        let keyboard = self.0.get().context("not connected to the compositor")?;
+        std::thread::sleep(DELAY_POPUP_HIDE);
+        let typed = keyboard.type_text(text, erase);
+        if typed.is_err() {
+            self.0.lost();
+        }
+        typed
+    }
+}
+
+/// A virtual keyboard on the compositor's first seat.
+struct Keyboard {
+    queue: EventQueue<State>,
+    keyboard: ZwpVirtualKeyboardV1,
+    /// Key event times count from here.
+    started: Instant,
+}
+
+impl Keyboard {
+    fn connect() -> Result<Self> {
+        let (globals, queue, socket) = super::connect_wayland::<State>()?;
+        let qh = queue.handle();
+        let seat: WlSeat = globals.bind(&qh, 1..=1, ()).context("the compositor has no seat")?;
+        let manager: ZwpVirtualKeyboardManagerV1 =
+            globals.bind(&qh, 1..=1, ()).context("the compositor doesn't offer zwp_virtual_keyboard_v1")?;
+        let keyboard = manager.create_virtual_keyboard(&seat, &qh, ());
+        info!(socket = %socket.display(), "typing picks through a Wayland virtual keyboard");
+        Ok(Self { queue, keyboard, started: Instant::now() })
+    }
+
+    fn type_text(&mut self, text: &str, erase: usize) -> Result<()> {
+        let (keymap, chars) = keymap(text)?;
+        let fd = memfd_create(c"accentd-keymap", MemFdCreateFlag::MFD_CLOEXEC).context("creating the keymap file")?;
+        let mut file = std::fs::File::from(fd);
+        file.write_all(keymap.as_bytes()).context("writing the keymap")?;
+        self.keyboard.keymap(KeymapFormat::XkbV1.into(), file.as_fd(), keymap.len() as u32);
+        self.keyboard.modifiers(0, 0, 0, 0);
+        let codes = std::iter::repeat_n(BACKSPACE, erase).chain(text.chars().map(|c| keycode(&chars, c)));
+        for code in codes {
+            let time = self.started.elapsed().as_millis() as u32;
+            self.keyboard.key(time, code, 1);
+            self.keyboard.key(time, code, 0);
+        }
+        // the compositor has handled it all before keys are relayed again
+        self.queue.roundtrip(&mut State).context("waiting for the compositor")?;
+        Ok(())
+    }
+}
+
+/// An XKB keymap with BackSpace on evdev key [`BACKSPACE`] and the
+/// characters of `text` on the keys after it, in order of first appearance,
+/// NUL-terminated; and that order.
+fn keymap(text: &str) -> Result<(String, Vec<char>)> {
+    let mut chars: Vec<char> = Vec::new();
+    for c in text.chars() {
+        if !chars.contains(&c) {
+            chars.push(c);
+        }
+    }
+    anyhow::ensure!(chars.len() < MAX_KEYS, "{} different characters don't fit on a keyboard", chars.len());
+    let keysyms: Vec<String> =
+        std::iter::once("BackSpace".to_string()).chain(chars.iter().map(|&c| format!("U{:04X}", c as u32))).collect();
+
+    let mut keymap = String::from("xkb_keymap {\nxkb_keycodes \"accentd\" {\n");
+    let _ = writeln!(keymap, "minimum = 8;\nmaximum = {};", keysyms.len() + 8);
+    for i in 1..=keysyms.len() {
+        let _ = writeln!(keymap, "<K{}> = {};", i, i + 8);
+    }
+    keymap.push_str("};\nxkb_types \"accentd\" { include \"complete\" };\n");
+    keymap.push_str("xkb_compatibility \"accentd\" { include \"complete\" };\nxkb_symbols \"accentd\" {\n");
+    for (i, keysym) in keysyms.iter().enumerate() {
+        let _ = writeln!(keymap, "key <K{}> {{ [ {} ] }};", i + 1, keysym);
+    }
+    keymap.push_str("};\n};\n\0");
+    Ok((keymap, chars))
+}
+
+/// The evdev code `c` has in the keymap for `chars`.
+fn keycode(chars: &[char], c: char) -> u32 {
+    let index = chars.iter().position(|&k| k == c).unwrap_or_default();
+    BACKSPACE + 1 + index as u32
+}
+
+struct State;
+
+impl Dispatch<WlRegistry, GlobalListContents> for State {
+    fn event(
+        _: &mut Self,
+        _: &WlRegistry,
+        _: <WlRegistry as wayland_client::Proxy>::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+delegate_noop!(State: ignore WlSeat);
+delegate_noop!(State: ZwpVirtualKeyboardManagerV1);
+delegate_noop!(State: ZwpVirtualKeyboardV1);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // --- spec: a keymap per pick ---
+
+    #[test]
+    fn keymap_has_backspace_then_each_character_once() {
+        let (keymap, chars) = keymap("a😀a").unwrap();
+        assert_eq!(chars, ['a', '😀']);
+        assert!(keymap.contains("<K1> = 9;"));
+        assert!(keymap.contains("key <K1> { [ BackSpace ] };"));
+        assert!(keymap.contains("key <K2> { [ U0061 ] };"));
+        assert!(keymap.contains("key <K3> { [ U1F600 ] };"), "outside the BMP too");
+        assert!(keymap.contains("maximum = 11;"));
+        assert!(keymap.ends_with('\0'));
+        assert_eq!((keycode(&chars, 'a'), keycode(&chars, '😀')), (2, 3));
+    }
+
+    #[test]
+    fn too_many_characters_for_a_keymap_are_an_error() {
+        let text: String = ('一'..).take(MAX_KEYS).collect();
+        assert!(keymap(&text).is_err());
+    }
+}
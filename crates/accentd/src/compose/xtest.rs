@@ -0,0 +1,191 @@
+//! `[compose] backend = "xtest"`: picks typed into the X server with the
+//! XTEST extension, as xdotool does. Characters the keyboard mapping lacks
+//! are put on unused keycodes for the moment they're typed.
+
+use super::{EventEmitter, Reconnecting, TextBackend, DELAY_POPUP_HIDE};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::info;
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{ConnectionExt as _, Keycode, Keysym, Window, KEY_PRESS_EVENT, KEY_RELEASE_EVENT};
+use x11rb::protocol::xtest::ConnectionExt as _;
+use x11rb::rust_connection::RustConnection;
+
+/// Apps look a keycode up when its event reaches them, which can be after
+/// the X server has sent it; its keysym stays that long.
+const REMAP_HOLD: Duration = Duration::from_millis(50);
+
+const XK_BACKSPACE: Keysym = 0xff08;
+const XK_TAB: Keysym = 0xff09;
+const XK_RETURN: Keysym = 0xff0d;
+const NO_SYMBOL: Keysym = 0;
+
+pub struct XTest(Reconnecting<Display>);
+
+impl XTest {
+    pub fn new() -> Self {
+        Self(Reconnecting::new("X display with XTEST", Display::connect))
+    }
+}
+
+impl TextBackend for XTest {
+    fn name(&self) -> &'static str {
+        "xtest"
+    }
+
+    fn type_text(&mut self, _: &mut dyn EventEmitter, _: Option<&str>, text: &str, erase: usize) -> Result<()> {
+        let display = self.0.get().context("not connected to the X server")?;
+        std::thread::sleep(DELAY_POPUP_HIDE);
+        let typed = display.type_text(text, erase);
+        if typed.is_err() {
+            self.0.lost();
+        }
+        typed
+    }
+}
+
+/// A connection to `$DISPLAY`'s X server.
+struct Display {
+    conn: RustConnection,
+    root: Window,
+}
+
+impl Display {
+    fn connect() -> Result<Self> {
+        let (conn, screen) = x11rb::connect(None).context("connecting to the X display in $DISPLAY")?;
+        conn.xtest_get_version(2, 2)?.reply().context("the X server doesn't offer XTEST")?;
+        let root = conn.setup().roots[screen].root;
+        info!("typing picks with XTEST");
+        Ok(Self { conn, root })
+    }
+
+    fn type_text(&mut self, text: &str, erase: usize) -> Result<()> {
+        let (first, last) = (self.conn.setup().min_keycode, self.conn.setup().max_keycode);
+        let mapping = self.conn.get_keyboard_mapping(first, last - first + 1)?.reply()?;
+        let keysyms: Vec<Keysym> = std::iter::repeat_n(XK_BACKSPACE, erase).chain(text.chars().map(keysym)).collect();
+        let plan = Plan::new(&mapping.keysyms, mapping.keysyms_per_keycode, first, &keysyms)?;
+
+        let per_keycode = mapping.keysyms_per_keycode;
+        for &(keycode, keysym) in &plan.remaps {
+            // every column, so neither Shift nor the layout group changes it
+            self.conn.change_keyboard_mapping(1, keycode, per_keycode, &vec![keysym; per_keycode as usize])?;
+        }
+        for &keycode in &plan.keycodes {
+            self.conn.xtest_fake_input(KEY_PRESS_EVENT, keycode, 0, self.root, 0, 0, 0)?;
+            self.conn.xtest_fake_input(KEY_RELEASE_EVENT, keycode, 0, self.root, 0, 0, 0)?;
+        }
+        self.sync()?;
+        if !plan.remaps.is_empty() {
+            std::thread::sleep(REMAP_HOLD);
+            for &(keycode, _) in &plan.remaps {
+                self.conn.change_keyboard_mapping(1, keycode, per_keycode, &vec![NO_SYMBOL; per_keycode as usize])?;
+            }
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the X server to have handled everything sent.
+    fn sync(&self) -> Result<()> {
+        self.conn.get_input_focus()?.reply().context("waiting for the X server")?;
+        Ok(())
+    }
+}
+
+/// The keysym that types `c`: Latin-1 ones are their code point, the rest
+/// of Unicode is offset by 0x01000000.
+fn keysym(c: char) -> Keysym {
+    match c {
+        '\n' => XK_RETURN,
+        '\t' => XK_TAB,
+        ' '..='~' | '\u{a0}'..='\u{ff}' => c as Keysym,
+        _ => 0x0100_0000 + c as Keysym,
+    }
+}
+
+/// The keycodes to tap for a run of keysyms, and the unused keycodes that
+/// must get a keysym first.
+#[derive(Debug, PartialEq)]
+struct Plan {
+    keycodes: Vec<Keycode>,
+    remaps: Vec<(Keycode, Keysym)>,
+}
+
+impl Plan {
+    /// From the keyboard mapping of the keycodes from `first` on,
+    /// `per_keycode` keysyms each: a keysym the mapping has unshifted is
+    /// typed with its keycode, any other goes on a keycode with none.
+    fn new(mapping: &[Keysym], per_keycode: u8, first: Keycode, keysyms: &[Keysym]) -> Result<Self> {
+        let rows = mapping.chunks(per_keycode.max(1) as usize).zip(first..=Keycode::MAX);
+        let mut unused =
+            rows.clone().filter(|(row, _)| row.iter().all(|&k| k == NO_SYMBOL)).map(|(_, keycode)| keycode);
+        let mut keycode_of: HashMap<Keysym, Keycode> = HashMap::new();
+        let mut plan = Plan { keycodes: Vec::new(), remaps: Vec::new() };
+        for &keysym in keysyms {
+            let keycode = match keycode_of.get(&keysym) {
+                Some(&keycode) => keycode,
+                None => {
+                    let keycode = match rows.clone().find(|(row, _)| row.first() == Some(&keysym)) {
+                        Some((_, keycode)) => keycode,
+                        None => {
+                            let keycode = unused.next().context("no unused keycode left to type the pick with")?;
+                            plan.remaps.push((keycode, keysym));
+                            keycode
+                        }
+                    };
+                    keycode_of.insert(keysym, keycode);
+                    keycode
+                }
+            };
+            plan.keycodes.push(keycode);
+        }
+        Ok(plan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // --- spec: keysyms ---
+
+    #[test]
+    fn latin1_keysyms_are_their_code_point_and_the_rest_offset() {
+        assert_eq!(keysym('e'), 0x65);
+        assert_eq!(keysym('é'), 0xe9);
+        assert_eq!(keysym('œ'), 0x0100_0153);
+        assert_eq!(keysym('😀'), 0x0101_f600);
+        assert_eq!(keysym('\n'), XK_RETURN);
+    }
+
+    // --- spec: temporary keycodes ---
+
+    /// Keycodes 8 to 12, two columns: BackSpace, e/E, free, é/É, free.
+    const MAPPING: [Keysym; 10] = [XK_BACKSPACE, 0, 0x65, 0x45, 0, 0, 0xe9, 0xc9, 0, 0];
+
+    #[test]
+    fn mapped_keysyms_use_their_keycode() {
+        let plan = Plan::new(&MAPPING, 2, 8, &[XK_BACKSPACE, 0xe9]).unwrap();
+        assert_eq!(plan, Plan { keycodes: vec![8, 11], remaps: vec![] });
+    }
+
+    #[test]
+    fn missing_keysyms_go_on_unused_keycodes_once_each() {
+        let (oe, e_macron) = (keysym('œ'), keysym('ē'));
+        let plan = Plan::new(&MAPPING, 2, 8, &[XK_BACKSPACE, oe, e_macron, oe]).unwrap();
+        assert_eq!(plan, Plan { keycodes: vec![8, 10, 12, 10], remaps: vec![(10, oe), (12, e_macron)] });
+    }
+
+    #[test]
+    fn shifted_keysyms_count_as_missing() {
+        let plan = Plan::new(&MAPPING, 2, 8, &[0x45]).unwrap();
+        assert_eq!(plan, Plan { keycodes: vec![10], remaps: vec![(10, 0x45)] });
+    }
+
+    #[test]
+    fn running_out_of_unused_keycodes_is_an_error() {
+        let keysyms = ['ā', 'ē', 'ī'].map(keysym);
+        assert!(Plan::new(&MAPPING, 2, 8, &keysyms).is_err());
+    }
+}
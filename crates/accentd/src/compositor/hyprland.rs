@@ -0,0 +1,194 @@
+use super::FocusedWindow;
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::sync::watch;
+
+/// socket2 events after which the focused window or output may have changed.
+const FOCUS_EVENTS: &[&str] = &[
+    "activewindow",
+    "activewindowv2",
+    "closewindow",
+    "fullscreen",
+    "focusedmon",
+    "windowtitle",
+];
+
+/// Locate the instance directory holding `.socket.sock` (hyprctl requests)
+/// and `.socket2.sock` (event stream).
+pub fn find_instance() -> Option<PathBuf> {
+    if let (Ok(runtime), Ok(sig)) = (
+        std::env::var("XDG_RUNTIME_DIR"),
+        std::env::var("HYPRLAND_INSTANCE_SIGNATURE"),
+    ) {
+        return Some(Path::new(&runtime).join("hypr").join(sig));
+    }
+    super::user_runtime_dirs().iter().find_map(|dir| {
+        std::fs::read_dir(dir.join("hypr"))
+            .ok()?
+            .flatten()
+            .map(|e| e.path())
+            .find(|p| p.join(".socket2.sock").exists())
+    })
+}
+
+/// Publish the focused window and keyboard layout group, then re-query them
+/// on every relevant socket2 event until the connection drops.
+pub async fn run(
+    instance: &Path,
+    tx: &watch::Sender<Option<FocusedWindow>>,
+    layout_tx: &watch::Sender<Option<usize>>,
+) -> Result<()> {
+    let events = UnixStream::connect(instance.join(".socket2.sock"))
+        .await
+        .context("connecting to Hyprland event socket")?;
+    let mut lines = BufReader::new(events).lines();
+
+    publish(instance, tx).await?;
+    publish_layout(instance, layout_tx).await?;
+
+    while let Some(line) = lines.next_line().await.context("reading Hyprland events")? {
+        let Some((event, _)) = line.split_once(">>") else {
+            continue;
+        };
+        if FOCUS_EVENTS.contains(&event) {
+            publish(instance, tx).await?;
+        } else if event == "activelayout" {
+            publish_layout(instance, layout_tx).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn publish(instance: &Path, tx: &watch::Sender<Option<FocusedWindow>>) -> Result<()> {
+    let window = query(instance, "j/activewindow").await?;
+    let monitors = query(instance, "j/monitors").await?;
+    let focus = window_from_json(&window).map(|mut w| {
+        w.output = focused_monitor(&monitors);
+        w
+    });
+    tx.send_if_modified(|current| {
+        if *current == focus {
+            return false;
+        }
+        *current = focus;
+        true
+    });
+    Ok(())
+}
+
+async fn publish_layout(instance: &Path, tx: &watch::Sender<Option<usize>>) -> Result<()> {
+    let devices = query(instance, "j/devices").await?;
+    if let Some(group) = layout_group(&devices) {
+        tx.send_if_modified(|current| current.replace(group) != Some(group));
+    }
+    Ok(())
+}
+
+/// One hyprctl request: write the command, read the JSON reply until EOF.
+async fn query(instance: &Path, command: &str) -> Result<Value> {
+    let mut stream = UnixStream::connect(instance.join(".socket.sock"))
+        .await
+        .context("connecting to Hyprland request socket")?;
+    stream.write_all(command.as_bytes()).await?;
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await?;
+    serde_json::from_slice(&reply).with_context(|| format!("parsing reply to {}", command))
+}
+
+/// `j/activewindow` returns `{}` when nothing is focused.
+fn window_from_json(window: &Value) -> Option<FocusedWindow> {
+    let class = window["class"].as_str()?.to_string();
+    let xwayland = window["xwayland"].as_bool().unwrap_or(false);
+    // Older releases report a bool; newer ones 0 (none), 1 (maximized), 2 (fullscreen).
+    let fullscreen = match &window["fullscreen"] {
+        Value::Bool(b) => *b,
+        v => v.as_u64().unwrap_or(0) >= 2,
+    };
+    Some(FocusedWindow {
+        app_id: (!xwayland).then(|| class.clone()),
+        class: xwayland.then_some(class),
+        title: window["title"].as_str().map(String::from),
+        fullscreen,
+        output: None,
+    })
+}
+
+fn focused_monitor(monitors: &Value) -> Option<String> {
+    monitors
+        .as_array()?
+        .iter()
+        .find(|m| m["focused"] == true)?["name"]
+        .as_str()
+        .map(String::from)
+}
+
+/// The main keyboard's active layout group. Releases before the index was
+/// reported give nothing.
+fn layout_group(devices: &Value) -> Option<usize> {
+    let keyboards = devices["keyboards"].as_array()?;
+    let main = keyboards.iter().find(|k| k["main"] == true).or(keyboards.first())?;
+    main["active_layout_index"].as_u64().map(|i| i as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn wayland_window_uses_class_as_app_id() {
+        let window = json!({"class": "kitty", "title": "~", "xwayland": false, "fullscreen": 0});
+        let focus = window_from_json(&window).unwrap();
+        assert_eq!(focus.app_id.as_deref(), Some("kitty"));
+        assert!(focus.class.is_none());
+        assert!(!focus.fullscreen);
+    }
+
+    #[test]
+    fn xwayland_window_reports_class() {
+        let window = json!({"class": "steam_app_1", "title": "Game", "xwayland": true, "fullscreen": 2});
+        let focus = window_from_json(&window).unwrap();
+        assert!(focus.app_id.is_none());
+        assert_eq!(focus.app(), Some("steam_app_1"));
+        assert!(focus.fullscreen);
+    }
+
+    #[test]
+    fn maximized_is_not_fullscreen() {
+        let window = json!({"class": "firefox", "fullscreen": 1});
+        assert!(!window_from_json(&window).unwrap().fullscreen);
+    }
+
+    #[test]
+    fn legacy_bool_fullscreen_is_understood() {
+        let window = json!({"class": "mpv", "fullscreen": true});
+        assert!(window_from_json(&window).unwrap().fullscreen);
+    }
+
+    #[test]
+    fn empty_reply_means_no_focus() {
+        assert!(window_from_json(&json!({})).is_none());
+    }
+
+    #[test]
+    fn main_keyboard_gives_the_layout_group() {
+        let devices = json!({"keyboards": [
+            {"name": "power-button", "active_layout_index": 0, "main": false},
+            {"name": "at-translated-set-2-keyboard", "layout": "us,it", "active_layout_index": 1, "main": true}
+        ]});
+        assert_eq!(layout_group(&devices), Some(1));
+        assert_eq!(layout_group(&json!({"keyboards": [{"name": "kbd"}]})), None);
+    }
+
+    #[test]
+    fn finds_focused_monitor_name() {
+        let monitors = json!([
+            {"name": "eDP-1", "focused": false},
+            {"name": "DP-2", "focused": true}
+        ]);
+        assert_eq!(focused_monitor(&monitors).as_deref(), Some("DP-2"));
+    }
+}
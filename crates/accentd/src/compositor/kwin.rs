@@ -0,0 +1,178 @@
+use super::FocusedWindow;
+use accentd_core::config;
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tracing::debug;
+use zbus::names::BusName;
+use zbus::Connection;
+
+const KWIN: &str = "org.kde.KWin";
+const PLUGIN: &str = "accentd-focus";
+const TRACKER_PATH: &str = "/accentd";
+
+/// How often to check that KWin is still on the bus.
+const LIVENESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// KWin script that reports focus changes back to us over D-Bus.
+/// Handles both Plasma 6 (windowActivated) and Plasma 5 (clientActivated).
+/// Each window's fullscreen changes are followed once, from its first
+/// activation until it closes.
+const SCRIPT: &str = r#"
+const SERVICE = "@SERVICE@";
+const followed = new Set();
+function report(w) {
+    if (!w) {
+        callDBus(SERVICE, "/accentd", "org.accentd.FocusTracker", "Clear");
+        return;
+    }
+    callDBus(SERVICE, "/accentd", "org.accentd.FocusTracker", "Update",
+             String(w.resourceClass), String(w.caption), !!w.fullScreen,
+             w.output ? String(w.output.name) : "");
+}
+function follow(w) {
+    report(w);
+    if (w && !followed.has(w)) {
+        followed.add(w);
+        w.fullScreenChanged.connect(function () {
+            if (workspace.activeWindow === w || workspace.activeClient === w) report(w);
+        });
+    }
+}
+function forget(w) {
+    followed.delete(w);
+}
+if (workspace.windowActivated) {
+    workspace.windowActivated.connect(follow);
+    workspace.windowRemoved.connect(forget);
+    follow(workspace.activeWindow);
+} else {
+    workspace.clientActivated.connect(follow);
+    workspace.clientRemoved.connect(forget);
+    follow(workspace.activeClient);
+}
+"#;
+
+/// D-Bus object the KWin script calls into.
+struct Tracker {
+    tx: mpsc::UnboundedSender<Option<FocusedWindow>>,
+}
+
+#[zbus::interface(name = "org.accentd.FocusTracker")]
+impl Tracker {
+    fn update(&self, app: String, title: String, fullscreen: bool, output: String) {
+        let _ = self.tx.send(Some(FocusedWindow {
+            app_id: Some(app).filter(|a| !a.is_empty()),
+            class: None,
+            title: Some(title),
+            fullscreen,
+            output: Some(output).filter(|o| !o.is_empty()),
+        }));
+    }
+
+    fn clear(&self) {
+        let _ = self.tx.send(None);
+    }
+}
+
+/// Load the focus script into KWin and forward its reports until KWin leaves the bus.
+pub async fn run(bus: &Path, tx: &watch::Sender<Option<FocusedWindow>>) -> Result<()> {
+    let (report_tx, mut report_rx) = mpsc::unbounded_channel();
+    let conn = zbus::connection::Builder::address(format!("unix:path={}", bus.display()).as_str())?
+        .serve_at(TRACKER_PATH, Tracker { tx: report_tx })?
+        .build()
+        .await
+        .with_context(|| format!("connecting to session bus {}", bus.display()))?;
+
+    let dbus = zbus::fdo::DBusProxy::new(&conn).await?;
+    let kwin = BusName::try_from(KWIN)?;
+    if !dbus.name_has_owner(kwin.clone()).await? {
+        anyhow::bail!("KWin is not running on this session bus");
+    }
+
+    let service = conn.unique_name().context("no unique bus name")?.to_string();
+    load_script(&conn, &service).await?;
+
+    let mut liveness = tokio::time::interval(LIVENESS_INTERVAL);
+    loop {
+        tokio::select! {
+            Some(focus) = report_rx.recv() => {
+                tx.send_if_modified(|current| {
+                    if *current == focus {
+                        return false;
+                    }
+                    *current = focus;
+                    true
+                });
+            }
+            _ = liveness.tick() => {
+                if !dbus.name_has_owner(kwin.clone()).await? {
+                    anyhow::bail!("KWin left the session bus");
+                }
+            }
+        }
+    }
+}
+
+async fn load_script(conn: &Connection, service: &str) -> Result<()> {
+    // KWin reads the script itself, so it must live somewhere the user can
+    // read (our private /tmp is not).
+    let path = config::socket_path()
+        .parent()
+        .map(|dir| dir.join("kwin-focus.js"))
+        .context("no runtime directory for the KWin script")?;
+    std::fs::write(&path, SCRIPT.replace("@SERVICE@", service))
+        .with_context(|| format!("writing {}", path.display()))?;
+
+    let scripting = zbus::Proxy::new(conn, KWIN, "/Scripting", "org.kde.kwin.Scripting").await?;
+    // Drop a copy left behind by a previous daemon instance
+    let _: bool = scripting.call("unloadScript", &(PLUGIN,)).await.unwrap_or(false);
+    let id: i32 = scripting
+        .call("loadScript", &(path.to_string_lossy().as_ref(), PLUGIN))
+        .await
+        .context("loading KWin script")?;
+
+    // Plasma 6 exposes scripts under /Scripting/ScriptN, Plasma 5 under /N
+    for script_path in [format!("/Scripting/Script{}", id), format!("/{}", id)] {
+        let script = zbus::Proxy::new(conn, KWIN, script_path.as_str(), "org.kde.kwin.Script").await?;
+        if script.call::<_, _, ()>("run", &()).await.is_ok() {
+            debug!(id, path = %script_path, "KWin focus script running");
+            return Ok(());
+        }
+    }
+    anyhow::bail!("KWin loaded the focus script but refused to run it")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reported(update: impl FnOnce(&Tracker)) -> Option<FocusedWindow> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        update(&Tracker { tx });
+        rx.try_recv().expect("a report")
+    }
+
+    #[test]
+    fn update_reports_the_window() {
+        let focus = reported(|t| t.update("firefox".into(), "Mozilla Firefox".into(), true, "DP-1".into())).unwrap();
+        assert_eq!(focus.app(), Some("firefox"));
+        assert_eq!(focus.title.as_deref(), Some("Mozilla Firefox"));
+        assert!(focus.fullscreen);
+        assert_eq!(focus.output.as_deref(), Some("DP-1"));
+    }
+
+    #[test]
+    fn empty_app_and_output_are_unknown() {
+        let focus = reported(|t| t.update(String::new(), "Desktop".into(), false, String::new())).unwrap();
+        assert!(focus.app_id.is_none());
+        assert!(focus.output.is_none());
+        assert!(!focus.fullscreen);
+    }
+
+    #[test]
+    fn clear_reports_no_focus() {
+        assert!(reported(Tracker::clear).is_none());
+    }
+}
@@ -0,0 +1,295 @@
+mod hyprland;
+mod kwin;
+pub mod sway;
+mod x11;
+
+use crate::{Shared, SuspendReason};
+use accentd_core::config::{CompositorConfig, FocusProvider};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
+use tracing::{debug, info};
+
+/// How long to wait before looking for a compositor again after it went away
+/// (or before the session started).
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// logind's state for the first seat, which has the console.
+const SEAT: &str = "/run/systemd/seats/seat0";
+
+/// What the compositor reports about the focused window.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FocusedWindow {
+    /// Wayland app_id (native Wayland clients).
+    pub app_id: Option<String>,
+    /// X11 WM_CLASS (X11 sessions and XWayland clients).
+    pub class: Option<String>,
+    pub title: Option<String>,
+    pub fullscreen: bool,
+    /// Name of the output the window is on, when the compositor reports it.
+    pub output: Option<String>,
+}
+
+impl FocusedWindow {
+    /// The best identifier for matching per-app rules: app_id, else class.
+    pub fn app(&self) -> Option<&str> {
+        self.app_id.as_deref().or(self.class.as_deref())
+    }
+
+    /// Whether focus went to a different window, as far as the compositor
+    /// tells. Going fullscreen or moving outputs keeps the same window.
+    pub fn is_other_window(&self, other: &FocusedWindow) -> bool {
+        self.app() != other.app() || self.title != other.title
+    }
+}
+
+/// Start tracking the focused window, keeping `Shared::focus` up to date and
+/// suspending hold detection over fullscreen windows if configured, and the
+/// active keyboard layout where the compositor reports it (sway, Hyprland).
+/// Providers push changes; nothing here polls.
+pub fn spawn(config: &CompositorConfig, shared: Arc<Mutex<Shared>>) {
+    let provider = config.provider;
+    let suspend_on_fullscreen = config.suspend_on_fullscreen;
+    if provider == FocusProvider::None {
+        return;
+    }
+
+    let (tx, mut rx) = watch::channel(None);
+    let (layout_tx, mut layout_rx) = watch::channel(None);
+
+    tokio::spawn(async move {
+        loop {
+            let result = match detect(provider) {
+                Some(Detected::Sway(socket)) => {
+                    info!(socket = %socket.display(), "tracking focus via sway IPC");
+                    sway::run(&socket, &tx, &layout_tx).await
+                }
+                Some(Detected::Hyprland(dir)) => {
+                    info!(instance = %dir.display(), "tracking focus via Hyprland IPC");
+                    hyprland::run(&dir, &tx, &layout_tx).await
+                }
+                Some(Detected::Kwin(bus)) => {
+                    debug!(bus = %bus.display(), "looking for KWin on the session bus");
+                    kwin::run(&bus, &tx).await
+                }
+                Some(Detected::X11(dpy)) => {
+                    info!(display = %dpy, "tracking focus via X11 EWMH");
+                    x11::run(&dpy, &tx).await
+                }
+                None => Ok(()),
+            };
+            if let Err(e) = result {
+                debug!(error = %e, "compositor IPC connection ended");
+            }
+            // Compositor gone or not started yet: forget the stale focus and retry
+            tx.send_replace(None);
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    });
+
+    let layout_shared = Arc::clone(&shared);
+    tokio::spawn(async move {
+        while layout_rx.changed().await.is_ok() {
+            // A compositor going away leaves the last layout in place
+            let Some(group) = *layout_rx.borrow_and_update() else {
+                continue;
+            };
+            crate::set_layout_group(&mut *layout_shared.lock().await, group);
+        }
+    });
+
+    tokio::spawn(async move {
+        while rx.changed().await.is_ok() {
+            let focus = rx.borrow_and_update().clone();
+            match &focus {
+                Some(w) => debug!(
+                    app = ?w.app(),
+                    title = ?w.title,
+                    fullscreen = w.fullscreen,
+                    output = ?w.output,
+                    "focus changed"
+                ),
+                None => debug!("focus unknown"),
+            }
+            let fullscreen = focus.as_ref().is_some_and(|w| w.fullscreen);
+            let mut shared = shared.lock().await;
+            // The accent would land in the wrong window; e.g. the user
+            // clicked elsewhere with the key still held
+            let moved = match (&shared.focus, &focus) {
+                (Some(old), Some(new)) => old.is_other_window(new),
+                _ => false,
+            };
+            if moved && crate::dismiss_popups(&mut shared) {
+                info!("focus changed, popup dismissed");
+            }
+            shared.focus = focus;
+            if suspend_on_fullscreen {
+                crate::set_suspended(&mut shared, SuspendReason::Fullscreen, fullscreen);
+            }
+        }
+    });
+}
+
+/// A running compositor and where to reach it.
+enum Detected {
+    Sway(PathBuf),
+    Hyprland(PathBuf),
+    /// Session bus socket; KWin may or may not be on it.
+    Kwin(PathBuf),
+    /// X display name.
+    X11(String),
+}
+
+fn detect(provider: FocusProvider) -> Option<Detected> {
+    let sway = || sway::find_socket().map(Detected::Sway);
+    let hyprland = || hyprland::find_instance().map(Detected::Hyprland);
+    let kwin = || find_session_bus().map(Detected::Kwin);
+    let x11 = || x11::find_display().map(Detected::X11);
+    match provider {
+        // X11 goes first: a $DISPLAY is only set for us when explicitly configured
+        // Any desktop has a session bus, so KWin only where Plasma runs
+        FocusProvider::Auto => {
+            x11().or_else(sway).or_else(hyprland).or_else(|| plasma_session().then(kwin).flatten())
+        }
+        FocusProvider::Sway => sway(),
+        FocusProvider::Hyprland => hyprland(),
+        FocusProvider::Kwin => kwin(),
+        FocusProvider::X11 => x11(),
+        FocusProvider::None => None,
+    }
+}
+
+/// Runtime directories of logged-in users, the active session's user's
+/// first. The daemon runs as a system service, so compositor sockets have to
+/// be found there rather than via the session's environment.
+fn user_runtime_dirs() -> Vec<PathBuf> {
+    if let Ok(dir) = std::env::var("XDG_RUNTIME_DIR") {
+        return vec![PathBuf::from(dir)];
+    }
+    let Ok(entries) = std::fs::read_dir("/run/user") else {
+        return Vec::new();
+    };
+    let mut dirs: Vec<PathBuf> = entries.flatten().map(|e| e.path()).collect();
+    let active = std::fs::read_to_string(SEAT).ok().and_then(|seat| active_uid(&seat));
+    // with two users logged in, the other one's sockets are the wrong session
+    dirs.sort_by_key(|dir| active.is_none_or(|uid| !dir.ends_with(uid.to_string())));
+    dirs
+}
+
+/// `ACTIVE_UID` of a logind seat file: whose session is in the foreground.
+fn active_uid(seat: &str) -> Option<u32> {
+    logind_field(seat, "ACTIVE_UID").and_then(|uid| uid.parse().ok())
+}
+
+/// Whether the session is Plasma: `XDG_CURRENT_DESKTOP` or
+/// `KDE_FULL_SESSION` when set for us, else the desktop logind recorded for
+/// the active session (`XDG_SESSION_DESKTOP`, which SDDM sets to `KDE`).
+fn plasma_session() -> bool {
+    if let Ok(desktop) = std::env::var("XDG_CURRENT_DESKTOP") {
+        return is_kde(&desktop);
+    }
+    if std::env::var_os("KDE_FULL_SESSION").is_some() {
+        return true;
+    }
+    let Some(seat) = std::fs::read_to_string(SEAT).ok() else {
+        return false;
+    };
+    let session = logind_field(&seat, "ACTIVE")
+        .and_then(|id| std::fs::read_to_string(Path::new("/run/systemd/sessions").join(id)).ok());
+    session.is_some_and(|session| logind_field(&session, "DESKTOP").is_some_and(is_kde))
+}
+
+/// A desktop name list (`KDE`, `KDE:plasma`) naming Plasma.
+fn is_kde(desktop: &str) -> bool {
+    desktop.split(':').any(|name| name.eq_ignore_ascii_case("kde") || name.eq_ignore_ascii_case("plasma"))
+}
+
+/// A `KEY=value` line of a logind state file.
+fn logind_field<'a>(file: &'a str, key: &str) -> Option<&'a str> {
+    file.lines().find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+}
+
+/// The Wayland compositor's socket: `$WAYLAND_DISPLAY` when set, else the
+/// first `wayland-*` socket in a user's runtime directory.
+pub fn find_wayland_socket() -> Option<PathBuf> {
+    let dirs = user_runtime_dirs();
+    if let Ok(display) = std::env::var("WAYLAND_DISPLAY") {
+        let path = PathBuf::from(&display);
+        return match dirs.first() {
+            Some(dir) if path.is_relative() => Some(dir.join(path)),
+            _ => Some(path),
+        };
+    }
+    dirs.iter().find_map(|dir| {
+        std::fs::read_dir(dir).ok()?.flatten().map(|e| e.path()).find(|p| {
+            p.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with("wayland-") && !n.ends_with(".lock"))
+        })
+    })
+}
+
+/// Find a user session bus socket. KWin itself is looked up once connected.
+pub fn find_session_bus() -> Option<PathBuf> {
+    user_runtime_dirs()
+        .into_iter()
+        .map(|dir| dir.join("bus"))
+        .find(|path| path.exists())
+}
+
+/// First file in `dir` whose name matches `prefix*suffix`.
+fn find_in_dir(dir: &Path, prefix: &str, suffix: &str) -> Option<PathBuf> {
+    std::fs::read_dir(dir).ok()?.flatten().map(|e| e.path()).find(|p| {
+        p.file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.starts_with(prefix) && n.ends_with(suffix))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(app_id: &str, title: &str) -> FocusedWindow {
+        FocusedWindow {
+            app_id: Some(app_id.into()),
+            title: Some(title.into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn other_app_or_title_is_another_window() {
+        let editor = window("org.gnome.TextEditor", "notes.txt");
+        assert!(editor.is_other_window(&window("firefox", "notes.txt")));
+        assert!(editor.is_other_window(&window("org.gnome.TextEditor", "todo.txt")));
+    }
+
+    #[test]
+    fn fullscreen_or_output_change_is_same_window() {
+        let editor = window("org.gnome.TextEditor", "notes.txt");
+        let moved = FocusedWindow {
+            fullscreen: true,
+            output: Some("HDMI-A-1".into()),
+            ..editor.clone()
+        };
+        assert!(!editor.is_other_window(&moved));
+    }
+
+    #[test]
+    fn active_uid_comes_from_the_seat_file() {
+        let seat = "# This is private data. Do not parse.\nIS_SEAT0=1\nACTIVE=2\nACTIVE_UID=1001\nSESSIONS=1 2\nUIDS=1000 1001\n";
+        assert_eq!(active_uid(seat), Some(1001));
+        assert_eq!(active_uid("IS_SEAT0=1\n"), None, "nobody in the foreground");
+    }
+
+    #[test]
+    fn plasma_is_known_by_its_desktop_name() {
+        let session = "UID=1000\nUSER=ana\nACTIVE=1\nTYPE=wayland\nCLASS=user\nDESKTOP=KDE\n";
+        assert_eq!(logind_field(session, "DESKTOP"), Some("KDE"));
+        assert_eq!(logind_field("ACTIVE_UID=1000\nACTIVE=1\n", "ACTIVE"), Some("1"), "not ACTIVE_UID");
+        assert!(is_kde("KDE"));
+        assert!(is_kde("KDE:plasma"));
+        assert!(!is_kde("GNOME"));
+        assert!(!is_kde("ubuntu:GNOME"));
+    }
+}
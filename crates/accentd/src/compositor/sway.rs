@@ -0,0 +1,267 @@
+use super::FocusedWindow;
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::watch;
+
+// i3/sway IPC framing: "i3-ipc" + u32 payload length + u32 message type (native endian).
+const MAGIC: &[u8; 6] = b"i3-ipc";
+const MSG_SUBSCRIBE: u32 = 2;
+const MSG_GET_TREE: u32 = 4;
+const MSG_GET_INPUTS: u32 = 100;
+const EVENT_WINDOW: u32 = 0x8000_0003;
+const EVENT_INPUT: u32 = 0x8000_0015;
+
+/// Locate the sway IPC socket: $SWAYSOCK, else a sway-ipc.*.sock in a user runtime dir.
+pub fn find_socket() -> Option<PathBuf> {
+    if let Ok(path) = std::env::var("SWAYSOCK") {
+        return Some(PathBuf::from(path));
+    }
+    super::user_runtime_dirs()
+        .iter()
+        .find_map(|dir| super::find_in_dir(dir, "sway-ipc.", ".sock"))
+}
+
+/// Publish the focused window and keyboard layout group, then follow window
+/// and input events until the connection drops.
+pub async fn run(
+    socket: &Path,
+    tx: &watch::Sender<Option<FocusedWindow>>,
+    layout_tx: &watch::Sender<Option<usize>>,
+) -> Result<()> {
+    let mut stream = UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("connecting to {}", socket.display()))?;
+
+    send(&mut stream, MSG_GET_TREE, b"").await?;
+    let (_, tree) = recv(&mut stream).await?;
+    let tree: Value = serde_json::from_slice(&tree).context("parsing sway tree")?;
+    tx.send_replace(find_focused(&tree).and_then(window_from_node));
+
+    send(&mut stream, MSG_GET_INPUTS, b"").await?;
+    let (_, inputs) = recv(&mut stream).await?;
+    let inputs: Value = serde_json::from_slice(&inputs).context("parsing sway inputs")?;
+    if let Some(group) = inputs.as_array().into_iter().flatten().find_map(layout_group) {
+        layout_tx.send_replace(Some(group));
+    }
+
+    send(&mut stream, MSG_SUBSCRIBE, br#"["window", "input"]"#).await?;
+    let (_, reply) = recv(&mut stream).await?;
+    let reply: Value = serde_json::from_slice(&reply).context("parsing subscribe reply")?;
+    if reply["success"] != true {
+        anyhow::bail!("sway refused window event subscription");
+    }
+
+    loop {
+        let (msg_type, payload) = recv(&mut stream).await?;
+        if msg_type != EVENT_WINDOW && msg_type != EVENT_INPUT {
+            continue;
+        }
+        let Ok(event) = serde_json::from_slice::<Value>(&payload) else {
+            continue;
+        };
+        if msg_type == EVENT_INPUT {
+            if let Some(group) = apply_input_event(&event) {
+                layout_tx.send_if_modified(|current| current.replace(group) != Some(group));
+            }
+            continue;
+        }
+        if let Some(focus) = apply_window_event(&event) {
+            tx.send_if_modified(|current| {
+                if *current == focus {
+                    return false;
+                }
+                *current = focus;
+                true
+            });
+        }
+    }
+}
+
+async fn send(stream: &mut UnixStream, msg_type: u32, payload: &[u8]) -> Result<()> {
+    stream.write_all(&encode(msg_type, payload)).await.context("writing sway IPC")
+}
+
+async fn recv(stream: &mut UnixStream) -> Result<(u32, Vec<u8>)> {
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header).await.context("reading sway IPC header")?;
+    let (len, msg_type) = decode_header(&header)?;
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await.context("reading sway IPC payload")?;
+    Ok((msg_type, payload))
+}
+
+/// `get_inputs` asked once, without a runtime: for startup, before the
+/// focus tracker runs.
+pub fn get_inputs(socket: &Path) -> Result<Value> {
+    use std::io::{Read, Write};
+
+    let mut stream = std::os::unix::net::UnixStream::connect(socket)
+        .with_context(|| format!("connecting to {}", socket.display()))?;
+    stream.set_read_timeout(Some(std::time::Duration::from_secs(1)))?;
+    stream.write_all(&encode(MSG_GET_INPUTS, b"")).context("writing sway IPC")?;
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header).context("reading sway IPC header")?;
+    let (len, _) = decode_header(&header)?;
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).context("reading sway IPC payload")?;
+    serde_json::from_slice(&payload).context("parsing sway inputs")
+}
+
+/// Payload length and message type.
+fn decode_header(header: &[u8; 14]) -> Result<(usize, u32)> {
+    if &header[..6] != MAGIC {
+        anyhow::bail!("bad sway IPC magic");
+    }
+    let len = u32::from_ne_bytes(header[6..10].try_into().unwrap()) as usize;
+    let msg_type = u32::from_ne_bytes(header[10..14].try_into().unwrap());
+    Ok((len, msg_type))
+}
+
+fn encode(msg_type: u32, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(14 + payload.len());
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+    buf.extend_from_slice(&msg_type.to_ne_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Depth-first search for the node with `"focused": true`.
+fn find_focused(node: &Value) -> Option<&Value> {
+    if node["focused"] == true {
+        return Some(node);
+    }
+    ["nodes", "floating_nodes"]
+        .iter()
+        .filter_map(|key| node[key].as_array())
+        .flatten()
+        .find_map(find_focused)
+}
+
+/// Turn a container into a FocusedWindow. Workspaces and outputs aren't windows.
+fn window_from_node(node: &Value) -> Option<FocusedWindow> {
+    let kind = node["type"].as_str()?;
+    if kind != "con" && kind != "floating_con" {
+        return None;
+    }
+    Some(FocusedWindow {
+        app_id: node["app_id"].as_str().map(String::from),
+        class: node["window_properties"]["class"].as_str().map(String::from),
+        title: node["name"].as_str().map(String::from),
+        fullscreen: node["fullscreen_mode"].as_u64().unwrap_or(0) > 0,
+        output: None,
+    })
+}
+
+/// Map a window event to the new focus state. `None` means "no change",
+/// `Some(None)` means nothing is focused anymore.
+fn apply_window_event(event: &Value) -> Option<Option<FocusedWindow>> {
+    let container = &event["container"];
+    match event["change"].as_str()? {
+        "focus" => Some(window_from_node(container)),
+        "title" | "fullscreen_mode" | "urgent" if container["focused"] == true => {
+            Some(window_from_node(container))
+        }
+        "close" if container["focused"] == true => Some(None),
+        _ => None,
+    }
+}
+
+/// A keyboard's active layout group, from its `get_inputs` entry.
+fn layout_group(input: &Value) -> Option<usize> {
+    if input["type"] != "keyboard" {
+        return None;
+    }
+    input["xkb_active_layout_index"].as_u64().map(|i| i as usize)
+}
+
+/// The layout group a keyboard switched to, if the event is a switch.
+fn apply_input_event(event: &Value) -> Option<usize> {
+    match event["change"].as_str()? {
+        "xkb_layout" | "xkb_keymap" => layout_group(&event["input"]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn encode_writes_magic_length_and_type() {
+        let buf = encode(MSG_SUBSCRIBE, b"[]");
+        assert_eq!(&buf[..6], b"i3-ipc");
+        assert_eq!(u32::from_ne_bytes(buf[6..10].try_into().unwrap()), 2);
+        assert_eq!(u32::from_ne_bytes(buf[10..14].try_into().unwrap()), MSG_SUBSCRIBE);
+        assert_eq!(&buf[14..], b"[]");
+    }
+
+    #[test]
+    fn finds_focused_window_in_tree() {
+        let tree = json!({
+            "type": "root", "focused": false,
+            "nodes": [{
+                "type": "output", "focused": false,
+                "nodes": [{
+                    "type": "workspace", "focused": false,
+                    "nodes": [{"type": "con", "focused": false, "app_id": "foot"}],
+                    "floating_nodes": [{
+                        "type": "floating_con", "focused": true,
+                        "app_id": "firefox", "name": "Mozilla Firefox", "fullscreen_mode": 1
+                    }]
+                }]
+            }]
+        });
+        let window = find_focused(&tree).and_then(window_from_node).unwrap();
+        assert_eq!(window.app_id.as_deref(), Some("firefox"));
+        assert_eq!(window.title.as_deref(), Some("Mozilla Firefox"));
+        assert!(window.fullscreen);
+    }
+
+    #[test]
+    fn focused_workspace_is_not_a_window() {
+        let tree = json!({"type": "workspace", "focused": true, "nodes": []});
+        assert!(find_focused(&tree).and_then(window_from_node).is_none());
+    }
+
+    #[test]
+    fn xwayland_window_reports_class() {
+        let node = json!({
+            "type": "con", "app_id": null, "name": "Steam",
+            "window_properties": {"class": "steam"}
+        });
+        let window = window_from_node(&node).unwrap();
+        assert_eq!(window.app(), Some("steam"));
+    }
+
+    #[test]
+    fn focus_event_updates_window() {
+        let event = json!({"change": "focus", "container": {"type": "con", "app_id": "foot", "focused": true}});
+        let focus = apply_window_event(&event).unwrap().unwrap();
+        assert_eq!(focus.app(), Some("foot"));
+    }
+
+    #[test]
+    fn events_for_unfocused_windows_are_ignored() {
+        let event = json!({"change": "title", "container": {"type": "con", "app_id": "foot", "focused": false}});
+        assert!(apply_window_event(&event).is_none());
+    }
+
+    #[test]
+    fn layout_switch_reports_the_group() {
+        let event = json!({"change": "xkb_layout", "input": {"type": "keyboard", "xkb_active_layout_index": 1}});
+        assert_eq!(apply_input_event(&event), Some(1));
+        let pointer = json!({"change": "added", "input": {"type": "pointer"}});
+        assert_eq!(apply_input_event(&pointer), None);
+    }
+
+    #[test]
+    fn closing_focused_window_clears_focus() {
+        let event = json!({"change": "close", "container": {"type": "con", "focused": true}});
+        assert_eq!(apply_window_event(&event), Some(None));
+    }
+}
@@ -0,0 +1,156 @@
+use super::FocusedWindow;
+use anyhow::{Context, Result};
+use tokio::sync::{mpsc, watch};
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{
+    Atom, AtomEnum, ChangeWindowAttributesAux, ConnectionExt, EventMask, Window,
+};
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+
+/// The X display to watch, from $DISPLAY. The system service doesn't get one
+/// by default; set `Environment=DISPLAY=:0 XAUTHORITY=...` in a drop-in.
+pub fn find_display() -> Option<String> {
+    std::env::var("DISPLAY").ok().filter(|d| !d.is_empty())
+}
+
+/// Publish the active window, then follow EWMH property changes until the
+/// X connection drops. x11rb is blocking, so the X side runs on its own thread.
+pub async fn run(display: &str, tx: &watch::Sender<Option<FocusedWindow>>) -> Result<()> {
+    let (report_tx, mut report_rx) = mpsc::unbounded_channel();
+    let display = display.to_string();
+    let worker = tokio::task::spawn_blocking(move || watch_display(&display, report_tx));
+
+    while let Some(focus) = report_rx.recv().await {
+        tx.send_if_modified(|current| {
+            if *current == focus {
+                return false;
+            }
+            *current = focus;
+            true
+        });
+    }
+    worker.await.context("X11 watcher panicked")?
+}
+
+struct Atoms {
+    active_window: Atom,
+    wm_state: Atom,
+    wm_state_fullscreen: Atom,
+    wm_name: Atom,
+    utf8_string: Atom,
+}
+
+impl Atoms {
+    fn intern(conn: &RustConnection) -> Result<Self> {
+        let atom = |name: &[u8]| -> Result<Atom> { Ok(conn.intern_atom(false, name)?.reply()?.atom) };
+        Ok(Self {
+            active_window: atom(b"_NET_ACTIVE_WINDOW")?,
+            wm_state: atom(b"_NET_WM_STATE")?,
+            wm_state_fullscreen: atom(b"_NET_WM_STATE_FULLSCREEN")?,
+            wm_name: atom(b"_NET_WM_NAME")?,
+            utf8_string: atom(b"UTF8_STRING")?,
+        })
+    }
+}
+
+fn watch_display(display: &str, tx: mpsc::UnboundedSender<Option<FocusedWindow>>) -> Result<()> {
+    let (conn, screen) = x11rb::connect(Some(display))
+        .with_context(|| format!("connecting to X display {}", display))?;
+    let root = conn.setup().roots[screen].root;
+    let atoms = Atoms::intern(&conn)?;
+
+    let watch_props = ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE);
+    conn.change_window_attributes(root, &watch_props)?;
+    conn.flush()?;
+
+    let mut active = active_window(&conn, root, &atoms)?;
+    if let Some(window) = active {
+        // Also follow fullscreen and title changes of the focused window
+        conn.change_window_attributes(window, &watch_props)?;
+    }
+    if tx.send(active.map(|w| describe(&conn, w, &atoms)).transpose()?).is_err() {
+        return Ok(());
+    }
+
+    loop {
+        let Event::PropertyNotify(event) = conn.wait_for_event()? else {
+            continue;
+        };
+        if event.window == root && event.atom == atoms.active_window {
+            active = active_window(&conn, root, &atoms)?;
+            if let Some(window) = active {
+                conn.change_window_attributes(window, &watch_props)?;
+            }
+        } else if Some(event.window) != active
+            || (event.atom != atoms.wm_state && event.atom != atoms.wm_name)
+        {
+            continue;
+        }
+        // The window may be gone by the time we ask about it
+        let focus = active.and_then(|w| describe(&conn, w, &atoms).ok());
+        if tx.send(focus).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+fn active_window(conn: &RustConnection, root: Window, atoms: &Atoms) -> Result<Option<Window>> {
+    let reply = conn
+        .get_property(false, root, atoms.active_window, AtomEnum::WINDOW, 0, 1)?
+        .reply()?;
+    Ok(reply.value32().and_then(|mut v| v.next()).filter(|&w| w != 0))
+}
+
+fn describe(conn: &RustConnection, window: Window, atoms: &Atoms) -> Result<FocusedWindow> {
+    let class = conn
+        .get_property(false, window, AtomEnum::WM_CLASS, AtomEnum::STRING, 0, 256)?
+        .reply()?;
+    let title = conn
+        .get_property(false, window, atoms.wm_name, atoms.utf8_string, 0, 1024)?
+        .reply()?;
+    let state = conn
+        .get_property(false, window, atoms.wm_state, AtomEnum::ATOM, 0, 64)?
+        .reply()?;
+    let fullscreen = state
+        .value32()
+        .is_some_and(|mut atoms_set| atoms_set.any(|a| a == atoms.wm_state_fullscreen));
+
+    Ok(FocusedWindow {
+        app_id: None,
+        class: parse_wm_class(&class.value),
+        title: Some(String::from_utf8_lossy(&title.value).into_owned()).filter(|t| !t.is_empty()),
+        fullscreen,
+        output: None,
+    })
+}
+
+/// WM_CLASS is "instance\0class\0"; the class part is what rules match on.
+fn parse_wm_class(value: &[u8]) -> Option<String> {
+    let mut parts = value.split(|&b| b == 0);
+    let instance = parts.next()?;
+    let class = parts.next().filter(|c| !c.is_empty()).unwrap_or(instance);
+    let class = String::from_utf8_lossy(class).into_owned();
+    (!class.is_empty()).then_some(class)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wm_class_prefers_class_over_instance() {
+        assert_eq!(parse_wm_class(b"navigator\0firefox\0").as_deref(), Some("firefox"));
+    }
+
+    #[test]
+    fn wm_class_falls_back_to_instance() {
+        assert_eq!(parse_wm_class(b"xterm\0").as_deref(), Some("xterm"));
+        assert_eq!(parse_wm_class(b"xterm").as_deref(), Some("xterm"));
+    }
+
+    #[test]
+    fn empty_wm_class_is_none() {
+        assert_eq!(parse_wm_class(b""), None);
+    }
+}
@@ -0,0 +1,422 @@
+//! Attaching keyboards: at startup, on `accentctl rescan`, when a keyboard
+//! that dropped off comes back, and on enable with `ungrab_when_disabled`.
+//!
+//! Each keyboard has a slot, the same index into every per-keyboard vector
+//! of [`Shared`]. Slots are never removed, so indices in flight stay valid;
+//! a keyboard coming back (by [`Identity`], else at the same path) gets its
+//! old slot and state machine again.
+
+use crate::{backend, grabber, Shared};
+use accentd_core::charmap::Layout;
+use accentd_core::ipc::{self, DaemonMsg};
+use accentd_core::state_machine::StateMachine;
+use anyhow::Result;
+use backend::EventSource;
+use grabber::{EvdevSource, Identity};
+use inotify::{Inotify, WatchMask};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{debug, info, warn};
+
+/// udev creates the node first and sets its permissions after; wait for both.
+const SETTLE: Duration = Duration::from_millis(500);
+
+/// Waits before each attempt to reopen a keyboard after an I/O error, e.g.
+/// on resume from suspend while its USB port is still powering up.
+const REOPEN_DELAYS: [Duration; 5] = [
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(4),
+];
+
+/// A keyboard's forwarding task while it runs, and which keyboard it was.
+#[derive(Debug, Default)]
+pub struct Slot {
+    task: Option<tokio::task::AbortHandle>,
+    /// The task waiting on logind to open the keyboard. Releasing the slot
+    /// clears it, and the task then hands the device back on arrival
+    /// (aborting the call could leave it taken).
+    opening: Option<tokio::task::Id>,
+    identity: Identity,
+}
+
+/// Grab (or, in a dry run or with `grab = false`, watch) the keyboard at
+/// `path` into slot `idx` and forward its events until it goes away.
+pub fn attach(handle: &Arc<Mutex<Shared>>, shared: &mut Shared, idx: usize, path: &Path) -> Result<()> {
+    let grab = !(shared.dry_run || shared.echo.is_some());
+
+    if let Some(logind) = shared.logind.clone() {
+        // logind answers over D-Bus; the slot counts as grabbed meanwhile so
+        // a rescan doesn't attach it twice
+        shared.devices[idx].grabbed = true;
+        let handle = Arc::clone(handle);
+        let path = path.to_owned();
+        let task = tokio::spawn(async move {
+            let source = logind.open(&path, grab).await;
+            let mut shared = handle.lock().await;
+            // released (disabled, or gone in a rescan) while logind answered
+            if shared.slots[idx].opening != Some(tokio::task::id()) {
+                debug!(path = %path.display(), "released while opening, handing it back");
+                return;
+            }
+            shared.slots[idx].opening = None;
+            if held_off(&shared) {
+                shared.devices[idx].grabbed = false;
+                return;
+            }
+            match source {
+                Ok(source) => {
+                    let identity = source.identity().clone();
+                    start(&handle, &mut shared, idx, &path, source, identity);
+                }
+                Err(e) => {
+                    shared.devices[idx].grabbed = false;
+                    warn!(path = %path.display(), error = %format!("{:#}", e), "failed to grab device");
+                }
+            }
+        });
+        shared.slots[idx].opening = Some(task.id());
+        return Ok(());
+    }
+
+    let source = if grab { EvdevSource::grab(path)? } else { EvdevSource::watch(path)? };
+    let identity = source.identity().clone();
+    start(handle, shared, idx, path, source, identity);
+    Ok(())
+}
+
+/// Set up slot `idx` for an opened keyboard and start forwarding its events.
+fn start(
+    handle: &Arc<Mutex<Shared>>,
+    shared: &mut Shared,
+    idx: usize,
+    path: &Path,
+    source: impl EventSource,
+    identity: Identity,
+) {
+    // Keys held while we start (Ctrl, Shift) and Caps Lock would
+    // otherwise only be noticed after their next press.
+    let held = source.key_state();
+    let sm = &mut shared.state_machines[idx];
+    sm.seed_key_state(&held.held, held.caps_lock);
+    let devices = &shared.config.devices;
+    let layout = match devices.layout_groups.get(shared.layout_group) {
+        Some(&layout) => layout,
+        None => devices.layout_for(source.name(), source.input_id()),
+    };
+    if layout != Layout::Us {
+        info!(device = %source.name(), ?layout, "keyboard layout");
+    }
+    sm.set_layout(layout);
+    sm.set_keymap(shared.keymap.clone());
+
+    // Our own nodes are asked of the emitter when needed, so whether this
+    // created one doesn't matter here
+    if let Err(e) = shared.emitter.keyboard_attached(idx, source.name(), source.input_id()) {
+        warn!(device = %source.name(), error = %format!("{:#}", e), "no virtual keyboard of its own, using the shared one");
+    }
+
+    let device = &mut shared.devices[idx];
+    device.name = source.name().to_string();
+    device.path = path.display().to_string();
+    device.grabbed = true;
+    device.error = None;
+    shared.slots[idx].identity = identity;
+
+    let handle = Arc::clone(handle);
+    let tx = shared.event_tx.clone();
+    let path = path.to_owned();
+    let task = tokio::spawn(async move {
+        let ended = backend::forward(source, idx, tx).await;
+        let mut shared = handle.lock().await;
+        shared.devices[idx].grabbed = false;
+        let Err(e) = ended else {
+            return;
+        };
+        shared.devices[idx].error = Some(format!("{:#}", e));
+        crate::broadcast_status(&mut shared);
+        if is_io_error(&e) {
+            drop(shared);
+            reopen(&handle, idx, &path).await;
+        }
+    });
+    shared.slots[idx].task = Some(task.abort_handle());
+}
+
+/// EIO: the device node is still there but reads fail for now, as after a
+/// resume. Others (ENODEV: unplugged) are left to hotplug and rescan.
+fn is_io_error(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .any(|e| e.raw_os_error() == Some(nix::libc::EIO))
+}
+
+/// Open and grab keyboard `idx` at `path` again after an I/O error, a few
+/// times with growing waits, reporting progress in its status.
+async fn reopen(handle: &Arc<Mutex<Shared>>, idx: usize, path: &Path) {
+    for (attempt, delay) in REOPEN_DELAYS.iter().enumerate() {
+        {
+            let mut shared = handle.lock().await;
+            shared.devices[idx].error =
+                Some(format!("reopening after I/O error, attempt {} of {}", attempt + 1, REOPEN_DELAYS.len()));
+            crate::broadcast_status(&mut shared);
+        }
+        tokio::time::sleep(*delay).await;
+
+        let mut guard = handle.lock().await;
+        let shared = &mut *guard;
+        // Released meanwhile (rescan, disabled) or attached by a reconnect
+        if shared.devices[idx].grabbed || held_off(shared) {
+            return;
+        }
+        match attach(handle, shared, idx, path) {
+            Ok(()) => {
+                info!(path = %path.display(), attempt = attempt + 1, "reopened device after I/O error");
+                crate::broadcast_status(shared);
+                return;
+            }
+            Err(e) => debug!(path = %path.display(), error = %format!("{:#}", e), "reopening failed"),
+        }
+    }
+    warn!(path = %path.display(), "giving up on device after repeated I/O errors, `accentctl rescan` retries");
+    let mut shared = handle.lock().await;
+    shared.devices[idx].error = Some("I/O error, gave up reopening".into());
+    crate::broadcast_status(&mut shared);
+}
+
+/// Stop reading keyboard `idx` and let go of its grab, closing its popup.
+fn release(shared: &mut Shared, idx: usize) {
+    if let Some(task) = shared.slots[idx].task.take() {
+        task.abort();
+    }
+    shared.slots[idx].opening = None;
+    shared.devices[idx].grabbed = false;
+    let actions = shared.state_machines[idx].ipc_dismiss();
+    crate::process_actions(shared, idx, actions);
+    info!(device = %shared.devices[idx].name, path = %shared.devices[idx].path, "released device");
+}
+
+/// A fresh slot for a keyboard not seen before, set up like the others.
+fn add_slot(shared: &mut Shared, path: &Path) -> usize {
+    let accents = crate::accent_table(&shared.config, &shared.usage, &shared.locale);
+    let mut sm = StateMachine::new(&shared.config, accents);
+    if let Some(first) = shared.state_machines.first() {
+        sm.set_enabled(first.is_enabled());
+    }
+    sm.set_suspended(!shared.suspended.is_empty());
+    shared.state_machines.push(sm);
+    shared.frames.push(Default::default());
+    shared.slots.push(Slot::default());
+    shared.devices.push(ipc::DeviceStatus {
+        name: String::new(),
+        path: path.display().to_string(),
+        grabbed: false,
+        enabled: false,
+        events: 0,
+        error: None,
+    });
+    shared.state_machines.len() - 1
+}
+
+/// The released slot of the keyboard at `path`: the one it had before it
+/// dropped off, else whatever was last at the same path.
+fn slot_for(shared: &Shared, path: &Path, identity: &Identity) -> Option<usize> {
+    let path = path.display().to_string();
+    let free = |idx: &usize| !shared.devices[*idx].grabbed;
+    (0..shared.slots.len())
+        .filter(free)
+        .find(|&idx| shared.slots[idx].identity.same_keyboard(identity))
+        .or_else(|| (0..shared.slots.len()).filter(free).find(|&idx| shared.devices[idx].path == path))
+}
+
+/// Keyboards found by discovery that aren't attached yet.
+fn unattached(shared: &Shared, found: &[PathBuf]) -> Vec<PathBuf> {
+    found
+        .iter()
+        .filter(|path| {
+            let path = path.display().to_string();
+            !shared.devices.iter().any(|d| d.grabbed && d.path == path)
+        })
+        .cloned()
+        .collect()
+}
+
+/// Keyboards are let go while disabled (`ungrab_when_disabled`).
+pub fn held_off(shared: &Shared) -> bool {
+    shared.config.general.ungrab_when_disabled
+        && !shared.state_machines.first().is_some_and(|sm| sm.is_enabled())
+}
+
+/// With `ungrab_when_disabled`, follow the enabled state: let go of every
+/// keyboard on disable, taking accentd out of the input path, and grab
+/// them again on enable.
+pub fn follow_enabled(handle: &Arc<Mutex<Shared>>, shared: &mut Shared) {
+    if !shared.config.general.ungrab_when_disabled {
+        return;
+    }
+    if held_off(shared) {
+        for idx in 0..shared.devices.len() {
+            if shared.devices[idx].grabbed {
+                release(shared, idx);
+            }
+        }
+        // Their releases now go straight to the apps; don't leave the
+        // virtual keyboard holding e.g. the modifier of the toggle binding
+        if let Err(e) = shared.emitter.release_all() {
+            warn!(error = %e, "failed to release held keys");
+        }
+    } else {
+        reattach(handle, shared);
+    }
+}
+
+/// Run keyboard discovery again: grab keyboards that appeared and release
+/// grabbed ones that are no longer found.
+pub async fn rescan(handle: &Arc<Mutex<Shared>>) -> DaemonMsg {
+    let mut guard = handle.lock().await;
+    let shared = &mut *guard;
+    if held_off(shared) {
+        return DaemonMsg::Ack { ok: false, message: "disabled, keyboards are not grabbed".into() };
+    }
+    let own_nodes = shared.emitter.dev_nodes();
+    let found = match grabber::find_keyboards(&shared.config.devices, &own_nodes) {
+        Ok(found) => found,
+        Err(e) => {
+            return DaemonMsg::Ack { ok: false, message: format!("rescan failed: {:#}", e) };
+        }
+    };
+
+    let mut released = 0;
+    for idx in 0..shared.devices.len() {
+        let device = &shared.devices[idx];
+        if device.grabbed && !found.iter().any(|path| path.display().to_string() == device.path) {
+            release(shared, idx);
+            released += 1;
+        }
+    }
+
+    let mut grabbed = 0;
+    for path in unattached(shared, &found) {
+        let identity = grabber::probe(&path, shared.config.devices.access).map(|p| p.identity).unwrap_or_default();
+        let idx = slot_for(shared, &path, &identity).unwrap_or_else(|| add_slot(shared, &path));
+        match attach(handle, shared, idx, &path) {
+            Ok(()) => grabbed += 1,
+            Err(e) => warn!(path = %path.display(), error = %e, "failed to grab device"),
+        }
+    }
+
+    info!(grabbed, released, "rescanned keyboards");
+    crate::broadcast_status(shared);
+    DaemonMsg::Ack {
+        ok: true,
+        message: format!("{} keyboard(s) grabbed, {} released", grabbed, released),
+    }
+}
+
+/// Grab keyboards that dropped off and came back, into their old slots.
+async fn reconnect(handle: &Arc<Mutex<Shared>>) {
+    let mut guard = handle.lock().await;
+    let shared = &mut *guard;
+    if held_off(shared) || shared.devices.iter().all(|d| d.grabbed) {
+        return;
+    }
+    if reattach(handle, shared) {
+        crate::broadcast_status(shared);
+    }
+}
+
+/// Grab the keyboards of released slots that are present again. Keyboards
+/// never seen before are left to `accentctl rescan`. Returns whether any
+/// was grabbed.
+fn reattach(handle: &Arc<Mutex<Shared>>, shared: &mut Shared) -> bool {
+    let own_nodes = shared.emitter.dev_nodes();
+    let found = match grabber::find_keyboards(&shared.config.devices, &own_nodes) {
+        Ok(found) => found,
+        Err(e) => {
+            warn!(error = %e, "keyboard discovery failed");
+            return false;
+        }
+    };
+
+    let mut changed = false;
+    for path in unattached(shared, &found) {
+        let Ok(identity) = grabber::probe(&path, shared.config.devices.access).map(|p| p.identity) else {
+            continue;
+        };
+        let Some(idx) = slot_for(shared, &path, &identity) else {
+            debug!(path = %path.display(), name = %identity.name, "new keyboard, not grabbing until rescan");
+            continue;
+        };
+        match attach(handle, shared, idx, &path) {
+            Ok(()) => changed = true,
+            Err(e) => warn!(path = %path.display(), error = %e, "failed to grab keyboard again"),
+        }
+    }
+    changed
+}
+
+/// Watch /dev/input and re-grab keyboards that reconnect, e.g. a Bluetooth
+/// keyboard waking up under a new event node.
+pub fn spawn_hotplug(shared: Arc<Mutex<Shared>>) {
+    let inotify = Inotify::init().and_then(|inotify| {
+        inotify.watches().add("/dev/input", WatchMask::CREATE | WatchMask::ATTRIB)?;
+        Ok(inotify)
+    });
+    let mut inotify = match inotify {
+        Ok(inotify) => inotify,
+        Err(e) => {
+            warn!(error = %e, "cannot watch /dev/input, reconnected keyboards need `accentctl rescan`");
+            return;
+        }
+    };
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<()>();
+
+    // inotify reads block, so they get their own thread
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 4096];
+        loop {
+            let events = match inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => events,
+                Err(e) => {
+                    warn!(error = %e, "inotify read error, keyboard reconnects no longer noticed");
+                    return;
+                }
+            };
+            let new_node = events
+                .filter_map(|event| event.name.and_then(|n| n.to_str()))
+                .any(|name| name.starts_with("event"));
+            if new_node && tx.send(()).is_err() {
+                return;
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        while rx.recv().await.is_some() {
+            tokio::time::sleep(SETTLE).await;
+            while rx.try_recv().is_ok() {}
+            reconnect(&shared).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // --- spec: recovering from I/O errors ---
+
+    #[test]
+    fn only_eio_is_worth_reopening() {
+        let eio = anyhow::Error::from(std::io::Error::from_raw_os_error(nix::libc::EIO));
+        assert!(is_io_error(&eio.context("relaying pointer events")));
+        let unplugged = anyhow::Error::from(std::io::Error::from_raw_os_error(nix::libc::ENODEV));
+        assert!(!is_io_error(&unplugged));
+        assert!(!is_io_error(&anyhow::anyhow!("device closed")));
+    }
+}
@@ -1,18 +1,22 @@
+use crate::backend::{EventSource, KeyState};
+use crate::uinput_emitter;
+use accentd_core::config::{DeviceAccess, DevicesConfig};
+use accentd_core::state_machine::{RawEvent, EV_KEY, EV_SYN};
 use anyhow::{Context, Result};
-use evdev::{Device, InputEvent};
+use evdev::uinput::VirtualDevice;
+use evdev::{Device, EventStream, InputEvent, LedType};
+use std::os::fd::{AsRawFd, RawFd};
 use std::path::{Path, PathBuf};
-use tokio::sync::mpsc;
+use std::time::{Duration, Instant};
 use tracing::{debug, info, warn};
 
-/// An event from a grabbed keyboard, tagged with the device index.
-#[derive(Debug, Clone)]
-pub struct DeviceEvent {
-    pub device_idx: usize,
-    pub event: InputEvent,
-}
-
-/// Find all keyboard devices under /dev/input/.
-pub fn find_keyboards() -> Result<Vec<PathBuf>> {
+/// Find the keyboard devices to grab under /dev/input/.
+///
+/// With `upstream` names configured, only the remapper virtual keyboards with
+/// those names are picked (the remapper already holds the physical ones).
+/// `own_nodes` are our own uinput device nodes, which are never grabbed.
+pub fn find_keyboards(devices: &DevicesConfig, own_nodes: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let upstream = &devices.upstream;
     let mut keyboards = Vec::new();
     let input_dir = Path::new("/dev/input");
 
@@ -25,18 +29,39 @@ pub fn find_keyboards() -> Result<Vec<PathBuf>> {
         if !name.starts_with("event") {
             continue;
         }
+        if own_nodes.contains(&path) {
+            debug!(path = %path.display(), "skipping own virtual device");
+            continue;
+        }
 
-        match Device::open(&path) {
-            Ok(dev) => {
+        match probe(&path, devices.access) {
+            Ok(Probe { identity, input_id, caps }) => {
+                let dev_name = identity.name.as_str();
                 // Skip our own virtual device to avoid feedback loop
-                if dev.name().map_or(false, |n| n.contains("accentd")) {
-                    debug!(path = %path.display(), name = ?dev.name(), "skipping own virtual device");
+                if is_own_device(dev_name, input_id) {
+                    if is_upstream(dev_name, upstream) {
+                        warn!(name = dev_name, "refusing to use our own virtual device as upstream");
+                    }
+                    debug!(path = %path.display(), name = dev_name, "skipping own virtual device");
+                    continue;
+                }
+                if !upstream.is_empty() && !is_upstream(dev_name, upstream) {
                     continue;
                 }
-                if is_keyboard(&dev) {
-                    info!(path = %path.display(), name = ?dev.name(), "found keyboard");
-                    keyboards.push(path);
+                if upstream.is_empty() && is_excluded(dev_name, &devices.exclude) {
+                    info!(path = %path.display(), name = dev_name, "skipping excluded device");
+                    continue;
                 }
+                // Upstream and included devices were named on purpose
+                let named = !upstream.is_empty() || matches_any(dev_name, &devices.include);
+                if !named && !caps.is_keyboard() {
+                    if caps.typing_keys {
+                        debug!(path = %path.display(), name = dev_name, score = caps.score(), "not keyboard enough");
+                    }
+                    continue;
+                }
+                info!(path = %path.display(), name = dev_name, "found keyboard");
+                keyboards.push(path);
             }
             Err(e) => {
                 debug!(path = %path.display(), error = %e, "skipping device");
@@ -47,53 +72,562 @@ pub fn find_keyboards() -> Result<Vec<PathBuf>> {
     Ok(keyboards)
 }
 
-/// Heuristic: a device is a keyboard if it has KEY events and supports
-/// common letter keys (KEY_A through KEY_Z).
-fn is_keyboard(dev: &Device) -> bool {
-    let Some(keys) = dev.supported_keys() else {
-        return false;
+/// What discovery learns about a device without grabbing it.
+pub struct Probe {
+    pub identity: Identity,
+    pub input_id: (u16, u16),
+    caps: Capabilities,
+}
+
+/// Look at the device at `path`: through the node itself, or with logind
+/// access (where we may not open it) through sysfs.
+pub fn probe(path: &Path, access: DeviceAccess) -> Result<Probe> {
+    match access {
+        DeviceAccess::Direct => {
+            let dev = Device::open(path).with_context(|| format!("opening {}", path.display()))?;
+            Ok(Probe {
+                identity: Identity::of(&dev),
+                input_id: (dev.input_id().vendor(), dev.input_id().product()),
+                caps: Capabilities::of(&dev),
+            })
+        }
+        DeviceAccess::Logind => Probe::from_sysfs(&sysfs_dir(path)),
+    }
+}
+
+/// /sys/class/input/eventN/device for /dev/input/eventN.
+pub fn sysfs_dir(path: &Path) -> PathBuf {
+    let node = path.file_name().unwrap_or_default();
+    Path::new("/sys/class/input").join(node).join("device")
+}
+
+impl Probe {
+    /// Read a device's `name`, `uniq`, `phys`, `id/` and `capabilities/`
+    /// files under `dir` (see [`sysfs_dir`]).
+    pub fn from_sysfs(dir: &Path) -> Result<Self> {
+        let read = |file: &str| {
+            std::fs::read_to_string(dir.join(file))
+                .map(|s| s.trim().to_string())
+                .with_context(|| format!("reading {}", dir.join(file).display()))
+        };
+        let hex = |file: &str| -> Result<u16> {
+            u16::from_str_radix(&read(file)?, 16).with_context(|| format!("parsing {}", file))
+        };
+        let bitmap = |file: &str| read(file).map(|s| Bitmap::parse(&s)).unwrap_or_default();
+
+        let keys = bitmap("capabilities/key");
+        Ok(Self {
+            identity: Identity {
+                name: read("name")?,
+                uniq: read("uniq").unwrap_or_default(),
+                phys: read("phys").unwrap_or_default(),
+            },
+            input_id: (hex("id/vendor")?, hex("id/product")?),
+            caps: Capabilities {
+                typing_keys: [KEY_A, KEY_Z, KEY_ENTER].iter().all(|&key| keys.contains(key)),
+                keys: keys.count(),
+                autorepeat: bitmap("capabilities/ev").contains(EV_REP),
+                pointer_axes: bitmap("capabilities/rel").count() > 0 || bitmap("capabilities/abs").count() > 0,
+            },
+        })
+    }
+}
+
+const KEY_ENTER: usize = 28;
+const KEY_A: usize = 30;
+const KEY_Z: usize = 44;
+const EV_REP: usize = 0x14;
+
+/// A capability bitmap as sysfs prints it: hex words of the platform's
+/// `long`, most significant first, e.g. "10000 0 0 7 ff9f207a".
+#[derive(Debug, Default)]
+struct Bitmap(Vec<u64>);
+
+impl Bitmap {
+    const WORD_BITS: usize = usize::BITS as usize;
+
+    fn parse(s: &str) -> Self {
+        Self(s.split_whitespace().rev().map(|word| u64::from_str_radix(word, 16).unwrap_or(0)).collect())
+    }
+
+    fn contains(&self, bit: usize) -> bool {
+        self.0
+            .get(bit / Self::WORD_BITS)
+            .is_some_and(|word| word >> (bit % Self::WORD_BITS) & 1 == 1)
+    }
+
+    fn count(&self) -> usize {
+        self.0.iter().map(|word| word.count_ones() as usize).sum()
+    }
+}
+
+/// Our virtual keyboard (by its fixed id, or its name if the id got lost on
+/// the way) and our pointer mirrors. Only these exact names count, so an
+/// upstream remapper is free to have "accentd" in its device name.
+fn is_own_device(name: &str, id: (u16, u16)) -> bool {
+    id == (uinput_emitter::VIRTUAL_VENDOR, uinput_emitter::VIRTUAL_PRODUCT)
+        || name == uinput_emitter::VIRTUAL_NAME
+        || name.ends_with(uinput_emitter::MIRROR_SUFFIX)
+}
+
+/// Name fragments of on-screen keyboards that inject through uinput. Grabbing
+/// them would pop accents up for taps that never involved holding a key.
+const OSK_NAMES: &[&str] = &["squeekboard", "wvkbd", "onboard", "maliit", "on-screen keyboard"];
+
+/// On-screen keyboards and user-excluded devices (case-insensitive substring).
+fn is_excluded(name: &str, exclude: &[String]) -> bool {
+    let lower = name.to_lowercase();
+    OSK_NAMES.iter().any(|osk| lower.contains(osk)) || matches_any(name, exclude)
+}
+
+/// Case-insensitive substring match against any of `names`.
+fn matches_any(name: &str, names: &[String]) -> bool {
+    let name = name.to_lowercase();
+    names.iter().any(|n| name.contains(&n.to_lowercase()))
+}
+
+/// Upstream names match the device name exactly, ignoring case.
+fn is_upstream(name: &str, upstream: &[String]) -> bool {
+    upstream.iter().any(|u| u.eq_ignore_ascii_case(name))
+}
+
+/// What the keyboard heuristic looks at. Mice with macro keys and remote
+/// controls often have the letter keys too; the rest tells them apart.
+#[derive(Debug, Default)]
+struct Capabilities {
+    /// KEY_A, KEY_Z and KEY_ENTER: the minimum for typing.
+    typing_keys: bool,
+    /// Key codes supported.
+    keys: usize,
+    /// EV_REP: the kernel autorepeats it, as it does keyboards.
+    autorepeat: bool,
+    /// Relative or absolute axes (mice, touchpads, joysticks).
+    pointer_axes: bool,
+}
+
+/// Score a keyboard needs to be grabbed.
+const KEYBOARD_SCORE: i32 = 2;
+
+impl Capabilities {
+    fn of(dev: &Device) -> Self {
+        let keys = dev.supported_keys();
+        Self {
+            typing_keys: keys.is_some_and(|keys| {
+                keys.contains(evdev::Key::KEY_A)
+                    && keys.contains(evdev::Key::KEY_Z)
+                    && keys.contains(evdev::Key::KEY_ENTER)
+            }),
+            keys: keys.map_or(0, |keys| keys.iter().count()),
+            autorepeat: dev.supported_events().contains(evdev::EventType::REPEAT),
+            pointer_axes: dev.supported_relative_axes().is_some_and(|axes| axes.iter().next().is_some())
+                || dev.supported_absolute_axes().is_some_and(|axes| axes.iter().next().is_some()),
+        }
+    }
+
+    /// How keyboard-like the device is: autorepeat counts most, then a full
+    /// set of keys; few keys or pointer axes count against it. Combo
+    /// keyboards (with a trackpoint) still pass.
+    fn score(&self) -> i32 {
+        let mut score = 0;
+        if self.autorepeat {
+            score += 2;
+        }
+        if self.keys >= 100 {
+            score += 1;
+        } else if self.keys < 60 {
+            score -= 1;
+        }
+        if self.pointer_axes {
+            score -= 1;
+        }
+        score
+    }
+
+    fn is_keyboard(&self) -> bool {
+        self.typing_keys && self.score() >= KEYBOARD_SCORE
+    }
+}
+
+/// Events of a combo keyboard's pointer side (see
+/// [`uinput_emitter::create_mirror_device`]), which bypass the state machine.
+fn is_pointer_event(event: &RawEvent) -> bool {
+    match event.event_type {
+        EV_REL | EV_ABS | EV_SW => true,
+        EV_KEY => uinput_emitter::is_button(event.code),
+        _ => false,
+    }
+}
+
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+const EV_SW: u16 = 0x05;
+
+nix::ioctl_write_ptr!(eviocsclockid, b'E', 0xa0, nix::libc::c_int);
+
+/// Have the device stamp its events on the monotonic clock, the one
+/// `Instant` uses, instead of wall-clock time that can jump.
+pub fn use_monotonic_clock(fd: RawFd) {
+    let clock = nix::libc::CLOCK_MONOTONIC;
+    // SAFETY: `fd` is an open evdev fd and `clock` outlives the call
+    if let Err(e) = unsafe { eviocsclockid(fd, &clock) } {
+        debug!(error = %e, "cannot switch event timestamps to the monotonic clock");
+    }
+}
+
+/// When the kernel saw an event stamped `stamp` on the monotonic clock: its
+/// age, taken from now. Stamps that can't be (wall-clock time, the future)
+/// count as now.
+pub fn event_time(stamp: &nix::libc::timeval) -> Instant {
+    let now = Instant::now();
+    let Ok(mono) = nix::time::clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC) else {
+        return now;
+    };
+    let (Ok(sec), Ok(usec)) = (u64::try_from(stamp.tv_sec), u32::try_from(stamp.tv_usec)) else {
+        return now;
     };
+    let stamp = Duration::new(sec, 0) + Duration::from_micros(usec.into());
+    Duration::from(mono).checked_sub(stamp).and_then(|age| now.checked_sub(age)).unwrap_or(now)
+}
 
-    // Must have KEY_A (30), KEY_Z (44), KEY_ENTER (28)
-    keys.contains(evdev::Key::KEY_A)
-        && keys.contains(evdev::Key::KEY_Z)
-        && keys.contains(evdev::Key::KEY_ENTER)
-}
-
-/// Grab a keyboard device and forward events to the channel.
-/// Runs until the sender is dropped or the device errors.
-pub async fn grab_device(
-    path: PathBuf,
-    device_idx: usize,
-    tx: mpsc::UnboundedSender<DeviceEvent>,
-) -> Result<()> {
-    let mut dev = Device::open(&path)
-        .with_context(|| format!("opening {}", path.display()))?;
-
-    let dev_name = dev.name().unwrap_or("unknown").to_string();
-    info!(device = %dev_name, path = %path.display(), "grabbing device");
-
-    dev.grab()
-        .with_context(|| format!("grabbing {}", path.display()))?;
-
-    // Wrap in tokio AsyncDevice for non-blocking reads
-    let mut stream = dev.into_event_stream()
-        .context("creating event stream")?;
-
-    loop {
-        match stream.next_event().await {
-            Ok(event) => {
-                if tx.send(DeviceEvent { device_idx, event }).is_err() {
-                    // Receiver dropped, shut down
-                    break;
-                }
+/// Relays a combo keyboard's pointer events untouched, frame by frame.
+struct Mirror {
+    device: VirtualDevice,
+    frame: Vec<InputEvent>,
+}
+
+/// What makes a keyboard the same one after it reconnects under another
+/// event node (Bluetooth keyboards do on every reconnect).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Identity {
+    pub name: String,
+    /// Unique id, e.g. a Bluetooth keyboard's address; often empty for USB.
+    pub uniq: String,
+    /// Physical path, e.g. "usb-0000:00:14.0-2/input0".
+    pub phys: String,
+}
+
+impl Identity {
+    fn of(dev: &Device) -> Self {
+        Self {
+            name: dev.name().unwrap_or("unknown").to_string(),
+            uniq: dev.unique_name().unwrap_or("").to_string(),
+            phys: dev.physical_path().unwrap_or("").to_string(),
+        }
+    }
+
+    /// Whether both describe the same keyboard: same name, and the same
+    /// unique id, or the same port when neither has one.
+    pub fn same_keyboard(&self, other: &Identity) -> bool {
+        if self.name != other.name {
+            return false;
+        }
+        if self.uniq.is_empty() && other.uniq.is_empty() {
+            !self.phys.is_empty() && self.phys == other.phys
+        } else {
+            self.uniq == other.uniq
+        }
+    }
+}
+
+/// A keyboard grabbed through evdev.
+pub struct EvdevSource {
+    name: String,
+    identity: Identity,
+    input_id: (u16, u16),
+    stream: EventStream,
+    key_state: KeyState,
+    mirror: Option<Mirror>,
+}
+
+impl EvdevSource {
+    /// Open and exclusively grab a keyboard device.
+    pub fn grab(path: &Path) -> Result<Self> {
+        Self::open(path, true)
+    }
+
+    /// Open a keyboard without grabbing it (`--dry-run`, `grab = false`): its
+    /// events keep reaching the apps and we only watch.
+    pub fn watch(path: &Path) -> Result<Self> {
+        Self::open(path, false)
+    }
+
+    fn open(path: &Path, grab: bool) -> Result<Self> {
+        let mut dev = Device::open(path)
+            .with_context(|| format!("opening {}", path.display()))?;
+
+        let identity = Identity::of(&dev);
+        let name = identity.name.clone();
+        let input_id = (dev.input_id().vendor(), dev.input_id().product());
+        use_monotonic_clock(dev.as_raw_fd());
+        if !grab {
+            info!(device = %name, path = %path.display(), "watching device (not grabbed)");
+            let key_state = KeyState::default();
+            let stream = dev.into_event_stream().context("creating event stream")?;
+            return Ok(Self { name, identity, input_id, stream, key_state, mirror: None });
+        }
+        info!(device = %name, path = %path.display(), "grabbing device");
+
+        dev.grab()
+            .with_context(|| format!("grabbing {}", path.display()))?;
+
+        // Read after grabbing, so no change slips in between (EVIOCGKEY/EVIOCGLED)
+        let key_state = KeyState {
+            held: dev.get_key_state().map(|keys| keys.iter().map(|k| k.code()).collect()).unwrap_or_default(),
+            caps_lock: dev.get_led_state().is_ok_and(|leds| leds.contains(LedType::LED_CAPSL)),
+        };
+        if !key_state.held.is_empty() {
+            debug!(device = %name, held = ?key_state.held, "keys already down at grab");
+        }
+
+        // Grabbing takes the trackpoint/touchpad too; give it its own device
+        let mirror = uinput_emitter::create_mirror_device(&dev)
+            .with_context(|| format!("mirroring pointer of {}", path.display()))?
+            .map(|device| Mirror { device, frame: Vec::new() });
+
+        // Wrap in tokio AsyncDevice for non-blocking reads
+        let stream = dev.into_event_stream()
+            .context("creating event stream")?;
+
+        Ok(Self { name, identity, input_id, stream, key_state, mirror })
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+}
+
+impl EventSource for EvdevSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn next_event(&mut self) -> Result<(RawEvent, Instant)> {
+        loop {
+            let event = self.stream.next_event().await?;
+            let raw = RawEvent::new(event.event_type().0, event.code(), event.value());
+            let time = event_time(&event.as_ref().time);
+            let Some(mirror) = &mut self.mirror else {
+                return Ok((raw, time));
+            };
+            if is_pointer_event(&raw) {
+                mirror.frame.push(event);
+                continue;
             }
-            Err(e) => {
-                warn!(device = %dev_name, error = %e, "device error, stopping grab");
-                break;
+            if raw.event_type == EV_SYN && !mirror.frame.is_empty() {
+                // emit() appends the SYN_REPORT
+                mirror.device.emit(&mirror.frame).context("relaying pointer events")?;
+                mirror.frame.clear();
             }
+            return Ok((raw, time));
         }
     }
 
-    Ok(())
+    fn input_id(&self) -> Option<(u16, u16)> {
+        Some(self.input_id)
+    }
+
+    fn key_state(&self) -> KeyState {
+        self.key_state.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // --- spec: remapper interop ---
+
+    #[test]
+    fn upstream_matches_whole_name_ignoring_case() {
+        let upstream = vec!["keyd virtual keyboard".to_string()];
+        assert!(is_upstream("keyd virtual keyboard", &upstream));
+        assert!(is_upstream("Keyd Virtual Keyboard", &upstream));
+        assert!(!is_upstream("keyd virtual pointer", &upstream));
+    }
+
+    #[test]
+    fn no_upstream_matches_nothing() {
+        assert!(!is_upstream("kanata", &[]));
+    }
+
+    #[test]
+    fn upstream_may_mention_accentd() {
+        assert!(!is_own_device("kanata for accentd", (0x1234, 0x5678)));
+        let upstream = vec!["kanata for accentd".to_string()];
+        assert!(is_upstream("kanata for accentd", &upstream));
+    }
+
+    // --- spec: on-screen keyboards and touch devices ---
+
+    #[test]
+    fn on_screen_keyboards_are_excluded() {
+        assert!(is_excluded("wvkbd", &[]));
+        assert!(is_excluded("Squeekboard virtual keyboard", &[]));
+        assert!(!is_excluded("AT Translated Set 2 keyboard", &[]));
+    }
+
+    #[test]
+    fn user_exclusions_match_substrings_ignoring_case() {
+        let exclude = vec!["yubico".to_string()];
+        assert!(is_excluded("Yubico YubiKey OTP+FIDO+CCID", &exclude));
+        assert!(!is_excluded("Logitech K120", &exclude));
+    }
+
+    // --- spec: reconnecting keyboards ---
+
+    fn identity(name: &str, uniq: &str, phys: &str) -> Identity {
+        Identity { name: name.into(), uniq: uniq.into(), phys: phys.into() }
+    }
+
+    #[test]
+    fn bluetooth_keyboard_is_known_by_address() {
+        let before = identity("K380", "f4:73:35:aa:bb:cc", "00:1a:7d:da:71:13");
+        let after = identity("K380", "f4:73:35:aa:bb:cc", "00:1a:7d:da:71:13");
+        let other = identity("K380", "f4:73:35:dd:ee:ff", "00:1a:7d:da:71:13");
+        assert!(before.same_keyboard(&after));
+        assert!(!before.same_keyboard(&other));
+    }
+
+    #[test]
+    fn keyboard_without_address_is_known_by_port() {
+        let usb = identity("Logitech K120", "", "usb-0000:00:14.0-2/input0");
+        assert!(usb.same_keyboard(&usb.clone()));
+        assert!(!usb.same_keyboard(&identity("Logitech K120", "", "usb-0000:00:14.0-3/input0")));
+        assert!(!identity("x", "", "").same_keyboard(&identity("x", "", "")));
+    }
+
+    #[test]
+    fn different_names_are_different_keyboards() {
+        let kbd = identity("Combo Keyboard", "1234", "");
+        assert!(!kbd.same_keyboard(&identity("Combo Consumer Control", "1234", "")));
+    }
+
+    // --- spec: keyboard heuristic ---
+
+    fn caps(keys: usize, autorepeat: bool, pointer_axes: bool) -> Capabilities {
+        Capabilities { typing_keys: true, keys, autorepeat, pointer_axes }
+    }
+
+    #[test]
+    fn keyboards_pass() {
+        assert!(caps(250, true, false).is_keyboard(), "laptop keyboard");
+        assert!(caps(120, true, true).is_keyboard(), "keyboard with a trackpoint");
+    }
+
+    #[test]
+    fn mice_and_remotes_with_letter_keys_fail() {
+        assert!(!caps(110, false, true).is_keyboard(), "gaming mouse macro keys");
+        assert!(!caps(45, true, false).is_keyboard(), "remote control");
+    }
+
+    #[test]
+    fn letters_are_required() {
+        let caps = Capabilities { typing_keys: false, ..caps(250, true, false) };
+        assert!(!caps.is_keyboard());
+    }
+
+    #[test]
+    fn include_matches_substrings_ignoring_case() {
+        let include = vec!["ergodox".to_string()];
+        assert!(matches_any("ErgoDox EZ", &include));
+        assert!(!matches_any("Logitech K120", &include));
+    }
+
+    // --- spec: discovery through sysfs (logind access) ---
+
+    #[test]
+    fn bitmap_words_are_most_significant_first() {
+        let bitmap = Bitmap::parse("3 1");
+        assert!(bitmap.contains(0));
+        assert!(bitmap.contains(Bitmap::WORD_BITS));
+        assert!(bitmap.contains(Bitmap::WORD_BITS + 1));
+        assert!(!bitmap.contains(1));
+        assert!(!bitmap.contains(10 * Bitmap::WORD_BITS));
+        assert_eq!(bitmap.count(), 3);
+    }
+
+    #[test]
+    fn probe_reads_sysfs() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |file: &str, contents: &str| {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        write("name", "AT Translated Set 2 keyboard\n");
+        write("phys", "isa0060/serio0/input0\n");
+        write("uniq", "\n");
+        write("id/vendor", "0001\n");
+        write("id/product", "0001\n");
+        write("capabilities/ev", "120013\n");
+        // Every key from 1 to 127 in the lowest words, whatever their width
+        let low = if Bitmap::WORD_BITS == 64 { "ffffffffffffffff fffffffffffffffe" } else { "ffffffff ffffffff ffffffff fffffffe" };
+        write("capabilities/key", &format!("{}\n", low));
+        write("capabilities/rel", "0\n");
+        write("capabilities/abs", "0\n");
+
+        let probe = Probe::from_sysfs(dir.path()).unwrap();
+        assert_eq!(probe.identity.name, "AT Translated Set 2 keyboard");
+        assert_eq!(probe.identity.phys, "isa0060/serio0/input0");
+        assert_eq!(probe.input_id, (1, 1));
+        assert!(probe.caps.typing_keys);
+        assert!(probe.caps.autorepeat);
+        assert_eq!(probe.caps.keys, 127);
+        assert!(probe.caps.is_keyboard());
+    }
+
+    #[test]
+    fn sysfs_dir_follows_the_node_name() {
+        assert_eq!(sysfs_dir(Path::new("/dev/input/event3")), Path::new("/sys/class/input/event3/device"));
+    }
+
+    // --- spec: event timestamps ---
+
+    #[test]
+    fn event_time_is_the_age_of_the_stamp() {
+        let mono = Duration::from(nix::time::clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC).unwrap());
+        let stamped = mono - Duration::from_millis(100);
+        let stamp = nix::libc::timeval { tv_sec: stamped.as_secs() as _, tv_usec: stamped.subsec_micros() as _ };
+        let age = Instant::now() - event_time(&stamp);
+        assert!(age >= Duration::from_millis(100) && age < Duration::from_secs(1), "{:?}", age);
+    }
+
+    #[test]
+    fn wall_clock_stamps_count_as_now() {
+        let wall = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+        let stamp = nix::libc::timeval { tv_sec: wall.as_secs() as _, tv_usec: 0 };
+        assert!(Instant::now() - event_time(&stamp) < Duration::from_secs(1));
+    }
+
+    // --- spec: combo keyboards ---
+
+    #[test]
+    fn pointer_side_events_bypass_the_keyboard() {
+        assert!(is_pointer_event(&RawEvent::new(EV_REL, 0, 3)));
+        assert!(is_pointer_event(&RawEvent::new(EV_ABS, 0x35, 812)));
+        assert!(is_pointer_event(&RawEvent::new(EV_SW, 0, 1)));
+        assert!(is_pointer_event(&RawEvent::key(0x110, 1))); // BTN_LEFT
+        assert!(is_pointer_event(&RawEvent::key(0x14a, 1))); // BTN_TOUCH
+    }
+
+    #[test]
+    fn keys_and_sync_stay_with_the_keyboard() {
+        assert!(!is_pointer_event(&RawEvent::key(30, 1)));
+        assert!(!is_pointer_event(&RawEvent::new(EV_SYN, 0, 0)));
+        assert!(!is_pointer_event(&RawEvent::new(0x04, 0x04, 0x70004))); // MSC_SCAN
+    }
+
+    #[test]
+    fn mirror_is_never_grabbed() {
+        assert!(is_own_device("TPPS/2 Elan TrackPoint (accentd mirror)", (0x17ef, 0x6009)));
+    }
+
+    #[test]
+    fn own_device_is_recognized() {
+        assert!(is_own_device("accentd virtual keyboard", (0xacce, 0x0001)));
+        assert!(is_own_device("accentd virtual keyboard", (0, 0)));
+        assert!(is_own_device("renamed", (0xacce, 0x0001)));
+        assert!(!is_own_device("kanata", (0x1234, 0x5678)));
+    }
+
 }
@@ -0,0 +1,61 @@
+use crate::compositor::sway;
+use evdev::Device;
+use serde_json::Value;
+use std::path::PathBuf;
+use tracing::debug;
+
+/// Find the desktop's autorepeat delay in milliseconds: sway's, when its
+/// socket can be found, else the kernel's per-device value (kbdrate), which
+/// is what a system service can always read. Session settings such as
+/// GNOME's are out of reach of the root daemon and not consulted.
+/// Returns the delay and the name of the source it came from.
+pub fn detect_repeat_delay(keyboards: &[PathBuf]) -> Option<(u64, &'static str)> {
+    if let Some(socket) = sway::find_socket() {
+        match sway::get_inputs(&socket) {
+            Ok(inputs) => {
+                if let Some(delay) = sway_delay(&inputs) {
+                    return Some((delay, "sway"));
+                }
+            }
+            Err(e) => debug!(error = %format!("{:#}", e), "repeat delay query failed"),
+        }
+    }
+    keyboards.iter().find_map(|path| {
+        let repeat = Device::open(path).ok()?.get_auto_repeat()?;
+        (repeat.delay > 0).then_some((repeat.delay as u64, "kernel"))
+    })
+}
+
+/// The repeat_delay of the first keyboard in sway's `get_inputs` reply.
+fn sway_delay(inputs: &Value) -> Option<u64> {
+    inputs
+        .as_array()?
+        .iter()
+        .filter(|input| input["type"] == "keyboard")
+        .find_map(|input| input["repeat_delay"].as_u64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_sway_delay(json: &str) -> Option<u64> {
+        sway_delay(&serde_json::from_str(json).ok()?)
+    }
+
+    #[test]
+    fn parses_first_sway_keyboard() {
+        let json = r#"[
+            {"identifier": "1:1:mouse", "type": "pointer"},
+            {"identifier": "1:1:kbd", "type": "keyboard", "repeat_delay": 250, "repeat_rate": 30}
+        ]"#;
+        assert_eq!(parse_sway_delay(json), Some(250));
+    }
+
+    #[test]
+    fn sway_without_keyboards_yields_none() {
+        assert_eq!(parse_sway_delay(r#"[{"type": "touchpad"}]"#), None);
+        assert_eq!(parse_sway_delay(r#"{"error": "nope"}"#), None);
+        assert_eq!(parse_sway_delay("not json"), None);
+    }
+}
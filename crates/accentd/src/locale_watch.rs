@@ -0,0 +1,197 @@
+use crate::Shared;
+use accentd_core::config::Config;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{debug, info, warn};
+
+/// Editors save in bursts (write, rename, chmod); wait this long for the
+/// burst to settle before reloading.
+const DEBOUNCE: Duration = Duration::from_millis(150);
+
+/// Directories that can hold the active locale or inline overrides.
+fn watched_dirs() -> Vec<PathBuf> {
+    vec![
+        Config::config_dir(),
+        Config::config_dir().join("locales"),
+        PathBuf::from("/usr/share/accentd/locales"),
+    ]
+}
+
+/// What a batch of changed paths calls for.
+#[derive(Debug, Default, PartialEq)]
+struct Reload {
+    config: bool,
+    locale: bool,
+}
+
+/// `config.toml` itself, or a `.toml` named after one of the `active`
+/// locales (or one they extend) in a locales directory. Anything else (editor backups, other
+/// locales) is ignored.
+fn reload_for(changed: &[PathBuf], config_dir: &Path, active: &[String]) -> Reload {
+    let mut reload = Reload::default();
+    for path in changed {
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name().and_then(|n| n.to_str())) else {
+            continue;
+        };
+        if dir == config_dir {
+            reload.config |= name == "config.toml";
+        } else if let Some(stem) = name.strip_suffix(".toml") {
+            reload.locale |= active.iter().any(|a| a == stem);
+        }
+    }
+    reload
+}
+
+/// Watch locale files and config.toml, re-applying the active locale map to
+/// all state machines when one of them changes.
+///
+/// Directories are watched instead of files so atomic saves (write to a temp
+/// file, rename over the original) are picked up too.
+pub fn spawn(shared: Arc<Mutex<Shared>>) {
+    let mut inotify = match Inotify::init() {
+        Ok(inotify) => inotify,
+        Err(e) => {
+            warn!(error = %e, "inotify unavailable, locale hot-reload disabled");
+            return;
+        }
+    };
+
+    let mask = WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE | WatchMask::DELETE;
+    let mut dirs: HashMap<WatchDescriptor, PathBuf> = HashMap::new();
+    for dir in watched_dirs() {
+        match inotify.watches().add(&dir, mask) {
+            Ok(wd) => {
+                debug!(dir = %dir.display(), "watching for locale changes");
+                dirs.insert(wd, dir);
+            }
+            Err(e) => debug!(dir = %dir.display(), error = %e, "not watching"),
+        }
+    }
+    if dirs.is_empty() {
+        return;
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<PathBuf>();
+    let user_locales = Config::config_dir().join("locales");
+
+    // inotify reads block, so they get their own thread
+    let created = user_locales.clone();
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 4096];
+        loop {
+            let events = match inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => events,
+                Err(e) => {
+                    warn!(error = %e, "inotify read error, locale hot-reload stopped");
+                    return;
+                }
+            };
+            for event in events {
+                let (Some(dir), Some(name)) = (dirs.get(&event.wd), event.name) else {
+                    continue;
+                };
+                let path = dir.join(name);
+                // `mkdir ~/.config/accentd/locales` after startup: watch it
+                // too, and reload in case files were moved in with it
+                if event.mask.contains(EventMask::ISDIR) && path == created {
+                    match inotify.watches().add(&path, mask) {
+                        Ok(wd) => {
+                            debug!(dir = %path.display(), "watching for locale changes");
+                            dirs.insert(wd, path.clone());
+                        }
+                        Err(e) => debug!(dir = %path.display(), error = %e, "not watching"),
+                    }
+                }
+                if tx.send(path).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        while let Some(first) = rx.recv().await {
+            tokio::time::sleep(DEBOUNCE).await;
+            let mut changed = vec![first];
+            while let Ok(name) = rx.try_recv() {
+                changed.push(name);
+            }
+
+            let mut shared = shared.lock().await;
+            // the locales the active ones extend count too
+            let mut names = shared.config.locale.active.names().to_vec();
+            names.extend(shared.locale.extends.iter().cloned());
+            let mut wanted = reload_for(&changed, &Config::config_dir(), &names);
+            wanted.locale |= changed.contains(&user_locales);
+            if wanted == Reload::default() {
+                continue;
+            }
+            reload(&mut shared, wanted.config);
+        }
+    });
+}
+
+fn reload(shared: &mut Shared, config_changed: bool) {
+    if config_changed {
+        // Only inline locale maps, how they merge, overrides, snippets,
+        // emoji and the keymap are picked up; the active locale stays whatever was
+        // last set (possibly via IPC).
+        match Config::load() {
+            Ok(config) => {
+                shared.config.locale.locales = config.locale.locales;
+                shared.config.locale.merge = config.locale.merge;
+                shared.config.locale.dedupe = config.locale.dedupe;
+                shared.config.locale.overrides = config.locale.overrides;
+                shared.config.locale.upper = config.locale.upper;
+                shared.config.locale.erase = config.locale.erase;
+                shared.config.locale.descriptions = config.locale.descriptions;
+                shared.config.snippets = config.snippets;
+                shared.config.emoji = config.emoji;
+                shared.config.keymap = config.keymap;
+                crate::refresh_keymap(shared);
+            }
+            Err(e) => {
+                warn!(error = %e, "config changed but failed to parse, keeping current locale");
+                return;
+            }
+        }
+    }
+
+    match shared.config.load_locale() {
+        Ok(locale) => {
+            info!(locale = %shared.config.locale.active, keys = locale.map.len(), "locale reloaded");
+            crate::set_locale_map(shared, locale);
+        }
+        Err(e) => {
+            warn!(locale = %shared.config.locale.active, error = %e, "locale changed but failed to load, keeping current map");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // --- spec: which changes reload ---
+
+    #[test]
+    fn only_config_toml_and_active_locale_files_reload() {
+        let config_dir = Path::new("/home/me/.config/accentd");
+        let active = ["fr".to_string(), "mine".to_string()];
+        let reload = |paths: &[&str]| {
+            let changed: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+            reload_for(&changed, config_dir, &active)
+        };
+        assert_eq!(reload(&["/home/me/.config/accentd/config.toml"]), Reload { config: true, locale: false });
+        assert_eq!(reload(&["/home/me/.config/accentd/locales/mine.toml"]), Reload { config: false, locale: true });
+        assert_eq!(reload(&["/usr/share/accentd/locales/fr.toml"]), Reload { config: false, locale: true });
+        assert_eq!(reload(&["/home/me/.config/accentd/locales/de.toml"]), Reload::default());
+        assert_eq!(reload(&["/home/me/.config/accentd/locales/mine.toml~"]), Reload::default());
+        assert_eq!(reload(&["/home/me/.config/accentd/locales/config.toml"]), Reload::default());
+        assert_eq!(reload(&["/home/me/.config/accentd/mine.toml"]), Reload::default(), "not a locales directory");
+    }
+}
@@ -0,0 +1,219 @@
+//! Device access through logind (`access = "logind"`).
+//!
+//! As the controller of its session, accentd asks logind for each keyboard
+//! (TakeDevice) instead of opening /dev/input itself, so it can run as a
+//! plain user service without the `input` group. A session has only one
+//! controller, and Wayland compositors that use logind already are it.
+
+use crate::backend::{EventSource, KeyState};
+use crate::grabber::{self, Identity};
+use accentd_core::config::DeviceAccess;
+use accentd_core::state_machine::RawEvent;
+use anyhow::{Context, Result};
+use nix::libc::input_event;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::Path;
+use std::time::Instant;
+use tokio::io::unix::AsyncFd;
+use tracing::{info, warn};
+use zbus::zvariant;
+
+const LOGIND: &str = "org.freedesktop.login1";
+
+nix::ioctl_write_int!(eviocgrab, b'E', 0x90);
+nix::ioctl_read_buf!(eviocgkey, b'E', 0x18, u8);
+nix::ioctl_read_buf!(eviocgled, b'E', 0x19, u8);
+
+const LED_CAPSL: usize = 1;
+
+/// Our session, under our control.
+#[derive(Clone)]
+pub struct Logind {
+    session: zbus::Proxy<'static>,
+}
+
+impl Logind {
+    /// Become the controller of the user's session.
+    pub async fn take_control() -> Result<Self> {
+        let conn = zbus::Connection::system().await.context("connecting to system bus")?;
+        let session = zbus::Proxy::new(
+            &conn,
+            LOGIND,
+            "/org/freedesktop/login1/session/auto",
+            "org.freedesktop.login1.Session",
+        )
+        .await?;
+        session
+            .call::<_, _, ()>("TakeControl", &(false,))
+            .await
+            .context("taking control of the session (does the compositor control it already?)")?;
+        info!("opening keyboards through logind");
+        Ok(Self { session })
+    }
+
+    /// Have logind open the keyboard at `path`, and grab it unless `grab`
+    /// is off.
+    pub async fn open(&self, path: &Path, grab: bool) -> Result<LogindSource> {
+        let rdev = nix::sys::stat::stat(path).with_context(|| format!("reading {}", path.display()))?.st_rdev;
+        let devnum = (nix::sys::stat::major(rdev) as u32, nix::sys::stat::minor(rdev) as u32);
+        let (fd, inactive): (zvariant::OwnedFd, bool) = self
+            .session
+            .call("TakeDevice", &devnum)
+            .await
+            .with_context(|| format!("taking {} from logind", path.display()))?;
+        if inactive {
+            warn!(path = %path.display(), "session inactive, no events until it is active again");
+        }
+        let file = File::from(OwnedFd::from(fd));
+        let source = LogindSource::new(path, file, grab, Release { session: self.session.clone(), devnum });
+        source.with_context(|| format!("opening {}", path.display()))
+    }
+}
+
+/// Hands a device back to logind when its source goes away, so it can be
+/// taken again (a second TakeDevice of the same device fails).
+struct Release {
+    session: zbus::Proxy<'static>,
+    devnum: (u32, u32),
+}
+
+impl Drop for Release {
+    fn drop(&mut self) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let session = self.session.clone();
+        let devnum = self.devnum;
+        runtime.spawn(async move {
+            let _ = session.call::<_, _, ()>("ReleaseDevice", &devnum).await;
+        });
+    }
+}
+
+/// A keyboard opened by logind, read straight from its fd.
+pub struct LogindSource {
+    identity: Identity,
+    input_id: (u16, u16),
+    fd: AsyncFd<File>,
+    key_state: KeyState,
+    pending: VecDeque<(RawEvent, Instant)>,
+    _release: Release,
+}
+
+impl LogindSource {
+    fn new(path: &Path, file: File, grab: bool, release: Release) -> Result<Self> {
+        let probe = grabber::probe(path, DeviceAccess::Logind)?;
+        let raw = file.as_raw_fd();
+        nix::fcntl::fcntl(raw, nix::fcntl::FcntlArg::F_SETFL(nix::fcntl::OFlag::O_NONBLOCK))?;
+        grabber::use_monotonic_clock(raw);
+
+        let mut key_state = KeyState::default();
+        if grab {
+            info!(device = %probe.identity.name, path = %path.display(), "grabbing device");
+            // SAFETY: `raw` is the open evdev fd owned by `file`
+            unsafe { eviocgrab(raw, 1) }.with_context(|| format!("grabbing {}", path.display()))?;
+            // Read after grabbing, so no change slips in between
+            let mut keys = [0u8; 96];
+            let mut leds = [0u8; 8];
+            // SAFETY: as above; the buffers cover KEY_MAX and LED_MAX bits
+            if unsafe { eviocgkey(raw, &mut keys) }.is_ok() {
+                key_state.held = set_bits(&keys).map(|code| code as u16).collect();
+            }
+            if unsafe { eviocgled(raw, &mut leds) }.is_ok() {
+                key_state.caps_lock = set_bits(&leds).any(|led| led == LED_CAPSL);
+            }
+        } else {
+            info!(device = %probe.identity.name, path = %path.display(), "watching device (not grabbed)");
+        }
+
+        Ok(Self {
+            identity: probe.identity,
+            input_id: probe.input_id,
+            fd: AsyncFd::new(file).context("registering device fd")?,
+            key_state,
+            pending: VecDeque::new(),
+            _release: release,
+        })
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+}
+
+impl EventSource for LogindSource {
+    fn name(&self) -> &str {
+        &self.identity.name
+    }
+
+    async fn next_event(&mut self) -> Result<(RawEvent, Instant)> {
+        let mut buffer = [0u8; EVENT_SIZE * 64];
+        while self.pending.is_empty() {
+            let mut ready = self.fd.readable().await?;
+            match ready.try_io(|fd| fd.get_ref().read(&mut buffer)) {
+                Ok(Ok(0)) => anyhow::bail!("device closed"),
+                Ok(Ok(n)) => self.pending.extend(parse_events(&buffer[..n])),
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_would_block) => continue,
+            }
+        }
+        Ok(self.pending.pop_front().expect("checked above"))
+    }
+
+    fn input_id(&self) -> Option<(u16, u16)> {
+        Some(self.input_id)
+    }
+
+    fn key_state(&self) -> KeyState {
+        self.key_state.clone()
+    }
+}
+
+const EVENT_SIZE: usize = std::mem::size_of::<input_event>();
+
+fn parse_events(bytes: &[u8]) -> impl Iterator<Item = (RawEvent, Instant)> + '_ {
+    bytes.chunks_exact(EVENT_SIZE).map(|chunk| {
+        // SAFETY: the chunk is one whole `input_event`, as the kernel wrote it
+        let event: input_event = unsafe { std::ptr::read_unaligned(chunk.as_ptr().cast()) };
+        let time = grabber::event_time(&event.time);
+        (RawEvent::new(event.type_, event.code, event.value), time)
+    })
+}
+
+/// Indices of the bits set in an EVIOCGKEY/EVIOCGLED buffer.
+fn set_bits(bytes: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    (0..bytes.len() * 8).filter(move |bit| bytes[bit / 8] >> (bit % 8) & 1 == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // --- spec: events are read from the raw fd ---
+
+    #[test]
+    fn parses_input_events() {
+        let stamped = |type_, code, value| input_event {
+            time: nix::libc::timeval { tv_sec: 0, tv_usec: 0 },
+            type_,
+            code,
+            value,
+        };
+        let written = [stamped(1, 18, 1), stamped(0, 0, 0)];
+        // SAFETY: reading plain old data as bytes
+        let bytes = unsafe { std::slice::from_raw_parts(written.as_ptr().cast::<u8>(), EVENT_SIZE * 2) };
+        let events: Vec<_> = parse_events(bytes).map(|(event, _)| event).collect();
+        assert_eq!(events, vec![RawEvent::key(18, 1), RawEvent::new(0, 0, 0)]);
+    }
+
+    #[test]
+    fn key_bitmap_gives_held_codes() {
+        let mut keys = [0u8; 96];
+        keys[42 / 8] |= 1 << (42 % 8);
+        keys[29 / 8] |= 1 << (29 % 8);
+        assert_eq!(set_bits(&keys).collect::<Vec<_>>(), vec![29, 42]);
+    }
+}
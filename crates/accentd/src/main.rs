@@ -1,17 +1,36 @@
+mod backend;
 mod compose;
+mod compositor;
+mod devices;
 mod grabber;
-mod state_machine;
+mod keyrepeat;
+mod locale_watch;
+mod logind;
+mod notify;
+mod panic_combo;
+mod record;
+mod session;
 mod uinput_emitter;
+mod xkb;
 
-use accentd_core::config::{self, Config};
-use accentd_core::ipc::{self, ClientMsg, DaemonMsg};
+use accentd_core::calibration::Calibration;
+use accentd_core::charmap::{self, AccentTable, Keymap, Selection};
+use accentd_core::clock::{Clock, SystemClock};
+use accentd_core::config::{self, CandidateOrder, Config, DeviceAccess};
+use accentd_core::ipc::{self, ClientMsg, DaemonMsg, SuspendReason};
+use accentd_core::locale::Locale;
+use accentd_core::state_machine::{self, Action, RawEvent, StateMachine};
+use accentd_core::usage::{self, UsageStats};
 use anyhow::{Context, Result};
-use evdev::uinput::VirtualDevice;
-use state_machine::{Action, StateMachine};
+use backend::Emitter;
+use clap::Parser;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::{mpsc, Mutex};
 use tracing::{error, info, warn};
 
@@ -19,13 +38,72 @@ use tracing::{error, info, warn};
 struct Shared {
     config: Config,
     state_machines: Vec<StateMachine>,
-    vdev: VirtualDevice,
+    emitter: Box<dyn backend::Emitter>,
+    /// Partly relayed input frame per keyboard, indexed like `state_machines`.
+    frames: Vec<backend::FrameBuffer>,
+    /// Each keyboard's forwarding task and identity, indexed like
+    /// `state_machines`.
+    slots: Vec<devices::Slot>,
+    /// Where grabber tasks send keyboard events.
+    event_tx: mpsc::UnboundedSender<backend::DeviceEvent>,
+    /// With `access = "logind"`, where keyboards are opened.
+    logind: Option<logind::Logind>,
     /// Channels to send messages to connected popup clients.
     popup_txs: Vec<mpsc::UnboundedSender<String>>,
+    /// The active locale as loaded, before any usage ranking.
+    locale: Locale,
+    usage: UsageStats,
+    /// Where popups go while no popup client is connected.
+    fallback: notify::Fallback,
+    /// Channels to clients subscribed to status changes.
+    status_txs: Vec<mpsc::UnboundedSender<String>>,
+    /// Focused window as reported by the compositor, if one is tracked.
+    focus: Option<compositor::FocusedWindow>,
+    /// The compositor's active layout group (0 until it reports one).
+    layout_group: usize,
+    /// `[keymap]` over the letters `[devices.xkb]` gives for `layout_group`.
+    keymap: Keymap,
+    /// Active reasons for suspending hold detection; empty means running.
+    suspended: BTreeSet<SuspendReason>,
+    /// Per keyboard, indexed like `state_machines`; `enabled` is filled in
+    /// when reporting.
+    devices: Vec<ipc::DeviceStatus>,
+    started: Instant,
+    /// `--dry-run`: keyboards are only watched; decisions are logged.
+    dry_run: bool,
+    /// `grab = false`: keyboards are only watched, and picks type over what
+    /// the apps already got.
+    echo: Option<backend::Echo>,
+    /// Hold durations being sampled for `accentctl calibrate`.
+    calibration: Option<Calibration>,
+    /// Channels to `accentctl monitor` clients.
+    monitor_txs: Vec<mpsc::UnboundedSender<String>>,
+}
+
+#[derive(Parser)]
+#[command(name = "accentd", about = "Press-and-hold accent daemon", version)]
+struct Cli {
+    /// Watch keyboards without grabbing them or creating a virtual device,
+    /// and log what would have been suppressed or typed
+    #[arg(long)]
+    dry_run: bool,
+    /// Write every keyboard event to FILE for a bug report. It holds
+    /// everything typed, passwords included
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+    /// Run the event log FILE through the state machines and print what
+    /// they did, instead of starting
+    #[arg(long, value_name = "FILE", requires = "simulate")]
+    replay: Option<PathBuf>,
+    /// With --replay: nothing is grabbed or typed (required)
+    #[arg(long, requires = "replay")]
+    simulate: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
     tracing_subscriber::fmt()
         .with_env_filter(
             tracing_subscriber::EnvFilter::from_default_env()
@@ -35,46 +113,130 @@ async fn main() -> Result<()> {
 
     info!("accentd starting");
 
-    let config = Config::load().context("loading config")?;
-    let locale_map = config.load_locale_map().context("loading locale")?;
-    info!(locale = %config.locale.active, keys = locale_map.len(), "locale loaded");
+    let mut config = Config::load().context("loading config")?;
+    if let Some(path) = &cli.replay {
+        return record::replay(path, config);
+    }
+    let locale = config.load_locale().context("loading locale")?;
+    info!(locale = %config.locale.active, keys = locale.map.len(), "locale loaded");
+
+    // Create virtual device first so its nodes can be excluded from grabbing
+    let emitter: Box<dyn backend::Emitter> = if cli.dry_run {
+        info!("dry run: keyboards are watched, not grabbed, and nothing is typed");
+        Box::new(backend::DryRun)
+    } else {
+        let text = compose::Backends::new(config.compose.clone());
+        let emitter = uinput_emitter::UinputEmitter::new(config.devices.per_keyboard_output, text)
+            .context("creating virtual device")?;
+        release_keys_on_panic(emitter.releaser());
+        Box::new(emitter)
+    };
+    let mut emitter = Box::new(backend::Queued::spawn(emitter)?);
+    let own_nodes = emitter.dev_nodes();
+    let passive = !config.general.grab && !cli.dry_run;
+    if passive {
+        info!("grab = false: keyboards are watched, not grabbed; picks replace what the hold typed");
+    }
+
+    let logind = match config.devices.access {
+        DeviceAccess::Direct => None,
+        DeviceAccess::Logind => Some(logind::Logind::take_control().await.context("access = \"logind\"")?),
+    };
 
     // Find and grab keyboards
-    let keyboards = grabber::find_keyboards().context("finding keyboards")?;
+    let upstream = &config.devices.upstream;
+    let keyboards = grabber::find_keyboards(&config.devices, &own_nodes).context("finding keyboards")?;
     if keyboards.is_empty() {
+        if !upstream.is_empty() {
+            anyhow::bail!("no upstream keyboard found named {:?} — is the remapper running?", upstream);
+        }
         anyhow::bail!("no keyboards found — check permissions (group 'input' or udev rules)");
     }
 
-    // Create virtual device
-    let vdev = uinput_emitter::create_virtual_device().context("creating virtual device")?;
+    if config.general.threshold_ms.is_none() {
+        if let Some((delay, source)) = keyrepeat::detect_repeat_delay(&keyboards) {
+            let threshold = config::threshold_from_repeat_delay(delay);
+            info!(delay, source, threshold, "hold threshold derived from key-repeat delay");
+            config.general.threshold_ms = Some(threshold);
+        }
+    }
+
+    let usage = UsageStats::load_from(&UsageStats::path()).unwrap_or_else(|e| {
+        warn!(error = %e, "ignoring unreadable usage stats");
+        UsageStats::default()
+    });
+
+    let keymap = xkb::keymap(&config, 0);
+    let mut recorder = match &cli.record {
+        Some(path) => Some(record::Recorder::create(path, &config, &locale, &keymap, &usage)?),
+        None => None,
+    };
 
-    // Create per-device state machines
+    // Create per-device state machines, all sharing one accent table
+    let accents = accent_table(&config, &usage, &locale);
     let state_machines: Vec<StateMachine> = keyboards
         .iter()
-        .map(|_| StateMachine::new(&config, locale_map.clone()))
+        .map(|_| StateMachine::new(&config, accents.clone()))
         .collect();
 
+    let (fallback, fallback_picks) = if config.popup.notify_fallback {
+        let (fallback, picks) = notify::Fallback::spawn();
+        (fallback, Some(picks))
+    } else {
+        (notify::Fallback::log_only(), None)
+    };
+
+    // Event channel from grabbed devices
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+
     let shared = Arc::new(Mutex::new(Shared {
         config: config.clone(),
+        frames: state_machines.iter().map(|_| Default::default()).collect(),
+        slots: state_machines.iter().map(|_| Default::default()).collect(),
+        event_tx,
+        logind,
         state_machines,
-        vdev,
+        emitter,
         popup_txs: Vec::new(),
+        locale,
+        usage,
+        fallback,
+        status_txs: Vec::new(),
+        focus: None,
+        layout_group: 0,
+        keymap,
+        suspended: BTreeSet::new(),
+        devices: keyboards
+            .iter()
+            .map(|path| ipc::DeviceStatus {
+                name: String::new(),
+                path: path.display().to_string(),
+                grabbed: false,
+                enabled: false,
+                events: 0,
+                error: None,
+            })
+            .collect(),
+        started: Instant::now(),
+        dry_run: cli.dry_run,
+        echo: passive.then(backend::Echo::default),
+        calibration: None,
+        monitor_txs: Vec::new(),
     }));
 
-    // Event channel from grabbed devices
-    let (event_tx, mut event_rx) = mpsc::unbounded_channel();
-
     // Spawn grabber tasks
-    for (idx, path) in keyboards.iter().enumerate() {
-        let tx = event_tx.clone();
-        let path = path.clone();
-        tokio::spawn(async move {
-            if let Err(e) = grabber::grab_device(path.clone(), idx, tx).await {
+    {
+        let mut guard = shared.lock().await;
+        for (idx, path) in keyboards.iter().enumerate() {
+            if devices::held_off(&guard) {
+                info!(path = %path.display(), "disabled, not grabbing until enabled");
+                continue;
+            }
+            if let Err(e) = devices::attach(&shared, &mut guard, idx, path) {
                 error!(path = %path.display(), error = %e, "grabber task failed");
             }
-        });
+        }
     }
-    drop(event_tx); // Close our copy so the channel closes when all grabbers exit
 
     // Start IPC listener
     let socket_path = config::socket_path();
@@ -93,6 +255,23 @@ async fn main() -> Result<()> {
     }
     info!(path = %socket_path.display(), "IPC socket listening");
 
+    if let Some(mut picks) = fallback_picks {
+        let shared = Arc::clone(&shared);
+        tokio::spawn(async move {
+            while let Some(index) = picks.recv().await {
+                info!(index, "selection via notification");
+                select(&mut *shared.lock().await, index);
+            }
+        });
+    }
+
+    locale_watch::spawn(Arc::clone(&shared));
+    devices::spawn_hotplug(Arc::clone(&shared));
+    compositor::spawn(&config.compositor, Arc::clone(&shared));
+    if config.general.suspend_on_lock {
+        session::spawn(Arc::clone(&shared));
+    }
+
     let shared_ipc = Arc::clone(&shared);
     tokio::spawn(async move {
         loop {
@@ -110,9 +289,11 @@ async fn main() -> Result<()> {
 
     // Panic key combo: Backspace → Escape → Enter within 1 second exits the daemon.
     // Safety escape hatch if the daemon hangs with EVIOCGRAB held.
-    let mut panic_ring: [(u16, Instant); 3] = [(0, Instant::now()); 3];
-    let mut panic_idx: usize = 0;
-    const PANIC_SEQ: [u16; 3] = [14, 1, 28]; // KEY_BACKSPACE, KEY_ESC, KEY_ENTER
+    let mut panic_combo = panic_combo::PanicCombo::new();
+    let clock = SystemClock;
+
+    let mut sigterm = signal(SignalKind::terminate()).context("installing SIGTERM handler")?;
+    let mut sigint = signal(SignalKind::interrupt()).context("installing SIGINT handler")?;
 
     // Main event loop: event-driven timer (no idle wakeups)
     loop {
@@ -123,88 +304,409 @@ async fn main() -> Result<()> {
                 .filter_map(|sm| sm.next_deadline())
                 .min()
         };
-        let sleep_fut = match deadline {
-            Some(dl) => {
-                let tokio_instant = tokio::time::Instant::from_std(dl);
-                tokio::time::sleep_until(tokio_instant)
-            }
-            None => tokio::time::sleep_until(tokio::time::Instant::now() + std::time::Duration::from_secs(86400)),
-        };
+        let sleep_fut = tokio::time::sleep(match deadline {
+            Some(dl) => clock.time_until(dl),
+            None => Duration::from_secs(86400),
+        });
         let has_deadline = deadline.is_some();
 
         tokio::select! {
             Some(dev_event) = event_rx.recv() => {
                 // Check panic key combo (key press events only)
-                if dev_event.event.event_type() == evdev::EventType::KEY && dev_event.event.value() == 1 {
-                    let code = dev_event.event.code();
-                    panic_ring[panic_idx] = (code, Instant::now());
-                    panic_idx = (panic_idx + 1) % 3;
-                    // Check if the last 3 presses match Backspace→Escape→Enter within 1s
-                    let oldest = (panic_idx) % 3;
-                    let codes = [
-                        panic_ring[oldest].0,
-                        panic_ring[(oldest + 1) % 3].0,
-                        panic_ring[(oldest + 2) % 3].0,
-                    ];
-                    if codes == PANIC_SEQ {
-                        let elapsed = panic_ring[(oldest + 2) % 3].1.duration_since(panic_ring[oldest].1);
-                        if elapsed.as_millis() < 1000 {
-                            info!("panic key combo detected (Backspace→Escape→Enter), exiting");
-                            std::process::exit(0);
-                        }
-                    }
+                if dev_event.frame.iter().any(|e| e.is_key() && e.value == 1 && panic_combo.press(e.code)) {
+                    info!("panic key combo detected (Backspace→Escape→Enter), exiting");
+                    break;
                 }
 
                 let mut shared = shared.lock().await;
                 let idx = dev_event.device_idx;
+                if let Some(rec) = &mut recorder {
+                    let name = shared.devices.get(idx).map_or("", |d| d.name.as_str());
+                    let layout = shared.state_machines.get(idx).map(|sm| sm.layout()).unwrap_or_default();
+                    if let Err(e) = rec.frame(idx, name, layout, &dev_event.frame, dev_event.time) {
+                        warn!(error = %e, "recording stopped");
+                        recorder = None;
+                    }
+                }
                 if idx < shared.state_machines.len() {
-                    let actions = shared.state_machines[idx].process_event(dev_event.event);
-                    process_actions(&mut shared, actions);
+                    // Modifiers in the frame apply to its other keys
+                    shared.state_machines[idx].begin_frame(&dev_event.frame);
+                    for event in dev_event.frame {
+                        process_device_event(&mut shared, idx, event, dev_event.time);
+                    }
                 }
             }
             _ = sleep_fut, if has_deadline => {
                 let mut shared = shared.lock().await;
-                let mut all_actions = Vec::new();
-                for sm in &mut shared.state_machines {
-                    all_actions.extend(sm.check_timer());
-                }
-                if !all_actions.is_empty() {
-                    process_actions(&mut shared, all_actions);
+                for idx in 0..shared.state_machines.len() {
+                    let actions = shared.state_machines[idx].check_timer();
+                    if !actions.is_empty() {
+                        send_monitor(&mut shared, idx, None, &actions);
+                        process_actions(&mut shared, idx, actions);
+                    }
                 }
             }
+            _ = sigterm.recv() => {
+                info!("SIGTERM received");
+                break;
+            }
+            _ = sigint.recv() => {
+                info!("SIGINT received");
+                break;
+            }
             else => break,
         }
     }
 
     info!("accentd shutting down");
+    if let Err(e) = shared.lock().await.emitter.release_all() {
+        warn!(error = %e, "failed to release held keys");
+    }
     let _ = std::fs::remove_file(&socket_path);
     Ok(())
 }
 
-fn process_actions(shared: &mut Shared, actions: Vec<Action>) {
+/// If the main loop panics the daemon is going down: release whatever the
+/// virtual keyboard holds first. Worker task panics don't end the daemon
+/// and are left to the default hook.
+fn release_keys_on_panic(releaser: uinput_emitter::Releaser) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        if std::thread::current().name() == Some("main") {
+            let _ = releaser.release_all();
+        }
+    }));
+}
+
+/// Feed one event from keyboard `idx`, seen `time`, to its state machine.
+fn process_device_event(shared: &mut Shared, idx: usize, event: RawEvent, time: Instant) {
+    shared.devices[idx].events += 1;
+    if let Some(calibration) = &mut shared.calibration {
+        calibration.record(&event, time);
+    }
+    let idle = shared.state_machines[idx].state_name() == "idle";
+    if let Some(echo) = &mut shared.echo {
+        echo.saw(&event, idle);
+    }
+    let actions = shared.state_machines[idx].process_event_at(event, time);
+    if event.is_key() {
+        send_monitor(shared, idx, Some(event), &actions);
+    }
+    process_device_actions(shared, idx, actions);
+}
+
+/// Run actions caused by an event from keyboard `idx`. Relays join that
+/// keyboard's pending frame, which is written out at its SYN_REPORT.
+fn process_device_actions(shared: &mut Shared, idx: usize, actions: impl IntoIterator<Item = Action>) {
+    let mut frame = std::mem::take(&mut shared.frames[idx]);
+    run_actions(shared, idx, &mut frame, actions);
+    shared.frames[idx] = frame;
+}
+
+/// Pick candidate `index` (1-based) in the first open popup.
+fn select(shared: &mut Shared, index: u8) {
+    for idx in 0..shared.state_machines.len() {
+        let actions = shared.state_machines[idx].ipc_select(index);
+        if !actions.is_empty() {
+            process_actions(shared, idx, actions);
+            return;
+        }
+    }
+}
+
+/// Close any open popup without choosing. Returns whether one was open.
+fn dismiss_popups(shared: &mut Shared) -> bool {
+    let mut was_open = false;
+    for idx in 0..shared.state_machines.len() {
+        let actions = shared.state_machines[idx].ipc_dismiss();
+        was_open |= !actions.is_empty();
+        process_actions(shared, idx, actions);
+    }
+    was_open
+}
+
+/// Run keyboard `idx`'s actions not tied to an input event (timers, IPC);
+/// any relays they produce go out together as one frame.
+fn process_actions(shared: &mut Shared, idx: usize, actions: impl IntoIterator<Item = Action>) {
+    let mut frame = backend::FrameBuffer::default();
+    run_actions(shared, idx, &mut frame, actions);
+    flush_frame(shared, idx, &mut frame);
+}
+
+fn flush_frame(shared: &mut Shared, idx: usize, frame: &mut backend::FrameBuffer) {
+    if let Err(e) = frame.flush(idx, shared.emitter.as_mut()) {
+        warn!(error = %e, "relay error");
+    }
+}
+
+fn run_actions(
+    shared: &mut Shared,
+    idx: usize,
+    frame: &mut backend::FrameBuffer,
+    actions: impl IntoIterator<Item = Action>,
+) {
     for action in actions {
         match action {
+            // Unless grabbed, the apps got the event already
+            Action::Relay(_) if shared.echo.is_some() => {}
             Action::Relay(event) => {
-                if let Err(e) = uinput_emitter::relay_event(&mut shared.vdev, &event) {
+                if let Err(e) = frame.relay(event, idx, shared.emitter.as_mut()) {
                     warn!(error = %e, "relay error");
                 }
             }
             Action::SendPopup(msg) => {
                 let line = ipc::encode(&msg);
                 shared.popup_txs.retain(|tx| tx.send(line.clone()).is_ok());
+                shared.status_txs.retain(|tx| tx.send(line.clone()).is_ok());
+                if shared.popup_txs.is_empty() {
+                    match &msg {
+                        DaemonMsg::ShowPopup { base, accents, labels, .. } => {
+                            shared.fallback.show(base, accents, labels)
+                        }
+                        DaemonMsg::HidePopup { .. } => shared.fallback.hide(),
+                        _ => {}
+                    }
+                }
             }
-            Action::EmitAccent(accent) => {
-                if let Err(e) = compose::emit_accent(&mut shared.vdev, &accent) {
+            Action::EmitAccent { base, accent, base_typed } => {
+                // Keep ordering: anything relayed before the accent goes first
+                flush_frame(shared, idx, frame);
+                let erase = shared.echo.as_mut().map(backend::Echo::take_erase).unwrap_or_default();
+                for event in erase {
+                    if let Err(e) = shared.emitter.relay(idx, &[event]) {
+                        warn!(error = %e, "erase error");
+                    }
+                }
+                // With `defer_base` the held letter never got out
+                let replaces = shared.locale.erase(&accent).saturating_sub(usize::from(!base_typed));
+                let app = shared.focus.as_ref().and_then(compositor::FocusedWindow::app);
+                if let Err(e) = shared.emitter.emit_text(idx, app, &accent, replaces) {
                     warn!(error = %e, "emit accent error");
                 }
+                if !shared.dry_run {
+                    record_pick(shared, base, &accent);
+                }
             }
             Action::Suppress => {}
         }
     }
 }
 
+/// The accent table for `map`, ranked by usage if configured. Tone
+/// locales are never reordered: their digits mean tones, not positions.
+fn accent_table(config: &Config, usage: &UsageStats, locale: &Locale) -> AccentTable {
+    match (config.popup.order, locale.selection) {
+        (CandidateOrder::Locale, _) | (_, Selection::Tones) => locale.table(),
+        (CandidateOrder::Adaptive | CandidateOrder::Frozen, Selection::Position) => {
+            Locale { map: usage.rank(&locale.map), ..locale.clone() }.table()
+        }
+    }
+}
+
+/// Follow the compositor to its layout group `group`: the keyboards take
+/// that entry of `layout_groups` and the group's XKB letters.
+fn set_layout_group(shared: &mut Shared, group: usize) {
+    if shared.layout_group == group {
+        return;
+    }
+    shared.layout_group = group;
+    let layout = shared.config.devices.layout_groups.get(group).copied();
+    info!(group, ?layout, "keyboard layout switched");
+    if let Some(layout) = layout {
+        for sm in &mut shared.state_machines {
+            sm.set_layout(layout);
+        }
+    }
+    refresh_keymap(shared);
+}
+
+/// Recompute the effective keymap after the config or layout group changed.
+fn refresh_keymap(shared: &mut Shared) {
+    if !shared.config.devices.xkb.enabled && shared.keymap == shared.config.keymap {
+        return;
+    }
+    shared.keymap = xkb::keymap(&shared.config, shared.layout_group);
+    for sm in &mut shared.state_machines {
+        sm.set_keymap(shared.keymap.clone());
+    }
+}
+
+/// Make `locale` the active one on every keyboard.
+fn set_locale_map(shared: &mut Shared, locale: Locale) {
+    let accents = accent_table(&shared.config, &shared.usage, &locale);
+    for idx in 0..shared.state_machines.len() {
+        let actions = shared.state_machines[idx].set_locale_map(accents.clone());
+        process_actions(shared, idx, actions);
+    }
+    shared.locale = locale;
+}
+
+/// Count a pick and re-rank, when candidates are ordered adaptively.
+fn record_pick(shared: &mut Shared, base: &str, accent: &str) {
+    if shared.config.popup.order != CandidateOrder::Adaptive {
+        return;
+    }
+    // Turkish İ counts towards i, not "i̇"
+    let accent = charmap::lowercase(accent, &shared.locale.language);
+    shared.usage.record(base, &accent);
+    if let Err(e) = shared.usage.save_to(&UsageStats::path()) {
+        warn!(error = %e, "failed to save usage stats");
+    }
+    let locale = std::mem::take(&mut shared.locale);
+    set_locale_map(shared, locale);
+}
+
+/// Replace or extend the usage stats, persist them and re-rank.
+fn import_stats(shared: &mut Shared, stats: UsageStats, merge: bool) -> Result<()> {
+    if stats.version > usage::FORMAT_VERSION {
+        anyhow::bail!(
+            "usage stats format v{} is newer than this daemon supports (v{})",
+            stats.version,
+            usage::FORMAT_VERSION
+        );
+    }
+    if merge {
+        shared.usage.merge(stats);
+    } else {
+        shared.usage = stats;
+        shared.usage.version = usage::FORMAT_VERSION;
+    }
+    shared.usage.save_to(&UsageStats::path())?;
+    let locale = std::mem::take(&mut shared.locale);
+    set_locale_map(shared, locale);
+    Ok(())
+}
+
+/// Add or remove a suspend reason, updating the state machines when the
+/// overall suspended state flips.
+fn set_suspended(shared: &mut Shared, reason: SuspendReason, active: bool) {
+    let was_suspended = !shared.suspended.is_empty();
+    if active {
+        shared.suspended.insert(reason);
+    } else {
+        shared.suspended.remove(&reason);
+    }
+    let suspended = !shared.suspended.is_empty();
+    if suspended == was_suspended {
+        return;
+    }
+
+    info!(?reason, suspended, "hold detection {}", if suspended { "suspended" } else { "resumed" });
+    for idx in 0..shared.state_machines.len() {
+        let actions = shared.state_machines[idx].set_suspended(suspended);
+        process_actions(shared, idx, actions);
+    }
+    broadcast_status(shared);
+}
+
+fn set_enabled(handle: &Arc<Mutex<Shared>>, shared: &mut Shared, enabled: bool) {
+    for idx in 0..shared.state_machines.len() {
+        let actions = shared.state_machines[idx].set_enabled(enabled);
+        process_actions(shared, idx, actions);
+    }
+    devices::follow_enabled(handle, shared);
+}
+
+fn status(shared: &Shared) -> DaemonMsg {
+    DaemonMsg::Status {
+        enabled: shared.state_machines.first().map(|s| s.is_enabled()).unwrap_or(false),
+        locale: shared.config.locale.active.to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        suspended: !shared.suspended.is_empty(),
+        suspended_by: shared.suspended.iter().copied().collect(),
+        uptime_secs: shared.started.elapsed().as_secs(),
+        backend: shared.emitter.name().to_string(),
+        devices: shared
+            .devices
+            .iter()
+            .zip(&shared.state_machines)
+            .map(|(device, sm)| ipc::DeviceStatus {
+                enabled: sm.is_enabled(),
+                ..device.clone()
+            })
+            .collect(),
+        popup_clients: shared.popup_txs.iter().filter(|tx| !tx.is_closed()).count(),
+    }
+}
+
+/// Tell monitor clients what keyboard `idx` did with `event` (or a timer),
+/// and log it in a dry run unless it was a plain relay.
+fn send_monitor(shared: &mut Shared, idx: usize, event: Option<RawEvent>, actions: &[Action]) {
+    if shared.dry_run && actions.iter().any(|a| !matches!(a, Action::Relay(_))) {
+        info!(
+            device = %shared.devices[idx].name,
+            code = ?event.map(|e| e.code),
+            "dry run: would {}",
+            state_machine::describe_actions(actions),
+        );
+    }
+    if shared.monitor_txs.is_empty() {
+        return;
+    }
+    let code = event.map(|e| e.code);
+    let msg = DaemonMsg::Monitor {
+        device: shared.devices[idx].name.clone(),
+        code,
+        value: event.map_or(0, |e| e.value),
+        base: code
+            .and_then(|c| shared.state_machines[idx].base(c).or_else(|| charmap::keycode_to_digit_base(c)))
+            .map(String::from),
+        actions: state_machine::describe_actions(actions),
+        state: shared.state_machines[idx].state_name().into(),
+    };
+    let line = ipc::encode(&msg);
+    shared.monitor_txs.retain(|tx| tx.send(line.clone()).is_ok());
+}
+
+/// Push the current status to every subscribed client.
+fn broadcast_status(shared: &mut Shared) {
+    if shared.status_txs.is_empty() {
+        return;
+    }
+    let line = ipc::encode(&status(shared));
+    shared.status_txs.retain(|tx| tx.send(line.clone()).is_ok());
+}
+
+/// Sample hold durations for `secs` seconds, then suggest a threshold and,
+/// with `apply`, switch every keyboard to it until restart.
+async fn calibrate(shared: &Mutex<Shared>, secs: u64, apply: bool) -> DaemonMsg {
+    {
+        let mut shared = shared.lock().await;
+        if shared.calibration.is_some() {
+            return DaemonMsg::Ack { ok: false, message: "a calibration is already running".into() };
+        }
+        shared.calibration = Some(Calibration::default());
+    }
+    info!(secs, "calibrating hold threshold");
+    tokio::time::sleep(Duration::from_secs(secs)).await;
+
+    let mut shared = shared.lock().await;
+    let calibration = shared.calibration.take().unwrap_or_default();
+    let current_ms = shared.config.general.threshold();
+    let suggested_ms = calibration.suggest();
+    let applied = match suggested_ms {
+        Some(ms) if apply => {
+            shared.config.general.threshold_ms = Some(ms);
+            for sm in &mut shared.state_machines {
+                sm.set_threshold(ms);
+            }
+            true
+        }
+        _ => false,
+    };
+    info!(samples = calibration.samples(), ?suggested_ms, applied, "calibration finished");
+    DaemonMsg::Calibration {
+        samples: calibration.samples(),
+        p99_ms: calibration.p99_ms(),
+        suggested_ms,
+        current_ms,
+        applied,
+    }
+}
 
 async fn handle_ipc_client(stream: UnixStream, shared: Arc<Mutex<Shared>>) {
+    let handle = Arc::clone(&shared);
     let (reader, mut writer) = stream.into_split();
     let mut lines = BufReader::new(reader).lines();
 
@@ -227,6 +729,19 @@ async fn handle_ipc_client(stream: UnixStream, shared: Arc<Mutex<Shared>>) {
             continue;
         };
 
+        // Runs for a while; takes the lock itself so typing goes on meanwhile
+        if let ClientMsg::Calibrate { secs, apply } = msg {
+            let reply = calibrate(&shared, secs, apply).await;
+            let _ = tx.send(ipc::encode(&reply));
+            continue;
+        }
+        // Starts grabber tasks, which need the shared handle
+        if let ClientMsg::Rescan = msg {
+            let reply = devices::rescan(&shared).await;
+            let _ = tx.send(ipc::encode(&reply));
+            continue;
+        }
+
         let mut shared = shared.lock().await;
 
         match msg {
@@ -241,14 +756,7 @@ async fn handle_ipc_client(stream: UnixStream, shared: Arc<Mutex<Shared>>) {
             }
             ClientMsg::Select { index } => {
                 info!(index, "popup selection via IPC");
-                // Find the first SM in Popup state and select
-                let actions = shared.state_machines.iter_mut()
-                    .find_map(|sm| {
-                        let a = sm.ipc_select(index);
-                        if a.is_empty() { None } else { Some(a) }
-                    })
-                    .unwrap_or_default();
-                process_actions(&mut shared, actions);
+                select(&mut shared, index);
                 let ack = DaemonMsg::Ack {
                     ok: true,
                     message: format!("selected {}", index),
@@ -257,18 +765,13 @@ async fn handle_ipc_client(stream: UnixStream, shared: Arc<Mutex<Shared>>) {
             }
             ClientMsg::Dismiss => {
                 info!("popup dismissed via IPC");
-                let mut all_actions = Vec::new();
-                for sm in &mut shared.state_machines {
-                    all_actions.extend(sm.ipc_dismiss());
-                }
-                process_actions(&mut shared, all_actions);
+                dismiss_popups(&mut shared);
             }
             ClientMsg::Toggle => {
                 let new_state = !shared.state_machines.first().map(|s| s.is_enabled()).unwrap_or(true);
-                for sm in &mut shared.state_machines {
-                    sm.set_enabled(new_state);
-                }
+                set_enabled(&handle, &mut shared, new_state);
                 info!(enabled = new_state, "toggled");
+                broadcast_status(&mut shared);
                 let ack = DaemonMsg::Ack {
                     ok: true,
                     message: format!("enabled: {}", new_state),
@@ -276,9 +779,8 @@ async fn handle_ipc_client(stream: UnixStream, shared: Arc<Mutex<Shared>>) {
                 let _ = tx.send(ipc::encode(&ack));
             }
             ClientMsg::Enable => {
-                for sm in &mut shared.state_machines {
-                    sm.set_enabled(true);
-                }
+                set_enabled(&handle, &mut shared, true);
+                broadcast_status(&mut shared);
                 let ack = DaemonMsg::Ack {
                     ok: true,
                     message: "enabled".into(),
@@ -286,9 +788,8 @@ async fn handle_ipc_client(stream: UnixStream, shared: Arc<Mutex<Shared>>) {
                 let _ = tx.send(ipc::encode(&ack));
             }
             ClientMsg::Disable => {
-                for sm in &mut shared.state_machines {
-                    sm.set_enabled(false);
-                }
+                set_enabled(&handle, &mut shared, false);
+                broadcast_status(&mut shared);
                 let ack = DaemonMsg::Ack {
                     ok: true,
                     message: "disabled".into(),
@@ -296,12 +797,11 @@ async fn handle_ipc_client(stream: UnixStream, shared: Arc<Mutex<Shared>>) {
                 let _ = tx.send(ipc::encode(&ack));
             }
             ClientMsg::SetLocale { locale } => {
-                shared.config.locale.active = locale.clone();
-                match shared.config.load_locale_map() {
-                    Ok(map) => {
-                        for sm in &mut shared.state_machines {
-                            sm.set_locale_map(map.clone());
-                        }
+                shared.config.locale.active = locale.as_str().into();
+                match shared.config.load_locale() {
+                    Ok(loaded) => {
+                        set_locale_map(&mut shared, loaded);
+                        broadcast_status(&mut shared);
                         let ack = DaemonMsg::Ack {
                             ok: true,
                             message: format!("locale set to {}", locale),
@@ -318,13 +818,63 @@ async fn handle_ipc_client(stream: UnixStream, shared: Arc<Mutex<Shared>>) {
                 }
             }
             ClientMsg::GetStatus => {
-                let enabled = shared.state_machines.first().map(|s| s.is_enabled()).unwrap_or(false);
-                let status = DaemonMsg::Status {
-                    enabled,
-                    locale: shared.config.locale.active.clone(),
-                    version: env!("CARGO_PKG_VERSION").to_string(),
+                let _ = tx.send(ipc::encode(&status(&shared)));
+            }
+            ClientMsg::Subscribe => {
+                shared.status_txs.push(tx.clone());
+                let _ = tx.send(ipc::encode(&status(&shared)));
+            }
+            ClientMsg::ExportStats => {
+                let stats = DaemonMsg::Stats { stats: shared.usage.clone() };
+                let _ = tx.send(ipc::encode(&stats));
+            }
+            ClientMsg::GetLocaleMap => {
+                let reply = DaemonMsg::LocaleMap {
+                    locale: shared.config.locale.active.to_string(),
+                    keys: shared.locale.map.clone(),
                 };
-                let _ = tx.send(ipc::encode(&status));
+                let _ = tx.send(ipc::encode(&reply));
+            }
+            ClientMsg::ListLocales => {
+                let reply = DaemonMsg::Locales { locales: shared.config.list_locales() };
+                let _ = tx.send(ipc::encode(&reply));
+            }
+            ClientMsg::ShowCheatSheet => {
+                let line = ipc::encode(&DaemonMsg::ShowCheatSheet);
+                shared.popup_txs.retain(|t| t.send(line.clone()).is_ok());
+                let ack = if shared.popup_txs.is_empty() {
+                    DaemonMsg::Ack { ok: false, message: "no popup client connected".into() }
+                } else {
+                    DaemonMsg::Ack { ok: true, message: "cheat sheet shown".into() }
+                };
+                let _ = tx.send(ipc::encode(&ack));
+            }
+            ClientMsg::Calibrate { .. } | ClientMsg::Rescan => {} // handled above, without the lock
+            ClientMsg::Monitor => {
+                let ack = if shared.config.general.allow_monitor {
+                    info!("monitor client connected");
+                    shared.monitor_txs.push(tx.clone());
+                    DaemonMsg::Ack { ok: true, message: "monitoring key events".into() }
+                } else {
+                    DaemonMsg::Ack {
+                        ok: false,
+                        message: "monitor is off; set allow_monitor = true under [general] and restart accentd".into(),
+                    }
+                };
+                let _ = tx.send(ipc::encode(&ack));
+            }
+            ClientMsg::ImportStats { stats, merge } => {
+                let ack = match import_stats(&mut shared, stats, merge) {
+                    Ok(()) => DaemonMsg::Ack {
+                        ok: true,
+                        message: if merge { "usage stats merged" } else { "usage stats imported" }.into(),
+                    },
+                    Err(e) => DaemonMsg::Ack {
+                        ok: false,
+                        message: format!("{:#}", e),
+                    },
+                };
+                let _ = tx.send(ipc::encode(&ack));
             }
         }
     }
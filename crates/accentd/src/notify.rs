@@ -0,0 +1,230 @@
+//! Fallback for when no popup client is connected (headless installs, or the
+//! popup service not running): the candidates are logged and, if configured,
+//! shown as a desktop notification in the user's session, with one action
+//! button per candidate where the notification server supports them.
+
+use crate::compositor;
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+use zbus::proxy::SignalStream;
+use zbus::zvariant::Value;
+use zbus::Connection;
+
+const NOTIFICATIONS: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+
+/// A notification to show.
+struct Note {
+    summary: String,
+    body: String,
+    /// Flattened (key, label) pairs, as the Notify call wants them.
+    actions: Vec<String>,
+}
+
+/// Handle to the notification task.
+#[derive(Clone)]
+pub struct Fallback {
+    /// `Some` to show, `None` to close.
+    tx: Option<mpsc::UnboundedSender<Option<Note>>>,
+}
+
+impl Fallback {
+    /// Log only.
+    pub fn log_only() -> Self {
+        Self { tx: None }
+    }
+
+    /// Log and mirror the popup as a desktop notification. The receiver
+    /// gets the 1-based index of each candidate picked with its button.
+    pub fn spawn() -> (Self, mpsc::UnboundedReceiver<u8>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (picks_tx, picks_rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(rx, picks_tx));
+        (Self { tx: Some(tx) }, picks_rx)
+    }
+
+    pub fn show(&self, base: &str, accents: &[String], labels: &[u8]) {
+        let body = describe(accents, labels);
+        info!(base, accents = %body, "no popup client connected; press a number to pick");
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(Some(Note {
+                summary: format!("Accents for {}", base),
+                body,
+                actions: actions(accents, labels),
+            }));
+        }
+    }
+
+    pub fn hide(&self) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(None);
+        }
+    }
+}
+
+/// The candidates with their number keys, e.g. `1 è  2 é`; one per line
+/// when there are snippets.
+pub fn describe(accents: &[String], labels: &[u8]) -> String {
+    accents
+        .iter()
+        .zip(labels)
+        .map(|(accent, label)| format!("{} {}", label, accent))
+        .collect::<Vec<_>>()
+        .join(if has_snippets(accents) { "\n" } else { "  " })
+}
+
+/// One button per candidate, keyed by its number.
+fn actions(accents: &[String], labels: &[u8]) -> Vec<String> {
+    accents
+        .iter()
+        .zip(labels)
+        .flat_map(|(accent, label)| [label.to_string(), accent.clone()])
+        .collect()
+}
+
+/// Whether any candidate is a multi-character snippet, which reads better
+/// one per line.
+fn has_snippets(accents: &[String]) -> bool {
+    accents.iter().any(|a| accentd_core::charmap::is_snippet(a))
+}
+
+async fn run(mut rx: mpsc::UnboundedReceiver<Option<Note>>, picks: mpsc::UnboundedSender<u8>) {
+    let mut conn: Option<Connection> = None;
+    // Id of the notification on screen, so updates replace it in place
+    let mut shown: u32 = 0;
+    // ActionInvoked signals, once connected
+    let mut invoked: Option<SignalStream<'static>> = None;
+
+    loop {
+        let msg = tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            Some(signal) = next_signal(&mut invoked) => {
+                if let Ok((id, key)) = signal.body().deserialize::<(u32, String)>() {
+                    if let Some(index) = picked(shown, id, &key) {
+                        debug!(index, "candidate picked from notification");
+                        let _ = picks.send(index);
+                    }
+                }
+                continue;
+            }
+        };
+        let result = match msg {
+            Some(note) => match connect(&mut conn, &mut invoked).await {
+                Ok(c) => notify(c, shown, &note).await.map(|id| shown = id),
+                Err(e) => Err(e),
+            },
+            None if shown != 0 => match connect(&mut conn, &mut invoked).await {
+                Ok(c) => close(c, shown).await.map(|()| shown = 0),
+                Err(e) => Err(e),
+            },
+            None => Ok(()),
+        };
+        if let Err(e) = result {
+            debug!(error = %e, "desktop notification failed");
+            // The session may have ended; look for a bus again next time
+            conn = None;
+            invoked = None;
+            shown = 0;
+        }
+    }
+}
+
+/// The next ActionInvoked signal; never resolves while not connected.
+async fn next_signal(invoked: &mut Option<SignalStream<'static>>) -> Option<zbus::Message> {
+    match invoked {
+        Some(stream) => stream.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The candidate index an ActionInvoked signal picks, if it's for the
+/// notification on screen.
+fn picked(shown: u32, id: u32, key: &str) -> Option<u8> {
+    if shown == 0 || id != shown {
+        return None;
+    }
+    key.parse().ok().filter(|&index| index > 0)
+}
+
+async fn connect<'a>(
+    conn: &'a mut Option<Connection>,
+    invoked: &mut Option<SignalStream<'static>>,
+) -> Result<&'a Connection> {
+    if conn.is_none() {
+        let bus = compositor::find_session_bus().context("no user session bus")?;
+        let c = zbus::connection::Builder::address(format!("unix:path={}", bus.display()).as_str())?
+            .build()
+            .await
+            .with_context(|| format!("connecting to session bus {}", bus.display()))?;
+        let proxy = zbus::Proxy::new(&c, NOTIFICATIONS, NOTIFICATIONS_PATH, NOTIFICATIONS).await?;
+        *invoked = Some(proxy.receive_signal("ActionInvoked").await.context("watching notification actions")?);
+        *conn = Some(c);
+    }
+    Ok(conn.as_ref().expect("connected above"))
+}
+
+async fn notify(conn: &Connection, replaces: u32, note: &Note) -> Result<u32> {
+    let proxy = zbus::Proxy::new(conn, NOTIFICATIONS, NOTIFICATIONS_PATH, NOTIFICATIONS).await?;
+    let hints: HashMap<&str, Value> = HashMap::from([("transient", Value::from(true))]);
+    let id: u32 = proxy
+        .call(
+            "Notify",
+            &("accentd", replaces, "", &note.summary, &note.body, &note.actions, hints, -1i32),
+        )
+        .await
+        .context("sending notification")?;
+    Ok(id)
+}
+
+async fn close(conn: &Connection, id: u32) -> Result<()> {
+    let proxy = zbus::Proxy::new(conn, NOTIFICATIONS, NOTIFICATIONS_PATH, NOTIFICATIONS).await?;
+    proxy
+        .call::<_, _, ()>("CloseNotification", &(id,))
+        .await
+        .context("closing notification")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // --- spec: headless fallback ---
+
+    #[test]
+    fn describe_pairs_labels_with_accents() {
+        let accents = vec!["è".to_string(), "é".to_string()];
+        assert_eq!(describe(&accents, &[1, 2]), "1 è  2 é");
+    }
+
+    #[test]
+    fn describe_puts_snippets_on_their_own_lines() {
+        let accents = vec!["é".to_string(), "etc.".to_string()];
+        assert_eq!(describe(&accents, &[1, 2]), "1 é\n2 etc.");
+    }
+
+    #[test]
+    fn one_action_per_candidate_keyed_by_number() {
+        let accents = vec!["è".to_string(), "é".to_string()];
+        assert_eq!(actions(&accents, &[1, 2]), ["1", "è", "2", "é"]);
+    }
+
+    #[test]
+    fn only_actions_on_the_shown_notification_pick() {
+        assert_eq!(picked(7, 7, "2"), Some(2));
+        assert_eq!(picked(7, 6, "2"), None);
+        assert_eq!(picked(0, 0, "2"), None);
+        assert_eq!(picked(7, 7, "default"), None);
+        assert_eq!(picked(7, 7, "0"), None);
+    }
+
+    #[test]
+    fn describe_empty() {
+        assert_eq!(describe(&[], &[]), "");
+    }
+}
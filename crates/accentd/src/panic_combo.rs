@@ -0,0 +1,94 @@
+use accentd_core::clock::{Clock, SystemClock};
+use std::time::{Duration, Instant};
+
+/// Backspace → Escape → Enter, the keys that force the daemon to exit.
+const PANIC_SEQ: [u16; 3] = [14, 1, 28]; // KEY_BACKSPACE, KEY_ESC, KEY_ENTER
+
+/// The whole sequence must be typed within this window.
+const PANIC_WINDOW: Duration = Duration::from_millis(1000);
+
+/// Detects the panic key combo, the safety escape hatch if the daemon hangs
+/// with EVIOCGRAB held.
+pub struct PanicCombo<C: Clock = SystemClock> {
+    clock: C,
+    /// Last three key presses and when they happened, oldest at `next`.
+    ring: [(u16, Instant); 3],
+    next: usize,
+}
+
+impl PanicCombo {
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+}
+
+impl<C: Clock> PanicCombo<C> {
+    pub fn with_clock(clock: C) -> Self {
+        let now = clock.now();
+        Self { clock, ring: [(0, now); 3], next: 0 }
+    }
+
+    /// Record a key press; true once the combo is complete.
+    pub fn press(&mut self, code: u16) -> bool {
+        self.ring[self.next] = (code, self.clock.now());
+        self.next = (self.next + 1) % 3;
+
+        let oldest = self.next;
+        let codes = [0, 1, 2].map(|i| self.ring[(oldest + i) % 3].0);
+        if codes != PANIC_SEQ {
+            return false;
+        }
+        let first = self.ring[oldest].1;
+        let last = self.ring[(oldest + 2) % 3].1;
+        last.duration_since(first) < PANIC_WINDOW
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use accentd_core::clock::MockClock;
+
+    const KEY_BACKSPACE: u16 = 14;
+    const KEY_ESC: u16 = 1;
+    const KEY_ENTER: u16 = 28;
+
+    #[test]
+    fn quick_sequence_triggers() {
+        let clock = MockClock::new();
+        let mut combo = PanicCombo::with_clock(clock.clone());
+        assert!(!combo.press(KEY_BACKSPACE));
+        clock.advance(Duration::from_millis(200));
+        assert!(!combo.press(KEY_ESC));
+        clock.advance(Duration::from_millis(200));
+        assert!(combo.press(KEY_ENTER));
+    }
+
+    #[test]
+    fn slow_sequence_does_not_trigger() {
+        let clock = MockClock::new();
+        let mut combo = PanicCombo::with_clock(clock.clone());
+        combo.press(KEY_BACKSPACE);
+        clock.advance(Duration::from_millis(600));
+        combo.press(KEY_ESC);
+        clock.advance(Duration::from_millis(600));
+        assert!(!combo.press(KEY_ENTER));
+    }
+
+    #[test]
+    fn sequence_after_other_keys_triggers() {
+        let mut combo = PanicCombo::with_clock(MockClock::new());
+        for code in [30, 31, KEY_BACKSPACE, KEY_ESC] {
+            assert!(!combo.press(code));
+        }
+        assert!(combo.press(KEY_ENTER));
+    }
+
+    #[test]
+    fn wrong_order_does_not_trigger() {
+        let mut combo = PanicCombo::with_clock(MockClock::new());
+        combo.press(KEY_ESC);
+        combo.press(KEY_BACKSPACE);
+        assert!(!combo.press(KEY_ENTER));
+    }
+}
@@ -0,0 +1,87 @@
+//! `--record` and `--replay --simulate`: event logs for bug reports (the
+//! format is in `accentd_core::evlog`).
+
+use accentd_core::charmap::{Keymap, Layout};
+use accentd_core::config::{CandidateOrder, Config};
+use accentd_core::evlog::{self, EventLog, Record};
+use accentd_core::locale::Locale;
+use accentd_core::state_machine::RawEvent;
+use accentd_core::usage::UsageStats;
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::Instant;
+use tracing::{info, warn};
+
+/// Writes every keyboard frame to an event log.
+pub struct Recorder {
+    out: BufWriter<std::fs::File>,
+    started: Instant,
+    named: BTreeSet<usize>,
+}
+
+impl Recorder {
+    /// Start a log at `path`, noting the settings replay should use. The
+    /// file is readable by its owner only: it holds everything typed.
+    pub fn create(path: &Path, config: &Config, locale: &Locale, keymap: &Keymap, usage: &UsageStats) -> Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("creating {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        writeln!(out, "{}", evlog::HEADER)?;
+        for line in evlog::settings_lines(config, locale, keymap, usage) {
+            writeln!(out, "{}", line)?;
+        }
+        out.flush()?;
+        warn!(path = %path.display(), "recording every key typed, passwords included");
+        Ok(Self { out, started: Instant::now(), named: BTreeSet::new() })
+    }
+
+    /// Append one frame from keyboard `device`, as read.
+    pub fn frame(&mut self, device: usize, name: &str, layout: Layout, frame: &[RawEvent], time: Instant) -> Result<()> {
+        if self.named.insert(device) {
+            writeln!(self.out, "{}", evlog::device_line(device, name))?;
+            writeln!(self.out, "{}", evlog::layout_line(device, layout))?;
+        }
+        let at = time.saturating_duration_since(self.started);
+        for &event in frame {
+            writeln!(self.out, "{}", Record { at, device, event })?;
+        }
+        self.out.flush().context("writing event log")
+    }
+}
+
+/// Run the log at `path` through fresh state machines and print what they
+/// did, with the settings it was recorded with. Logs that don't say how
+/// candidates were ordered replay them in the locale's order, not by this
+/// machine's usage stats.
+pub fn replay(path: &Path, mut config: Config) -> Result<()> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let log = EventLog::parse(&text).with_context(|| format!("parsing {}", path.display()))?;
+    if let Some(locale) = &log.locale {
+        config.locale.active = locale.as_str().into();
+    }
+    if let Some(ms) = log.threshold_ms {
+        config.general.threshold_ms = Some(ms);
+    }
+    config.popup.order = log.order.unwrap_or(CandidateOrder::Locale);
+    config.keymap = match &log.keymap {
+        Some(keymap) => keymap.clone(),
+        None => crate::xkb::keymap(&config, 0),
+    };
+    let locale = config.load_locale().context("loading locale")?;
+    let usage = log.usage.clone().unwrap_or_default();
+    let accents = crate::accent_table(&config, &usage, &locale);
+    info!(path = %path.display(), events = log.records.len(), locale = %config.locale.active, "replaying");
+    for line in evlog::replay(&config, accents, &log) {
+        println!("{}", line);
+    }
+    Ok(())
+}
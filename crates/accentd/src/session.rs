@@ -0,0 +1,161 @@
+use crate::{Shared, SuspendReason};
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{debug, info};
+use zbus::message::Type as MessageType;
+use zbus::proxy::CacheProperties;
+use zbus::zvariant::{OwnedObjectPath, OwnedValue, Value};
+use zbus::{Connection, MatchRule, MessageStream};
+
+const LOGIND: &str = "org.freedesktop.login1";
+const SESSION_IFACE: &str = "org.freedesktop.login1.Session";
+
+/// How long to wait before reconnecting to the system bus after losing it.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Suspend hold detection while a graphical session is locked or idle, so
+/// nothing pops up over the lock screen and password typing is passed through
+/// untouched.
+pub fn spawn(shared: Arc<Mutex<Shared>>) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = run(&shared).await {
+                debug!(error = %e, "logind connection ended");
+            }
+            // Don't stay suspended on stale information
+            let mut shared = shared.lock().await;
+            crate::set_suspended(&mut shared, SuspendReason::Locked, false);
+            crate::set_suspended(&mut shared, SuspendReason::Idle, false);
+            drop(shared);
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    });
+}
+
+async fn run(shared: &Mutex<Shared>) -> Result<()> {
+    let conn = Connection::system().await.context("connecting to system bus")?;
+    let rule = MatchRule::builder()
+        .msg_type(MessageType::Signal)
+        .sender(LOGIND)?
+        .build();
+    let mut signals = MessageStream::for_match_rule(rule, &conn, None).await?;
+    info!("tracking session lock state via logind");
+
+    // Sessions asked to lock via the Lock signal whose locker hasn't reported
+    // LockedHint yet (or never will)
+    let mut lock_requested = HashSet::new();
+    apply(shared, session_state(&conn, &lock_requested).await?).await;
+
+    while let Some(msg) = signals.next().await {
+        let msg = msg?;
+        let header = msg.header();
+        let (Some(member), Some(path)) = (header.member(), header.path()) else {
+            continue;
+        };
+        let path = OwnedObjectPath::from(path.to_owned());
+        match member.as_str() {
+            "Lock" => {
+                lock_requested.insert(path);
+            }
+            "Unlock" => {
+                lock_requested.remove(&path);
+            }
+            "PropertiesChanged" => {
+                // A locker reporting LockedHint takes over from the Lock
+                // signal; other changes (IdleHint, often right after Lock)
+                // leave the request standing
+                let changed = msg.body().deserialize::<(String, HashMap<String, OwnedValue>, Vec<String>)>();
+                if let Ok((iface, changed, _)) = changed {
+                    if iface == SESSION_IFACE && reports_locked(&changed) {
+                        lock_requested.remove(&path);
+                    }
+                }
+            }
+            "SessionNew" | "SessionRemoved" => {}
+            _ => continue,
+        }
+        apply(shared, session_state(&conn, &lock_requested).await?).await;
+    }
+    Ok(())
+}
+
+/// Lock and idle state summed over active graphical sessions.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct SessionState {
+    locked: bool,
+    idle: bool,
+}
+
+async fn apply(shared: &Mutex<Shared>, state: SessionState) {
+    let mut shared = shared.lock().await;
+    crate::set_suspended(&mut shared, SuspendReason::Locked, state.locked);
+    crate::set_suspended(&mut shared, SuspendReason::Idle, state.idle);
+}
+
+async fn session_state(
+    conn: &Connection,
+    lock_requested: &HashSet<OwnedObjectPath>,
+) -> Result<SessionState> {
+    let manager = zbus::Proxy::new(conn, LOGIND, "/org/freedesktop/login1", "org.freedesktop.login1.Manager").await?;
+    let sessions: Vec<(String, u32, String, String, OwnedObjectPath)> =
+        manager.call("ListSessions", &()).await.context("listing sessions")?;
+
+    let mut state = SessionState::default();
+    for (_, _, _, _, path) in sessions {
+        let session = zbus::proxy::Builder::<zbus::Proxy>::new(conn)
+            .destination(LOGIND)?
+            .path(path.clone())?
+            .interface(SESSION_IFACE)?
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        // The session may have gone away in the meantime
+        let Ok(kind) = session.get_property::<String>("Type").await else {
+            continue;
+        };
+        if !is_graphical(&kind) || !session.get_property::<bool>("Active").await.unwrap_or(false) {
+            continue;
+        }
+        let locked = session.get_property::<bool>("LockedHint").await.unwrap_or(false);
+        let idle = session.get_property::<bool>("IdleHint").await.unwrap_or(false);
+        state.locked |= locked || lock_requested.contains(&path);
+        state.idle |= idle;
+    }
+    Ok(state)
+}
+
+/// Whether a session's PropertiesChanged sets LockedHint.
+fn reports_locked(changed: &HashMap<String, OwnedValue>) -> bool {
+    matches!(changed.get("LockedHint").map(|v| &**v), Some(Value::Bool(true)))
+}
+
+/// TTY sessions (ssh, getty) don't have a lock screen worth tracking.
+fn is_graphical(kind: &str) -> bool {
+    matches!(kind, "wayland" | "x11" | "mir")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_graphical_sessions_count() {
+        assert!(is_graphical("wayland"));
+        assert!(is_graphical("x11"));
+        assert!(!is_graphical("tty"));
+        assert!(!is_graphical("unspecified"));
+    }
+
+    #[test]
+    fn only_locked_hint_set_ends_a_lock_request() {
+        let changed = |name: &str, value: bool| HashMap::from([(name.to_string(), OwnedValue::from(value))]);
+        assert!(reports_locked(&changed("LockedHint", true)));
+        assert!(!reports_locked(&changed("LockedHint", false)));
+        assert!(!reports_locked(&changed("IdleHint", true)), "idle right after Lock");
+        assert!(!reports_locked(&HashMap::new()));
+    }
+}
@@ -1,21 +1,54 @@
-use crate::compose::EventEmitter;
+use crate::backend::{DownKeys, Emitter};
+use crate::compose::{self, Backends, EventEmitter};
 use anyhow::{Context, Result};
 use evdev::uinput::VirtualDeviceBuilder;
 use evdev::uinput::VirtualDevice;
-use evdev::{AttributeSet, InputEvent, Key};
-use tracing::info;
+use accentd_core::state_machine::RawEvent;
+use evdev::{AbsInfo, AttributeSet, BusType, Device, EventType, InputEvent, InputId, Key, MiscType, UinputAbsSetup};
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info, warn};
 
-pub fn create_virtual_device() -> Result<VirtualDevice> {
+/// Fixed USB-style id of our virtual keyboard, so remappers can be told to
+/// leave it alone (keyd: `[ids]` `-acce:0001`).
+pub const VIRTUAL_VENDOR: u16 = 0xacce;
+pub const VIRTUAL_PRODUCT: u16 = 0x0001;
+
+pub const VIRTUAL_NAME: &str = "accentd virtual keyboard";
+
+/// Appended to a combo keyboard's name for its pointer mirror.
+pub const MIRROR_SUFFIX: &str = " (accentd mirror)";
+
+/// A virtual keyboard builder with every key and MSC_SCAN, so relayed
+/// scancodes reach hwdb remaps and apps that read them.
+///
+/// No EV_REP: the kernel would then repeat keys on the virtual device by
+/// itself, on top of the keyboard's own repeats we relay (with its delay
+/// and rate), and repeat a held key through the hold.
+fn keyboard_builder(name: &str, id: InputId) -> Result<VirtualDeviceBuilder<'_>> {
     let mut keys = AttributeSet::<Key>::new();
     for code in 0..=255u16 {
         keys.insert(Key::new(code));
     }
+    let mut misc = AttributeSet::<MiscType>::new();
+    misc.insert(MiscType::MSC_SCAN);
 
-    let vdev = VirtualDeviceBuilder::new()
+    VirtualDeviceBuilder::new()
         .context("creating VirtualDeviceBuilder")?
-        .name("accentd virtual keyboard")
+        .name(name)
+        .input_id(id)
         .with_keys(&keys)
         .context("setting keys")?
+        .with_msc(&misc)
+        .context("setting scancodes")
+}
+
+pub fn create_virtual_device() -> Result<VirtualDevice> {
+    let id = InputId::new(BusType::BUS_VIRTUAL, VIRTUAL_VENDOR, VIRTUAL_PRODUCT, 1);
+    let vdev = keyboard_builder(VIRTUAL_NAME, id)?
         .build()
         .context("building virtual device")?;
 
@@ -23,7 +56,270 @@ pub fn create_virtual_device() -> Result<VirtualDevice> {
     Ok(vdev)
 }
 
-pub fn relay_event(emitter: &mut impl EventEmitter, event: &InputEvent) -> Result<()> {
-    emitter.emit_events(&[*event])?;
+/// A virtual device with the pointer side of a combo keyboard (trackpoint,
+/// touchpad, lid/tablet switches): its REL/ABS/SW axes, mouse and touch
+/// buttons, and input properties. `None` if the keyboard has no such side.
+///
+/// Keeps the original ids so libinput applies the same device quirks; the
+/// name is marked so we never grab the mirror itself.
+pub fn create_mirror_device(dev: &Device) -> Result<Option<VirtualDevice>> {
+    let rel = dev.supported_relative_axes().filter(|axes| axes.iter().next().is_some());
+    let abs = dev.supported_absolute_axes().filter(|axes| axes.iter().next().is_some());
+    let switches = dev.supported_switches().filter(|sw| sw.iter().next().is_some());
+    if rel.is_none() && abs.is_none() && switches.is_none() {
+        return Ok(None);
+    }
+
+    let name = format!("{}{}", dev.name().unwrap_or("keyboard"), MIRROR_SUFFIX);
+    let mut builder = VirtualDeviceBuilder::new()
+        .context("creating VirtualDeviceBuilder")?
+        .name(&name)
+        .input_id(dev.input_id())
+        .with_properties(dev.properties())
+        .context("setting properties")?;
+
+    let mut buttons = AttributeSet::<Key>::new();
+    for key in dev.supported_keys().into_iter().flat_map(|keys| keys.iter()) {
+        if is_button(key.code()) {
+            buttons.insert(key);
+        }
+    }
+    if buttons.iter().next().is_some() {
+        builder = builder.with_keys(&buttons).context("setting buttons")?;
+    }
+    if let Some(axes) = rel {
+        builder = builder.with_relative_axes(axes).context("setting relative axes")?;
+    }
+    if let Some(axes) = abs {
+        let info = dev.get_abs_state().context("reading absolute axes")?;
+        for axis in axes.iter() {
+            let i = &info[axis.0 as usize];
+            let setup = UinputAbsSetup::new(
+                axis,
+                AbsInfo::new(i.value, i.minimum, i.maximum, i.fuzz, i.flat, i.resolution),
+            );
+            builder = builder.with_absolute_axis(&setup).context("setting absolute axis")?;
+        }
+    }
+    if let Some(switches) = switches {
+        builder = builder.with_switches(switches).context("setting switches")?;
+    }
+
+    let vdev = builder.build().context("building mirror device")?;
+    info!(name = %name, "virtual pointer mirror created");
+    Ok(Some(vdev))
+}
+
+/// Mouse, joystick and touch buttons (`BTN_MISC` and up) rather than keys.
+pub fn is_button(code: u16) -> bool {
+    code >= 0x100
+}
+
+/// A virtual keyboard standing in for the physical keyboard `name`, with
+/// its vendor and product id so per-device settings (games, libinput quirks)
+/// still apply. Recognised as ours by its node, not its name.
+pub fn create_keyboard_clone(name: &str, input_id: Option<(u16, u16)>) -> Result<VirtualDevice> {
+    let (vendor, product) = input_id.unwrap_or((VIRTUAL_VENDOR, VIRTUAL_PRODUCT));
+    let vdev = keyboard_builder(name, InputId::new(BusType::BUS_VIRTUAL, vendor, product, 1))?
+        .build()
+        .context("building virtual device")?;
+
+    info!(name, "virtual keyboard created for keyboard");
+    Ok(vdev)
+}
+
+/// Output through our uinput virtual keyboard, composing accents with
+/// Ctrl+Shift+U unless a `[compose] backend` types them. With
+/// `per_keyboard_output`, each keyboard gets a virtual keyboard of its own
+/// instead.
+pub struct UinputEmitter {
+    /// "accentd virtual keyboard": output 0.
+    vdev: VirtualDevice,
+    /// Per keyboard, indexed like its slot; `None` without
+    /// `per_keyboard_output`.
+    clones: Option<Vec<Option<KeyboardClone>>>,
+    releaser: Releaser,
+    /// Type picks instead of Ctrl+Shift+U, when they can.
+    text: Backends,
+}
+
+/// A keyboard's own virtual keyboard.
+struct KeyboardClone {
+    vdev: VirtualDevice,
+    name: String,
+    input_id: Option<(u16, u16)>,
+    /// Its index among the releaser's outputs.
+    output: usize,
+}
+
+impl UinputEmitter {
+    pub fn new(per_keyboard: bool, text: Backends) -> Result<Self> {
+        let vdev = create_virtual_device()?;
+        let releaser = Releaser::default();
+        releaser.set(0, &vdev)?;
+        Ok(Self { vdev, clones: per_keyboard.then(Vec::new), releaser, text })
+    }
+
+    /// A handle that can release held keys from anywhere, e.g. a panic hook.
+    pub fn releaser(&self) -> Releaser {
+        self.releaser.clone()
+    }
+
+}
+
+/// Keyboard `device`'s virtual keyboard and its output index: its own among
+/// `clones`, else the shared `vdev`.
+fn output<'a>(
+    vdev: &'a mut VirtualDevice,
+    clones: &'a mut Option<Vec<Option<KeyboardClone>>>,
+    device: usize,
+) -> (&'a mut VirtualDevice, usize) {
+    match clones.as_mut().and_then(|clones| clones.get_mut(device)).and_then(Option::as_mut) {
+        Some(clone) => (&mut clone.vdev, clone.output),
+        None => (vdev, 0),
+    }
+}
+
+impl Emitter for UinputEmitter {
+    fn name(&self) -> &'static str {
+        self.text.name()
+    }
+
+    fn relay(&mut self, device: usize, frame: &[RawEvent]) -> Result<()> {
+        let (vdev, output) = output(&mut self.vdev, &mut self.clones, device);
+        relay_frame(vdev, frame)?;
+        self.releaser.track(output, frame);
+        Ok(())
+    }
+
+    fn emit_text(&mut self, device: usize, app: Option<&str>, text: &str, erase: usize) -> Result<()> {
+        let (keyboard, _) = output(&mut self.vdev, &mut self.clones, device);
+        if let Some(backend) = self.text.for_app(app) {
+            match backend.type_text(keyboard, app, text, erase) {
+                Ok(()) => return Ok(()),
+                Err(e) => debug!(backend = backend.name(), error = %format!("{:#}", e), "typing with Ctrl+Shift+U instead"),
+            }
+        }
+        compose::emit_accent(keyboard, text, erase, self.text.hex_digits())
+    }
+
+    fn keyboard_attached(&mut self, device: usize, name: &str, input_id: Option<(u16, u16)>) -> Result<bool> {
+        let Some(clones) = &mut self.clones else {
+            return Ok(false);
+        };
+        if clones.len() <= device {
+            clones.resize_with(device + 1, || None);
+        }
+        // The same keyboard back in its slot keeps its virtual keyboard
+        if let Some(clone) = &clones[device] {
+            if clone.name == name && clone.input_id == input_id {
+                return Ok(false);
+            }
+        }
+        let vdev = create_keyboard_clone(name, input_id)?;
+        // Outputs are never removed: another keyboard in the slot takes over
+        // its output (dropping the old device releases whatever it held)
+        let output = match &clones[device] {
+            Some(old) => old.output,
+            None => 1 + clones.iter().flatten().count(),
+        };
+        self.releaser.set(output, &vdev)?;
+        clones[device] = Some(KeyboardClone { vdev, name: name.to_string(), input_id, output });
+        Ok(true)
+    }
+
+    fn dev_nodes(&mut self) -> Vec<PathBuf> {
+        let clones = self.clones.iter_mut().flatten().flatten().map(|clone| &mut clone.vdev);
+        let mut nodes = Vec::new();
+        for vdev in std::iter::once(&mut self.vdev).chain(clones) {
+            match vdev.enumerate_dev_nodes_blocking() {
+                Ok(found) => nodes.extend(found.flatten()),
+                Err(e) => warn!(error = %e, "cannot list virtual device nodes, relying on its name"),
+            }
+        }
+        nodes
+    }
+
+    fn release_all(&mut self) -> Result<()> {
+        self.releaser.release_all()
+    }
+}
+
+/// Releases the keys our virtual devices hold down. Writes through its own
+/// handles on the devices, so it works without the emitter (which may be
+/// locked by whatever panicked).
+#[derive(Clone, Default)]
+pub struct Releaser {
+    outputs: Arc<Mutex<Vec<Output>>>,
+}
+
+/// One virtual keyboard, as the releaser sees it.
+struct Output {
+    device: File,
+    down: DownKeys,
+}
+
+impl Releaser {
+    /// Track virtual keyboard `vdev` as output `index`, replacing whatever
+    /// was there.
+    fn set(&self, index: usize, vdev: &VirtualDevice) -> Result<()> {
+        // SAFETY: the fd is open for as long as `vdev`, which outlives this borrow
+        let fd = unsafe { BorrowedFd::borrow_raw(vdev.as_raw_fd()) }
+            .try_clone_to_owned()
+            .context("duplicating uinput fd")?;
+        let output = Output { device: File::from(fd), down: DownKeys::default() };
+        let mut outputs = self.outputs.lock().unwrap_or_else(|e| e.into_inner());
+        if index < outputs.len() {
+            outputs[index] = output;
+        } else {
+            outputs.push(output);
+        }
+        Ok(())
+    }
+
+    fn track(&self, output: usize, frame: &[RawEvent]) {
+        if let Some(output) = self.outputs.lock().ok().as_mut().and_then(|outputs| outputs.get_mut(output)) {
+            output.down.track(frame);
+        }
+    }
+
+    pub fn release_all(&self) -> Result<()> {
+        // try_lock: the panicking thread may be the one holding it
+        let mut outputs = match self.outputs.try_lock() {
+            Ok(outputs) => outputs,
+            Err(std::sync::TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(std::sync::TryLockError::WouldBlock) => anyhow::bail!("key state is locked"),
+        };
+        for output in outputs.iter_mut() {
+            let releases = output.down.take_releases();
+            if releases.is_empty() {
+                continue;
+            }
+            info!(count = releases.len(), "releasing keys still held on the virtual device");
+            let mut frame: Vec<InputEvent> = releases
+                .iter()
+                .map(|e| InputEvent::new(EventType::KEY, e.code, 0))
+                .collect();
+            frame.push(InputEvent::new(EventType::SYNCHRONIZATION, 0, 0));
+            (&output.device).write_all(event_bytes(&frame)).context("writing key releases")?;
+        }
+        Ok(())
+    }
+}
+
+/// The raw `struct input_event` bytes uinput expects.
+fn event_bytes(events: &[InputEvent]) -> &[u8] {
+    // SAFETY: InputEvent is a transparent wrapper around libc::input_event, a
+    // plain C struct; the slice covers exactly the events' memory.
+    unsafe { std::slice::from_raw_parts(events.as_ptr().cast::<u8>(), std::mem::size_of_val(events)) }
+}
+
+/// Write a frame in one emit; `VirtualDevice::emit` appends the SYN_REPORT.
+pub fn relay_frame(emitter: &mut impl EventEmitter, frame: &[RawEvent]) -> Result<()> {
+    let events: Vec<InputEvent> = frame
+        .iter()
+        .map(|e| InputEvent::new(EventType(e.event_type), e.code, e.value))
+        .collect();
+    emitter.emit_events(&events)?;
     Ok(())
 }
@@ -0,0 +1,179 @@
+//! `[devices.xkb]`: which letter each key types, from the user's XKB layout
+//! rather than a built-in table. The letters become `[keymap]` entries, so
+//! the state machines need nothing new and `[keymap]` still wins.
+//!
+//! Resolving needs libxkbcommon, linked only with the `xkb` feature.
+
+use accentd_core::charmap::Keymap;
+use accentd_core::config::{Config, XkbConfig};
+use anyhow::Result;
+use std::collections::BTreeMap;
+use tracing::warn;
+
+/// The config's `[keymap]`, on top of the letters of the XKB layout's
+/// `group` when `[devices.xkb]` is enabled.
+pub fn keymap(config: &Config, group: usize) -> Keymap {
+    if !config.devices.xkb.enabled {
+        return config.keymap.clone();
+    }
+    let merged = letters(&config.devices.xkb, group).and_then(|mut entries| {
+        entries.extend(BTreeMap::from(config.keymap.clone()));
+        Keymap::try_from(entries).map_err(anyhow::Error::msg)
+    });
+    merged.unwrap_or_else(|e| {
+        warn!(error = %e, "[devices.xkb] unavailable, using the built-in layouts");
+        config.keymap.clone()
+    })
+}
+
+#[cfg(not(feature = "xkb"))]
+fn letters(_: &XkbConfig, _: usize) -> Result<BTreeMap<String, String>> {
+    anyhow::bail!("accentd was built without the xkb feature")
+}
+
+/// Every key's unshifted letter in `group` of the layout, `""` for keys
+/// that don't type one.
+#[cfg(feature = "xkb")]
+fn letters(config: &XkbConfig, group: usize) -> Result<BTreeMap<String, String>> {
+    use anyhow::Context;
+    use std::ffi::CString;
+    use tracing::info;
+
+    let mut names =
+        [&config.rules, &config.model, &config.layout, &config.variant, &config.options].map(String::clone);
+    if names.iter().any(String::is_empty) {
+        if let Some(display) = std::env::var("DISPLAY").ok().filter(|d| !d.is_empty()) {
+            match server_names(&display) {
+                Ok(server) => {
+                    for (name, from_server) in names.iter_mut().zip(server) {
+                        if name.is_empty() {
+                            *name = from_server;
+                        }
+                    }
+                }
+                Err(e) => warn!(error = %e, "can't read the X server's keymap names"),
+            }
+        }
+    }
+    info!(rules = %names[0], model = %names[1], layout = %names[2], variant = %names[3], options = %names[4], group, "letters from XKB");
+
+    let names: Vec<CString> = names
+        .into_iter()
+        .map(CString::new)
+        .collect::<Result<_, _>>()
+        .context("NUL in [devices.xkb]")?;
+    // libxkbcommon only takes NULL, not "", to mean "the default"
+    let ptr = |i: usize| if names[i].is_empty() { std::ptr::null() } else { names[i].as_ptr() };
+    let rmlvo = ffi::RuleNames { rules: ptr(0), model: ptr(1), layout: ptr(2), variant: ptr(3), options: ptr(4) };
+
+    // SAFETY: each object is checked for NULL before use and released once;
+    // `rmlvo`'s strings outlive the call that reads them.
+    unsafe {
+        let context = ffi::xkb_context_new(0);
+        anyhow::ensure!(!context.is_null(), "creating an XKB context");
+        let keymap = ffi::xkb_keymap_new_from_names(context, &rmlvo, 0);
+        ffi::xkb_context_unref(context);
+        anyhow::ensure!(!keymap.is_null(), "no XKB keymap for {:?}", config);
+        let state = ffi::xkb_state_new(keymap);
+        ffi::xkb_keymap_unref(keymap);
+        anyhow::ensure!(!state.is_null(), "creating an XKB state");
+        ffi::xkb_state_update_mask(state, 0, 0, 0, 0, 0, group as u32);
+        let letters = (1..=255u16)
+            .map(|code| {
+                // XKB keycodes are evdev's plus 8
+                let c = char::from_u32(ffi::xkb_state_key_get_utf32(state, code as u32 + 8)).unwrap_or('\0');
+                let letter = if c.is_alphabetic() { c.to_lowercase().collect() } else { String::new() };
+                (code.to_string(), letter)
+            })
+            .collect();
+        ffi::xkb_state_unref(state);
+        Ok(letters)
+    }
+}
+
+/// The X server's RMLVO names (`_XKB_RULES_NAMES` on the root window).
+#[cfg(feature = "xkb")]
+fn server_names(display: &str) -> Result<Vec<String>> {
+    use anyhow::Context;
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xproto::{AtomEnum, ConnectionExt};
+
+    let (conn, screen) = x11rb::connect(Some(display)).with_context(|| format!("connecting to X display {}", display))?;
+    let root = conn.setup().roots[screen].root;
+    let atom = conn.intern_atom(false, b"_XKB_RULES_NAMES")?.reply()?.atom;
+    let reply = conn.get_property(false, root, atom, AtomEnum::STRING, 0, 1024)?.reply()?;
+    Ok(reply.value.split(|&b| b == 0).map(|name| String::from_utf8_lossy(name).into_owned()).collect())
+}
+
+#[cfg(feature = "xkb")]
+mod ffi {
+    use std::os::raw::{c_char, c_int, c_void};
+
+    #[repr(C)]
+    pub struct RuleNames {
+        pub rules: *const c_char,
+        pub model: *const c_char,
+        pub layout: *const c_char,
+        pub variant: *const c_char,
+        pub options: *const c_char,
+    }
+
+    #[link(name = "xkbcommon")]
+    extern "C" {
+        pub fn xkb_context_new(flags: c_int) -> *mut c_void;
+        pub fn xkb_context_unref(context: *mut c_void);
+        pub fn xkb_keymap_new_from_names(context: *mut c_void, names: *const RuleNames, flags: c_int) -> *mut c_void;
+        pub fn xkb_keymap_unref(keymap: *mut c_void);
+        pub fn xkb_state_new(keymap: *mut c_void) -> *mut c_void;
+        pub fn xkb_state_unref(state: *mut c_void);
+        pub fn xkb_state_update_mask(
+            state: *mut c_void,
+            depressed_mods: u32,
+            latched_mods: u32,
+            locked_mods: u32,
+            depressed_layout: u32,
+            latched_layout: u32,
+            locked_layout: u32,
+        ) -> c_int;
+        pub fn xkb_state_key_get_utf32(state: *mut c_void, key: u32) -> u32;
+    }
+}
+
+#[cfg(all(test, feature = "xkb"))]
+mod tests {
+    use super::*;
+
+    // --- spec: letters come from the XKB layout ---
+
+    #[test]
+    fn azerty_letters_from_xkb() {
+        let mut config = Config::default();
+        config.devices.xkb = XkbConfig { enabled: true, rules: "evdev".into(), layout: "fr".into(), ..Default::default() };
+        config.keymap = Keymap::try_from(BTreeMap::from([("18".to_string(), String::new())])).unwrap();
+        let keymap = keymap(&config, 0);
+        let us = accentd_core::charmap::Layout::Us;
+        assert_eq!(keymap.base(us, 16), Some("a"), "KEY_Q types a on AZERTY");
+        assert_eq!(keymap.base(us, 30), None, "KEY_A types q");
+        assert_eq!(keymap.base(us, 18), None, "[keymap] wins");
+    }
+
+    #[test]
+    fn later_groups_have_their_own_letters() {
+        let mut config = Config::default();
+        config.devices.xkb = XkbConfig { enabled: true, rules: "evdev".into(), layout: "us,fr".into(), ..Default::default() };
+        let us = accentd_core::charmap::Layout::Us;
+        assert_eq!(keymap(&config, 0).base(us, 30), Some("a"));
+        assert_eq!(keymap(&config, 1).base(us, 16), Some("a"), "KEY_Q types a in the fr group");
+    }
+
+    #[test]
+    fn cyrillic_letters_from_xkb() {
+        let mut config = Config::default();
+        config.devices.xkb = XkbConfig { enabled: true, rules: "evdev".into(), layout: "ru".into(), ..Default::default() };
+        let keymap = keymap(&config, 0);
+        let us = accentd_core::charmap::Layout::Us;
+        assert_eq!(keymap.base(us, 20), Some("е"), "KEY_T types е on ЙЦУКЕН");
+        assert_eq!(keymap.base(us, 48), Some("и"));
+        assert_eq!(keymap.base(us, 30), None, "KEY_A types ф");
+    }
+}
@@ -0,0 +1,253 @@
+//! End-to-end tests: a synthetic uinput keyboard feeds the real daemon binary,
+//! and we read what comes out of its virtual keyboard.
+//!
+//! Needs write access to /dev/uinput and read access to /dev/input (root or
+//! the `input` group plus the udev rule). Without it the tests skip themselves.
+
+use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+use evdev::{AttributeSet, Device, EventType, InputEvent, Key};
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Name of the synthetic input keyboard; the daemon reads only this one.
+const INPUT_NAME: &str = "e2e synthetic keyboard";
+const OUTPUT_NAME: &str = "accentd virtual keyboard";
+const THRESHOLD_MS: u64 = 200;
+
+/// Daemons share the output device name, so run one at a time.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+struct Harness {
+    daemon: Child,
+    input: VirtualDevice,
+    output: Device,
+    socket: PathBuf,
+    _dir: tempfile::TempDir,
+}
+
+impl Harness {
+    /// Start a daemon reading from a fresh synthetic keyboard, or None when
+    /// this machine doesn't allow uinput.
+    fn start() -> Option<Self> {
+        if std::fs::OpenOptions::new().write(true).open("/dev/uinput").is_err() {
+            eprintln!("skipping: /dev/uinput not writable");
+            return None;
+        }
+
+        let mut keys = AttributeSet::<Key>::new();
+        for code in 1..=255u16 {
+            keys.insert(Key::new(code));
+        }
+        let mut input = VirtualDeviceBuilder::new()
+            .ok()?
+            .name(INPUT_NAME)
+            .with_keys(&keys)
+            .ok()?
+            .build()
+            .ok()?;
+        wait_for(|| input.enumerate_dev_nodes_blocking().ok()?.flatten().next());
+
+        let dir = tempfile::tempdir().unwrap();
+        let config_dir = dir.path().join("accentd");
+        std::fs::create_dir_all(&config_dir).unwrap();
+        std::fs::write(
+            config_dir.join("config.toml"),
+            format!(
+                r#"
+[general]
+threshold_ms = {THRESHOLD_MS}
+suspend_on_lock = false
+
+[popup]
+keep_open = true
+
+[compositor]
+provider = "none"
+
+[devices]
+upstream = ["{INPUT_NAME}"]
+
+[locale]
+active = "it"
+"#
+            ),
+        )
+        .unwrap();
+
+        let socket = dir.path().join("accentd.sock");
+        let daemon = Command::new(env!("CARGO_BIN_EXE_accentd"))
+            .env("XDG_CONFIG_HOME", dir.path())
+            .env("ACCENTD_SOCK", &socket)
+            .spawn()
+            .expect("spawning accentd");
+
+        wait_for(|| socket.exists().then_some(())).expect("daemon never opened its socket");
+
+        let output = wait_for(find_output).expect("daemon output device not found");
+        Some(Self { daemon, input, output, socket, _dir: dir })
+    }
+
+    fn key(&mut self, code: Key, value: i32) {
+        self.input
+            .emit(&[InputEvent::new(EventType::KEY, code.code(), value)])
+            .unwrap();
+    }
+
+    fn tap(&mut self, code: Key) {
+        self.key(code, 1);
+        self.key(code, 0);
+    }
+
+    fn send(&self, line: &str) {
+        let mut stream = UnixStream::connect(&self.socket).unwrap();
+        stream.write_all(line.as_bytes()).unwrap();
+        stream.write_all(b"\n").unwrap();
+        // Give the daemon a moment to handle it before the stream closes
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    /// Key events seen on the output until `until` matches one or the timeout.
+    fn read_until(&mut self, until: (Key, i32), timeout: Duration) -> Vec<(Key, i32)> {
+        let deadline = Instant::now() + timeout;
+        let mut seen = Vec::new();
+        while Instant::now() < deadline {
+            let Ok(events) = self.output.fetch_events() else {
+                std::thread::sleep(Duration::from_millis(5));
+                continue;
+            };
+            for ev in events.filter(|e| e.event_type() == EventType::KEY) {
+                seen.push((Key::new(ev.code()), ev.value()));
+                if seen.last() == Some(&until) {
+                    return seen;
+                }
+            }
+        }
+        seen
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+fn wait_for<T>(mut probe: impl FnMut() -> Option<T>) -> Option<T> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if let Some(found) = probe() {
+            return Some(found);
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    None
+}
+
+/// The most recently created accentd output device, opened non-blocking.
+fn find_output() -> Option<Device> {
+    let (_, dev) = evdev::enumerate()
+        .filter(|(_, dev)| dev.name() == Some(OUTPUT_NAME))
+        .max_by_key(|(path, _)| event_number(path))?;
+    set_nonblocking(&dev);
+    Some(dev)
+}
+
+fn event_number(path: &Path) -> u32 {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .and_then(|n| n.strip_prefix("event"))
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0)
+}
+
+fn set_nonblocking(dev: &Device) {
+    use std::os::fd::AsRawFd;
+    let fd = dev.as_raw_fd();
+    let flags = nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_GETFL).unwrap();
+    let flags = nix::fcntl::OFlag::from_bits_truncate(flags) | nix::fcntl::OFlag::O_NONBLOCK;
+    nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_SETFL(flags)).unwrap();
+}
+
+#[test]
+fn plain_key_is_relayed() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(mut h) = Harness::start() else { return };
+
+    h.tap(Key::KEY_F);
+    let seen = h.read_until((Key::KEY_F, 0), Duration::from_secs(2));
+    assert_eq!(seen, vec![(Key::KEY_F, 1), (Key::KEY_F, 0)]);
+}
+
+#[test]
+fn hold_and_select_composes_accent() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(mut h) = Harness::start() else { return };
+
+    h.key(Key::KEY_E, 1);
+    std::thread::sleep(Duration::from_millis(THRESHOLD_MS + 100));
+    h.key(Key::KEY_E, 0);
+    h.tap(Key::KEY_1);
+
+    let seen = h.read_until((Key::KEY_ENTER, 0), Duration::from_secs(3));
+    let expected = [
+        // Base letter typed immediately, released when the popup opens
+        (Key::KEY_E, 1),
+        (Key::KEY_E, 0),
+        // Delete it, then Ctrl+Shift+U 00e8 Enter for "è"
+        (Key::KEY_BACKSPACE, 1),
+        (Key::KEY_BACKSPACE, 0),
+        (Key::KEY_LEFTCTRL, 1),
+        (Key::KEY_LEFTSHIFT, 1),
+        (Key::KEY_U, 1),
+        (Key::KEY_U, 0),
+        (Key::KEY_LEFTSHIFT, 0),
+        (Key::KEY_LEFTCTRL, 0),
+        (Key::KEY_0, 1),
+        (Key::KEY_0, 0),
+        (Key::KEY_0, 1),
+        (Key::KEY_0, 0),
+        (Key::KEY_E, 1),
+        (Key::KEY_E, 0),
+        (Key::KEY_8, 1),
+        (Key::KEY_8, 0),
+        (Key::KEY_ENTER, 1),
+        (Key::KEY_ENTER, 0),
+    ];
+    assert_eq!(seen, expected);
+}
+
+#[test]
+fn ipc_select_composes_accent() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(mut h) = Harness::start() else { return };
+
+    h.key(Key::KEY_A, 1);
+    std::thread::sleep(Duration::from_millis(THRESHOLD_MS + 100));
+    h.send(r#"{"type":"select","index":1}"#);
+    h.key(Key::KEY_A, 0);
+
+    let seen = h.read_until((Key::KEY_ENTER, 0), Duration::from_secs(3));
+    // "à" is U+00E0
+    let hex: Vec<_> = seen.iter().filter(|(_, v)| *v == 1).map(|(k, _)| *k).collect();
+    assert!(hex.ends_with(&[Key::KEY_0, Key::KEY_0, Key::KEY_E, Key::KEY_0, Key::KEY_ENTER]), "{:?}", seen);
+}
+
+#[test]
+fn disabled_daemon_only_relays() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(mut h) = Harness::start() else { return };
+
+    h.send(r#"{"type":"disable"}"#);
+    h.key(Key::KEY_E, 1);
+    std::thread::sleep(Duration::from_millis(THRESHOLD_MS + 100));
+    h.key(Key::KEY_E, 0);
+    h.tap(Key::KEY_1);
+
+    let seen = h.read_until((Key::KEY_1, 0), Duration::from_secs(2));
+    assert_eq!(seen, vec![(Key::KEY_E, 1), (Key::KEY_E, 0), (Key::KEY_1, 1), (Key::KEY_1, 0)]);
+}
@@ -5,7 +5,7 @@
 set -e
 
 cd "$(dirname "$0")"
-cargo build --release 2>&1
+cargo build --release --workspace 2>&1
 
 export ACCENTD_SOCK="/tmp/accentd-test.sock"
 sudo rm -f "$ACCENTD_SOCK"